@@ -0,0 +1,108 @@
+//! Compares ways of splitting a POST body into payloads, and of parsing each payload, across
+//! payload sizes. Run with `cargo bench --bench json_stream`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::value::RawValue;
+
+/// About this many bytes of payloads go in each body.
+const BODY_BYTES: usize = 1 << 20;
+
+/// A JSON object of about `size` bytes, with nesting and escapes like real events.
+fn payload(size: usize, seq: usize) -> String {
+    let head = serde_json::json!({
+        "seq": seq,
+        "level": "info",
+        "device": {"id": "a1b2c3", "os": "linux", "tags": [1, 2, 3]},
+        "message": "quoted \"text\" and unicode \u{e9}\u{4e2d}",
+        "pad": "",
+    })
+    .to_string();
+    let pad = "x".repeat(size.saturating_sub(head.len()));
+    head.replacen(r#""pad":"""#, &format!(r#""pad":"{}""#, pad), 1)
+}
+
+fn body(size: usize) -> Vec<u8> {
+    let mut body = vec![];
+    for seq in 0..(BODY_BYTES / size).max(1) {
+        body.extend_from_slice(payload(size, seq).as_bytes());
+        body.push(b'\n');
+    }
+    body
+}
+
+/// Splitting a body as the server does, skipping over each value and then checking the payload is
+/// UTF-8, against deserializing borrowed raw values, which checks UTF-8 as it goes.
+fn split_body(c: &mut Criterion) {
+    let mut group = c.benchmark_group("split_body");
+    for size in [128, 1024, 16 << 10] {
+        let body = body(size);
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_with_input(BenchmarkId::new("ignored_any", size), &body, |b, body| {
+            b.iter(|| {
+                let mut values =
+                    serde_json::Deserializer::from_slice(body).into_iter::<serde::de::IgnoredAny>();
+                let mut start = 0;
+                let mut count = 0;
+                while let Some(value) = values.next() {
+                    value.unwrap();
+                    let end = values.byte_offset();
+                    std::str::from_utf8(&body[start..end]).unwrap();
+                    start = end;
+                    count += 1;
+                }
+                count
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("raw_value", size), &body, |b, body| {
+            b.iter(|| {
+                serde_json::Deserializer::from_slice(body)
+                    .into_iter::<&RawValue>()
+                    .map(|value| value.unwrap().get().len())
+                    .sum::<usize>()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// What a backend could do with a payload it's handed: parse it into a tree with serde_json or
+/// simd-json, or only validate it as a raw value.
+fn parse_payload(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_payload");
+    for size in [128, 1024, 16 << 10] {
+        let payload = payload(size, 0);
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("serde_value", size),
+            &payload,
+            |b, payload| b.iter(|| serde_json::from_str::<serde_json::Value>(payload).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("simd_json", size),
+            &payload,
+            |b, payload| {
+                // simd-json parses in place, so each iteration needs its own copy.
+                b.iter(|| {
+                    let mut bytes = payload.as_bytes().to_vec();
+                    simd_json::to_owned_value(&mut bytes).unwrap()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("raw_value", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    serde_json::from_str::<&RawValue>(payload)
+                        .unwrap()
+                        .get()
+                        .len()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, split_body, parse_payload);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+use super::*;
+use serde_json::{json, Map};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Events from these targets aren't sent, since sending them makes more of them.
+const IGNORED_TARGETS: [&str; 6] = [
+    "telemetry_client",
+    "reqwest",
+    "hyper",
+    "h2",
+    "native_tls",
+    "tokio",
+];
+
+/// Sends `tracing` events to the server as JSON objects with the time, level, target, fields and
+/// the names of the spans the event is in, outermost first. Events are dropped rather than
+/// waited on when the client's buffer is full. Made with [Client::layer].
+pub struct TelemetryLayer {
+    client: Client,
+}
+
+impl TelemetryLayer {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+impl<S> Layer<S> for TelemetryLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if IGNORED_TARGETS
+            .iter()
+            .any(|target| metadata.target().starts_with(target))
+        {
+            return;
+        }
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        let spans: Vec<&str> = ctx
+            .event_scope(event)
+            .map(|scope| scope.from_root().map(|span| span.name()).collect())
+            .unwrap_or_default();
+        let payload = json!({
+            "timestamp": humantime::format_rfc3339_micros(std::time::SystemTime::now()).to_string(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+            "spans": spans,
+        });
+        // The client counts what it drops. Logging it here would only come back around.
+        let _ = self.client.send(payload);
+    }
+}
+
+/// An event's fields as JSON values, with the types tracing records them as.
+#[derive(Default)]
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value).into());
+    }
+}
@@ -0,0 +1,460 @@
+//! Submits events to a telemetry server from Rust applications. A [Client] buffers events and
+//! posts them to one stream in batches, retrying with backoff, and [TelemetryLayer] sends
+//! `tracing` events through it. Events the server can't take can be spilled to a file and
+//! replayed later.
+
+mod layer;
+mod spill;
+#[cfg(test)]
+mod tests;
+pub use layer::TelemetryLayer;
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING};
+use reqwest::{StatusCode, Url};
+use serde_json::{json, Value};
+use spill::Spill;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+/// Returned by the server when a stream is created, and sent back to add to it.
+const STREAM_TOKEN_HEADER: &str = "x-stream-token";
+
+/// This machine's clock, from which the server works out how far off it is when a stream starts.
+const CLIENT_NOW_HEADER: &str = "x-client-now";
+
+/// How a [Client] submits events. Start with [Client::builder].
+#[derive(Clone)]
+pub struct ClientBuilder {
+    url: Url,
+    headers: HeaderMap,
+    batch_size: usize,
+    flush_interval: Duration,
+    buffer_size: usize,
+    gzip: bool,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    spill: Option<(PathBuf, u64)>,
+    heartbeat_interval: Option<Duration>,
+}
+
+impl ClientBuilder {
+    /// A header to send with each request. The server stores the headers a stream was started
+    /// with, so this can identify the device or application.
+    pub fn header(mut self, name: &str, value: &str) -> Result<Self> {
+        self.headers
+            .insert(HeaderName::try_from(name)?, HeaderValue::try_from(value)?);
+        Ok(self)
+    }
+
+    /// Sent as `Authorization: Bearer <token>`, for servers that require one.
+    pub fn auth_token(self, token: &str) -> Result<Self> {
+        self.header("authorization", &format!("Bearer {}", token))
+    }
+
+    /// Events are posted once this many are waiting. Defaults to 100.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Waiting events are posted at least this often. Defaults to a second.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Events beyond this many waiting to be posted are dropped, or spilled with
+    /// [Self::spill_file]. Defaults to 10000.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    /// Compress request bodies with gzip.
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// How many times a batch is retried after connection errors and server errors before it's
+    /// dropped, or spilled with [Self::spill_file]. The wait between attempts starts at `initial_backoff` and doubles up to
+    /// `max_backoff`. Defaults to 5 retries, from 100ms up to 30s.
+    pub fn retries(
+        mut self,
+        max_retries: u32,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    ) -> Self {
+        self.max_retries = max_retries;
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Instead of dropping events when the buffer is full or a batch runs out of retries, append
+    /// them to the file at `path`, up to `max_bytes`. They're posted before the next batch once
+    /// the server takes events again, including by a later client given the same file.
+    pub fn spill_file(mut self, path: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        self.spill = Some((path.into(), max_bytes));
+        self
+    }
+
+    /// Post a `{"type": "heartbeat"}` event when the stream has had nothing posted for this long,
+    /// so the server can tell a quiet application from one that's gone. Checked each flush
+    /// interval. Off by default.
+    pub fn heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = Some(heartbeat_interval);
+        self
+    }
+
+    /// Starts submitting in the background. Needs a Tokio runtime.
+    pub fn build(self) -> Client {
+        let (sender, receiver) = mpsc::channel(self.buffer_size);
+        let spill = self
+            .spill
+            .clone()
+            .map(|(path, max_bytes)| Arc::new(Spill::new(path, max_bytes)));
+        let worker = Worker {
+            http: reqwest::Client::new(),
+            stream_token: None,
+            event_id_prefix: format!("{:016x}", rand::random::<u64>()),
+            next_event_id: 0,
+            last_stored: Instant::now(),
+            spill: spill.clone(),
+            options: self,
+        };
+        tokio::spawn(worker.run(receiver));
+        Client {
+            sender,
+            dropped: Default::default(),
+            spill,
+        }
+    }
+}
+
+enum Message {
+    Event(Value),
+    Flush(oneshot::Sender<Result<()>>),
+    Close(oneshot::Sender<Result<()>>),
+}
+
+/// Submits events to a stream on a telemetry server. Clones submit to the same stream. The
+/// stream is closed by [Self::close], or once every clone has been dropped.
+#[derive(Clone)]
+pub struct Client {
+    sender: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+    spill: Option<Arc<Spill>>,
+}
+
+impl Client {
+    /// Submits to the server at `url`, like "http://localhost:4318/".
+    pub fn builder(url: &str) -> Result<ClientBuilder> {
+        Ok(ClientBuilder {
+            url: url.parse().with_context(|| format!("parsing {:?}", url))?,
+            headers: HeaderMap::new(),
+            batch_size: 100,
+            flush_interval: Duration::from_secs(1),
+            buffer_size: 10000,
+            gzip: false,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            spill: None,
+            heartbeat_interval: None,
+        })
+    }
+
+    /// Queues an event to be posted. Object payloads without an `event_id` are given one, so the
+    /// server drops the copies when a batch is retried. If the buffer is full the event is
+    /// spilled, or if there's no spill file or it's full, fails without waiting and drops the
+    /// event.
+    pub fn send(&self, payload: Value) -> Result<()> {
+        match self.sender.try_send(Message::Event(payload)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(message)) => {
+                if let (Some(spill), Message::Event(payload)) = (&self.spill, message) {
+                    if spill.append(&[payload])? == 0 {
+                        return Ok(());
+                    }
+                }
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                bail!("buffer full, event dropped")
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => bail!("client closed"),
+        }
+    }
+
+    /// Posts the events waiting, and waits for the server to store them.
+    pub async fn flush(&self) -> Result<()> {
+        self.request(Message::Flush).await
+    }
+
+    /// Posts the events waiting and closes the stream. Events sent by clones after this fail.
+    pub async fn close(self) -> Result<()> {
+        self.request(Message::Close).await
+    }
+
+    /// How many events [Self::send] dropped because the buffer and any spill file were full.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// A tracing layer that sends events through this client.
+    pub fn layer(&self) -> TelemetryLayer {
+        TelemetryLayer::new(self.clone())
+    }
+
+    async fn request(&self, message: fn(oneshot::Sender<Result<()>>) -> Message) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        self.sender
+            .send(message(done))
+            .await
+            .map_err(|_| anyhow!("client closed"))?;
+        result.await.map_err(|_| anyhow!("client closed"))?
+    }
+}
+
+/// The outcome of one attempt at posting a batch.
+enum Attempt {
+    Stored,
+    Retry(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+/// Owns the stream, and posts batches to it in order.
+struct Worker {
+    http: reqwest::Client,
+    options: ClientBuilder,
+    stream_token: Option<HeaderValue>,
+    /// Random per client, so event IDs don't collide with other clients'.
+    event_id_prefix: String,
+    next_event_id: u64,
+    /// When the server last took a batch, for heartbeats.
+    last_stored: Instant,
+    spill: Option<Arc<Spill>>,
+}
+
+impl Worker {
+    async fn run(mut self, mut receiver: mpsc::Receiver<Message>) {
+        let mut batch = vec![];
+        let mut interval = tokio::time::interval(self.options.flush_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(Message::Event(payload)) => {
+                        batch.push(self.with_event_id(payload));
+                        if batch.len() >= self.options.batch_size {
+                            self.submit_logged(&mut batch, false).await;
+                        }
+                    }
+                    Some(Message::Flush(done)) => {
+                        let _ = done.send(self.submit(&mut batch, false).await);
+                    }
+                    Some(Message::Close(done)) => {
+                        let _ = done.send(self.submit(&mut batch, true).await);
+                        return;
+                    }
+                    None => {
+                        self.submit_logged(&mut batch, true).await;
+                        return;
+                    }
+                },
+                _ = interval.tick() => {
+                    if batch.is_empty() && self.heartbeat_due() {
+                        batch.push(self.with_event_id(json!({ "type": "heartbeat" })));
+                    }
+                    self.submit_logged(&mut batch, false).await
+                }
+            }
+        }
+    }
+
+    /// Heartbeats are only for streams that have been started.
+    fn heartbeat_due(&self) -> bool {
+        self.stream_token.is_some()
+            && self
+                .options
+                .heartbeat_interval
+                .is_some_and(|heartbeat_interval| self.last_stored.elapsed() >= heartbeat_interval)
+    }
+
+    fn with_event_id(&mut self, mut payload: Value) -> Value {
+        if let Value::Object(fields) = &mut payload {
+            if !fields.contains_key("event_id") {
+                let event_id = format!("{}-{}", self.event_id_prefix, self.next_event_id);
+                fields.insert("event_id".to_owned(), event_id.into());
+                self.next_event_id += 1;
+            }
+        }
+        payload
+    }
+
+    /// For submissions nobody's waiting on.
+    async fn submit_logged(&mut self, batch: &mut Vec<Value>, close: bool) {
+        if let Err(err) = self.submit(batch, close).await {
+            warn!(err = format!("{:#}", err), "submitting telemetry");
+        }
+    }
+
+    /// Posts the batch, retrying as configured, after anything spilled. The batch is emptied
+    /// either way.
+    async fn submit(&mut self, batch: &mut Vec<Value>, close: bool) -> Result<()> {
+        self.replay_spilled().await;
+        // Without a stream there's nothing to close.
+        if batch.is_empty() && !(close && self.stream_token.is_some()) {
+            return Ok(());
+        }
+        let events = std::mem::take(batch);
+        let body = self.encode(&events)?;
+        let mut backoff = self.options.initial_backoff;
+        let mut retries = 0;
+        loop {
+            let err = match self.post(&body, close).await {
+                Attempt::Stored => {
+                    debug!(events = events.len(), "submitted telemetry");
+                    return Ok(());
+                }
+                Attempt::Failed(err) => return Err(err),
+                Attempt::Retry(err) => err,
+            };
+            if retries == self.options.max_retries {
+                if let Some(spill) = &self.spill {
+                    let dropped = spill.append(&events)?;
+                    return Err(err.context(format!(
+                        "spilled {} events to {} after {} attempts, dropping {}",
+                        events.len() - dropped,
+                        spill.path().display(),
+                        retries + 1,
+                        dropped
+                    )));
+                }
+                return Err(err.context(format!(
+                    "dropping {} events after {} attempts",
+                    events.len(),
+                    retries + 1
+                )));
+            }
+            warn!(
+                err = format!("{:#}", err),
+                ?backoff,
+                "retrying telemetry submission"
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.options.max_backoff);
+            retries += 1;
+        }
+    }
+
+    /// Posts spilled events in batches, with no retries. Whatever isn't stored stays spilled.
+    async fn replay_spilled(&mut self) {
+        let Some(spill) = self.spill.clone() else {
+            return;
+        };
+        let result: Result<()> = async {
+            // Events spilled by [Client::send] haven't been given IDs yet.
+            let events: Vec<_> = spill
+                .take()?
+                .into_iter()
+                .map(|event| self.with_event_id(event))
+                .collect();
+            let mut batches = events.chunks(self.options.batch_size);
+            while let Some(events) = batches.next() {
+                let body = self.encode(events)?;
+                match self.post(&body, false).await {
+                    Attempt::Stored => debug!(events = events.len(), "replayed spilled telemetry"),
+                    Attempt::Failed(err) => {
+                        warn!(err = format!("{:#}", err), "dropping spilled telemetry")
+                    }
+                    Attempt::Retry(err) => {
+                        let unsent: Vec<_> = std::iter::once(events)
+                            .chain(batches)
+                            .flatten()
+                            .cloned()
+                            .collect();
+                        spill.append(&unsent)?;
+                        debug!(
+                            err = format!("{:#}", err),
+                            "server not taking spilled telemetry"
+                        );
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            warn!(err = format!("{:#}", err), "replaying spilled telemetry");
+        }
+    }
+
+    fn encode(&self, events: &[Value]) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        for event in events {
+            serde_json::to_writer(&mut body, event)?;
+            body.push(b'\n');
+        }
+        if self.options.gzip {
+            let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(&body)?;
+            body = encoder.finish()?;
+        }
+        Ok(body)
+    }
+
+    async fn post(&mut self, body: &[u8], close: bool) -> Attempt {
+        let mut url = self.options.url.clone();
+        if close {
+            url.query_pairs_mut().append_pair("close", "true");
+        }
+        let mut request = self
+            .http
+            .post(url)
+            .headers(self.options.headers.clone())
+            .body(body.to_vec());
+        if self.options.gzip {
+            request = request.header(CONTENT_ENCODING, "gzip");
+        }
+        match &self.stream_token {
+            Some(stream_token) => request = request.header(STREAM_TOKEN_HEADER, stream_token),
+            None => {
+                let now = humantime::format_rfc3339_millis(std::time::SystemTime::now());
+                request = request.header(CLIENT_NOW_HEADER, now.to_string());
+            }
+        }
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(err) => return Attempt::Retry(err.into()),
+        };
+        if let Some(stream_token) = response.headers().get(STREAM_TOKEN_HEADER) {
+            self.stream_token = Some(stream_token.clone());
+        }
+        let status = response.status();
+        if status == StatusCode::OK {
+            self.last_stored = Instant::now();
+            return Attempt::Stored;
+        }
+        let text = response.text().await.unwrap_or_default();
+        let err = anyhow!("server responded {}: {}", status, text);
+        match status {
+            // The stream was pruned or the server's storage replaced, so start another.
+            StatusCode::NOT_FOUND | StatusCode::FORBIDDEN if self.stream_token.is_some() => {
+                self.stream_token = None;
+                Attempt::Retry(err)
+            }
+            StatusCode::TOO_MANY_REQUESTS => Attempt::Retry(err),
+            status if status.is_server_error() => Attempt::Retry(err),
+            // Including partly stored batches, whose failed events won't do better next time.
+            _ => Attempt::Failed(err),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Events kept on disk as JSON lines while the server can't take them, to be replayed once it
+/// can. Shared by the worker and [crate::Client::send], which spills when the buffer is full.
+pub(crate) struct Spill {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Held while the file is appended to or taken. Both are quick, so senders don't wait long.
+    lock: Mutex<()>,
+}
+
+impl Spill {
+    pub(crate) fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            path,
+            max_bytes,
+            lock: Default::default(),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Appends as many of the events as fit, returning how many didn't.
+    pub(crate) fn append(&self, events: &[Value]) -> Result<usize> {
+        let _lock = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("opening {}", self.path.display()))?;
+        let mut size = file.metadata()?.len();
+        let mut lines = vec![];
+        let mut appended = 0;
+        for event in events {
+            let line = serde_json::to_string(event)? + "\n";
+            if size + line.len() as u64 > self.max_bytes {
+                break;
+            }
+            size += line.len() as u64;
+            lines.extend_from_slice(line.as_bytes());
+            appended += 1;
+        }
+        file.write_all(&lines)?;
+        file.sync_data()?;
+        Ok(events.len() - appended)
+    }
+
+    /// Removes the spilled events from disk and returns them, oldest first. A line cut short by
+    /// a crash is skipped.
+    pub(crate) fn take(&self) -> Result<Vec<Value>> {
+        let _lock = self.lock.lock().unwrap();
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(err).with_context(|| format!("opening {}", self.path.display()))
+            }
+        };
+        let mut events = vec![];
+        for line in BufReader::new(file).lines() {
+            if let Ok(event) = serde_json::from_str(&line?) {
+                events.push(event);
+            }
+        }
+        std::fs::remove_file(&self.path)?;
+        Ok(events)
+    }
+}
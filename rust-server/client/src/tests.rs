@@ -0,0 +1,167 @@
+use super::*;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use telemetry_storage::Memory;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Serves the app on a local port, returning its URL.
+async fn serve(app: axum::Router) -> Result<String> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Ok(url)
+}
+
+async fn stored_events(url: &str) -> Result<Vec<Value>> {
+    let body: Value = reqwest::get(format!("{}api/events", url))
+        .await?
+        .text()
+        .await?
+        .parse()?;
+    Ok(body["events"].as_array().context("no events")?.clone())
+}
+
+#[tokio::test]
+async fn test_client_batches() -> Result<()> {
+    let url = serve(telemetry::router(Box::new(Memory::default()))).await?;
+    let client = Client::builder(&url)?
+        .gzip(true)
+        .batch_size(2)
+        .flush_interval(Duration::from_secs(3600))
+        .build();
+    for index in 0..3 {
+        client.send(json!({ "index": index }))?;
+    }
+    client.send(json!({ "event_id": "mine" }))?;
+    client.clone().close().await?;
+    assert!(client.send(json!({})).is_err());
+    let events = stored_events(&url).await?;
+    assert_eq!(events.len(), 4);
+    // One stream, resumed for each batch.
+    assert!(events
+        .iter()
+        .all(|event| event["stream_id"] == events[0]["stream_id"]));
+    assert_eq!(events[2]["payload"]["index"], 2);
+    assert!(events[2]["payload"]["event_id"].is_string());
+    assert_eq!(events[3]["payload"]["event_id"], "mine");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_retries() -> Result<()> {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::post({
+            let attempts = Arc::clone(&attempts);
+            || async move {
+                match attempts.fetch_add(1, Ordering::Relaxed) {
+                    0 => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::OK,
+                }
+            }
+        }),
+    );
+    let url = serve(app).await?;
+    let client = Client::builder(&url)?
+        .retries(1, Duration::from_millis(1), Duration::from_millis(1))
+        .build();
+    client.send(json!({}))?;
+    client.flush().await?;
+    assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tracing_layer() -> Result<()> {
+    let url = serve(telemetry::router(Box::new(Memory::default()))).await?;
+    let client = Client::builder(&url)?.build();
+    let subscriber = tracing_subscriber::registry().with(client.layer());
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("work").entered();
+        // Events from this crate are ignored, tests included.
+        tracing::info!(target: "app", answer = 42, "hello");
+    });
+    client.flush().await?;
+    let events = stored_events(&url).await?;
+    assert_eq!(events.len(), 1);
+    let payload = &events[0]["payload"];
+    assert_eq!(payload["level"], "INFO");
+    assert_eq!(payload["target"], "app");
+    assert_eq!(payload["fields"], json!({"message": "hello", "answer": 42}));
+    assert_eq!(payload["spans"], json!(["work"]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_spills_while_server_unreachable() -> Result<()> {
+    let up = Arc::new(AtomicBool::new(false));
+    let received = Arc::new(std::sync::Mutex::new(vec![]));
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::post({
+            let up = Arc::clone(&up);
+            let received = Arc::clone(&received);
+            |body: String| async move {
+                if !up.load(Ordering::Relaxed) {
+                    return StatusCode::SERVICE_UNAVAILABLE;
+                }
+                let mut received = received.lock().unwrap();
+                for line in body.lines() {
+                    received.push(line.parse::<Value>().unwrap());
+                }
+                StatusCode::OK
+            }
+        }),
+    );
+    let url = serve(app).await?;
+    let dir = tempfile::tempdir()?;
+    let spill_path = dir.path().join("spill.jsonl");
+    let builder = Client::builder(&url)?
+        .flush_interval(Duration::from_secs(3600))
+        .retries(0, Duration::from_millis(1), Duration::from_millis(1))
+        .spill_file(&spill_path, 1 << 20);
+
+    let client = builder.clone().build();
+    client.send(json!({ "index": 0 }))?;
+    client.send(json!({ "index": 1 }))?;
+    let err = client.flush().await.expect_err("server is down");
+    assert!(format!("{:#}", err).contains("spilled 2 events"));
+    client.close().await?;
+    assert!(spill_path.exists());
+
+    // A later client replays the spill ahead of its own events.
+    up.store(true, Ordering::Relaxed);
+    let client = builder.build();
+    client.send(json!({ "index": 2 }))?;
+    client.flush().await?;
+    assert!(!spill_path.exists());
+    let received = received.lock().unwrap();
+    let indexes: Vec<_> = received.iter().map(|event| &event["index"]).collect();
+    assert_eq!(indexes, [0, 1, 2]);
+    assert!(received.iter().all(|event| event["event_id"].is_string()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_heartbeats() -> Result<()> {
+    let url = serve(telemetry::router(Box::new(Memory::default()))).await?;
+    let client = Client::builder(&url)?
+        .flush_interval(Duration::from_millis(10))
+        .heartbeat_interval(Duration::from_millis(20))
+        .build();
+    // Nothing to keep alive until there's a stream.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(stored_events(&url).await?.is_empty());
+    client.send(json!({ "index": 0 }))?;
+    client.flush().await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.close().await?;
+    let events = stored_events(&url).await?;
+    assert_eq!(events[0]["payload"]["index"], 0);
+    assert!(events.len() > 1);
+    assert!(events[1..]
+        .iter()
+        .all(|event| event["payload"]["type"] == "heartbeat"));
+    Ok(())
+}
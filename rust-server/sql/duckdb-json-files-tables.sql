@@ -9,5 +9,17 @@ CREATE TABLE events(
     stream_event_index integer,
     insert_datetime TIMESTAMP,
     payload JSON,
+    event_id text,
+    collector json,
+    revision integer,
+    client_datetime TIMESTAMP,
     unique (stream_id, stream_event_index)
 );
+
+CREATE TABLE event_revisions(
+    stream_id ubigint references streams(stream_id),
+    stream_event_index integer,
+    revision integer,
+    insert_datetime TIMESTAMP,
+    payload JSON
+);
@@ -1,7 +1,7 @@
 CREATE VIEW streams AS SELECT *
 FROM read_json(
     'json_files/streams.*.json.zst', ("compression" = 'zstd'), (format = 'newline_delimited'), (maximum_depth = 0),
-    ("columns" = main.struct_pack(start_datetime := 'timestamp', headers := 'json', stream_id := 'ubigint')));
+    ("columns" = main.struct_pack(start_datetime := 'timestamp', headers := 'json', stream_id := 'ubigint', stream_uuid := 'uuid')));
 
 
 CREATE VIEW events AS SELECT *
@@ -14,4 +14,31 @@ FROM read_json(
         insert_datetime := 'timestamp',
         payload := 'json',
         stream_id := 'ubigint',
-        stream_event_index := 'integer')));
+        stream_uuid := 'uuid',
+        stream_event_index := 'integer',
+        event_id := 'text',
+        collector := 'json',
+        revision := 'integer',
+        client_datetime := 'timestamp')));
+
+
+-- Corrected events appear once per revision in the files. This picks the latest of each.
+CREATE VIEW latest_events AS SELECT *
+FROM events
+QUALIFY row_number() OVER (
+    PARTITION BY stream_id, stream_uuid, stream_event_index
+    ORDER BY coalesce(revision, 0) DESC) = 1;
+
+
+-- Lines with only a clock_skew_ms were recorded when the stream started, not at its end.
+CREATE VIEW stream_ends AS SELECT *
+FROM read_json(
+    'json_files/stream_ends.*.json.zst',
+    "compression" = 'zstd',
+    format = 'newline_delimited',
+    (columns = main.struct_pack(
+        stream_id := 'ubigint',
+        stream_uuid := 'uuid',
+        end_datetime := 'timestamp',
+        event_count := 'ubigint',
+        clock_skew_ms := 'bigint')));
\ No newline at end of file
@@ -3,7 +3,11 @@ create sequence seq_stream_id;
 CREATE TABLE streams(
     stream_id integer primary key default nextval('seq_stream_id'),
     headers text not null,
-    start_timestamp timestamp not null default current_timestamp
+    start_timestamp timestamp not null default current_timestamp,
+    end_datetime timestamp,
+    event_count ubigint,
+    clock_skew_ms bigint,
+    stream_uuid uuid unique
 );
 
 CREATE TABLE events(
@@ -11,5 +15,26 @@ CREATE TABLE events(
     insert_timestamp timestamp default current_timestamp,
     payload blob not null,
     stream_id integer references streams(stream_id),
-    primary key (stream_id, stream_event_index)
-);
\ No newline at end of file
+    event_id text,
+    collector text,
+    client_datetime timestamp,
+    revision integer not null default 0,
+    primary key (stream_id, stream_event_index),
+    unique (stream_id, event_id)
+);
+
+-- Earlier revisions of events, replaced in events by corrections.
+CREATE TABLE event_revisions(
+    stream_id integer references streams(stream_id),
+    stream_event_index integer,
+    revision integer not null,
+    insert_timestamp timestamp,
+    payload blob not null
+);
+
+CREATE VIEW event_history AS
+    SELECT stream_id, stream_event_index, revision, insert_timestamp, payload, false AS latest
+    FROM event_revisions
+    UNION ALL
+    SELECT stream_id, stream_event_index, revision, insert_timestamp, payload, true AS latest
+    FROM events;
\ No newline at end of file
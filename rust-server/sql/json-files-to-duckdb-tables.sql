@@ -8,22 +8,48 @@ FROM read_json(
     'json_files/streams.*.json.zst', ("compression" = 'zstd'), (format = 'newline_delimited'), (maximum_depth = 0),
     ("columns" = main.struct_pack(start_datetime := 'timestamp', headers := 'json', stream_id := 'ubigint')));
 
-insert into events (
-    insert_datetime,
-    payload,
-    stream_id,
-    stream_event_index)
+-- Corrected events appear once per revision. The latest goes into events, and the rest into
+-- event_revisions. Revision lines don't repeat the event ID and collector of the original.
+create temp table event_lines as
 select
-    json->>'insert_datetime',
-    json->>'payload',
-    json->>'stream_id',
-    json->>'stream_event_index'
+    json->>'insert_datetime' as insert_datetime,
+    json->>'payload' as payload,
+    json->>'stream_id' as stream_id,
+    json->>'stream_event_index' as stream_event_index,
+    max(json->>'event_id') over event as event_id,
+    max(json->'collector') over event as collector,
+    max(json->>'client_datetime') over event as client_datetime,
+    coalesce(cast(json->>'revision' as integer), 0) as revision,
+    row_number() over (event order by coalesce(cast(json->>'revision' as integer), 0) desc) = 1 as latest
 from read_json(
       'json_files/events.*.json.zst',
       "compression" = 'zstd',
       format = 'newline_delimited',
       maximum_depth = 0,
-      records = false);
+      records = false)
+window event as (partition by json->>'stream_id', json->>'stream_event_index');
+
+insert into events (
+    insert_datetime,
+    payload,
+    stream_id,
+    stream_event_index,
+    event_id,
+    collector,
+    revision,
+    client_datetime)
+select insert_datetime, payload, stream_id, stream_event_index, event_id, collector, revision,
+    client_datetime
+from event_lines where latest;
+
+insert into event_revisions (
+    insert_datetime,
+    payload,
+    stream_id,
+    stream_event_index,
+    revision)
+select insert_datetime, payload, stream_id, stream_event_index, revision
+from event_lines where not latest;
 
 --insert into events (
 --    insert_datetime,
@@ -0,0 +1,3 @@
+-- When clients say events happened by their own clocks, and how far off those clocks were.
+ALTER TABLE events ADD COLUMN IF NOT EXISTS client_datetime TIMESTAMP;
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS clock_skew_ms BIGINT;
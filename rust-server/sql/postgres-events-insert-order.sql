@@ -0,0 +1,2 @@
+-- An index for reading events in insert order a batch at a time.
+CREATE INDEX IF NOT EXISTS events_insert_order ON events(insert_datetime, stream_id, stream_event_index);
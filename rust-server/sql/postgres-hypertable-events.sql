@@ -0,0 +1,5 @@
+-- Readies the events table to become a TimescaleDB hypertable, whose unique indexes have to
+-- include the insert time. Event IDs are then kept unique by postgres-unique-event-ids.sql, which
+-- finds them with this index.
+DROP INDEX IF EXISTS events_stream_event_id;
+CREATE INDEX events_stream_event_id ON events(stream_id, event_id);
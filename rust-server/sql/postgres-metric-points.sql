@@ -0,0 +1,8 @@
+-- Metric data points, each a number at a time, kept apart from events so they can be queried.
+CREATE TABLE IF NOT EXISTS metric_points(
+  name TEXT NOT NULL,
+  resource JSONB,
+  attributes JSONB,
+  datetime TIMESTAMP NOT NULL,
+  value DOUBLE PRECISION NOT NULL);
+CREATE INDEX IF NOT EXISTS metric_points_name_datetime ON metric_points(name, datetime);
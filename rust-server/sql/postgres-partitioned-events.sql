@@ -0,0 +1,37 @@
+-- Replaces the events table with one partitioned by insert time. The existing table is kept as
+-- events_unpartitioned, to be attached as the partition of everything before the first new one,
+-- and its indexes are renamed to match so the new table's indexes can take their names. Event IDs
+-- are then kept unique by postgres-unique-event-ids.sql.
+ALTER TABLE events RENAME TO events_unpartitioned;
+DO $$
+DECLARE
+  index_name TEXT;
+BEGIN
+  FOR index_name IN
+    SELECT indexname FROM pg_indexes
+    WHERE schemaname = current_schema() AND tablename = 'events_unpartitioned'
+      AND indexname LIKE 'events\_%'
+  LOOP
+    EXECUTE format('ALTER INDEX %I RENAME TO %I',
+      index_name, 'events_unpartitioned' || substr(index_name, 7));
+  END LOOP;
+END $$;
+CREATE TABLE events (LIKE events_unpartitioned INCLUDING DEFAULTS INCLUDING GENERATED)
+  PARTITION BY RANGE (insert_datetime);
+ALTER TABLE events ADD FOREIGN KEY (stream_id) REFERENCES streams(stream_id);
+CREATE INDEX IF NOT EXISTS events_insert_order ON events(insert_datetime, stream_id, stream_event_index);
+-- The existing table gets this trigger back from the partitioned one when it's attached.
+DROP TRIGGER IF EXISTS events_stream_stats ON events_unpartitioned;
+CREATE TRIGGER events_stream_stats
+  AFTER INSERT OR DELETE OR UPDATE OF stream_id, payload, insert_datetime ON events
+  FOR EACH ROW EXECUTE FUNCTION events_stream_stats();
+-- Events outside every partition, like old ones being imported.
+CREATE TABLE events_default PARTITION OF events DEFAULT;
+CREATE UNIQUE INDEX events_default_stream_event_id ON events_default(stream_id, event_id);
+-- Views follow the table they were made with through the rename, so this one is made again.
+CREATE OR REPLACE VIEW event_history AS
+  SELECT stream_id, stream_event_index, revision, insert_datetime, payload, FALSE AS latest
+  FROM event_revisions
+  UNION ALL
+  SELECT stream_id, stream_event_index, revision, insert_datetime, payload, TRUE AS latest
+  FROM events;
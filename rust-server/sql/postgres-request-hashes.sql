@@ -0,0 +1,5 @@
+-- Hashes of recent request bodies, and when each was first received, to catch duplicates.
+CREATE TABLE IF NOT EXISTS request_hashes(
+  hash TEXT PRIMARY KEY,
+  received_datetime TIMESTAMP NOT NULL);
+CREATE INDEX IF NOT EXISTS request_hashes_received ON request_hashes(received_datetime);
@@ -0,0 +1,8 @@
+-- Counts of events by a group in buckets of insert time, kept up to date by rollups.
+CREATE TABLE IF NOT EXISTS rollups(
+  name TEXT NOT NULL,
+  definition TEXT NOT NULL,
+  bucket_start TIMESTAMP NOT NULL,
+  group_key TEXT,
+  count BIGINT NOT NULL);
+CREATE INDEX IF NOT EXISTS rollups_name_bucket ON rollups(name, bucket_start);
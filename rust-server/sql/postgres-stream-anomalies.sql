@@ -0,0 +1,7 @@
+-- Stream event indexes that have no stored event, and why.
+CREATE TABLE IF NOT EXISTS stream_anomalies(
+  stream_id INTEGER NOT NULL,
+  stream_event_index INTEGER NOT NULL,
+  kind TEXT NOT NULL,
+  detected_datetime TIMESTAMP NOT NULL,
+  details TEXT);
@@ -0,0 +1,7 @@
+-- Key-value labels on streams, which streams can be found by.
+CREATE TABLE IF NOT EXISTS stream_labels(
+  stream_id INTEGER REFERENCES streams(stream_id) ON DELETE CASCADE NOT NULL,
+  key TEXT NOT NULL,
+  value TEXT NOT NULL,
+  PRIMARY KEY (stream_id, key));
+CREATE INDEX IF NOT EXISTS stream_labels_key_value ON stream_labels(key, value, stream_id);
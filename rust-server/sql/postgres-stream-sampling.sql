@@ -0,0 +1,2 @@
+-- The sampling policy applied to a stream's events, and how many it kept and dropped.
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS sampling JSONB;
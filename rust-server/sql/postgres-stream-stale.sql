@@ -0,0 +1,2 @@
+-- When open streams went quiet, set and cleared by stale stream detection.
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS stale_datetime TIMESTAMP;
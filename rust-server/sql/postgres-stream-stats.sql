@@ -0,0 +1,26 @@
+-- Counts of each stream's stored events, kept up to date as they change, so listing streams
+-- doesn't have to count them.
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS stored_event_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS stored_payload_bytes BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS last_insert_datetime TIMESTAMP;
+UPDATE streams SET (stored_event_count, stored_payload_bytes, last_insert_datetime) = (
+  SELECT COUNT(*), COALESCE(SUM(pg_column_size(payload)), 0), MAX(insert_datetime)
+  FROM events WHERE events.stream_id = streams.stream_id);
+CREATE OR REPLACE FUNCTION events_stream_stats() RETURNS trigger LANGUAGE plpgsql AS $$
+BEGIN
+  IF TG_OP IN ('DELETE', 'UPDATE') THEN
+    UPDATE streams SET stored_event_count = stored_event_count - 1,
+      stored_payload_bytes = stored_payload_bytes - pg_column_size(OLD.payload)
+    WHERE stream_id = OLD.stream_id;
+  END IF;
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    UPDATE streams SET stored_event_count = stored_event_count + 1,
+      stored_payload_bytes = stored_payload_bytes + pg_column_size(NEW.payload),
+      last_insert_datetime = GREATEST(last_insert_datetime, NEW.insert_datetime)
+    WHERE stream_id = NEW.stream_id;
+  END IF;
+  RETURN NULL;
+END $$;
+CREATE TRIGGER events_stream_stats
+  AFTER INSERT OR DELETE OR UPDATE OF stream_id, payload, insert_datetime ON events
+  FOR EACH ROW EXECUTE FUNCTION events_stream_stats();
@@ -0,0 +1,4 @@
+-- UUIDv7s for streams, which unlike the serial IDs don't collide between databases. Existing
+-- streams keep only their serial IDs.
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS stream_uuid UUID;
+CREATE UNIQUE INDEX IF NOT EXISTS streams_stream_uuid ON streams(stream_uuid);
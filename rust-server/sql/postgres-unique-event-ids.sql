@@ -0,0 +1,17 @@
+-- Unique indexes on partitioned tables and hypertables have to include the partition key, so
+-- event IDs are kept unique across partitions by this trigger instead, which skips events repeating
+-- one. The lock holds back another transaction storing the same event until this one has finished.
+CREATE OR REPLACE FUNCTION events_unique_event_id() RETURNS trigger LANGUAGE plpgsql AS $$
+BEGIN
+  IF NEW.event_id IS NULL THEN
+    RETURN NEW;
+  END IF;
+  PERFORM pg_advisory_xact_lock(hashtextextended(NEW.stream_id || ':' || NEW.event_id, 0));
+  IF EXISTS (SELECT 1 FROM events WHERE stream_id = NEW.stream_id AND event_id = NEW.event_id) THEN
+    RETURN NULL;
+  END IF;
+  RETURN NEW;
+END $$;
+CREATE TRIGGER events_unique_event_id
+  BEFORE INSERT ON events
+  FOR EACH ROW EXECUTE FUNCTION events_unique_event_id();
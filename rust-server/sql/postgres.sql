@@ -8,3 +8,32 @@ CREATE TABLE IF NOT EXISTS events(
   insert_datetime TIMESTAMP NOT NULL,
   payload JSONB NOT NULL,
   stream_id INTEGER REFERENCES streams(stream_id) NOT NULL);
+ALTER TABLE events ADD COLUMN IF NOT EXISTS event_id TEXT;
+ALTER TABLE events ADD COLUMN IF NOT EXISTS collector JSONB;
+ALTER TABLE events ADD COLUMN IF NOT EXISTS revision INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS end_datetime TIMESTAMP;
+ALTER TABLE streams ADD COLUMN IF NOT EXISTS event_count BIGINT;
+CREATE UNIQUE INDEX IF NOT EXISTS events_stream_event_id ON events(stream_id, event_id);
+-- Earlier revisions of events, replaced in events by corrections.
+CREATE TABLE IF NOT EXISTS event_revisions(
+  stream_id INTEGER REFERENCES streams(stream_id) NOT NULL,
+  stream_event_index INTEGER NOT NULL,
+  revision INTEGER NOT NULL,
+  insert_datetime TIMESTAMP NOT NULL,
+  payload JSONB NOT NULL);
+CREATE OR REPLACE VIEW event_history AS
+  SELECT stream_id, stream_event_index, revision, insert_datetime, payload, FALSE AS latest
+  FROM event_revisions
+  UNION ALL
+  SELECT stream_id, stream_event_index, revision, insert_datetime, payload, TRUE AS latest
+  FROM events;
+-- Admin operations that changed stored data, like merging streams.
+CREATE TABLE IF NOT EXISTS audit_log(
+  datetime TIMESTAMP NOT NULL,
+  operation TEXT NOT NULL,
+  details JSONB NOT NULL);
+-- Short links to UI views, by a hash of the view's query string.
+CREATE TABLE IF NOT EXISTS links(
+  link_id TEXT PRIMARY KEY,
+  query TEXT NOT NULL,
+  created_datetime TIMESTAMP NOT NULL);
@@ -0,0 +1,2 @@
+-- Upgrades a version 6 database to record admin operations.
+CREATE TABLE audit_log(datetime text not null, operation text not null, details blob) strict;
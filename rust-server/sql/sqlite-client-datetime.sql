@@ -0,0 +1,3 @@
+-- Upgrades a version 10 database to record client timestamps and clock skew.
+ALTER TABLE events ADD COLUMN client_datetime text;
+ALTER TABLE streams ADD COLUMN clock_skew_ms integer;
@@ -0,0 +1,2 @@
+-- Upgrades a version 4 database to store server-side event metadata.
+ALTER TABLE events ADD COLUMN collector blob;
@@ -0,0 +1,3 @@
+-- Upgrades a version 1 database to have client-supplied event IDs.
+ALTER TABLE events ADD COLUMN event_id text;
+CREATE UNIQUE INDEX events_stream_event_id ON events(stream_id, event_id);
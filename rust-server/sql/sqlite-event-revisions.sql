@@ -0,0 +1,7 @@
+-- Upgrades a version 5 database to keep earlier revisions of corrected events.
+ALTER TABLE events ADD COLUMN revision integer not null default 0;
+CREATE TABLE event_revisions(stream_id integer references streams(stream_id), stream_event_index integer, revision integer not null, insert_datetime text, payload blob) strict;
+CREATE VIEW event_history AS
+    SELECT stream_id, stream_event_index, revision, insert_datetime, payload, 0 AS latest FROM event_revisions
+    UNION ALL
+    SELECT stream_id, stream_event_index, revision, insert_datetime, payload, 1 AS latest FROM events;
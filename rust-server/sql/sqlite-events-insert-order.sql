@@ -0,0 +1,2 @@
+-- Upgrades a version 8 database with an index for reading events in insert order a batch at a time.
+CREATE INDEX events_insert_order ON events(insert_datetime, stream_id, stream_event_index);
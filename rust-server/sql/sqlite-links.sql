@@ -0,0 +1,2 @@
+-- Upgrades a version 7 database to store short links to UI views.
+CREATE TABLE links(link_id text not null primary key, query text not null, created_datetime text not null) strict;
@@ -0,0 +1,3 @@
+-- Upgrades a version 14 database to keep metric data points apart from events.
+CREATE TABLE metric_points(name text not null, resource blob, attributes blob, datetime text not null, value real not null) strict;
+CREATE INDEX metric_points_name_datetime ON metric_points(name, datetime);
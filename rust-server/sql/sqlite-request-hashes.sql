@@ -0,0 +1,3 @@
+-- Upgrades a version 18 database to record hashes of recent request bodies, to catch duplicates.
+CREATE TABLE request_hashes(hash text not null primary key, received_datetime text not null) strict;
+CREATE INDEX request_hashes_received ON request_hashes(received_datetime);
@@ -0,0 +1,3 @@
+-- Upgrades a version 13 database to keep counts of events for rollups.
+CREATE TABLE rollups(name text not null, definition text not null, bucket_start text not null, group_key text, count integer not null) strict;
+CREATE INDEX rollups_name_bucket ON rollups(name, bucket_start);
@@ -0,0 +1,2 @@
+-- Upgrades a version 11 database to record gaps and duplicates in stream event indexes.
+CREATE TABLE stream_anomalies(stream_id integer not null, stream_event_index integer not null, kind text not null, detected_datetime text not null, details text) strict;
@@ -0,0 +1,3 @@
+-- Upgrades a version 3 database to record how streams ended.
+ALTER TABLE streams ADD COLUMN end_datetime text;
+ALTER TABLE streams ADD COLUMN event_count integer;
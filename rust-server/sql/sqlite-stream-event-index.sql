@@ -0,0 +1,2 @@
+-- Upgrades a version 2 database to record each event's index within its stream.
+ALTER TABLE events ADD COLUMN stream_event_index integer;
@@ -0,0 +1,3 @@
+-- Upgrades a version 15 database to label streams with key-value pairs they can be found by.
+CREATE TABLE stream_labels(stream_id integer not null references streams(stream_id) on delete cascade, key text not null, value text not null, primary key (stream_id, key)) strict;
+CREATE INDEX stream_labels_key_value ON stream_labels(key, value, stream_id);
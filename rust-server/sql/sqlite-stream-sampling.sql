@@ -0,0 +1,2 @@
+-- Upgrades a version 12 database to record how streams' events were sampled.
+ALTER TABLE streams ADD COLUMN sampling blob;
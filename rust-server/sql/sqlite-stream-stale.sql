@@ -0,0 +1,2 @@
+-- Upgrades a version 9 database to record when open streams went quiet.
+ALTER TABLE streams ADD COLUMN stale_datetime text;
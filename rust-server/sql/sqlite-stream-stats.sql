@@ -0,0 +1,28 @@
+-- Upgrades a version 16 database to keep counts of each stream's stored events up to date as
+-- they change, so listing streams doesn't have to count them.
+ALTER TABLE streams ADD COLUMN stored_event_count integer not null default 0;
+ALTER TABLE streams ADD COLUMN stored_payload_bytes integer not null default 0;
+ALTER TABLE streams ADD COLUMN last_insert_datetime text;
+UPDATE streams SET (stored_event_count, stored_payload_bytes, last_insert_datetime) = (
+    SELECT count(*), coalesce(sum(length(payload)), 0), max(insert_datetime)
+    FROM events WHERE events.stream_id = streams.stream_id);
+CREATE TRIGGER events_stream_stats_insert AFTER INSERT ON events BEGIN
+    UPDATE streams SET stored_event_count = stored_event_count + 1,
+        stored_payload_bytes = stored_payload_bytes + coalesce(length(new.payload), 0),
+        last_insert_datetime = coalesce(max(last_insert_datetime, new.insert_datetime), new.insert_datetime, last_insert_datetime)
+    WHERE stream_id = new.stream_id;
+END;
+CREATE TRIGGER events_stream_stats_delete AFTER DELETE ON events BEGIN
+    UPDATE streams SET stored_event_count = stored_event_count - 1,
+        stored_payload_bytes = stored_payload_bytes - coalesce(length(old.payload), 0)
+    WHERE stream_id = old.stream_id;
+END;
+CREATE TRIGGER events_stream_stats_update AFTER UPDATE OF stream_id, payload, insert_datetime ON events BEGIN
+    UPDATE streams SET stored_event_count = stored_event_count - 1,
+        stored_payload_bytes = stored_payload_bytes - coalesce(length(old.payload), 0)
+    WHERE stream_id = old.stream_id;
+    UPDATE streams SET stored_event_count = stored_event_count + 1,
+        stored_payload_bytes = stored_payload_bytes + coalesce(length(new.payload), 0),
+        last_insert_datetime = coalesce(max(last_insert_datetime, new.insert_datetime), new.insert_datetime, last_insert_datetime)
+    WHERE stream_id = new.stream_id;
+END;
@@ -0,0 +1,4 @@
+-- Upgrades a version 17 database to give streams UUIDs. Existing streams keep only their integer
+-- IDs.
+ALTER TABLE streams ADD COLUMN stream_uuid text;
+CREATE UNIQUE INDEX streams_stream_uuid ON streams(stream_uuid);
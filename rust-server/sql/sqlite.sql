@@ -1,6 +1,53 @@
 -- Payload is what the application sends, collector is what the server has added.
-CREATE TABLE streams(stream_id integer not null primary key, headers blob, start_datetime text not null) strict;
-CREATE TABLE events(insert_datetime text, payload blob, stream_id integer references streams(stream_id)) strict;
+CREATE TABLE streams(stream_id integer not null primary key, headers blob, start_datetime text not null, end_datetime text, event_count integer, stale_datetime text, clock_skew_ms integer, sampling blob, stored_event_count integer not null default 0, stored_payload_bytes integer not null default 0, last_insert_datetime text, stream_uuid text) strict;
+CREATE UNIQUE INDEX streams_stream_uuid ON streams(stream_uuid);
+CREATE TABLE events(insert_datetime text, stream_event_index integer, payload blob, stream_id integer references streams(stream_id), event_id text, collector blob, revision integer not null default 0, client_datetime text, unique (stream_id, event_id)) strict;
+CREATE INDEX events_insert_order ON events(insert_datetime, stream_id, stream_event_index);
+-- Keep streams' counts of their stored events up to date.
+CREATE TRIGGER events_stream_stats_insert AFTER INSERT ON events BEGIN
+    UPDATE streams SET stored_event_count = stored_event_count + 1,
+        stored_payload_bytes = stored_payload_bytes + coalesce(length(new.payload), 0),
+        last_insert_datetime = coalesce(max(last_insert_datetime, new.insert_datetime), new.insert_datetime, last_insert_datetime)
+    WHERE stream_id = new.stream_id;
+END;
+CREATE TRIGGER events_stream_stats_delete AFTER DELETE ON events BEGIN
+    UPDATE streams SET stored_event_count = stored_event_count - 1,
+        stored_payload_bytes = stored_payload_bytes - coalesce(length(old.payload), 0)
+    WHERE stream_id = old.stream_id;
+END;
+CREATE TRIGGER events_stream_stats_update AFTER UPDATE OF stream_id, payload, insert_datetime ON events BEGIN
+    UPDATE streams SET stored_event_count = stored_event_count - 1,
+        stored_payload_bytes = stored_payload_bytes - coalesce(length(old.payload), 0)
+    WHERE stream_id = old.stream_id;
+    UPDATE streams SET stored_event_count = stored_event_count + 1,
+        stored_payload_bytes = stored_payload_bytes + coalesce(length(new.payload), 0),
+        last_insert_datetime = coalesce(max(last_insert_datetime, new.insert_datetime), new.insert_datetime, last_insert_datetime)
+    WHERE stream_id = new.stream_id;
+END;
+-- Earlier revisions of events, replaced in events by corrections.
+CREATE TABLE event_revisions(stream_id integer references streams(stream_id), stream_event_index integer, revision integer not null, insert_datetime text, payload blob) strict;
+CREATE VIEW event_history AS
+    SELECT stream_id, stream_event_index, revision, insert_datetime, payload, 0 AS latest FROM event_revisions
+    UNION ALL
+    SELECT stream_id, stream_event_index, revision, insert_datetime, payload, 1 AS latest FROM events;
+-- Admin operations that changed stored data, like merging streams.
+CREATE TABLE audit_log(datetime text not null, operation text not null, details blob) strict;
+-- Short links to UI views, by a hash of the view's query string.
+CREATE TABLE links(link_id text not null primary key, query text not null, created_datetime text not null) strict;
+-- Stream event indexes that have no stored event, and why.
+CREATE TABLE stream_anomalies(stream_id integer not null, stream_event_index integer not null, kind text not null, detected_datetime text not null, details text) strict;
+-- Counts of events by a group in buckets of insert time, kept up to date by rollups.
+CREATE TABLE rollups(name text not null, definition text not null, bucket_start text not null, group_key text, count integer not null) strict;
+CREATE INDEX rollups_name_bucket ON rollups(name, bucket_start);
+-- Metric data points, each a number at a time, kept apart from events so they can be queried.
+CREATE TABLE metric_points(name text not null, resource blob, attributes blob, datetime text not null, value real not null) strict;
+CREATE INDEX metric_points_name_datetime ON metric_points(name, datetime);
+-- Key-value labels on streams, which streams can be found by.
+CREATE TABLE stream_labels(stream_id integer not null references streams(stream_id) on delete cascade, key text not null, value text not null, primary key (stream_id, key)) strict;
+CREATE INDEX stream_labels_key_value ON stream_labels(key, value, stream_id);
+-- Hashes of recent request bodies, and when each was first received, to catch duplicates.
+CREATE TABLE request_hashes(hash text not null primary key, received_datetime text not null) strict;
+CREATE INDEX request_hashes_received ON request_hashes(received_datetime);
 -- This is just an example of how you can do indexes on JSON. The user could do it for their own
 -- payloads and query patterns.
 --CREATE INDEX event_types on events(payload->'type');
\ No newline at end of file
@@ -0,0 +1,145 @@
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// Who may use the server and how often. These are reloaded with the config file.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub(crate) struct AccessArgs {
+    /// Token clients must send as "Authorization: Bearer <token>". Can be repeated. If none are
+    /// given, clients don't need one.
+    #[arg(long = "auth-token")]
+    auth_tokens: Vec<String>,
+    /// Token that /admin requests must send instead, as with --auth-token. Admin tokens are
+    /// accepted everywhere else too. Can be repeated. If none are given, /admin takes the same
+    /// tokens as everything else.
+    #[arg(long = "admin-token")]
+    admin_tokens: Vec<String>,
+    /// Requests allowed per second from each client IP.
+    #[arg(long)]
+    rate_limit: Option<f64>,
+    /// Requests a client IP can make at once before the rate limit applies. Defaults to a second's
+    /// worth.
+    #[arg(long)]
+    rate_limit_burst: Option<u32>,
+}
+
+/// Checks requests against the current [AccessArgs].
+#[derive(Default)]
+pub(crate) struct Access {
+    args: RwLock<AccessArgs>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+/// Past this many clients, buckets that have refilled are forgotten.
+const MAX_IDLE_BUCKETS: usize = 10000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Access {
+    pub(crate) fn new(args: AccessArgs) -> Self {
+        Self {
+            args: RwLock::new(args),
+            buckets: Default::default(),
+        }
+    }
+
+    /// The current settings, without the tokens themselves.
+    pub(crate) fn to_json(&self) -> Value {
+        let args = self.args.read().unwrap();
+        json!({
+            "auth_tokens": args.auth_tokens.len(),
+            "admin_tokens": args.admin_tokens.len(),
+            "rate_limit": args.rate_limit,
+            "rate_limit_burst": args.rate_limit_burst,
+        })
+    }
+
+    pub(crate) fn reload(&self, args: AccessArgs) {
+        *self.args.write().unwrap() = args;
+    }
+
+    pub(crate) fn check(
+        &self,
+        headers: &HeaderMap,
+        remote_ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), StatusCode> {
+        let args = self.args.read().unwrap();
+        if !args.auth_tokens.is_empty() {
+            let token = bearer_token(headers);
+            let mut tokens = args.auth_tokens.iter().chain(&args.admin_tokens);
+            if !token.is_some_and(|token| tokens.any(|t| t == token)) {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        }
+        let (Some(rate), Some(remote_ip)) = (args.rate_limit, remote_ip) else {
+            return Ok(());
+        };
+        let burst = args.rate_limit_burst.map_or(rate.ceil().max(1.), f64::from);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(remote_ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1. {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        bucket.tokens -= 1.;
+        Ok(())
+    }
+
+    /// Checks an /admin request has an admin token, if there are any, on top of [Self::check].
+    pub(crate) fn check_admin(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let args = self.args.read().unwrap();
+        if args.admin_tokens.is_empty() {
+            return Ok(());
+        }
+        let token = bearer_token(headers);
+        if !token.is_some_and(|token| args.admin_tokens.iter().any(|t| t == token)) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(())
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Middleware rejecting requests that [Access::check] doesn't allow, and /admin requests that
+/// [Access::check_admin] doesn't.
+pub(crate) async fn guard(State(access): State<Arc<Access>>, req: Request, next: Next) -> Response {
+    let remote_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Err(status_code) = access.check(req.headers(), remote_ip, Instant::now()) {
+        return status_code.into_response();
+    }
+    if req.uri().path().starts_with("/admin/") {
+        if let Err(status_code) = access.check_admin(req.headers()) {
+            return status_code.into_response();
+        }
+    }
+    next.run(req).await
+}
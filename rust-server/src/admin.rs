@@ -0,0 +1,177 @@
+use crate::export::{self, ExportFormat};
+use crate::import;
+use crate::replicate::replicate;
+use crate::views::ViewParams;
+use crate::{Args, Storage};
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::PathBuf;
+use telemetry_storage::{Connection, EncryptionArgs};
+use tracing::info;
+
+// Commands that open the storage, do one thing with it, and exit. They use the storage given
+// after them, or by --storage. Not a doc comment, since clap would show it as the server's about.
+#[derive(Clone, clap::Subcommand)]
+pub(crate) enum AdminCommand {
+    /// Prints stored events as JSON lines, oldest first.
+    Query(QueryArgs),
+    /// Writes stored events with their streams in a file format. They're read and written in
+    /// batches, so exports can be bigger than memory.
+    Export(ExportArgs),
+    /// Stores the streams and events from JSON files storage output, or ndjson exports, keeping
+    /// their stream IDs and insert times. Importing the same files again skips what's stored.
+    Import(ImportArgs),
+    /// Copies what's been stored since the last run to other storage, keeping stream IDs and
+    /// insert times, so it can run repeatedly to keep the other storage up to date.
+    Replicate(Box<ReplicateArgs>),
+    /// Prunes the storage once with the --retain-* limits.
+    Prune(StorageArgs),
+    /// Applies any schema migrations the storage is missing.
+    Migrate(StorageArgs),
+    /// Prints counts of what's stored as JSON.
+    Stats(StorageArgs),
+}
+
+/// The storage for a command, as a subcommand of it.
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct StorageArgs {
+    #[command(subcommand)]
+    pub storage: Option<Storage>,
+}
+
+#[derive(Clone, clap::Args)]
+pub(crate) struct QueryArgs {
+    #[command(flatten)]
+    view: ViewParams,
+    #[command(flatten)]
+    storage: StorageArgs,
+}
+
+#[derive(Clone, clap::Args)]
+pub(crate) struct ExportArgs {
+    #[command(flatten)]
+    view: ViewParams,
+    #[arg(long, value_enum, default_value_t)]
+    format: ExportFormat,
+    /// File to write to, instead of stdout.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    storage: StorageArgs,
+}
+
+#[derive(Clone, clap::Args)]
+// Otherwise the files would swallow the storage subcommand's name.
+#[command(subcommand_precedence_over_arg = true)]
+pub(crate) struct ImportArgs {
+    /// Files to read, zstd compressed if they end in ".zst". Directories are read for their
+    /// ".json.zst" files.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// The key encrypted files (ending in ".enc") were written with.
+    #[command(flatten)]
+    encryption: EncryptionArgs,
+    /// The zstd dictionary the files were compressed with, if any.
+    #[arg(long)]
+    compression_dictionary: Option<PathBuf>,
+    #[command(flatten)]
+    storage: StorageArgs,
+}
+
+#[derive(Clone, clap::Args)]
+pub(crate) struct ReplicateArgs {
+    /// Storage to copy to, as a URI like --storage takes.
+    #[arg(long, value_name = "STORAGE_URI")]
+    to: Storage,
+    /// File recording how far replication got, so each run carries on from the last. It's
+    /// created if it doesn't exist.
+    #[arg(long)]
+    state: PathBuf,
+    #[command(flatten)]
+    storage: StorageArgs,
+}
+
+impl AdminCommand {
+    pub(crate) fn storage(&self) -> Option<&Storage> {
+        match self {
+            Self::Query(query) => query.storage.storage.as_ref(),
+            Self::Export(export) => export.storage.storage.as_ref(),
+            Self::Import(import) => import.storage.storage.as_ref(),
+            Self::Replicate(replicate) => replicate.storage.storage.as_ref(),
+            Self::Prune(args) | Self::Migrate(args) | Self::Stats(args) => args.storage.as_ref(),
+        }
+    }
+
+    pub(crate) async fn run(&self, args: &Args, out: &mut (impl Write + Send)) -> Result<()> {
+        // Opening applies migrations, so that's all migrate has to do.
+        let mut conn = args.storage()?.open().await?;
+        let result = self.run_on(args, &mut *conn, out).await;
+        conn.shutdown().await?;
+        result
+    }
+
+    async fn run_on(
+        &self,
+        args: &Args,
+        conn: &mut (dyn Connection + Send),
+        out: &mut (impl Write + Send),
+    ) -> Result<()> {
+        match self {
+            Self::Query(query) => {
+                for event in conn.query_events(&query.view.query()?).await? {
+                    serde_json::to_writer(&mut *out, &event)?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            Self::Export(export) => {
+                // Events are streamed, so there's no limit unless one's given.
+                let query = export.view.query_or_limit(i64::MAX as usize)?;
+                let formatter = export.format.formatter();
+                let exported = match &export.output {
+                    Some(path) => {
+                        let file = std::fs::File::create(path)
+                            .with_context(|| format!("creating {}", path.display()))?;
+                        let mut file = std::io::BufWriter::new(file);
+                        let exported = export::export(conn, &query, &*formatter, &mut file).await?;
+                        file.into_inner()?.sync_all()?;
+                        exported
+                    }
+                    None => export::export(conn, &query, &*formatter, out).await?,
+                };
+                info!(exported, format = ?export.format, "exported events");
+            }
+            Self::Import(args) => {
+                let dictionary = args
+                    .compression_dictionary
+                    .as_ref()
+                    .map(|path| {
+                        std::fs::read(path).with_context(|| format!("reading {}", path.display()))
+                    })
+                    .transpose()?;
+                let key = args.encryption.key()?;
+                let imported =
+                    import::import(conn, &args.files, key.as_ref(), dictionary.as_deref()).await?;
+                writeln!(out, "{}", serde_json::to_string(&imported)?)?;
+            }
+            Self::Replicate(args) => {
+                let mut to = args.to.clone().open().await?;
+                let replicated = replicate(conn, &mut *to, &args.state).await;
+                to.shutdown().await?;
+                writeln!(out, "{}", serde_json::to_string(&replicated?)?)?;
+            }
+            Self::Prune(_) => {
+                let policy = args.retention.policy().ok_or_else(|| {
+                    anyhow!("nothing to prune without --retain-for, --retain-max-events or --retain-max-bytes")
+                })?;
+                let pruned = conn.prune(&policy).await?;
+                writeln!(out, "{}", serde_json::to_string(&pruned)?)?;
+            }
+            Self::Migrate(_) => writeln!(out, "storage is up to date")?,
+            Self::Stats(_) => {
+                let stats = conn.stats().await?;
+                writeln!(out, "{}", serde_json::to_string_pretty(&stats)?)?;
+            }
+        }
+        Ok(())
+    }
+}
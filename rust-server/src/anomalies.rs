@@ -0,0 +1,34 @@
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use telemetry_storage::AnomalyKind;
+
+/// Running totals of the gaps and duplicates found in stream event indexes since the server
+/// started.
+#[derive(Default)]
+pub(crate) struct AnomalyStats {
+    gaps: AtomicU64,
+    duplicates: AtomicU64,
+    /// Anomalies that storage couldn't record. They're still counted above.
+    unrecorded: AtomicU64,
+}
+
+impl AnomalyStats {
+    pub(crate) fn record(&self, kind: AnomalyKind, recorded: bool) {
+        let counter = match kind {
+            AnomalyKind::Gap => &self.gaps,
+            AnomalyKind::Duplicate => &self.duplicates,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if !recorded {
+            self.unrecorded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "gaps": self.gaps.load(Ordering::Relaxed),
+            "duplicates": self.duplicates.load(Ordering::Relaxed),
+            "unrecorded": self.unrecorded.load(Ordering::Relaxed),
+        })
+    }
+}
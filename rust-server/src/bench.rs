@@ -0,0 +1,227 @@
+use crate::admin::StorageArgs;
+use crate::{Args, STREAM_TOKEN_HEADER};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use telemetry_storage::{Connection, NewEvent, RawValue};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Generates synthetic streams of events, POSTed to a running server or stored straight into the
+/// storage given after it, and prints the throughput and latency percentiles as JSON.
+#[derive(Clone, clap::Args)]
+pub(crate) struct BenchArgs {
+    /// Base URL of a running server to POST to, like http://localhost:4318. Without it, events are
+    /// stored straight into the storage, through the same connection the server would use.
+    #[arg(long)]
+    url: Option<reqwest::Url>,
+    /// Streams sending at once, each from its own task.
+    #[arg(long, default_value_t = 4)]
+    streams: usize,
+    /// Events in each POST, or in each flush of the storage. Latencies are per batch.
+    #[arg(long, default_value_t = 100)]
+    batch: usize,
+    /// Bytes of each event's JSON payload, padded out. Payloads are never smaller than their
+    /// fields.
+    #[arg(long, default_value_t = 256)]
+    event_bytes: usize,
+    /// Events per second across all the streams. As fast as they're stored if unset.
+    #[arg(long)]
+    rate: Option<f64>,
+    /// How long to send for, like "30s".
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    duration: Duration,
+    #[command(flatten)]
+    pub storage: StorageArgs,
+}
+
+/// Where batches are sent.
+#[derive(Clone)]
+enum Target {
+    Server {
+        client: reqwest::Client,
+        url: reqwest::Url,
+    },
+    Storage(Arc<Mutex<Box<dyn Connection + Send>>>),
+}
+
+/// What one stream's task sent.
+#[derive(Default)]
+struct StreamResults {
+    events: u64,
+    bytes: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+impl BenchArgs {
+    pub(crate) async fn run(&self, args: &Args, out: &mut impl Write) -> Result<()> {
+        if self.streams == 0 || self.batch == 0 {
+            bail!("--streams and --batch must be at least 1");
+        }
+        if self.rate.is_some_and(|rate| rate <= 0.0) {
+            bail!("--rate must be positive");
+        }
+        let target = match &self.url {
+            Some(url) => Target::Server {
+                client: reqwest::Client::new(),
+                url: url.clone(),
+            },
+            None => {
+                let conn = args.storage()?.open().await.context("opening storage")?;
+                Target::Storage(Arc::new(Mutex::new(conn)))
+            }
+        };
+        let start = Instant::now();
+        let deadline = start + self.duration;
+        let tasks: Vec<_> = (0..self.streams)
+            .map(|stream| {
+                let args = self.clone();
+                let target = target.clone();
+                tokio::spawn(async move { args.send_stream(stream, target, start, deadline).await })
+            })
+            .collect();
+        let mut total = StreamResults::default();
+        for task in tasks {
+            let results = task.await??;
+            total.events += results.events;
+            total.bytes += results.bytes;
+            total.errors += results.errors;
+            total.latencies.extend(results.latencies);
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        if let Target::Storage(conn) = &target {
+            conn.lock().await.shutdown().await?;
+        }
+        total.latencies.sort();
+        let percentile = |p: f64| {
+            let index = ((total.latencies.len().max(1) - 1) as f64 * p).round() as usize;
+            total
+                .latencies
+                .get(index)
+                .map(|latency| latency.as_secs_f64() * 1e3)
+        };
+        let report = json!({
+            "target": match &self.url {
+                Some(url) => url.as_str(),
+                None => "storage",
+            },
+            "streams": self.streams,
+            "batch": self.batch,
+            "event_bytes": self.event_bytes,
+            "batches": total.latencies.len(),
+            "events": total.events,
+            "errors": total.errors,
+            "elapsed_s": elapsed,
+            "events_per_s": total.events as f64 / elapsed,
+            "bytes_per_s": total.bytes as f64 / elapsed,
+            "latency_ms": {
+                "p50": percentile(0.5),
+                "p90": percentile(0.9),
+                "p99": percentile(0.99),
+                "max": total.latencies.last().map(|latency| latency.as_secs_f64() * 1e3),
+            },
+        });
+        serde_json::to_writer(&mut *out, &report)?;
+        out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Sends batches for one stream until the deadline, paced to its share of the rate.
+    async fn send_stream(
+        &self,
+        stream: usize,
+        target: Target,
+        start: Instant,
+        deadline: Instant,
+    ) -> Result<StreamResults> {
+        let batch_interval = self
+            .rate
+            .map(|rate| Duration::from_secs_f64(self.batch as f64 * self.streams as f64 / rate));
+        let mut results = StreamResults::default();
+        let mut stream_token = None;
+        let mut storage_stream = None;
+        let mut seq = 0;
+        for batch_number in 0u32.. {
+            if let Some(interval) = batch_interval {
+                tokio::time::sleep_until(start + interval * batch_number).await;
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            let payloads: Vec<String> = (0..self.batch)
+                .map(|_| {
+                    seq += 1;
+                    self.payload(stream, seq)
+                })
+                .collect();
+            let sent = Instant::now();
+            let stored = match &target {
+                Target::Server { client, url } => {
+                    let mut request = client.post(url.clone()).body(payloads.join("\n"));
+                    if let Some(token) = &stream_token {
+                        request = request.header(STREAM_TOKEN_HEADER, token);
+                    }
+                    match request.send().await {
+                        Ok(response) if response.status().is_success() => {
+                            if let Some(token) = response.headers().get(STREAM_TOKEN_HEADER) {
+                                stream_token = Some(token.clone());
+                            }
+                            Ok(())
+                        }
+                        Ok(response) => Err(anyhow::anyhow!("status {}", response.status())),
+                        Err(err) => Err(err.into()),
+                    }
+                }
+                Target::Storage(conn) => {
+                    let mut conn = conn.lock().await;
+                    let stream_id = match storage_stream {
+                        Some(stream_id) => stream_id,
+                        None => {
+                            let headers = json!({"bench": {"stream": stream}});
+                            *storage_stream.insert(conn.new_stream(headers).await?)
+                        }
+                    };
+                    let index = seq - payloads.len() as u64;
+                    // Checked as the server checks its payloads before storing them.
+                    let events = payloads
+                        .iter()
+                        .enumerate()
+                        .map(|(offset, payload)| {
+                            let payload: &RawValue = serde_json::from_str(payload)?;
+                            Ok(NewEvent::new(index + offset as u64 + 1, payload))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    let stored = conn.insert_events(stream_id, &events).await.map(drop);
+                    stored.and(conn.flush().await)
+                }
+            };
+            results.latencies.push(sent.elapsed());
+            match stored {
+                Ok(()) => {
+                    results.events += payloads.len() as u64;
+                    results.bytes += payloads
+                        .iter()
+                        .map(|payload| payload.len() as u64)
+                        .sum::<u64>();
+                }
+                Err(err) => {
+                    // Only the first is logged, so a server that's down doesn't flood the output.
+                    if results.errors == 0 {
+                        tracing::warn!(stream, %err, "sending batch");
+                    }
+                    results.errors += 1;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    fn payload(&self, stream: usize, seq: u64) -> String {
+        let payload = json!({"bench": true, "stream": stream, "seq": seq, "pad": ""}).to_string();
+        let pad = self.event_bytes.saturating_sub(payload.len());
+        json!({"bench": true, "stream": stream, "seq": seq, "pad": "x".repeat(pad)}).to_string()
+    }
+}
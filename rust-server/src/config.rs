@@ -0,0 +1,668 @@
+use crate::dedup::DedupAction;
+use crate::encoding::LegacyEncoding;
+use crate::event_schema::EventSchema;
+use crate::ingest;
+use crate::limits::EventLimits;
+use crate::{RedactArgs, RoutingArgs, Storage, TransformArgs};
+use anyhow::{anyhow, bail, Context, Result};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use telemetry_storage::Rollup;
+use toml::Spanned;
+
+/// The config file version written by this server. Older versions stay readable: when the
+/// schema changes, add a struct for the new version and convert the old ones into it.
+pub(crate) const CURRENT_VERSION: i64 = 1;
+
+/// Settings from a TOML config file, in the latest version's schema.
+pub(crate) type Config = ConfigV1;
+
+#[derive(Clone, clap::Subcommand)]
+pub(crate) enum ConfigCommand {
+    /// Checks a config file, printing every problem found with its line and column.
+    Validate { path: PathBuf },
+}
+
+impl ConfigCommand {
+    pub(crate) fn run(&self, out: &mut impl std::io::Write) -> Result<()> {
+        match self {
+            ConfigCommand::Validate { path } => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                let errors = match parse(&text) {
+                    Ok(_) => return Ok(writeln!(out, "{}: ok", path.display())?),
+                    Err(errors) => errors,
+                };
+                for error in &errors {
+                    writeln!(out, "{}:{}", path.display(), error.display(&text))?;
+                }
+                bail!("{} has {} error(s)", path.display(), errors.len())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigV1 {
+    #[allow(dead_code)]
+    version: i64,
+    /// Addresses to serve HTTP on.
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// Addresses to serve HTTPS on, with the tls certificate and key.
+    #[serde(default)]
+    pub listen_tls: Vec<String>,
+    /// Unix sockets to serve HTTP on.
+    #[serde(default)]
+    pub listen_unix: Vec<String>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    pub log_level: Option<Spanned<String>>,
+    /// Storage URI, as taken by --storage.
+    pub storage: Option<Spanned<String>>,
+    /// Storage URIs to fall back to, in order, as taken by --fallback-storage.
+    #[serde(default)]
+    pub fallback_storage: Vec<Spanned<String>>,
+    pub stream_token_secret: Option<String>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+    #[serde(default)]
+    pub limits: EventLimits,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub enrich: EnrichConfig,
+    #[serde(default)]
+    pub streams: StreamsConfig,
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+    /// Storage URIs by sink name.
+    #[serde(default)]
+    pub sinks: BTreeMap<String, Spanned<String>>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub rollups: RollupsConfig,
+    /// Endpoints under /ingest, by name.
+    #[serde(default)]
+    pub ingest: BTreeMap<String, IngestConfig>,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// PEM certificate chain path.
+    pub cert: Option<String>,
+    /// PEM PKCS #8 private key path.
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    #[serde(default)]
+    pub admin_tokens: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    pub requests_per_second: Option<f64>,
+    pub burst: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PipelineConfig {
+    #[serde(default)]
+    pub normalize: bool,
+    pub legacy_encoding: Option<Spanned<String>>,
+    #[serde(default)]
+    pub redact: RedactConfig,
+    #[serde(default)]
+    pub transform: TransformConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RedactConfig {
+    /// JSON pointers.
+    #[serde(default)]
+    pub mask: Vec<Spanned<String>>,
+    #[serde(default)]
+    pub remove: Vec<Spanned<String>>,
+    /// Regexes.
+    #[serde(default)]
+    pub patterns: Vec<Spanned<String>>,
+    #[serde(default)]
+    pub builtin: Vec<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TransformConfig {
+    /// Path to a .wasm or .wat file.
+    pub script: Option<Spanned<String>>,
+    pub fuel: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RouteConfig {
+    pub sink: String,
+    /// A condition, like `payload.kind == "crash"`.
+    pub when: Spanned<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetentionConfig {
+    /// A duration, like "30days".
+    pub retain_for: Option<Spanned<String>>,
+    pub retain_max_events: Option<u64>,
+    pub retain_max_bytes: Option<u64>,
+    pub prune_interval: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RollupsConfig {
+    /// A duration, like "1m".
+    pub interval: Option<Spanned<String>>,
+    /// Rollups by name, as GROUP/BUCKET like "payload.level/5m".
+    #[serde(default)]
+    pub counts: BTreeMap<String, Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StreamsConfig {
+    /// A duration, like "10m".
+    pub stale_after: Option<Spanned<String>>,
+    pub stale_check_interval: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DedupConfig {
+    /// A duration, like "10m".
+    pub window: Option<Spanned<String>>,
+    /// "reject" or "tag".
+    pub action: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EnrichConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub headers: Vec<String>,
+    pub tls_identity_header: Option<String>,
+    pub instance_id: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SamplingConfig {
+    pub every: Option<u64>,
+    pub field: Option<String>,
+    /// Chances of keeping events by their field value, like `{ debug = 0.01 }`.
+    #[serde(default)]
+    pub rates: BTreeMap<String, Spanned<f64>>,
+    pub throttle_events_per_second: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IngestConfig {
+    pub max_event_bytes: Option<usize>,
+    pub max_event_depth: Option<usize>,
+    pub max_array_length: Option<usize>,
+    /// Path to a JSON Schema file.
+    pub schema: Option<Spanned<String>>,
+    pub sink: Option<String>,
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+}
+
+impl IngestConfig {
+    /// The endpoint as taken by --ingest.
+    fn to_arg(&self, name: &str) -> String {
+        fn push(settings: &mut Vec<String>, key: &str, value: Option<impl Display>) {
+            if let Some(value) = value {
+                settings.push(format!("{}={}", key, value));
+            }
+        }
+        let mut settings = vec![];
+        push(&mut settings, "max_event_bytes", self.max_event_bytes);
+        push(&mut settings, "max_event_depth", self.max_event_depth);
+        push(&mut settings, "max_array_length", self.max_array_length);
+        push(
+            &mut settings,
+            "schema",
+            self.schema.as_ref().map(Spanned::get_ref),
+        );
+        push(&mut settings, "sink", self.sink.as_ref());
+        let sampling = &self.sampling;
+        push(&mut settings, "sample_every", sampling.every);
+        push(&mut settings, "sample_field", sampling.field.as_ref());
+        for (value, rate) in &sampling.rates {
+            let rate = format!("{}={}", value, rate.get_ref());
+            push(&mut settings, "sample_rate", Some(rate));
+        }
+        push(
+            &mut settings,
+            "throttle_events_per_second",
+            sampling.throttle_events_per_second,
+        );
+        format!("{}:{}", name, settings.join(","))
+    }
+}
+
+/// Just enough of any version to know how to read the rest.
+#[derive(Deserialize)]
+struct Versioned {
+    version: Option<Spanned<i64>>,
+}
+
+/// Reads and parses a config file, with all its errors in the message if it isn't valid.
+pub(crate) fn load(path: &Path) -> Result<Config> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    parse(&text).map_err(|errors| {
+        let lines: Vec<String> = errors
+            .iter()
+            .map(|error| format!("{}:{}", path.display(), error.display(&text)))
+            .collect();
+        anyhow!("invalid config file\n{}", lines.join("\n"))
+    })
+}
+
+/// Parses a config file of any supported version, returning every problem found if it isn't
+/// valid.
+pub(crate) fn parse(text: &str) -> Result<Config, Vec<ConfigError>> {
+    let versioned: Versioned = toml::from_str(text).map_err(|err| vec![err.into()])?;
+    let Some(version) = versioned.version else {
+        return Err(vec![ConfigError {
+            span: None,
+            message: format!(
+                "missing `version`, add `version = {}` for this server's schema",
+                CURRENT_VERSION
+            ),
+        }]);
+    };
+    let config: ConfigV1 = match *version.get_ref() {
+        1 => toml::from_str(text).map_err(|err| vec![err.into()])?,
+        other => {
+            return Err(vec![ConfigError {
+                span: Some(version.span()),
+                message: format!(
+                    "unsupported version {}, this server reads versions up to {}",
+                    other, CURRENT_VERSION
+                ),
+            }])
+        }
+    };
+    let errors = config.check();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(config)
+}
+
+impl ConfigV1 {
+    /// The command line flags that have the same effect, so that flags on the actual command line
+    /// can go after them and override them. Storage is left out if the command line chooses it.
+    pub(crate) fn to_args(&self, with_storage: bool) -> Vec<String> {
+        fn push(args: &mut Vec<String>, flag: &str, value: Option<impl Display>) {
+            if let Some(value) = value {
+                args.extend([format!("--{}", flag), value.to_string()]);
+            }
+        }
+        let mut args = vec![];
+        for listen in &self.listen {
+            push(&mut args, "listen", Some(listen));
+        }
+        for listen in &self.listen_tls {
+            push(&mut args, "listen-tls", Some(listen));
+        }
+        for listen in &self.listen_unix {
+            push(&mut args, "listen-unix", Some(listen));
+        }
+        push(&mut args, "tls-cert", self.tls.cert.as_ref());
+        push(&mut args, "tls-key", self.tls.key.as_ref());
+        push(
+            &mut args,
+            "log-level",
+            self.log_level.as_ref().map(Spanned::get_ref),
+        );
+        if with_storage {
+            push(
+                &mut args,
+                "storage",
+                self.storage.as_ref().map(Spanned::get_ref),
+            );
+            for uri in &self.fallback_storage {
+                push(&mut args, "fallback-storage", Some(uri.get_ref()));
+            }
+        }
+        push(
+            &mut args,
+            "stream-token-secret",
+            self.stream_token_secret.as_ref(),
+        );
+        for token in &self.auth.tokens {
+            push(&mut args, "auth-token", Some(token));
+        }
+        for token in &self.auth.admin_tokens {
+            push(&mut args, "admin-token", Some(token));
+        }
+        push(&mut args, "rate-limit", self.rate_limit.requests_per_second);
+        push(&mut args, "rate-limit-burst", self.rate_limit.burst);
+        if self.pipeline.normalize {
+            args.push("--normalize".to_owned());
+        }
+        let legacy_encoding = self.pipeline.legacy_encoding.as_ref();
+        push(
+            &mut args,
+            "legacy-encoding",
+            legacy_encoding.map(Spanned::get_ref),
+        );
+        let redact = &self.pipeline.redact;
+        for (flag, values) in [
+            ("redact-mask", &redact.mask),
+            ("redact-remove", &redact.remove),
+            ("redact-pattern", &redact.patterns),
+            ("redact-builtin", &redact.builtin),
+        ] {
+            for value in values {
+                push(&mut args, flag, Some(value.get_ref()));
+            }
+        }
+        let transform = &self.pipeline.transform;
+        push(
+            &mut args,
+            "transform-script",
+            transform.script.as_ref().map(Spanned::get_ref),
+        );
+        push(&mut args, "transform-fuel", transform.fuel);
+        for (name, uri) in &self.sinks {
+            let sink = format!("{}={}", name, uri.get_ref());
+            push(&mut args, "sink", Some(sink));
+        }
+        for route in &self.routes {
+            let route = format!("{}:{}", route.sink, route.when.get_ref());
+            push(&mut args, "route", Some(route));
+        }
+        push(&mut args, "max-event-bytes", self.limits.max_event_bytes);
+        push(&mut args, "max-event-depth", self.limits.max_event_depth);
+        push(&mut args, "max-array-length", self.limits.max_array_length);
+        let retention = &self.retention;
+        push(
+            &mut args,
+            "retain-for",
+            retention.retain_for.as_ref().map(Spanned::get_ref),
+        );
+        push(&mut args, "retain-max-events", retention.retain_max_events);
+        push(&mut args, "retain-max-bytes", retention.retain_max_bytes);
+        let prune_interval = retention.prune_interval.as_ref();
+        push(
+            &mut args,
+            "prune-interval",
+            prune_interval.map(Spanned::get_ref),
+        );
+        if self.enrich.enabled {
+            args.push("--enrich".to_owned());
+        }
+        for header in &self.enrich.headers {
+            push(&mut args, "enrich-header", Some(header));
+        }
+        push(
+            &mut args,
+            "tls-identity-header",
+            self.enrich.tls_identity_header.as_ref(),
+        );
+        push(&mut args, "instance-id", self.enrich.instance_id.as_ref());
+        let sampling = &self.sampling;
+        push(&mut args, "sample-every", sampling.every);
+        push(&mut args, "sample-field", sampling.field.as_ref());
+        for (value, rate) in &sampling.rates {
+            let rate = format!("{}={}", value, rate.get_ref());
+            push(&mut args, "sample-rate", Some(rate));
+        }
+        push(
+            &mut args,
+            "throttle-events-per-second",
+            sampling.throttle_events_per_second,
+        );
+        let streams = &self.streams;
+        let stale_after = streams.stale_after.as_ref();
+        push(&mut args, "stale-after", stale_after.map(Spanned::get_ref));
+        let stale_check_interval = streams.stale_check_interval.as_ref();
+        push(
+            &mut args,
+            "stale-check-interval",
+            stale_check_interval.map(Spanned::get_ref),
+        );
+        for (name, count) in &self.rollups.counts {
+            let rollup = format!("{}={}", name, count.get_ref());
+            push(&mut args, "rollup", Some(rollup));
+        }
+        let rollup_interval = self.rollups.interval.as_ref();
+        push(
+            &mut args,
+            "rollup-interval",
+            rollup_interval.map(Spanned::get_ref),
+        );
+        for (name, ingest) in &self.ingest {
+            push(&mut args, "ingest", Some(ingest.to_arg(name)));
+        }
+        let dedup_window = self.dedup.window.as_ref();
+        push(
+            &mut args,
+            "dedup-window",
+            dedup_window.map(Spanned::get_ref),
+        );
+        let dedup_action = self.dedup.action.as_ref();
+        push(
+            &mut args,
+            "dedup-action",
+            dedup_action.map(Spanned::get_ref),
+        );
+        args
+    }
+
+    /// Checks the values that are only strings to TOML.
+    fn check(&self) -> Vec<ConfigError> {
+        let mut errors = vec![];
+        let mut check = |value: &Option<Spanned<String>>, parse: &dyn Fn(&str) -> Result<()>| {
+            if let Some(value) = value {
+                if let Err(err) = parse(value.get_ref()) {
+                    errors.push(ConfigError {
+                        span: Some(value.span()),
+                        message: format!("{:#}", err),
+                    });
+                }
+            }
+        };
+        check(&self.log_level, &|level| {
+            Ok(LevelFilter::from_str(level).map(drop)?)
+        });
+        check(&self.storage, &|uri| Storage::from_str(uri).map(drop));
+        for uri in &self.fallback_storage {
+            check(&Some(uri.clone()), &|uri| Storage::from_str(uri).map(drop));
+        }
+        check(&self.pipeline.legacy_encoding, &|label| {
+            LegacyEncoding::from_str(label).map(drop)
+        });
+        let redact = &self.pipeline.redact;
+        let redact_values = [
+            &redact.mask,
+            &redact.remove,
+            &redact.patterns,
+            &redact.builtin,
+        ];
+        for (flag, values) in [
+            "redact-mask",
+            "redact-remove",
+            "redact-pattern",
+            "redact-builtin",
+        ]
+        .into_iter()
+        .zip(redact_values)
+        {
+            for value in values {
+                check(&Some(value.clone()), &|value| {
+                    RedactArgs::check(flag, value)
+                });
+            }
+        }
+        let transform = &self.pipeline.transform;
+        check(&transform.script, &TransformArgs::check);
+        for (name, uri) in &self.sinks {
+            check(&Some(uri.clone()), &|uri| {
+                RoutingArgs::check("sink", &format!("{}={}", name, uri))
+            });
+        }
+        for route in &self.routes {
+            check(&Some(route.when.clone()), &|when| {
+                RoutingArgs::check("route", &format!("{}:{}", route.sink, when))
+            });
+        }
+        let duration =
+            |text: &str| -> Result<()> { Ok(humantime::parse_duration(text).map(drop)?) };
+        check(&self.retention.retain_for, &duration);
+        check(&self.retention.prune_interval, &duration);
+        check(&self.streams.stale_after, &duration);
+        check(&self.streams.stale_check_interval, &duration);
+        check(&self.rollups.interval, &duration);
+        check(&self.dedup.window, &duration);
+        check(&self.dedup.action, &|action| {
+            <DedupAction as clap::ValueEnum>::from_str(action, false)
+                .map(drop)
+                .map_err(|_| anyhow!("unknown dedup action {:?}", action))
+        });
+        for (name, count) in &self.rollups.counts {
+            check(&Some(count.clone()), &|count| {
+                Rollup::from_str(&format!("{}={}", name, count)).map(drop)
+            });
+        }
+        for ingest in self.ingest.values() {
+            check(&ingest.schema, &|path| EventSchema::load(path).map(drop));
+        }
+        for (name, ingest) in &self.ingest {
+            if let Err(err) = ingest::check_name(name) {
+                errors.push(ConfigError {
+                    span: None,
+                    message: format!("{:#}", err),
+                });
+            }
+            if !ingest.sampling.rates.is_empty() && ingest.sampling.field.is_none() {
+                errors.push(ConfigError {
+                    span: None,
+                    message: format!("ingest endpoint {} has sampling rates but no field", name),
+                });
+            }
+        }
+        let ingest_rates = self
+            .ingest
+            .values()
+            .flat_map(|ingest| &ingest.sampling.rates);
+        for rate in self
+            .sampling
+            .rates
+            .values()
+            .chain(ingest_rates.map(|(_, rate)| rate))
+        {
+            if !(0.0..=1.0).contains(rate.get_ref()) {
+                errors.push(ConfigError {
+                    span: Some(rate.span()),
+                    message: "sample rate must be between 0 and 1".to_owned(),
+                });
+            }
+        }
+        errors
+    }
+}
+
+/// A problem with a config file, located by byte range where possible.
+#[derive(Debug)]
+pub(crate) struct ConfigError {
+    span: Option<Range<usize>>,
+    message: String,
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        let mut message = err.message().trim_end().to_owned();
+        if let Some(suggestion) = suggest_field(&message) {
+            message += &format!(", did you mean `{}`?", suggestion);
+        }
+        Self {
+            span: err.span(),
+            message,
+        }
+    }
+}
+
+impl ConfigError {
+    /// Formats as "line:column: message" with the offending line underneath, for the file the
+    /// error came from.
+    pub(crate) fn display<'a>(&'a self, text: &'a str) -> impl Display + 'a {
+        DisplayConfigError { error: self, text }
+    }
+}
+
+struct DisplayConfigError<'a> {
+    error: &'a ConfigError,
+    text: &'a str,
+}
+
+impl Display for DisplayConfigError<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let Some(span) = &self.error.span else {
+            return write!(f, " {}", self.error.message);
+        };
+        let line_start = self.text[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.text[span.start..]
+            .find('\n')
+            .map_or(self.text.len(), |i| span.start + i);
+        let line_number = self.text[..span.start].matches('\n').count() + 1;
+        let column = self.text[line_start..span.start].chars().count() + 1;
+        let width = self.text[span.start..span.end.min(line_end)]
+            .chars()
+            .count()
+            .max(1);
+        writeln!(f, "{}:{}: {}", line_number, column, self.error.message)?;
+        writeln!(f, "    {}", &self.text[line_start..line_end])?;
+        write!(f, "    {}{}", " ".repeat(column - 1), "^".repeat(width))
+    }
+}
+
+/// Picks the closest expected field for serde's "unknown field `x`, expected one of `a`, `b`"
+/// message, if any is close enough to be a typo.
+fn suggest_field(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("unknown field ")?;
+    let mut quoted = rest.split('`').skip(1).step_by(2);
+    let unknown = quoted.next()?;
+    quoted
+        .map(|expected| (strsim::damerau_levenshtein(unknown, expected), expected))
+        .filter(|(distance, expected)| *distance <= 2.max(expected.len() / 3))
+        .min()
+        .map(|(_, expected)| expected)
+}
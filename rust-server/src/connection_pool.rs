@@ -0,0 +1,55 @@
+use futures::FutureExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use telemetry_storage::Connection;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Connections to the same storage, each used by one request at a time. Requests only wait for
+/// each other once every connection is in use.
+pub(crate) struct ConnectionPool {
+    conns: Vec<Mutex<Box<dyn Connection + Send>>>,
+    /// Where the next request starts looking for a free connection, so they're used in turn.
+    next: AtomicUsize,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(conns: Vec<Box<dyn Connection + Send>>) -> Self {
+        assert!(!conns.is_empty(), "a pool needs a connection");
+        Self {
+            conns: conns.into_iter().map(Mutex::new).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// A free connection, or whichever comes free first if they're all in use.
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, Box<dyn Connection + Send>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.conns.len();
+        for offset in 0..count {
+            if let Ok(conn) = self.conns[(start + offset) % count].try_lock() {
+                return conn;
+            }
+        }
+        let waiting = self.conns.iter().map(|conn| conn.lock().boxed());
+        futures::future::select_all(waiting).await.0
+    }
+
+    /// Every connection, for what has to be done to each of them, like committing or shutting
+    /// down.
+    pub(crate) fn all(&self) -> impl Iterator<Item = &Mutex<Box<dyn Connection + Send>>> {
+        self.conns.iter()
+    }
+
+    /// Swaps in connections to other storage, one for each there was, handing back the old ones.
+    /// Each is swapped once it's free, so requests using it finish with the old storage.
+    pub(crate) async fn replace(
+        &self,
+        conns: Vec<Box<dyn Connection + Send>>,
+    ) -> Vec<Box<dyn Connection + Send>> {
+        assert_eq!(conns.len(), self.conns.len(), "a connection for each");
+        let mut old = vec![];
+        for (slot, conn) in self.conns.iter().zip(conns) {
+            old.push(std::mem::replace(&mut *slot.lock().await, conn));
+        }
+        old
+    }
+}
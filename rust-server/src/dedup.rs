@@ -0,0 +1,85 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Catching requests that repeat one received a little earlier, from agents that resend whole
+/// batches over and over.
+#[derive(Clone, Default, clap::Args)]
+pub struct DedupArgs {
+    /// Treat a POST with the same body as one received within this long, like "10m", as a
+    /// duplicate. Hashes of recent bodies are kept in the storage, so repeats are caught across
+    /// restarts, and between servers sharing a database.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    dedup_window: Option<Duration>,
+    /// What to do with duplicates: "reject" them with 409 Conflict, or "tag" their events with
+    /// when the body was first received, as duplicate_of in their collector fields.
+    #[arg(long, value_enum, default_value = "reject")]
+    dedup_action: DedupAction,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum DedupAction {
+    #[default]
+    Reject,
+    Tag,
+}
+
+impl DedupAction {
+    /// As given on the command line.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Tag => "tag",
+        }
+    }
+}
+
+impl DedupArgs {
+    pub(crate) fn dedup(&self) -> Option<Dedup> {
+        Some(Dedup {
+            window: self.dedup_window?,
+            action: self.dedup_action,
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!(self.dedup().as_ref().map(|dedup| json!({
+            "window": humantime::format_duration(dedup.window).to_string(),
+            "action": dedup.action.name(),
+        })))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Dedup {
+    pub window: Duration,
+    pub action: DedupAction,
+}
+
+/// Hashes what makes a request the same as another: where it was posted, the stream token it
+/// resumes a stream with, and its body after decompressing. The same events sent to different
+/// streams aren't duplicates. The body's hashed a chunk at a time as it's read.
+pub(crate) struct RequestHasher(Sha256);
+
+impl RequestHasher {
+    pub(crate) fn new(path: &str, stream_token: Option<&[u8]>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+        hasher.update(stream_token.unwrap_or_default());
+        hasher.update([0]);
+        Self(hasher)
+    }
+
+    pub(crate) fn update(&mut self, body: &[u8]) {
+        self.0.update(body);
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.0
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::Encoding;
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// Field added to object payloads that had to be converted to UTF-8, naming the original encoding.
+pub(crate) const SOURCE_ENCODING_FIELD: &str = "_source_encoding";
+
+/// What to do with payloads that aren't valid UTF-8. Field devices routinely send text in legacy
+/// encodings.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum LegacyEncoding {
+    #[default]
+    Reject,
+    /// Guess the encoding from the payload bytes.
+    Detect,
+    /// Assume a particular encoding, by WHATWG label (like "latin1" or "shift_jis").
+    Fixed(&'static Encoding),
+}
+
+impl FromStr for LegacyEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "detect" => Ok(Self::Detect),
+            label => Encoding::for_label(label.as_bytes())
+                .map(Self::Fixed)
+                .ok_or_else(|| anyhow!("unknown encoding {:?}", label)),
+        }
+    }
+}
+
+impl LegacyEncoding {
+    /// As given on the command line.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Detect => "detect",
+            Self::Fixed(encoding) => encoding.name(),
+        }
+    }
+
+    /// Converts payload bytes to UTF-8 text. Converted object payloads get a marker field recording
+    /// the source encoding.
+    pub(crate) fn decode<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, str>> {
+        let utf8_err = match std::str::from_utf8(bytes) {
+            Ok(text) => return Ok(Cow::Borrowed(text)),
+            Err(err) => err,
+        };
+        let encoding = match self {
+            Self::Reject => return Err(anyhow!(utf8_err).context("payload is not utf-8")),
+            Self::Detect => {
+                let mut detector = chardetng::EncodingDetector::new();
+                detector.feed(bytes, true);
+                detector.guess(None, true)
+            }
+            Self::Fixed(encoding) => *encoding,
+        };
+        let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+        if had_errors {
+            return Err(anyhow!(
+                "payload is neither utf-8 nor valid {}",
+                encoding.name()
+            ));
+        }
+        let mut value: serde_json::Value = serde_json::from_str(&text)?;
+        if let Some(object) = value.as_object_mut() {
+            object.insert(SOURCE_ENCODING_FIELD.to_owned(), encoding.name().into());
+        }
+        Ok(Cow::Owned(value.to_string()))
+    }
+}
@@ -0,0 +1,105 @@
+use crate::request_id::RequestId;
+use axum::http::HeaderMap;
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use std::net::SocketAddr;
+
+#[derive(Clone, clap::Args)]
+pub struct EnrichArgs {
+    /// Record where each event came from (remote address, receive time, server instance, request
+    /// ID) in its collector column.
+    #[arg(long)]
+    enrich: bool,
+    /// Request header to copy into the collector metadata. Can be repeated.
+    #[arg(long = "enrich-header")]
+    headers: Vec<String>,
+    /// Header set by a TLS terminating proxy with the client certificate identity.
+    #[arg(long)]
+    tls_identity_header: Option<String>,
+    /// Identifies this server in collector metadata. Defaults to a random ID per process.
+    #[arg(long)]
+    instance_id: Option<String>,
+}
+
+impl EnrichArgs {
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "enabled": self.enrich,
+            "headers": self.headers,
+            "tls_identity_header": self.tls_identity_header,
+            "instance_id": self.instance_id,
+        })
+    }
+
+    pub(crate) fn enricher(&self) -> Option<Enricher> {
+        if !self.enrich {
+            return None;
+        }
+        Some(Enricher {
+            instance_id: self
+                .instance_id
+                .clone()
+                .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>())),
+            headers: self.headers.clone(),
+            tls_identity_header: self.tls_identity_header.clone(),
+        })
+    }
+}
+
+/// Builds the server-side metadata stored alongside client payloads.
+pub(crate) struct Enricher {
+    instance_id: String,
+    headers: Vec<String>,
+    tls_identity_header: Option<String>,
+}
+
+impl Enricher {
+    /// Metadata common to all events received over one connection or request.
+    pub(crate) fn source(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        headers: &HeaderMap,
+        request_id: Option<RequestId>,
+    ) -> Source {
+        let header_value = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let mut object = Map::new();
+        object.insert("instance_id".to_owned(), self.instance_id.clone().into());
+        if let Some(remote_addr) = remote_addr {
+            object.insert("remote_ip".to_owned(), remote_addr.ip().to_string().into());
+        }
+        if let Some(request_id) = request_id {
+            object.insert("request_id".to_owned(), request_id.as_str().into());
+        }
+        if let Some(identity) = self.tls_identity_header.as_deref().and_then(header_value) {
+            object.insert("tls_identity".to_owned(), identity.into());
+        }
+        let selected: Map<_, _> = self
+            .headers
+            .iter()
+            .filter_map(|name| Some((name.to_lowercase(), header_value(name)?.into())))
+            .collect();
+        if !selected.is_empty() {
+            object.insert("headers".to_owned(), Value::Object(selected));
+        }
+        Source(object)
+    }
+}
+
+pub(crate) struct Source(Map<String, Value>);
+
+impl Source {
+    /// The collector metadata for an event received now.
+    pub(crate) fn event(&self) -> Value {
+        let mut object = self.0.clone();
+        object.insert(
+            "received_datetime".to_owned(),
+            json!(Utc::now().to_rfc3339()),
+        );
+        Value::Object(object)
+    }
+}
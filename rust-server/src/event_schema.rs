@@ -0,0 +1,338 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The JSON Schema keywords events are checked against. Schemas using others are refused rather
+/// than half checked.
+const KEYWORDS: &[&str] = &[
+    "type",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "enum",
+    "const",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "minLength",
+    "maxLength",
+    "minItems",
+    "maxItems",
+    "pattern",
+];
+
+/// Keywords that don't say anything about what's valid.
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+const TYPES: &[&str] = &[
+    "null", "boolean", "object", "array", "number", "integer", "string",
+];
+
+/// A JSON Schema events have to match, loaded from a file. Only the validation keywords in
+/// [KEYWORDS] are supported, without references.
+#[derive(Clone)]
+pub(crate) struct EventSchema {
+    path: PathBuf,
+    schema: Arc<Value>,
+    /// Each "pattern" in the schema, compiled.
+    patterns: Arc<HashMap<String, Regex>>,
+}
+
+impl EventSchema {
+    pub(crate) fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let schema: Value =
+            serde_json::from_str(&text).with_context(|| format!("parsing {}", path))?;
+        let mut patterns = HashMap::new();
+        check_schema(&schema, "", &mut patterns).with_context(|| format!("checking {}", path))?;
+        Ok(Self {
+            path: path.into(),
+            schema: Arc::new(schema),
+            patterns: Arc::new(patterns),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Checks a payload against the schema, reporting the first place it doesn't match.
+    pub(crate) fn validate(&self, payload: &Value) -> Result<(), SchemaViolation> {
+        self.validate_at(&self.schema, payload, &mut String::new())
+    }
+
+    fn validate_at(
+        &self,
+        schema: &Value,
+        value: &Value,
+        path: &mut String,
+    ) -> Result<(), SchemaViolation> {
+        // Takes the path rather than borrowing it, since it changes as items are checked.
+        let violation = |path: &str, message: String| SchemaViolation {
+            path: path.to_owned(),
+            message,
+        };
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => {
+                return Err(violation(path, "nothing is allowed here".to_owned()))
+            }
+            Value::Object(schema) => schema,
+            _ => unreachable!("checked on load"),
+        };
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::String(name) => vec![name],
+                types => types
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .flat_map(Value::as_str)
+                    .collect(),
+            };
+            if !types.iter().any(|name| is_type(value, name)) {
+                return Err(violation(
+                    path,
+                    format!("{} isn't {}", value, types.join(" or ")),
+                ));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                return Err(violation(
+                    path,
+                    format!("{} isn't one of {}", value, json!(allowed)),
+                ));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if value != expected {
+                return Err(violation(path, format!("{} isn't {}", value, expected)));
+            }
+        }
+        if let Some(number) = value.as_f64() {
+            let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+            for (keyword, out_of_bounds) in [
+                ("minimum", bound("minimum").filter(|min| number < *min)),
+                ("maximum", bound("maximum").filter(|max| number > *max)),
+                (
+                    "exclusiveMinimum",
+                    bound("exclusiveMinimum").filter(|min| number <= *min),
+                ),
+                (
+                    "exclusiveMaximum",
+                    bound("exclusiveMaximum").filter(|max| number >= *max),
+                ),
+            ] {
+                if let Some(bound) = out_of_bounds {
+                    return Err(violation(
+                        path,
+                        format!("{} is past {} {}", number, keyword, bound),
+                    ));
+                }
+            }
+        }
+        let count = |keyword: &str| schema.get(keyword).and_then(Value::as_u64);
+        if let Some(text) = value.as_str() {
+            let len = text.chars().count() as u64;
+            if let Some(min) = count("minLength").filter(|min| len < *min) {
+                return Err(violation(
+                    path,
+                    format!("{} characters is under minLength {}", len, min),
+                ));
+            }
+            if let Some(max) = count("maxLength").filter(|max| len > *max) {
+                return Err(violation(
+                    path,
+                    format!("{} characters is over maxLength {}", len, max),
+                ));
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                if !self.patterns[pattern].is_match(text) {
+                    return Err(violation(
+                        path,
+                        format!("{:?} doesn't match {:?}", text, pattern),
+                    ));
+                }
+            }
+        }
+        if let Some(items) = value.as_array() {
+            let len = items.len() as u64;
+            if let Some(min) = count("minItems").filter(|min| len < *min) {
+                return Err(violation(
+                    path,
+                    format!("{} items is under minItems {}", len, min),
+                ));
+            }
+            if let Some(max) = count("maxItems").filter(|max| len > *max) {
+                return Err(violation(
+                    path,
+                    format!("{} items is over maxItems {}", len, max),
+                ));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    let len = path.len();
+                    path.push_str(&format!("/{}", index));
+                    self.validate_at(item_schema, item, path)?;
+                    path.truncate(len);
+                }
+            }
+        }
+        if let Some(object) = value.as_object() {
+            let required = schema.get("required").and_then(Value::as_array);
+            for key in required.into_iter().flatten().flat_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    return Err(violation(path, format!("missing required {:?}", key)));
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (key, field) in object {
+                let field_schema = match properties.and_then(|properties| properties.get(key)) {
+                    Some(field_schema) => field_schema,
+                    None => match schema.get("additionalProperties") {
+                        Some(additional) => additional,
+                        None => continue,
+                    },
+                };
+                let len = path.len();
+                path.push_str(&format!("/{}", key.replace('~', "~0").replace('/', "~1")));
+                self.validate_at(field_schema, field, path)?;
+                path.truncate(len);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "string" => value.is_string(),
+        _ => false,
+    }
+}
+
+/// Checks that a schema only uses supported keywords, with values of the right shape, and compiles
+/// its patterns.
+fn check_schema(schema: &Value, at: &str, patterns: &mut HashMap<String, Regex>) -> Result<()> {
+    let where_ = || match at {
+        "" => "the top level".to_owned(),
+        at => at.to_owned(),
+    };
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => bail!("schema at {} isn't an object or boolean", where_()),
+    };
+    for (keyword, value) in schema {
+        if ANNOTATIONS.contains(&keyword.as_str()) {
+            continue;
+        }
+        if !KEYWORDS.contains(&keyword.as_str()) {
+            bail!("{:?} at {} isn't supported", keyword, where_());
+        }
+        let shaped = match keyword.as_str() {
+            "type" => match value {
+                Value::String(name) => TYPES.contains(&name.as_str()),
+                Value::Array(names) => names
+                    .iter()
+                    .all(|name| name.as_str().is_some_and(|name| TYPES.contains(&name))),
+                _ => false,
+            },
+            "properties" => match value.as_object() {
+                Some(properties) => {
+                    for (key, property) in properties {
+                        check_schema(property, &format!("{}/properties/{}", at, key), patterns)?;
+                    }
+                    true
+                }
+                None => false,
+            },
+            "additionalProperties" | "items" => {
+                check_schema(value, &format!("{}/{}", at, keyword), patterns)?;
+                true
+            }
+            "required" => value
+                .as_array()
+                .is_some_and(|keys| keys.iter().all(Value::is_string)),
+            "enum" => value.is_array(),
+            "const" => true,
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => value.is_number(),
+            "minLength" | "maxLength" | "minItems" | "maxItems" => value.is_u64(),
+            _ => match value.as_str() {
+                Some(pattern) => {
+                    let regex =
+                        Regex::new(pattern).with_context(|| format!("pattern at {}", where_()))?;
+                    patterns.insert(pattern.to_owned(), regex);
+                    true
+                }
+                None => false,
+            },
+        };
+        if !shaped {
+            bail!(
+                "{:?} at {} is {}, which isn't valid for it",
+                keyword,
+                where_(),
+                value
+            );
+        }
+    }
+    Ok(())
+}
+
+/// An event that doesn't match its ingest endpoint's schema.
+#[derive(Debug)]
+pub(crate) struct SchemaViolation {
+    /// A JSON pointer to where in the event.
+    path: String,
+    message: String,
+}
+
+impl SchemaViolation {
+    /// The fields added to error responses for clients to act on.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "code": "schema_violation",
+            "path": self.path,
+        })
+    }
+}
+
+impl Display for SchemaViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let path = match self.path.as_str() {
+            "" => "/",
+            path => path,
+        };
+        write!(
+            f,
+            "event doesn't match schema at {}: {}",
+            path, self.message
+        )
+    }
+}
+
+impl std::error::Error for SchemaViolation {}
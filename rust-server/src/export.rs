@@ -0,0 +1,170 @@
+mod avro;
+mod csv;
+mod parquet;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Write;
+use telemetry_storage::{Connection, EventQuery, Snapshot};
+
+/// Events exported over HTTP when the view doesn't say how many. The response is built in memory.
+pub(crate) const DEFAULT_EXPORT_LIMIT: usize = 1_000_000;
+
+/// Events are read from storage and formatted in batches of this many.
+const EXPORT_BATCH_SIZE: usize = 10_000;
+
+/// A sink for exported files.
+pub(crate) type Output<'a> = &'a mut (dyn Write + Send);
+
+/// Writes exported events in one file format. Events are objects with `stream_id`,
+/// `stream_event_index`, `insert_datetime`, `payload` and `stream` fields, as returned by
+/// [with_streams].
+pub(crate) trait Formatter: Send + Sync {
+    fn content_type(&self) -> &'static str;
+    fn file_extension(&self) -> &'static str;
+    /// Starts a file that events are added to in batches.
+    fn start<'a>(&self, out: Output<'a>) -> Result<Box<dyn BatchWriter + 'a>>;
+}
+
+/// A file being exported by a [Formatter].
+pub(crate) trait BatchWriter: Send {
+    fn write_batch(&mut self, events: &[Value]) -> Result<()>;
+    /// Writes whatever the format needs after the last event.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Reads the selected events with their streams from storage, in batches, and writes them in the
+/// formatter's format. Returns how many events were exported.
+pub(crate) async fn export(
+    conn: &mut (dyn Connection + Send),
+    query: &EventQuery,
+    formatter: &dyn Formatter,
+    out: Output<'_>,
+) -> Result<u64> {
+    let mut writer = formatter.start(out)?;
+    let mut exported = 0;
+    conn.snapshot_batches(query, EXPORT_BATCH_SIZE, &mut |snapshot| {
+        let events = with_streams(snapshot)?;
+        exported += events.len() as u64;
+        writer.write_batch(&events)
+    })
+    .await?;
+    writer.finish()?;
+    Ok(exported)
+}
+
+/// The built-in formatters, selected by name for each export.
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    #[default]
+    Ndjson,
+    Csv,
+    Parquet,
+    Avro,
+}
+
+impl ExportFormat {
+    pub(crate) fn formatter(self) -> Box<dyn Formatter> {
+        match self {
+            Self::Ndjson => Box::new(Ndjson),
+            Self::Csv => Box::new(csv::Csv),
+            Self::Parquet => Box::new(parquet::Parquet),
+            Self::Avro => Box::new(avro::Avro),
+        }
+    }
+}
+
+/// The snapshot's events, each with its stream's headers, start and end in a `stream` field. Every
+/// stream in an export comes with its events, and every event with its stream.
+pub(crate) fn with_streams(snapshot: Snapshot) -> Result<Vec<Value>> {
+    let mut streams: HashMap<i64, Value> = HashMap::new();
+    for mut stream in snapshot.streams {
+        let stream_id = stream
+            .as_object_mut()
+            .and_then(|stream| stream.remove("stream_id"))
+            .and_then(|stream_id| stream_id.as_i64())
+            .context("stream has no stream_id")?;
+        streams.insert(stream_id, stream);
+    }
+    snapshot
+        .events
+        .into_iter()
+        .map(|mut event| {
+            let stream_id = event["stream_id"]
+                .as_i64()
+                .context("event has no stream_id")?;
+            let stream = streams
+                .get(&stream_id)
+                .with_context(|| format!("stream {} missing from snapshot", stream_id))?;
+            event["stream"] = stream.clone();
+            Ok(event)
+        })
+        .collect()
+}
+
+/// One JSON event per line.
+pub(crate) struct Ndjson;
+
+impl Formatter for Ndjson {
+    fn content_type(&self) -> &'static str {
+        "application/x-ndjson"
+    }
+    fn file_extension(&self) -> &'static str {
+        "ndjson"
+    }
+    fn start<'a>(&self, out: Output<'a>) -> Result<Box<dyn BatchWriter + 'a>> {
+        Ok(Box::new(NdjsonWriter { out }))
+    }
+}
+
+struct NdjsonWriter<'a> {
+    out: Output<'a>,
+}
+
+impl BatchWriter for NdjsonWriter<'_> {
+    fn write_batch(&mut self, events: &[Value]) -> Result<()> {
+        for event in events {
+            serde_json::to_writer(&mut *self.out, event)?;
+            self.out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// The event fields every format has a column for.
+struct EventColumns<'a> {
+    stream_id: i64,
+    stream_event_index: i64,
+    insert_datetime: &'a str,
+    payload: &'a Value,
+    stream: &'a Value,
+}
+
+impl<'a> EventColumns<'a> {
+    fn of(event: &'a Value) -> Result<Self> {
+        let field = |name| {
+            event
+                .get(name)
+                .ok_or_else(|| anyhow!("event missing {}", name))
+        };
+        let integer = |name| {
+            field(name)?
+                .as_i64()
+                .with_context(|| format!("event {} isn't an integer", name))
+        };
+        Ok(Self {
+            stream_id: integer("stream_id")?,
+            stream_event_index: integer("stream_event_index")?,
+            insert_datetime: field("insert_datetime")?
+                .as_str()
+                .context("event insert_datetime isn't a string")?,
+            payload: field("payload")?,
+            stream: field("stream")?,
+        })
+    }
+}
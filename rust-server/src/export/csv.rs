@@ -0,0 +1,106 @@
+use super::*;
+use serde_json::Map;
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, BufWriter, Seek};
+
+/// Payload and stream fields flattened into columns named by their dotted paths, for spreadsheets.
+/// Arrays are kept as JSON text, and events without a field leave its column empty.
+pub(crate) struct Csv;
+
+impl Formatter for Csv {
+    fn content_type(&self) -> &'static str {
+        "text/csv"
+    }
+    fn file_extension(&self) -> &'static str {
+        "csv"
+    }
+    fn start<'a>(&self, out: Output<'a>) -> Result<Box<dyn BatchWriter + 'a>> {
+        Ok(Box::new(CsvWriter {
+            out,
+            rows: BufWriter::new(tempfile::tempfile().context("creating csv spill file")?),
+            flattened_columns: Default::default(),
+        }))
+    }
+}
+
+/// The event columns, then the flattened fields, as spilled.
+type Row = (i64, i64, String, Map<String, Value>);
+
+/// The header needs every column, which isn't known until the last event, so rows are spilled to
+/// a temporary file as JSON lines until then.
+struct CsvWriter<'a> {
+    out: Output<'a>,
+    rows: BufWriter<std::fs::File>,
+    flattened_columns: BTreeSet<String>,
+}
+
+impl BatchWriter for CsvWriter<'_> {
+    fn write_batch(&mut self, events: &[Value]) -> Result<()> {
+        for event in events {
+            let columns = EventColumns::of(event)?;
+            let mut fields = Map::new();
+            flatten("payload", columns.payload, &mut fields);
+            flatten("stream", columns.stream, &mut fields);
+            for name in fields.keys() {
+                if !self.flattened_columns.contains(name) {
+                    self.flattened_columns.insert(name.clone());
+                }
+            }
+            let row = (
+                columns.stream_id,
+                columns.stream_event_index,
+                columns.insert_datetime,
+                fields,
+            );
+            serde_json::to_writer(&mut self.rows, &row)?;
+            self.rows.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let Self {
+            out,
+            rows,
+            flattened_columns,
+        } = *self;
+        let mut rows = rows.into_inner()?;
+        rows.rewind()?;
+        let mut writer = ::csv::Writer::from_writer(out);
+        writer.write_record(
+            ["stream_id", "stream_event_index", "insert_datetime"]
+                .into_iter()
+                .chain(flattened_columns.iter().map(String::as_str)),
+        )?;
+        for line in BufReader::new(rows).lines() {
+            let (stream_id, stream_event_index, insert_datetime, fields): Row =
+                serde_json::from_str(&line?)?;
+            let mut record = vec![
+                stream_id.to_string(),
+                stream_event_index.to_string(),
+                insert_datetime,
+            ];
+            record.extend(flattened_columns.iter().map(|name| match fields.get(name) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(text)) => text.clone(),
+                Some(value) => value.to_string(),
+            }));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn flatten(path: &str, value: &Value, fields: &mut Map<String, Value>) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object {
+                flatten(&format!("{}.{}", path, key), child, fields);
+            }
+        }
+        _ => {
+            fields.insert(path.to_owned(), value.clone());
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use super::*;
+use ::parquet::basic::{Compression, ZstdLevel};
+use ::parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use ::parquet::file::properties::WriterProperties;
+use ::parquet::file::writer::SerializedFileWriter;
+use ::parquet::schema::parser::parse_message_type;
+use std::sync::Arc;
+
+const SCHEMA: &str = "
+    message event {
+        required int64 stream_id;
+        required int64 stream_event_index;
+        required binary insert_datetime (STRING);
+        required binary payload (JSON);
+        required binary stream (JSON);
+    }
+";
+
+/// A zstd compressed Parquet file, for loading into warehouses. Each batch of events is a row group.
+pub(crate) struct Parquet;
+
+impl Formatter for Parquet {
+    fn content_type(&self) -> &'static str {
+        "application/vnd.apache.parquet"
+    }
+    fn file_extension(&self) -> &'static str {
+        "parquet"
+    }
+    fn start<'a>(&self, out: Output<'a>) -> Result<Box<dyn BatchWriter + 'a>> {
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        let writer = SerializedFileWriter::new(
+            Vec::new(),
+            Arc::new(parse_message_type(SCHEMA)?),
+            Arc::new(properties),
+        )?;
+        Ok(Box::new(ParquetWriter { out, writer }))
+    }
+}
+
+/// The file writer writes to a buffer, which is passed on after each row group, so the output
+/// doesn't need to be seekable.
+struct ParquetWriter<'a> {
+    out: Output<'a>,
+    writer: SerializedFileWriter<Vec<u8>>,
+}
+
+impl BatchWriter for ParquetWriter<'_> {
+    fn write_batch(&mut self, events: &[Value]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let events = events
+            .iter()
+            .map(EventColumns::of)
+            .collect::<Result<Vec<_>>>()?;
+        let integers = |column: fn(&EventColumns) -> i64| events.iter().map(column).collect();
+        let int64_columns: [Vec<i64>; 2] = [
+            integers(|event| event.stream_id),
+            integers(|event| event.stream_event_index),
+        ];
+        let byte_array_columns: [Vec<ByteArray>; 3] = [
+            events
+                .iter()
+                .map(|event| event.insert_datetime.into())
+                .collect(),
+            events
+                .iter()
+                .map(|event| event.payload.to_string().into_bytes().into())
+                .collect(),
+            events
+                .iter()
+                .map(|event| event.stream.to_string().into_bytes().into())
+                .collect(),
+        ];
+        let mut row_group = self.writer.next_row_group()?;
+        for values in &int64_columns {
+            let mut column = row_group.next_column()?.context("missing column")?;
+            column
+                .typed::<Int64Type>()
+                .write_batch(values, None, None)?;
+            column.close()?;
+        }
+        for values in &byte_array_columns {
+            let mut column = row_group.next_column()?.context("missing column")?;
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(values, None, None)?;
+            column.close()?;
+        }
+        row_group.close()?;
+        self.out.write_all(self.writer.inner())?;
+        self.writer.inner_mut().clear();
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let Self { out, writer } = *self;
+        out.write_all(&writer.into_inner()?)?;
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use crate::connection_pool::ConnectionPool;
+use crate::pause::Pause;
+use crate::Storage;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use telemetry_storage::Connection;
+use tracing::*;
+
+/// Storage to serve into instead when the storage before it can't be used, so collecting carries
+/// on while a central database is down.
+#[derive(Clone, clap::Args)]
+pub(crate) struct FallbackArgs {
+    /// Storage URI to fall back to, like "sqlite://fallback.db", when the storage can't be opened
+    /// on startup, or fails --fallback-after checks in a row while serving. Can be repeated, to
+    /// fall back to each in turn.
+    #[arg(long, global = true)]
+    pub fallback_storage: Vec<Storage>,
+    /// How often the storage in use is checked while serving, when there's storage to fall back
+    /// to.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    pub fallback_check_interval: Duration,
+    /// Failed checks in a row before falling back.
+    #[arg(long, default_value = "3")]
+    pub fallback_after: u64,
+}
+
+impl FallbackArgs {
+    pub(crate) fn to_json(&self) -> Value {
+        if self.fallback_storage.is_empty() {
+            return json!(null);
+        }
+        json!({
+            "storage": self.fallback_storage.iter().map(Storage::info).collect::<Vec<_>>(),
+            "check_interval": humantime::format_duration(self.fallback_check_interval).to_string(),
+            "after": self.fallback_after,
+        })
+    }
+}
+
+/// The main storage and its fallbacks, which of them is being served into, and how it came to be.
+pub(crate) struct Fallback {
+    /// The main storage first, then the fallbacks in order.
+    chain: Vec<Storage>,
+    check_interval: Duration,
+    after: u64,
+    /// Where in the chain the storage in use is. It only moves forward, once the pool's been
+    /// swapped over.
+    active: AtomicUsize,
+    fallbacks: AtomicU64,
+    checks: AtomicU64,
+    failed_checks: AtomicU64,
+    last_error: std::sync::Mutex<Option<String>>,
+}
+
+impl Fallback {
+    pub(crate) fn new(main: Storage, args: &FallbackArgs) -> Self {
+        let mut chain = vec![main];
+        chain.extend(args.fallback_storage.iter().cloned());
+        Self {
+            chain,
+            check_interval: args.fallback_check_interval,
+            after: args.fallback_after,
+            active: Default::default(),
+            fallbacks: Default::default(),
+            checks: Default::default(),
+            failed_checks: Default::default(),
+            last_error: Default::default(),
+        }
+    }
+
+    /// Every storage that can be served into.
+    pub(crate) fn chain(&self) -> &[Storage] {
+        &self.chain
+    }
+
+    /// Where in the chain the storage in use is. Stream IDs are only good in the storage that gave
+    /// them out, so this tells them apart.
+    pub(crate) fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Opens connections to the first storage in the chain that opens.
+    pub(crate) async fn open(&self, connections: usize) -> Result<Vec<Box<dyn Connection + Send>>> {
+        let (index, conns) = self.open_from(0, connections).await?;
+        self.set_active(index);
+        Ok(conns)
+    }
+
+    fn set_active(&self, index: usize) {
+        if index > self.active.swap(index, Ordering::Relaxed) {
+            self.fallbacks.fetch_add(1, Ordering::Relaxed);
+            warn!(index, storage = %self.chain[index].info(), "fell back to storage");
+        }
+    }
+
+    /// Opens connections to the first storage that opens from `start` on in the chain, returning
+    /// where it is in the chain. Returns the last storage's error if none of them open.
+    async fn open_from(
+        &self,
+        start: usize,
+        connections: usize,
+    ) -> Result<(usize, Vec<Box<dyn Connection + Send>>)> {
+        for (index, storage) in self.chain.iter().enumerate().skip(start) {
+            let mut conns = vec![];
+            let opened = async {
+                for _ in 0..connections {
+                    conns.push(storage.clone().open().await?);
+                }
+                anyhow::Ok(())
+            }
+            .await;
+            match opened {
+                Ok(()) => return Ok((index, conns)),
+                Err(err) if index + 1 < self.chain.len() => {
+                    warn!(index, err = format!("{:#}", err), "can't open storage");
+                    self.set_last_error(&err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        unreachable!("the chain has the main storage")
+    }
+
+    /// Checks the storage in use every --fallback-check-interval, and once it's failed
+    /// --fallback-after checks in a row, swaps the pool's connections for ones to the next storage
+    /// that opens, and calls `on_swap` to forget what's known of the old storage. Requests in
+    /// progress finish with the old storage. Does nothing once there's nothing left to fall back
+    /// to.
+    pub(crate) async fn check_periodically(
+        &self,
+        pool: &ConnectionPool,
+        pause: &Pause,
+        on_swap: impl Fn(),
+    ) {
+        let mut interval = tokio::time::interval(self.check_interval);
+        // The first tick is immediate, and the storage was only just opened.
+        interval.tick().await;
+        let mut failing = 0;
+        loop {
+            let active = self.active();
+            if active + 1 >= self.chain.len() {
+                return;
+            }
+            interval.tick().await;
+            // Storage being worked on while paused isn't failing.
+            let _in_flight = pause.enter().await;
+            self.checks.fetch_add(1, Ordering::Relaxed);
+            match self.chain[active].clone().check().await {
+                Ok(()) => {
+                    failing = 0;
+                    continue;
+                }
+                Err(err) => {
+                    failing += 1;
+                    self.failed_checks.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        index = active,
+                        failing,
+                        err = format!("{:#}", err),
+                        "storage check failed"
+                    );
+                    self.set_last_error(&err);
+                }
+            }
+            if failing < self.after {
+                continue;
+            }
+            match self.open_from(active + 1, pool.all().count()).await {
+                Ok((index, conns)) => {
+                    failing = 0;
+                    let old_conns = pool.replace(conns).await;
+                    self.set_active(index);
+                    on_swap();
+                    for mut old in old_conns {
+                        if let Err(err) = old.shutdown().await {
+                            debug!(?err, "shutting down storage fallen back from");
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!(
+                        err = format!("{:#}", err),
+                        "no fallback storage opens, staying put"
+                    );
+                    self.set_last_error(&err);
+                }
+            }
+        }
+    }
+
+    fn set_last_error(&self, err: &anyhow::Error) {
+        *self.last_error.lock().unwrap() = Some(format!("{:#}", err));
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let active = self.active.load(Ordering::Relaxed);
+        json!({
+            "active": active,
+            "storage": self.chain[active].info(),
+            "fallbacks": self.fallbacks.load(Ordering::Relaxed),
+            "checks": self.checks.load(Ordering::Relaxed),
+            "failed_checks": self.failed_checks.load(Ordering::Relaxed),
+            "last_error": self.last_error.lock().unwrap().clone(),
+        })
+    }
+}
@@ -0,0 +1,169 @@
+use crate::msgpack::{self, Value};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::io::Read;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The largest forward protocol message read, after decompressing. Connections sending larger
+/// ones are closed.
+pub(crate) const MAX_FORWARD_MESSAGE_BYTES: usize = 16 << 20;
+
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct ForwardArgs {
+    /// Address to receive events on from Fluentd and Fluent Bit forward outputs, over TCP, like
+    /// "[::]:24224". Can be repeated.
+    #[arg(long)]
+    pub fluent_forward: Vec<SocketAddr>,
+}
+
+impl ForwardArgs {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!(self.fluent_forward)
+    }
+}
+
+/// A forward protocol message's events, and the chunk ID to acknowledge once they're stored if
+/// the sender wants acknowledgements.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ForwardMessage {
+    pub tag: String,
+    pub entries: Vec<(Option<DateTime<Utc>>, serde_json::Value)>,
+    pub chunk: Option<String>,
+}
+
+/// The headers of the stream for a tag's events.
+pub(crate) fn stream_headers(tag: &str) -> serde_json::Value {
+    json!({"fluent": {"tag": tag}})
+}
+
+/// Reads the next message from a connection, keeping what's been read past it in `buf`. None at
+/// the end of the connection.
+pub(crate) async fn read_message(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> Result<Option<Value>> {
+    loop {
+        if let Some((value, len)) = msgpack::decode(buf)? {
+            buf.drain(..len);
+            return Ok(Some(value));
+        }
+        if buf.len() > MAX_FORWARD_MESSAGE_BYTES {
+            bail!("message is longer than {} bytes", MAX_FORWARD_MESSAGE_BYTES);
+        }
+        buf.reserve(64 << 10);
+        if reader.read_buf(buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            bail!("connection closed partway through a message");
+        }
+    }
+}
+
+/// Parses a message in any of the protocol's modes: Message, Forward, PackedForward and
+/// CompressedPackedForward.
+pub(crate) fn parse_message(message: Value) -> Result<ForwardMessage> {
+    let Value::Array(items) = message else {
+        bail!("message isn't an array");
+    };
+    let mut items = items.into_iter();
+    let tag = match items.next() {
+        Some(Value::Str(tag)) => tag,
+        _ => bail!("message has no tag"),
+    };
+    let (entries, option) = match items.next().context("message has no events")? {
+        Value::Array(entries) => (
+            entries.into_iter().map(entry).collect::<Result<_>>()?,
+            items.next(),
+        ),
+        Value::Str(packed) => {
+            let option = items.next();
+            (
+                packed_entries(packed.into_bytes(), option.as_ref())?,
+                option,
+            )
+        }
+        Value::Bin(packed) => {
+            let option = items.next();
+            (packed_entries(packed, option.as_ref())?, option)
+        }
+        time => {
+            let record = items.next().context("message has no record")?;
+            (vec![entry(Value::Array(vec![time, record]))?], items.next())
+        }
+    };
+    let chunk = option
+        .as_ref()
+        .and_then(|option| option.get("chunk"))
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+    Ok(ForwardMessage {
+        tag,
+        entries,
+        chunk,
+    })
+}
+
+/// Entries packed one after another, gzipped if the option says so.
+fn packed_entries(
+    packed: Vec<u8>,
+    option: Option<&Value>,
+) -> Result<Vec<(Option<DateTime<Utc>>, serde_json::Value)>> {
+    let packed = match option.and_then(|option| option.get("compressed")) {
+        None => packed,
+        Some(Value::Str(gzip)) if gzip == "gzip" => {
+            let mut unpacked = vec![];
+            flate2::read::MultiGzDecoder::new(&packed[..])
+                .take(MAX_FORWARD_MESSAGE_BYTES as u64 + 1)
+                .read_to_end(&mut unpacked)
+                .context("decompressing entries")?;
+            if unpacked.len() > MAX_FORWARD_MESSAGE_BYTES {
+                bail!(
+                    "entries are longer than {} bytes",
+                    MAX_FORWARD_MESSAGE_BYTES
+                );
+            }
+            unpacked
+        }
+        Some(compressed) => bail!("unsupported compression {:?}", compressed),
+    };
+    let mut rest = &packed[..];
+    let mut entries = vec![];
+    while !rest.is_empty() {
+        let (value, len) = msgpack::decode(rest)?.context("packed entries are truncated")?;
+        entries.push(entry(value)?);
+        rest = &rest[len..];
+    }
+    Ok(entries)
+}
+
+/// An entry, `[time, record]`. Fluent Bit may send `[[time, metadata], record]`, whose metadata
+/// isn't kept.
+fn entry(entry: Value) -> Result<(Option<DateTime<Utc>>, serde_json::Value)> {
+    let Value::Array(items) = entry else {
+        bail!("entry isn't an array");
+    };
+    let [time, record, ..] = &items[..] else {
+        bail!("entry has no record");
+    };
+    let time = match time {
+        Value::Array(time_and_metadata) => time_and_metadata.first(),
+        time => Some(time),
+    };
+    let record = record.to_json();
+    if !record.is_object() {
+        bail!("record isn't a map");
+    }
+    Ok((time.and_then(Value::event_time), record))
+}
+
+/// The response acknowledging a chunk.
+pub(crate) fn ack(chunk: &str) -> Vec<u8> {
+    // A map of one entry, "ack".
+    let mut out = vec![0x81];
+    msgpack::encode_str(&mut out, "ack");
+    msgpack::encode_str(&mut out, chunk);
+    out
+}
@@ -0,0 +1,158 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use telemetry_storage::{EventSelection, RollupRow, SeriesAggregate, SeriesPoint};
+
+/// The body of `/grafana/search`. Older plugin versions send no body.
+#[derive(Default, serde::Deserialize)]
+pub(crate) struct SearchRequest {
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QueryRequest {
+    pub range: Range,
+    pub interval_ms: Option<u64>,
+    pub max_data_points: Option<u64>,
+    pub targets: Vec<QueryTarget>,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct QueryTarget {
+    /// Missing while a panel's query is being written.
+    pub target: Option<String>,
+    #[serde(default)]
+    pub hide: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct AnnotationsRequest {
+    pub range: Range,
+    /// Echoed back with each annotation. Its `query` is a UI view's query string.
+    pub annotation: Value,
+}
+
+/// A dashboard's time range, as RFC 3339 times.
+#[derive(serde::Deserialize)]
+pub(crate) struct Range {
+    pub from: String,
+    pub to: String,
+}
+
+impl Range {
+    pub(crate) fn selection(&self) -> Result<EventSelection> {
+        let parse = |datetime: &str| {
+            DateTime::parse_from_rfc3339(datetime)
+                .map(|datetime| datetime.to_utc())
+                .with_context(|| format!("parsing time {:?}", datetime))
+        };
+        Ok(EventSelection {
+            stream_id: None,
+            since: Some(parse(&self.from)?),
+            until: Some(parse(&self.to)?),
+        })
+    }
+}
+
+/// What a query target charts.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Target {
+    /// Events counted, or a numeric payload field, in buckets of the dashboard's interval.
+    Series(SeriesAggregate),
+    /// A rollup's counts, a series for each of its groups.
+    Rollup(String),
+}
+
+/// Parses a series, like `count` or `avg:latency_s`, or `rollup:NAME`.
+impl std::str::FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text.strip_prefix("rollup:") {
+            Some(name) => Ok(Self::Rollup(name.to_owned())),
+            None => Ok(Self::Series(text.parse()?)),
+        }
+    }
+}
+
+impl QueryRequest {
+    /// Grafana's interval, in whole seconds, widened so there are no more points than asked for.
+    pub(crate) fn bucket(&self, selection: &EventSelection) -> Duration {
+        let mut secs = self.interval_ms.unwrap_or(60_000).div_ceil(1000);
+        if let (Some(since), Some(until), Some(max_points)) =
+            (selection.since, selection.until, self.max_data_points)
+        {
+            let range_secs = (until - since).num_seconds().max(0) as u64;
+            secs = secs.max(range_secs.div_ceil(max_points.max(1)));
+        }
+        Duration::from_secs(secs.max(1))
+    }
+}
+
+fn epoch_ms(datetime: DateTime<Utc>) -> i64 {
+    datetime.timestamp_millis()
+}
+
+pub(crate) fn series_response(target: &str, points: &[SeriesPoint]) -> Value {
+    let datapoints: Vec<_> = points
+        .iter()
+        .map(|point| json!([point.value, epoch_ms(point.bucket_start)]))
+        .collect();
+    json!({ "target": target, "datapoints": datapoints })
+}
+
+/// A series for each group of the rollup, named after the rollup and group.
+pub(crate) fn rollup_responses(name: &str, rows: &[RollupRow]) -> Vec<Value> {
+    let groups: BTreeSet<_> = rows.iter().map(|row| &row.group).collect();
+    groups
+        .into_iter()
+        .map(|group| {
+            let datapoints: Vec<_> = rows
+                .iter()
+                .filter(|row| &row.group == group)
+                .map(|row| json!([row.count, epoch_ms(row.bucket_start)]))
+                .collect();
+            let target = format!("{} {}", name, group.as_deref().unwrap_or("-"));
+            json!({ "target": target, "datapoints": datapoints })
+        })
+        .collect()
+}
+
+/// An event from [telemetry_storage::Connection::query_events] as an annotation.
+pub(crate) fn annotation_response(annotation: &Value, event: &Value) -> Result<Value> {
+    let inserted = event["insert_datetime"]
+        .as_str()
+        .context("event has no insert_datetime")?;
+    let time = epoch_ms(telemetry_storage::parse_datetime(inserted)?);
+    Ok(json!({
+        "annotation": annotation,
+        "time": time,
+        "title": format!(
+            "stream {} event {}",
+            event["stream_id"], event["stream_event_index"]
+        ),
+        "text": event["payload"].to_string(),
+        "tags": [format!("stream:{}", event["stream_id"])],
+    }))
+}
+
+/// Series to offer: the event count, and averages of the numeric fields of some recent events.
+/// The other aggregates and rollups can be typed in.
+pub(crate) fn search_response(search: &str, events: &[Value]) -> Value {
+    let fields: BTreeSet<&String> = events
+        .iter()
+        .filter_map(|event| event["payload"].as_object())
+        .flat_map(|payload| payload.iter())
+        .filter(|(_, value)| value.is_number())
+        .map(|(field, _)| field)
+        .collect();
+    let names = std::iter::once("count".to_owned())
+        .chain(fields.into_iter().map(|field| format!("avg:{}", field)))
+        .filter(|name| name.contains(search))
+        .collect::<Vec<_>>();
+    json!(names)
+}
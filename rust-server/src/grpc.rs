@@ -0,0 +1,494 @@
+use crate::protobuf::{fields, MessageBuilder};
+use anyhow::{Context, Result};
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use futures::{Stream, StreamExt};
+use hyper::body::Frame;
+use std::convert::Infallible;
+use std::fmt::Display;
+
+/// The native service, as its methods' paths start.
+pub(crate) const SERVICE: &str = "telemetry.v1.Telemetry";
+
+/// The service's definition, as it's in the repository and described by reflection.
+const PROTO_FILE: &str = "telemetry/v1/telemetry.proto";
+
+/// The largest message read from a client, the usual default for gRPC servers.
+const MAX_GRPC_MESSAGE_BYTES: usize = 4 << 20;
+
+pub(crate) const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+/// The status codes the service ends calls with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Code {
+    Ok = 0,
+    InvalidArgument = 3,
+    NotFound = 5,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    Unimplemented = 12,
+    Internal = 13,
+}
+
+/// How a call ended, sent to the client in the response's trailers.
+#[derive(Debug)]
+pub(crate) struct Status {
+    code: Code,
+    message: String,
+}
+
+impl Status {
+    pub(crate) fn new(code: Code, message: impl Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    /// For errors from reading a request's messages.
+    pub(crate) fn invalid_argument(err: anyhow::Error) -> Self {
+        Self::new(Code::InvalidArgument, format!("{:#}", err))
+    }
+
+    fn trailers(&self) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from(self.code as u32));
+        if !self.message.is_empty() {
+            // Percent-encoded, as the spec has it.
+            let mut message = String::new();
+            for byte in self.message.bytes() {
+                match byte {
+                    b' '..=b'~' if byte != b'%' => message.push(byte as char),
+                    byte => message.push_str(&format!("%{:02X}", byte)),
+                }
+            }
+            trailers.insert("grpc-message", HeaderValue::from_str(&message).unwrap());
+        }
+        trailers
+    }
+}
+
+/// Whether a request is gRPC rather than gRPC-Web or something else.
+pub(crate) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type == GRPC_CONTENT_TYPE
+                || content_type.starts_with(&format!("{}+", GRPC_CONTENT_TYPE))
+                || content_type.starts_with(&format!("{};", GRPC_CONTENT_TYPE))
+        })
+}
+
+/// Reads the length-prefixed messages of a request body as they arrive.
+pub(crate) struct MessageReader<S> {
+    body: S,
+    buffer: Vec<u8>,
+}
+
+impl<S: Stream<Item = Result<Bytes, axum::Error>> + Unpin> MessageReader<S> {
+    pub(crate) fn new(body: S) -> Self {
+        Self {
+            body,
+            buffer: vec![],
+        }
+    }
+
+    /// The next message, or None once the client's done sending.
+    pub(crate) async fn next(&mut self) -> Option<Result<Vec<u8>, Status>> {
+        loop {
+            if let [compressed, len @ ..] = &self.buffer[..self.buffer.len().min(5)] {
+                if let Ok(len) = <[u8; 4]>::try_from(len) {
+                    let len = u32::from_be_bytes(len) as usize;
+                    if *compressed != 0 {
+                        return Some(Err(Status::new(
+                            Code::Unimplemented,
+                            "compressed messages aren't supported",
+                        )));
+                    }
+                    if len > MAX_GRPC_MESSAGE_BYTES {
+                        return Some(Err(Status::new(
+                            Code::ResourceExhausted,
+                            format!(
+                                "message of {} bytes is over the limit of {}",
+                                len, MAX_GRPC_MESSAGE_BYTES
+                            ),
+                        )));
+                    }
+                    if self.buffer.len() >= 5 + len {
+                        let message = self.buffer[5..5 + len].to_vec();
+                        self.buffer.drain(..5 + len);
+                        return Some(Ok(message));
+                    }
+                }
+            }
+            match self.body.next().await {
+                Some(Ok(bytes)) => self.buffer.extend_from_slice(&bytes),
+                Some(Err(err)) => {
+                    return Some(Err(Status::new(
+                        Code::Internal,
+                        format!("reading request: {}", err),
+                    )))
+                }
+                None if self.buffer.is_empty() => return None,
+                None => {
+                    return Some(Err(Status::new(
+                        Code::InvalidArgument,
+                        "request ends partway through a message",
+                    )))
+                }
+            }
+        }
+    }
+
+    /// The only message of a request to a method that isn't client streaming.
+    pub(crate) async fn unary(&mut self) -> Result<Vec<u8>, Status> {
+        let message = self
+            .next()
+            .await
+            .unwrap_or_else(|| Err(Status::new(Code::InvalidArgument, "request has no message")))?;
+        match self.next().await {
+            None => Ok(message),
+            Some(Err(status)) => Err(status),
+            Some(Ok(_)) => Err(Status::new(
+                Code::InvalidArgument,
+                "request has more than one message",
+            )),
+        }
+    }
+}
+
+/// Responds with each of the messages in turn, then the status the call ends with: the first
+/// error, if there is one.
+pub(crate) fn response(
+    messages: impl Stream<Item = Result<Vec<u8>, Status>> + Send + 'static,
+) -> Response {
+    let frames = futures::stream::unfold(Some(messages.boxed()), |messages| async move {
+        let mut messages = messages?;
+        let (frame, messages) = match messages.next().await {
+            Some(Ok(message)) => {
+                let mut data = Vec::with_capacity(5 + message.len());
+                data.push(0);
+                data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+                data.extend_from_slice(&message);
+                (Frame::data(Bytes::from(data)), Some(messages))
+            }
+            Some(Err(status)) => (Frame::trailers(status.trailers()), None),
+            None => (Frame::trailers(Status::new(Code::Ok, "").trailers()), None),
+        };
+        Some((Ok::<_, Infallible>(frame), messages))
+    });
+    (
+        [(axum::http::header::CONTENT_TYPE, GRPC_CONTENT_TYPE)],
+        Body::new(http_body_util::StreamBody::new(frames)),
+    )
+        .into_response()
+}
+
+/// A `SubmitRequest` or `AppendEventsRequest`, which are the same but for `close`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Append {
+    /// Empty to start a stream.
+    pub stream_token: String,
+    /// JSON text.
+    pub events: Vec<String>,
+    pub close: bool,
+}
+
+impl Append {
+    pub(crate) fn decode(message: &[u8]) -> Result<Self> {
+        let mut append = Self::default();
+        for field in fields(message) {
+            match field? {
+                (1, value) => append.stream_token = utf8(value.bytes()?)?,
+                (2, value) => append.events.push(utf8(value.bytes()?)?),
+                (3, value) => append.close = value.varint()? != 0,
+                _ => {}
+            }
+        }
+        Ok(append)
+    }
+}
+
+/// The token of an `OpenStreamRequest` or `CloseStreamRequest`, the only field they have.
+pub(crate) fn decode_stream_token(message: &[u8]) -> Result<String> {
+    let mut stream_token = String::new();
+    for field in fields(message) {
+        if let (1, value) = field? {
+            stream_token = utf8(value.bytes()?)?;
+        }
+    }
+    Ok(stream_token)
+}
+
+/// Strings have to be UTF-8 in proto3.
+fn utf8(bytes: &[u8]) -> Result<String> {
+    Ok(std::str::from_utf8(bytes)
+        .context("string field isn't UTF-8")?
+        .to_owned())
+}
+
+/// How an `Append` went. The first two fields are also an `OpenStreamResponse`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Acknowledgement {
+    pub stream_token: String,
+    pub last_stream_event_index: u64,
+    pub accepted: u64,
+    /// Index and error of each event that wasn't stored.
+    pub failed: Vec<(u64, String)>,
+}
+
+impl Acknowledgement {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut message = MessageBuilder::default()
+            .string(1, &self.stream_token)
+            .varint(2, self.last_stream_event_index)
+            .varint(3, self.accepted);
+        for (index, error) in &self.failed {
+            let failure = MessageBuilder::default().varint(1, *index).string(2, error);
+            message = message.message(4, failure);
+        }
+        message.build()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FieldType {
+    Bool,
+    Uint64,
+    String,
+    Message(&'static str),
+}
+
+/// A field of one of the service's messages, as reflection describes it.
+struct FieldSchema {
+    name: &'static str,
+    number: u32,
+    field_type: FieldType,
+    repeated: bool,
+}
+
+const fn field(name: &'static str, number: u32, field_type: FieldType) -> FieldSchema {
+    FieldSchema {
+        name,
+        number,
+        field_type,
+        repeated: false,
+    }
+}
+
+const fn repeated(name: &'static str, number: u32, field_type: FieldType) -> FieldSchema {
+    FieldSchema {
+        repeated: true,
+        ..field(name, number, field_type)
+    }
+}
+
+/// The messages in [PROTO_FILE].
+const MESSAGES: &[(&str, &[FieldSchema])] = &[
+    (
+        "SubmitRequest",
+        &[
+            field("stream_token", 1, FieldType::String),
+            repeated("events", 2, FieldType::String),
+            field("close", 3, FieldType::Bool),
+        ],
+    ),
+    (
+        "OpenStreamRequest",
+        &[field("stream_token", 1, FieldType::String)],
+    ),
+    (
+        "OpenStreamResponse",
+        &[
+            field("stream_token", 1, FieldType::String),
+            field("last_stream_event_index", 2, FieldType::Uint64),
+        ],
+    ),
+    (
+        "AppendEventsRequest",
+        &[
+            field("stream_token", 1, FieldType::String),
+            repeated("events", 2, FieldType::String),
+        ],
+    ),
+    (
+        "Acknowledgement",
+        &[
+            field("stream_token", 1, FieldType::String),
+            field("last_stream_event_index", 2, FieldType::Uint64),
+            field("accepted", 3, FieldType::Uint64),
+            repeated("failed", 4, FieldType::Message("EventFailure")),
+        ],
+    ),
+    (
+        "EventFailure",
+        &[
+            field("index", 1, FieldType::Uint64),
+            field("error", 2, FieldType::String),
+        ],
+    ),
+    (
+        "CloseStreamRequest",
+        &[field("stream_token", 1, FieldType::String)],
+    ),
+    ("CloseStreamResponse", &[]),
+];
+
+/// The methods in [PROTO_FILE]: name, request and response, and whether both are streamed.
+const METHODS: &[(&str, &str, &str, bool)] = &[
+    ("Submit", "SubmitRequest", "Acknowledgement", false),
+    (
+        "OpenStream",
+        "OpenStreamRequest",
+        "OpenStreamResponse",
+        false,
+    ),
+    (
+        "AppendEvents",
+        "AppendEventsRequest",
+        "Acknowledgement",
+        true,
+    ),
+    (
+        "CloseStream",
+        "CloseStreamRequest",
+        "CloseStreamResponse",
+        false,
+    ),
+];
+
+/// The package the service and its messages are in.
+fn package() -> &'static str {
+    SERVICE.rsplit_once('.').unwrap().0
+}
+
+/// The encoded `FileDescriptorProto` of [PROTO_FILE].
+fn file_descriptor() -> Vec<u8> {
+    let qualified = |name: &str| format!(".{}.{}", package(), name);
+    let mut file = MessageBuilder::default()
+        .string(1, PROTO_FILE)
+        .string(2, package());
+    for (name, fields) in MESSAGES {
+        let mut message = MessageBuilder::default().string(1, name);
+        for field in *fields {
+            let (field_type, type_name) = match field.field_type {
+                FieldType::Bool => (8, None),
+                FieldType::Uint64 => (4, None),
+                FieldType::String => (9, None),
+                FieldType::Message(name) => (11, Some(qualified(name))),
+            };
+            let mut json_name = String::new();
+            for (i, word) in field.name.split('_').enumerate() {
+                match i {
+                    0 => json_name.push_str(word),
+                    _ => {
+                        let mut chars = word.chars();
+                        json_name.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+                        json_name.push_str(chars.as_str());
+                    }
+                }
+            }
+            let mut descriptor = MessageBuilder::default()
+                .string(1, field.name)
+                .varint(3, field.number.into())
+                .varint(4, if field.repeated { 3 } else { 1 })
+                .varint(5, field_type);
+            if let Some(type_name) = type_name {
+                descriptor = descriptor.string(6, &type_name);
+            }
+            message = message.message(2, descriptor.string(10, &json_name));
+        }
+        file = file.message(4, message);
+    }
+    let mut service = MessageBuilder::default().string(1, SERVICE.rsplit_once('.').unwrap().1);
+    for (name, input, output, streaming) in METHODS {
+        let mut method = MessageBuilder::default()
+            .string(1, name)
+            .string(2, &qualified(input))
+            .string(3, &qualified(output));
+        if *streaming {
+            method = method.varint(5, 1).varint(6, 1);
+        }
+        service = service.message(2, method);
+    }
+    file.message(6, service).string(12, "proto3").build()
+}
+
+/// Whether the name is of something in [PROTO_FILE].
+fn is_defined_symbol(symbol: &str) -> bool {
+    let Some(name) = symbol
+        .strip_prefix(package())
+        .and_then(|name| name.strip_prefix('.'))
+    else {
+        return false;
+    };
+    let service = SERVICE.rsplit_once('.').unwrap().1;
+    name == service
+        || MESSAGES.iter().any(|(message, _)| *message == name)
+        || METHODS.iter().any(|(method, ..)| {
+            name.strip_prefix(service)
+                .and_then(|name| name.strip_prefix('.'))
+                == Some(*method)
+        })
+}
+
+/// Answers a `ServerReflectionRequest`, which the v1 and v1alpha reflection services share.
+/// Only the native service is described.
+pub(crate) fn reflect(request: &[u8]) -> Result<Vec<u8>> {
+    let error = |code: Code, message: &str| {
+        let error = MessageBuilder::default()
+            .varint(1, code as u64)
+            .string(2, message);
+        (7, error)
+    };
+    let file = || {
+        let files = MessageBuilder::default().bytes(1, &file_descriptor());
+        (4, files)
+    };
+    let mut host = String::new();
+    let mut answer = error(Code::InvalidArgument, "no question asked");
+    for field in fields(request) {
+        answer = match field? {
+            (1, value) => {
+                host = utf8(value.bytes()?)?;
+                continue;
+            }
+            (3, value) if utf8(value.bytes()?)? == PROTO_FILE => file(),
+            (3, _) => error(Code::NotFound, "file not found"),
+            (4, value) if is_defined_symbol(&utf8(value.bytes()?)?) => file(),
+            (4, _) => error(Code::NotFound, "symbol not found"),
+            (5 | 6, _) => error(Code::NotFound, "no extensions are defined"),
+            (7, _) => {
+                let service = MessageBuilder::default().string(1, SERVICE);
+                (6, MessageBuilder::default().message(1, service))
+            }
+            _ => continue,
+        };
+    }
+    let (number, answer) = answer;
+    Ok(MessageBuilder::default()
+        .string(1, &host)
+        .bytes(2, request)
+        .message(number, answer)
+        .build())
+}
+
+/// Serves `ServerReflectionInfo`, answering each request of the stream as it arrives.
+pub(crate) async fn reflection_handler(req: axum::http::Request<Body>) -> Response {
+    if !is_grpc(req.headers()) {
+        return axum::http::StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let messages = MessageReader::new(req.into_body().into_data_stream());
+    response(futures::stream::unfold(
+        messages,
+        |mut messages| async move {
+            let answer = match messages.next().await? {
+                Ok(request) => reflect(&request).map_err(Status::invalid_argument),
+                Err(status) => Err(status),
+            };
+            Some((answer, messages))
+        },
+    ))
+}
@@ -0,0 +1,324 @@
+use anyhow::{bail, Context, Result};
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Semaphore;
+use tokio_native_tls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::*;
+
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct HttpServerArgs {
+    /// Address to serve HTTPS on, with --tls-cert and --tls-key, like "[::]:4319". Can be
+    /// repeated.
+    #[arg(long)]
+    pub listen_tls: Vec<SocketAddr>,
+    /// Unix socket to serve HTTP on, like "/run/telemetry.sock". A socket left at the path by an
+    /// earlier run is replaced. Can be repeated.
+    #[arg(long)]
+    pub listen_unix: Vec<PathBuf>,
+    /// PEM certificate chain for --listen-tls.
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM PKCS #8 private key for --tls-cert.
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+    /// Also serve HTTP/2 without TLS (h2c, with prior knowledge) on the --listen addresses.
+    /// HTTP/1.1 is always served.
+    #[arg(long)]
+    pub http2: bool,
+    /// The most requests an HTTP/2 connection can have in flight at once. Defaults to 200.
+    #[arg(long)]
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Ping HTTP/2 clients this often, like "30s", closing connections that don't answer within
+    /// --http2-keep-alive-timeout. Not done by default.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// How long to wait for an answer to an HTTP/2 keep-alive ping. Defaults to 20s.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub http2_keep_alive_timeout: Option<Duration>,
+    /// Close HTTP/1.1 connections that don't send the next request's headers within this long,
+    /// like "60s". "0s" closes connections after each response. Idle connections are kept open
+    /// by default.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub http_keep_alive_timeout: Option<Duration>,
+    /// The most HTTP connections served at once, over all the listeners. Connections past
+    /// it wait to be accepted until others close, with a warning logged.
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+}
+
+impl HttpServerArgs {
+    pub(crate) fn to_json(&self) -> Value {
+        let format = |duration: Option<Duration>| {
+            duration.map(|duration| humantime::format_duration(duration).to_string())
+        };
+        json!({
+            "listen_tls": self.listen_tls,
+            "listen_unix": self.listen_unix,
+            "tls_cert": self.tls_cert,
+            "http2": self.http2,
+            "http2_max_concurrent_streams": self.http2_max_concurrent_streams,
+            "http2_keep_alive_interval": format(self.http2_keep_alive_interval),
+            "http2_keep_alive_timeout": format(self.http2_keep_alive_timeout),
+            "http_keep_alive_timeout": format(self.http_keep_alive_timeout),
+            "max_connections": self.max_connections,
+        })
+    }
+
+    /// The connection builder for these settings.
+    fn builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        let mut http1 = builder.http1();
+        http1.timer(TokioTimer::new());
+        match self.http_keep_alive_timeout {
+            Some(Duration::ZERO) => http1.keep_alive(false).header_read_timeout(None),
+            timeout => http1.header_read_timeout(timeout),
+        };
+        let mut http2 = builder.http2();
+        http2
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.http2_max_concurrent_streams)
+            .keep_alive_interval(self.http2_keep_alive_interval);
+        if let Some(timeout) = self.http2_keep_alive_timeout {
+            http2.keep_alive_timeout(timeout);
+        }
+        if self.http2 {
+            builder
+        } else {
+            builder.http1_only()
+        }
+    }
+
+    /// Whether there are listeners other than --listen, which then has no default.
+    pub(crate) fn has_listeners(&self) -> bool {
+        !self.listen_tls.is_empty() || !self.listen_unix.is_empty()
+    }
+
+    fn tls_acceptor(&self) -> Result<TlsAcceptor> {
+        let (Some(cert_path), Some(key_path)) = (&self.tls_cert, &self.tls_key) else {
+            bail!("--listen-tls needs --tls-cert and --tls-key");
+        };
+        let cert =
+            std::fs::read(cert_path).with_context(|| format!("reading {}", cert_path.display()))?;
+        let key =
+            std::fs::read(key_path).with_context(|| format!("reading {}", key_path.display()))?;
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+            .with_context(|| format!("loading {}", cert_path.display()))?;
+        Ok(native_tls::TlsAcceptor::new(identity)?.into())
+    }
+
+    /// The limit on connections shared by all the listeners.
+    pub(crate) fn connection_limit(&self) -> Option<Arc<Semaphore>> {
+        self.max_connections
+            .map(|max| Arc::new(Semaphore::new(max)))
+    }
+}
+
+/// Somewhere HTTP is served.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A listening socket handed over by the service manager.
+#[cfg(unix)]
+pub(crate) type InheritedSocket = std::os::fd::OwnedFd;
+#[cfg(windows)]
+pub(crate) type InheritedSocket = std::os::windows::io::OwnedSocket;
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => {
+                let (conn, peer) = listener.accept().await?;
+                Ok((Stream::Tcp(conn), Some(peer)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (conn, _) = listener.accept().await?;
+                Ok((Stream::Unix(conn), None))
+            }
+        }
+    }
+}
+
+/// Binds every listener: the --listen addresses, and those in the args. Sockets passed by systemd
+/// are served too.
+pub(crate) async fn bind(
+    listen: &[SocketAddr],
+    activated: Vec<(String, InheritedSocket)>,
+    args: &HttpServerArgs,
+) -> Result<Vec<Listener>> {
+    let mut listeners = vec![];
+    for (name, fd) in activated {
+        let listener = adopt(&name, fd, args)
+            .with_context(|| format!("serving socket {:?} from systemd", name))?;
+        listeners.push(listener);
+    }
+    for addr in listen {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("binding {}", addr))?;
+        let listener_local_addr = listener.local_addr()?;
+        info!(?listener_local_addr, "serving http");
+        listeners.push(Listener::Tcp(listener));
+    }
+    if !args.listen_tls.is_empty() {
+        let acceptor = args.tls_acceptor()?;
+        for addr in &args.listen_tls {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("binding {}", addr))?;
+            let listener_local_addr = listener.local_addr()?;
+            info!(?listener_local_addr, "serving https");
+            listeners.push(Listener::Tls(listener, acceptor.clone()));
+        }
+    }
+    for path in &args.listen_unix {
+        #[cfg(windows)]
+        bail!(
+            "--listen-unix {} isn't supported on Windows",
+            path.display()
+        );
+        #[cfg(unix)]
+        {
+            let listener =
+                bind_unix(path).with_context(|| format!("binding {}", path.display()))?;
+            info!(?path, "serving http on unix socket");
+            listeners.push(Listener::Unix(listener));
+        }
+    }
+    Ok(listeners)
+}
+
+/// Serves a socket passed by systemd, which can be TCP or a Unix socket. TCP sockets named "https"
+/// with FileDescriptorName= serve HTTPS with --tls-cert and --tls-key.
+fn adopt(name: &str, socket: InheritedSocket, args: &HttpServerArgs) -> Result<Listener> {
+    let listener = std::net::TcpListener::from(socket);
+    // Only TCP sockets have an IP address.
+    let listener_local_addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        #[cfg(unix)]
+        Err(_) => {
+            let listener = std::os::unix::net::UnixListener::from(InheritedSocket::from(listener));
+            listener.set_nonblocking(true)?;
+            info!(name, "serving http on unix socket from systemd");
+            return Ok(Listener::Unix(UnixListener::from_std(listener)?));
+        }
+        #[cfg(windows)]
+        Err(err) => return Err(err.into()),
+    };
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    if name == "https" {
+        info!(name, ?listener_local_addr, "serving https from systemd");
+        return Ok(Listener::Tls(listener, args.tls_acceptor()?));
+    }
+    info!(name, ?listener_local_addr, "serving http from systemd");
+    Ok(Listener::Tcp(listener))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("it exists and isn't a socket"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// Serves the app on connections from the listener until accepting fails for good. Like
+/// [axum::serve], requests over TCP have the peer's [ConnectInfo].
+pub(crate) async fn serve(
+    listener: Listener,
+    app: Router,
+    args: &HttpServerArgs,
+    connection_limit: Option<Arc<Semaphore>>,
+) -> Result<()> {
+    let builder = Arc::new(args.builder());
+    loop {
+        let permit = match &connection_limit {
+            Some(limit) => Some(match Arc::clone(limit).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    warn!(
+                        max_connections = args.max_connections,
+                        "at the connection limit, waiting for a connection to close"
+                    );
+                    Arc::clone(limit).acquire_owned().await?
+                }
+            }),
+            None => None,
+        };
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Usually out of file descriptors, which closing connections will free up.
+                warn!(?err, "accepting http connection");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let tls = match &listener {
+            Listener::Tls(_, acceptor) => Some(acceptor.clone()),
+            _ => None,
+        };
+        let app = app.clone();
+        let builder = Arc::clone(&builder);
+        tokio::spawn(async move {
+            let result = match (stream, tls) {
+                (Stream::Tcp(conn), Some(tls)) => match tls.accept(conn).await {
+                    Ok(conn) => serve_connection(&builder, conn, app, peer).await,
+                    Err(err) => Err(err.into()),
+                },
+                (Stream::Tcp(conn), None) => serve_connection(&builder, conn, app, peer).await,
+                #[cfg(unix)]
+                (Stream::Unix(conn), _) => serve_connection(&builder, conn, app, peer).await,
+            };
+            if let Err(err) = result {
+                debug!(%err, ?peer, "http connection");
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn serve_connection(
+    builder: &auto::Builder<TokioExecutor>,
+    io: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    app: Router,
+    peer: Option<SocketAddr>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let service = hyper::service::service_fn(move |mut req: hyper::Request<_>| {
+        if let Some(peer) = peer {
+            req.extensions_mut().insert(ConnectInfo(peer));
+        }
+        app.clone().oneshot(req)
+    });
+    builder
+        .serve_connection_with_upgrades(TokioIo::new(io), service)
+        .await
+}
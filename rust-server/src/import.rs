@@ -0,0 +1,190 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use telemetry_storage::{
+    parse_datetime, read_json_lines, Connection, EncryptionKey, ImportedEvent, ImportedStream,
+    StreamId, ENCRYPTED_SUFFIX,
+};
+use tracing::{info, warn};
+
+/// Events are stored in transactions of this many.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// What an import stored.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub(crate) struct Imported {
+    pub streams: u64,
+    pub events: u64,
+    /// Events the storage already had, at the same or a later revision.
+    pub skipped_events: u64,
+}
+
+/// Stores the streams and events in files written by JSON files storage, or in JSON lines like
+/// the ndjson export, keeping their IDs and times. Directories are read for their ".json.zst"
+/// files, and encrypted ones are decrypted with `key`. Files compressed with a zstd dictionary
+/// need it as `dictionary`.
+pub(crate) async fn import(
+    conn: &mut (dyn Connection + Send),
+    inputs: &[PathBuf],
+    key: Option<&EncryptionKey>,
+    dictionary: Option<&[u8]>,
+) -> Result<Imported> {
+    let files = list_files(inputs)?;
+    // Streams are stored first so events can refer to them, wherever they are in the files. They
+    // start in one table and end in another, or are repeated on each event of an export.
+    let mut stream_fields: BTreeMap<u32, Map<String, Value>> = BTreeMap::new();
+    for path in &files {
+        for line in read_json_lines(path, key, dictionary)? {
+            let Value::Object(mut line) = line? else {
+                continue;
+            };
+            let stream_id = stream_id(&line)?;
+            let fields = match line.remove("stream") {
+                Some(Value::Object(stream)) => stream,
+                _ if line.contains_key("payload") => continue,
+                _ => line,
+            };
+            let stream = stream_fields.entry(stream_id.0).or_default();
+            stream.extend(fields.into_iter().filter(|(_, value)| !value.is_null()));
+        }
+    }
+    let mut streams = vec![];
+    for (stream_id, fields) in stream_fields {
+        let stream_id = StreamId(stream_id);
+        if !fields.contains_key("start_datetime") {
+            // Its start was in a file that was pruned or not given.
+            warn!(%stream_id, "skipping stream end without a start");
+            continue;
+        }
+        streams.push(
+            imported_stream(stream_id, &fields)
+                .with_context(|| format!("reading stream {}", stream_id))?,
+        );
+    }
+    conn.import_streams(&streams).await?;
+    let mut imported = Imported {
+        streams: streams.len() as u64,
+        ..Default::default()
+    };
+    info!(imported.streams, "imported streams");
+
+    let mut batch = vec![];
+    for path in &files {
+        for line in read_json_lines(path, key, dictionary)? {
+            let line = line?;
+            if line.get("payload").is_none() {
+                continue;
+            }
+            let event = imported_event(&line)
+                .with_context(|| format!("reading an event in {}", path.display()))?;
+            batch.push(event);
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                imported.add_events(conn, &batch).await?;
+                batch.clear();
+            }
+        }
+    }
+    imported.add_events(conn, &batch).await?;
+    info!(imported.events, imported.skipped_events, "imported events");
+    Ok(imported)
+}
+
+impl Imported {
+    /// Stores the events, counting those stored and skipped.
+    pub(crate) async fn add_events(
+        &mut self,
+        conn: &mut (dyn Connection + Send),
+        events: &[ImportedEvent],
+    ) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let stored = conn
+            .import_events(events)
+            .await
+            .context("storing events, whose streams must be in the files or already stored")?;
+        self.events += stored;
+        self.skipped_events += events.len() as u64 - stored;
+        Ok(())
+    }
+}
+
+/// The files to read, with directories replaced by their ".json.zst" files, encrypted or not, in
+/// name order.
+pub(crate) fn list_files(inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for input in inputs {
+        if !input.is_dir() {
+            files.push(input.clone());
+            continue;
+        }
+        let mut dir_files = vec![];
+        for entry in
+            std::fs::read_dir(input).with_context(|| format!("reading {}", input.display()))?
+        {
+            let path = entry?.path();
+            let name = path.to_string_lossy();
+            if name.ends_with(".json.zst")
+                || name.ends_with(&format!(".json.zst{}", ENCRYPTED_SUFFIX))
+            {
+                dir_files.push(path);
+            }
+        }
+        dir_files.sort();
+        files.extend(dir_files);
+    }
+    Ok(files)
+}
+
+fn stream_id(fields: &Map<String, Value>) -> Result<StreamId> {
+    let stream_id = fields
+        .get("stream_id")
+        .and_then(Value::as_i64)
+        .context("line has no stream_id")?;
+    // Postgres stores IDs as signed, so they can be negative in its exports.
+    Ok(StreamId(stream_id as u32))
+}
+
+fn imported_stream(stream_id: StreamId, fields: &Map<String, Value>) -> Result<ImportedStream> {
+    Ok(ImportedStream {
+        stream_id,
+        headers: fields.get("headers").cloned().unwrap_or_default(),
+        start_datetime: datetime(&fields["start_datetime"])?,
+        end_datetime: fields.get("end_datetime").map(datetime).transpose()?,
+        event_count: fields.get("event_count").and_then(Value::as_u64),
+        clock_skew_ms: fields.get("clock_skew_ms").and_then(Value::as_i64),
+        stream_uuid: fields
+            .get("stream_uuid")
+            .and_then(Value::as_str)
+            .map(str::parse)
+            .transpose()?,
+    })
+}
+
+fn imported_event(line: &Value) -> Result<ImportedEvent> {
+    let Value::Object(fields) = line else {
+        bail!("event isn't an object");
+    };
+    let optional = |name: &str| fields.get(name).filter(|value| !value.is_null());
+    Ok(ImportedEvent {
+        stream_id: stream_id(fields)?,
+        stream_event_index: line["stream_event_index"]
+            .as_u64()
+            .context("event has no stream_event_index")?,
+        insert_datetime: datetime(&line["insert_datetime"])?,
+        revision: line["revision"].as_u64().unwrap_or(0),
+        payload: line["payload"].clone(),
+        event_id: optional("event_id")
+            .map(|event_id| event_id.as_str().context("event_id isn't a string"))
+            .transpose()?
+            .map(str::to_owned),
+        collector: optional("collector").cloned(),
+        client_datetime: optional("client_datetime").map(datetime).transpose()?,
+    })
+}
+
+fn datetime(value: &Value) -> Result<DateTime<Utc>> {
+    parse_datetime(value.as_str().context("datetime isn't a string")?)
+}
@@ -0,0 +1,196 @@
+use crate::event_schema::EventSchema;
+use crate::limits::EventLimits;
+use crate::sampling::{Sampler, SamplingArgs};
+use crate::RoutingArgs;
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Endpoints at /ingest/NAME taking events with settings of their own, for kinds of events that
+/// need different limits, validation, sampling or storage from what's posted to /.
+#[derive(Clone, Default, clap::Args)]
+pub struct IngestArgs {
+    /// An ingest endpoint, as NAME:KEY=VALUE,..., like
+    /// "crash:schema=crash.schema.json,max_event_bytes=1048576,sink=crashes". Keys are the limit
+    /// and sampling flags without their dashes, like max_event_depth or sample_every, schema for a
+    /// JSON Schema file events have to match, and sink to store its events in a --sink. Unset
+    /// limits are the server's. Can be repeated.
+    #[arg(long = "ingest", value_parser = parse_ingest)]
+    ingest: Vec<IngestSpec>,
+}
+
+/// One --ingest endpoint's settings.
+#[derive(Clone)]
+pub(crate) struct IngestSpec {
+    name: String,
+    limits: EventLimits,
+    schema: Option<EventSchema>,
+    sampling: Option<SamplingArgs>,
+    sink: Option<String>,
+}
+
+/// Names go in the path, so are kept to characters that needn't be escaped.
+pub(crate) fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "ingest endpoint name {:?} must be letters, digits, - and _",
+            name
+        );
+    }
+    Ok(())
+}
+
+fn parse_ingest(text: &str) -> Result<IngestSpec> {
+    let (name, settings) = text.split_once(':').unwrap_or((text, ""));
+    check_name(name)?;
+    let mut spec = IngestSpec {
+        name: name.to_owned(),
+        limits: EventLimits::default(),
+        schema: None,
+        sampling: None,
+        sink: None,
+    };
+    for setting in settings.split(',').filter(|setting| !setting.is_empty()) {
+        let (key, value) = setting
+            .split_once('=')
+            .with_context(|| format!("ingest setting {:?} must be KEY=VALUE", setting))?;
+        let limit = || -> Result<Option<usize>> {
+            Ok(Some(
+                value
+                    .parse()
+                    .with_context(|| format!("{} {:?}", key, value))?,
+            ))
+        };
+        match key {
+            "max_event_bytes" => spec.limits.max_event_bytes = limit()?,
+            "max_event_depth" => spec.limits.max_event_depth = limit()?,
+            "max_array_length" => spec.limits.max_array_length = limit()?,
+            "schema" => spec.schema = Some(EventSchema::load(value)?),
+            "sink" => spec.sink = Some(value.to_owned()),
+            key => spec
+                .sampling
+                .get_or_insert_with(Default::default)
+                .set(key, value)
+                .with_context(|| format!("ingest endpoint {}", name))?,
+        }
+    }
+    if let Some(sampling) = &spec.sampling {
+        sampling.check()?;
+    }
+    Ok(spec)
+}
+
+impl IngestArgs {
+    /// Checks that each endpoint's sink was given, and that no two endpoints have the same name.
+    pub(crate) fn check_sinks(&self, routing: &RoutingArgs) -> Result<()> {
+        for (index, spec) in self.ingest.iter().enumerate() {
+            if self.ingest[..index]
+                .iter()
+                .any(|other| other.name == spec.name)
+            {
+                bail!("ingest endpoint {} is given twice", spec.name);
+            }
+            let Some(sink) = &spec.sink else {
+                continue;
+            };
+            if !routing.sinks().iter().any(|(name, _)| name == sink) {
+                bail!(
+                    "ingest endpoint {} is to unknown sink {:?}",
+                    spec.name,
+                    sink
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The endpoints by name, with the server's limits filling in those they don't set.
+    pub(crate) fn endpoints(&self, limits: EventLimits) -> HashMap<String, Arc<IngestEndpoint>> {
+        self.ingest
+            .iter()
+            .map(|spec| {
+                let endpoint = IngestEndpoint {
+                    limits: EventLimits {
+                        max_event_bytes: spec.limits.max_event_bytes.or(limits.max_event_bytes),
+                        max_event_depth: spec.limits.max_event_depth.or(limits.max_event_depth),
+                        max_array_length: spec.limits.max_array_length.or(limits.max_array_length),
+                    },
+                    schema: spec.schema.clone(),
+                    sampler: spec.sampling.as_ref().and_then(SamplingArgs::sampler),
+                    sink: spec.sink.clone(),
+                    events: Default::default(),
+                    invalid: Default::default(),
+                };
+                (spec.name.clone(), Arc::new(endpoint))
+            })
+            .collect()
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let endpoints: BTreeMap<_, _> = self
+            .ingest
+            .iter()
+            .map(|spec| {
+                let endpoint = json!({
+                    "limits": spec.limits,
+                    "schema": spec.schema.as_ref().map(EventSchema::path),
+                    "sampling": spec.sampling.as_ref().map(SamplingArgs::to_json),
+                    "sink": spec.sink,
+                });
+                (spec.name.clone(), endpoint)
+            })
+            .collect();
+        json!(endpoints)
+    }
+}
+
+/// An ingest endpoint, which events posted to it are checked, sampled and stored by instead of
+/// the server's settings.
+pub(crate) struct IngestEndpoint {
+    pub limits: EventLimits,
+    pub schema: Option<EventSchema>,
+    /// None to sample as events posted to / are.
+    pub sampler: Option<Sampler>,
+    /// Where all its events are stored, rather than by the routing rules.
+    pub sink: Option<String>,
+    events: AtomicU64,
+    invalid: AtomicU64,
+}
+
+impl IngestEndpoint {
+    /// Checks an event against the limits and schema, counting it either way.
+    pub(crate) fn check(&self, payload: &str) -> Result<()> {
+        self.events.fetch_add(1, Ordering::Relaxed);
+        let checked = self.check_event(payload);
+        if checked.is_err() {
+            self.invalid.fetch_add(1, Ordering::Relaxed);
+        }
+        checked
+    }
+
+    /// Checks an event without counting it, for strict requests checking theirs before any are
+    /// stored.
+    pub(crate) fn check_event(&self, payload: &str) -> Result<()> {
+        self.limits.check(payload)?;
+        if let Some(schema) = &self.schema {
+            let value: Value = serde_json::from_str(payload)?;
+            schema.validate(&value)?;
+        }
+        Ok(())
+    }
+
+    /// Counts since the server started.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "events": self.events.load(Ordering::Relaxed),
+            "invalid": self.invalid.load(Ordering::Relaxed),
+            "sampling": self.sampler.as_ref().map(Sampler::to_json),
+        })
+    }
+}
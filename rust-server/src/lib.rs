@@ -0,0 +1,4140 @@
+//! The telemetry server. The binary runs it from the command line with [run], and other axum
+//! applications can mount its endpoints with [router] or [Server::builder].
+
+#[cfg(test)]
+mod tests;
+
+mod access;
+mod admin;
+mod anomalies;
+mod bench;
+mod config;
+mod connection_pool;
+mod dedup;
+mod encoding;
+mod enrich;
+mod event_schema;
+mod export;
+mod fallback;
+mod forward;
+mod grafana;
+mod grpc;
+mod http_server;
+mod import;
+mod ingest;
+mod limits;
+mod loki;
+mod msgpack;
+mod open_streams;
+mod otlp;
+mod pause;
+mod pipeline;
+mod pipeline_test;
+mod protobuf;
+mod replicate;
+mod request_id;
+mod retention;
+mod rollups;
+mod router;
+mod routing;
+mod sampling;
+mod sinks;
+mod snappy;
+mod stale;
+mod statsd;
+mod storage_uri;
+mod stream_queues;
+mod stream_token;
+mod syslog;
+#[cfg(unix)]
+mod systemd;
+mod views;
+#[cfg(windows)]
+mod win_service;
+mod zstd_train;
+
+use access::{Access, AccessArgs};
+use admin::{AdminCommand, StorageArgs};
+use anomalies::AnomalyStats;
+use bench::BenchArgs;
+use config::ConfigCommand;
+use connection_pool::ConnectionPool;
+pub use dedup::DedupArgs;
+use dedup::{Dedup, DedupAction, RequestHasher};
+pub use encoding::LegacyEncoding;
+pub use enrich::EnrichArgs;
+use enrich::{Enricher, Source};
+use event_schema::SchemaViolation;
+use export::ExportFormat;
+use fallback::{Fallback, FallbackArgs};
+use forward::ForwardArgs;
+use http_server::HttpServerArgs;
+pub use ingest::IngestArgs;
+use ingest::IngestEndpoint;
+pub use limits::EventLimits;
+use limits::LimitExceeded;
+use open_streams::OpenStreams;
+use otlp::OtlpArgs;
+use pause::Pause;
+use pipeline::*;
+pub use pipeline::{RedactArgs, TransformArgs};
+use pipeline_test::PipelineTestArgs;
+use request_id::RequestId;
+use retention::{RetentionArgs, RetentionStats};
+use rollups::{RollupArgs, RollupStats};
+pub use router::{router, ServerBuilder};
+use routing::Routes;
+pub use routing::RoutingArgs;
+use sampling::Sampler;
+pub use sampling::SamplingArgs;
+use sinks::Sink;
+use stale::StaleArgs;
+use statsd::{Statsd, StatsdArgs};
+use stream_queues::{StreamQueues, StreamTurn};
+use stream_token::{StreamTokens, STREAM_TOKEN_HEADER};
+use syslog::SyslogArgs;
+use views::{encode_cursor, ViewParams, UI_PATH};
+use zstd_train::ZstdTrainArgs;
+
+use telemetry_storage::*;
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::body::Bytes;
+use axum::extract::ws::{Message, WebSocket};
+use axum::extract::{ConnectInfo, Path, Query, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use bytes::BytesMut;
+use chrono::SecondsFormat;
+use clap::Parser;
+use futures::stream::BoxStream;
+use futures::FutureExt;
+use futures::{future, select_biased};
+use futures::{Stream, StreamExt};
+use std::borrow::Cow;
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::{poll_fn, Future};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use tokio::io::AsyncWriteExt;
+use tokio::signal::ctrl_c;
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+use tracing::*;
+use Error::*;
+
+#[derive(clap::Parser)]
+#[command(args_override_self = true)]
+struct Args {
+    /// TOML config file. Flags given on the command line override it, and add to its lists. On
+    /// SIGHUP it's read again, and the auth tokens, rate limits and log level are updated.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Address to serve HTTP on. Can be repeated. Defaults to "[::]:4318" unless there are other
+    /// listeners.
+    #[arg(long)]
+    listen: Vec<SocketAddr>,
+    #[command(flatten)]
+    http_server: HttpServerArgs,
+    /// Overrides RUST_LOG's default level, like "info" or "debug". Only a server started with a
+    /// log level can have it changed by reloading.
+    #[arg(long)]
+    log_level: Option<log::LevelFilter>,
+    #[command(flatten)]
+    access: AccessArgs,
+    /// Check that the storage can be opened and written to, and has the schema this server
+    /// expects, then exit. Exits nonzero if not.
+    #[arg(long)]
+    check: bool,
+    /// Convert timestamps to RFC 3339 UTC and unit-suffixed fields to seconds and bytes.
+    #[arg(long)]
+    normalize: bool,
+    #[command(flatten)]
+    redact: RedactArgs,
+    #[command(flatten)]
+    transform: TransformArgs,
+    #[command(flatten)]
+    routing: RoutingArgs,
+    /// What to do with payloads that aren't UTF-8: "reject", "detect", or an encoding label like
+    /// "latin1" or "shift_jis".
+    #[arg(long, default_value = "reject")]
+    legacy_encoding: LegacyEncoding,
+    /// Key for signing the stream tokens clients use to resume streams. Tokens don't survive a
+    /// restart without one.
+    #[arg(long)]
+    stream_token_secret: Option<String>,
+    #[command(flatten)]
+    enrich: EnrichArgs,
+    #[command(flatten)]
+    limits: EventLimits,
+    #[command(flatten)]
+    sampling: SamplingArgs,
+    #[command(flatten)]
+    ingest: IngestArgs,
+    #[command(flatten)]
+    dedup: DedupArgs,
+    #[command(flatten)]
+    retention: RetentionArgs,
+    #[command(flatten)]
+    stale: StaleArgs,
+    #[command(flatten)]
+    rollups: RollupArgs,
+    #[command(flatten)]
+    otlp: OtlpArgs,
+    #[command(flatten)]
+    syslog: SyslogArgs,
+    #[command(flatten)]
+    statsd: StatsdArgs,
+    #[command(flatten)]
+    forward: ForwardArgs,
+    /// Connections to open to the storage, so that many requests can use it at once. Only SQLite
+    /// without --rotate-size and Postgres can have more than one.
+    #[arg(long, default_value_t = 1)]
+    storage_connections: usize,
+    /// Storage as a URI, like "sqlite://telemetry.db", "jsonfiles://./out" or
+    /// "postgres://user@host/db?tls=require", instead of a storage subcommand.
+    #[arg(long = "storage", global = true)]
+    storage_uri: Option<Storage>,
+    #[command(flatten)]
+    fallback: FallbackArgs,
+    /// Serves only the query, export and UI endpoints, against existing storage that's left as it
+    /// is, like a snapshot of a production database. Its schema has to be this server's already,
+    /// as it isn't migrated.
+    #[arg(
+        long,
+        conflicts_with_all = ["fallback_storage", "syslog_udp", "syslog_tcp", "statsd_udp", "fluent_forward"],
+    )]
+    read_only: bool,
+    /// Keep everything in memory until exit, for trying things out without a database or files.
+    /// The same as --storage memory://.
+    #[arg(long, global = true)]
+    ephemeral: bool,
+    /// Run as a Windows service, started by the service control manager. Install it with the
+    /// flag in its command line, like `sc create telemetry binPath= "C:\telemetry\server.exe
+    /// --windows-service --storage sqlite://C:/telemetry/telemetry.db"`.
+    #[cfg(windows)]
+    #[arg(long)]
+    windows_service: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Args {
+    /// Parses the command line over the settings from its --config file, if it has one.
+    fn load(argv: Vec<OsString>) -> Result<Self> {
+        let args = Self::try_parse_from(&argv)?;
+        let Some(path) = &args.config else {
+            return Ok(args);
+        };
+        let config = config::load(path)?;
+        let with_storage = args.storage_subcommand().is_none() && !args.ephemeral;
+        let mut with_config = argv[..1].to_vec();
+        with_config.extend(config.to_args(with_storage).into_iter().map(OsString::from));
+        with_config.extend_from_slice(&argv[1..]);
+        Self::try_parse_from(with_config).with_context(|| format!("applying {}", path.display()))
+    }
+
+    /// The addresses to serve HTTP on.
+    fn listen(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() && !self.http_server.has_listeners() {
+            // This is just the OTLP/HTTP port, because if we're using this we're probably not
+            // using OTLP.
+            return vec![SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 4318))];
+        }
+        self.listen.clone()
+    }
+
+    /// The storage to serve events into, or for an admin command to use.
+    fn storage(&self) -> Result<Storage> {
+        match &self.command {
+            Some(Command::PipelineTest(_)) => bail!("pipeline tests don't use storage"),
+            Some(Command::Config(_)) => bail!("config commands don't use storage"),
+            Some(Command::ZstdTrain(_)) => bail!("training dictionaries doesn't use storage"),
+            _ => {}
+        }
+        let ephemeral = self.ephemeral.then_some(Storage::Memory(MemoryOpen {}));
+        let given: Vec<&Storage> = [
+            self.storage_subcommand(),
+            self.storage_uri.as_ref(),
+            ephemeral.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        match given[..] {
+            [storage] => {
+                let mut storage = storage.clone();
+                self.otlp.apply(&mut storage)?;
+                Ok(storage)
+            }
+            [] => bail!("a storage subcommand, --storage or --ephemeral is needed"),
+            _ => bail!("only one of a storage subcommand, --storage and --ephemeral can be given"),
+        }
+    }
+
+    fn storage_subcommand(&self) -> Option<&Storage> {
+        match &self.command {
+            Some(Command::Storage(storage)) => Some(storage),
+            Some(Command::Serve(args)) => args.storage.as_ref(),
+            Some(Command::Admin(admin)) => admin.storage(),
+            Some(Command::Bench(bench)) => bench.storage.storage.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// What this server is running with, logged on startup and served at /admin/info. Settings
+    /// that can be reloaded or changed through /admin are added when it's served.
+    fn info(&self, storage: &Storage) -> serde_json::Value {
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "config": self.config,
+            "listen": self.listen(),
+            "http_server": self.http_server.to_json(),
+            "syslog": self.syslog.to_json(),
+            "statsd": self.statsd.to_json(),
+            "fluent_forward": self.forward.to_json(),
+            "storage": storage.info(),
+            "fallback": self.fallback.to_json(),
+            "read_only": self.read_only,
+            "storage_connections": self.storage_connections,
+            "features": {
+                "normalize": self.normalize,
+                "redact": self.redact.to_json(),
+                "transform": self.transform.to_json(),
+                "routing": self.routing.to_json(),
+                "legacy_encoding": self.legacy_encoding.name(),
+                "stream_token_secret": self.stream_token_secret.is_some(),
+                "enrich": self.enrich.to_json(),
+            },
+            "limits": self.limits,
+            "sampling": self.sampling.to_json(),
+            "ingest": self.ingest.to_json(),
+            "dedup": self.dedup.to_json(),
+            "retention": self.retention.to_json(),
+            "stale_streams": self.stale.to_json(),
+            "rollups": self.rollups.to_json(),
+        })
+    }
+
+    fn pipeline(&self) -> Pipeline {
+        Pipeline::new(
+            self.redact.redactor(),
+            self.normalize,
+            self.transform.transform(),
+        )
+    }
+}
+
+#[derive(Clone, clap::Subcommand)]
+enum Command {
+    // Serving is also what happens when a storage subcommand is given on its own.
+    #[command(flatten)]
+    Storage(Storage),
+    /// Serves events into the storage given after it, or by --storage.
+    Serve(StorageArgs),
+    #[command(flatten)]
+    Admin(AdminCommand),
+    PipelineTest(PipelineTestArgs),
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    Bench(BenchArgs),
+    ZstdTrain(ZstdTrainArgs),
+}
+
+#[derive(Clone, clap::Subcommand)]
+enum Storage {
+    Sqlite(SqliteOpen),
+    DuckDB(DuckDbOpen),
+    JsonFiles(JsonFilesOpen),
+    Postgres(PostgresOpener),
+    Memory(MemoryOpen),
+}
+
+impl Storage {
+    pub(crate) async fn open(self) -> Result<Box<dyn Connection + Send>> {
+        // Each backend has its own connection type, so they're boxed to be used alike.
+        match self {
+            Storage::Sqlite(open) => open.open_boxed().await,
+            Storage::DuckDB(open) => open.open_boxed().await,
+            Storage::JsonFiles(open) => open.open_boxed().await,
+            Storage::Postgres(open) => open.open_boxed().await,
+            Storage::Memory(open) => open.open_boxed().await,
+        }
+    }
+
+    /// Opens the storage without writing to it, see [StorageOpen::open_read_only].
+    pub(crate) async fn open_read_only(self) -> Result<Box<dyn Connection + Send>> {
+        match self {
+            Storage::Sqlite(open) => open.open_read_only_boxed().await,
+            Storage::DuckDB(open) => open.open_read_only_boxed().await,
+            Storage::JsonFiles(open) => open.open_read_only_boxed().await,
+            Storage::Postgres(open) => open.open_read_only_boxed().await,
+            Storage::Memory(open) => open.open_read_only_boxed().await,
+        }
+    }
+
+    pub(crate) async fn check(self) -> Result<()> {
+        match self {
+            Storage::Sqlite(open) => open.check().await,
+            Storage::DuckDB(open) => open.check().await,
+            Storage::JsonFiles(open) => open.check().await,
+            Storage::Postgres(open) => open.check().await,
+            Storage::Memory(open) => open.check().await,
+        }
+    }
+
+    /// How many connections can be open to the storage at once.
+    fn max_connections(&self) -> usize {
+        match self {
+            Storage::Sqlite(open) => open.max_connections(),
+            Storage::DuckDB(open) => open.max_connections(),
+            Storage::JsonFiles(open) => open.max_connections(),
+            Storage::Postgres(open) => open.max_connections(),
+            Storage::Memory(open) => open.max_connections(),
+        }
+    }
+
+    fn info(&self) -> serde_json::Value {
+        match self {
+            Storage::Sqlite(open) => open.info(),
+            Storage::DuckDB(open) => open.info(),
+            Storage::JsonFiles(open) => open.info(),
+            Storage::Postgres(open) => open.info(),
+            Storage::Memory(open) => open.info(),
+        }
+    }
+}
+
+/// Runs the server binary with the command line given: serving, or one of its other commands.
+/// Whether the command line asks to run as a Windows service, in which case it's run with
+/// [run_windows_service] rather than [run].
+#[cfg(windows)]
+pub fn windows_service_requested(argv: &[OsString]) -> bool {
+    win_service::requested(argv)
+}
+
+/// Connects to the service control manager and serves until the service is stopped. Blocks, and
+/// runs the server on a runtime of its own.
+#[cfg(windows)]
+pub fn run_windows_service(argv: Vec<OsString>) -> Result<()> {
+    win_service::dispatch(argv)
+}
+
+pub async fn run(argv: Vec<OsString>) -> Result<()> {
+    // Let clap print help and usage errors itself.
+    Args::try_parse_from(&argv).unwrap_or_else(|err| err.exit());
+    let args = Args::load(argv.clone())?;
+    let mut logger = env_logger::Builder::from_default_env();
+    if args.log_level.is_some() {
+        // Leave the level to the log crate's max level, which can be changed on reload.
+        logger.filter_level(log::LevelFilter::Trace);
+    }
+    logger
+        .format(|fmt, record| {
+            let level_style = fmt.default_level_style(record.level());
+            let localtime = chrono::Local::now();
+            // Lines logged while handling a request say which.
+            let request_id = RequestId::current()
+                .map(|request_id| format!(" request_id={}", request_id))
+                .unwrap_or_default();
+            writeln!(
+                fmt,
+                "[{} {level_style}{}{level_style:#} {}{}] {}",
+                localtime,
+                record.level(),
+                record.target(),
+                request_id,
+                record.args()
+            )
+        })
+        .init();
+    if let Some(level) = args.log_level {
+        log::set_max_level(level);
+    }
+    debug!(test_arg = "hi mum", "debug level test");
+    if let Some(Command::PipelineTest(test)) = &args.command {
+        return test.run(&args, &mut std::io::stdout().lock()).await;
+    }
+    if let Some(Command::Config(config)) = &args.command {
+        return config.run(&mut std::io::stdout().lock());
+    }
+    if let Some(Command::ZstdTrain(train)) = &args.command {
+        return train.run(&mut std::io::stdout().lock());
+    }
+    if let Some(Command::Bench(bench)) = &args.command {
+        return bench.run(&args, &mut std::io::stdout().lock()).await;
+    }
+    if let Some(Command::Admin(admin)) = &args.command {
+        let mut stdout = std::io::BufWriter::new(std::io::stdout());
+        admin.run(&args, &mut stdout).await?;
+        return Ok(stdout.flush()?);
+    }
+    if args.check {
+        args.storage()?.check().await.context("checking storage")?;
+        info!("storage checked ok");
+        return Ok(());
+    }
+    let storage = args.storage()?;
+    args.routing.check_sinks()?;
+    args.ingest.check_sinks(&args.routing)?;
+    args.rollups.check_names()?;
+    let info = args.info(&storage);
+    info!(%info, "starting");
+    let fallback = Fallback::new(storage, &args.fallback);
+    for storage in fallback.chain() {
+        if args.storage_connections > storage.max_connections() {
+            bail!(
+                "--storage-connections {} is more than the storage can have open",
+                args.storage_connections
+            );
+        }
+    }
+    let db_conns = match args.read_only {
+        true => {
+            let mut conns = vec![];
+            for _ in 0..args.storage_connections {
+                conns.push(fallback.chain()[0].clone().open_read_only().await?);
+            }
+            conns
+        }
+        false => fallback.open(args.storage_connections).await?,
+    };
+    let mut db_conns = db_conns.into_iter();
+    let db_conn = db_conns.next().expect("at least one connection");
+    let commit_on_sigint = db_conn.commit_on_sigint();
+    let mut builder = Server::builder(db_conn)
+        .normalize(args.normalize)
+        .redact(&args.redact)
+        .transform(&args.transform)
+        .legacy_encoding(args.legacy_encoding)
+        .enrich(&args.enrich)
+        .limits(args.limits)
+        .sampling(&args.sampling)
+        .routing(&args.routing)
+        .ingest(&args.ingest)
+        .dedup(&args.dedup)
+        .read_only(args.read_only);
+    if let Some(secret) = &args.stream_token_secret {
+        builder = builder.stream_token_secret(secret);
+    }
+    for db_conn in db_conns {
+        builder = builder.connection(db_conn);
+    }
+    for (name, storage) in args.routing.sinks() {
+        let sink = storage
+            .clone()
+            .open()
+            .await
+            .with_context(|| format!("opening sink {}", name))?;
+        builder = builder.sink(name, sink);
+    }
+    let server = builder.fallback(fallback).build();
+
+    // This catches signals that trigger commit. Spin it up even if not committing on sigint to
+    // ensure all behaviours are handled correctly.
+    tokio::spawn({
+        let db_conn = Arc::clone(&server.db_conn);
+        async move {
+            if !db_conn.lock().await.commit_on_sigint() {
+                std::future::pending::<()>().await;
+                return;
+            }
+            loop {
+                ctrl_c().await.unwrap();
+                for conn in db_conn.all() {
+                    log_commit(&mut **conn.lock().await).await.unwrap();
+                }
+            }
+        }
+    });
+
+    let access = Arc::new(Access::new(args.access.clone()));
+    if args.config.is_some() {
+        tokio::spawn(reload_on_hangup(argv, Arc::clone(&access)));
+    }
+    #[cfg(unix)]
+    let activated = systemd::take_listen_fds()?;
+    #[cfg(windows)]
+    let activated = vec![];
+    // Sockets from systemd stand in for the default address.
+    let listen = match activated.is_empty() {
+        true => args.listen(),
+        false => args.listen.clone(),
+    };
+    // These all write to storage.
+    if !args.read_only {
+        tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.prune_periodically(args.retention).await }
+        });
+        tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.mark_stale_periodically(args.stale).await }
+        });
+        tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.create_partitions_periodically().await }
+        });
+        tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.roll_up_periodically(args.rollups).await }
+        });
+    }
+    tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.fall_back_periodically().await }
+    });
+    let syslog_streams = Arc::new(OpenStreams::default());
+    for addr in &args.syslog.syslog_udp {
+        let socket = tokio::net::UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("binding {}", addr))?;
+        let syslog_local_addr = socket.local_addr()?;
+        info!(?syslog_local_addr, "receiving syslog over udp");
+        let server = Arc::clone(&server);
+        let streams = Arc::clone(&syslog_streams);
+        tokio::spawn(async move { server.serve_syslog_udp(socket, &streams).await });
+    }
+    for addr in &args.syslog.syslog_tcp {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("binding {}", addr))?;
+        let syslog_local_addr = listener.local_addr()?;
+        info!(?syslog_local_addr, "receiving syslog over tcp");
+        let server = Arc::clone(&server);
+        let streams = Arc::clone(&syslog_streams);
+        tokio::spawn(async move { server.serve_syslog_tcp(listener, streams).await });
+    }
+    let statsd = Arc::new(Statsd::new(&args.statsd));
+    for addr in &args.statsd.statsd_udp {
+        let socket = tokio::net::UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("binding {}", addr))?;
+        let statsd_local_addr = socket.local_addr()?;
+        info!(?statsd_local_addr, "receiving statsd over udp");
+        let server = Arc::clone(&server);
+        let statsd = Arc::clone(&statsd);
+        tokio::spawn(async move { server.serve_statsd_udp(socket, &statsd).await });
+    }
+    if let Some(interval) = args.statsd.statsd_flush_interval {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move { server.flush_statsd_periodically(&statsd, interval).await });
+    }
+    for addr in &args.forward.fluent_forward {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("binding {}", addr))?;
+        let forward_local_addr = listener.local_addr()?;
+        info!(?forward_local_addr, "receiving fluent forward protocol");
+        tokio::spawn(Arc::clone(&server).serve_fluent_forward(listener));
+    }
+    // TODO: Catch a signal or handle an endpoint that triggers the db conn to be committed. Also do
+    // this on a timer.
+    let tower_layer = tower_http::trace::TraceLayer::new_for_http()
+        .make_span_with(tower_http::trace::DefaultMakeSpan::new().include_headers(true))
+        .on_request(())
+        .on_body_chunk(());
+    let app = server
+        .router()
+        .route(
+            "/admin/info",
+            axum::routing::get({
+                let access = Arc::clone(&access);
+                let server = Arc::clone(&server);
+                move || {
+                    // With what's changed since startup.
+                    let mut info = info.clone();
+                    info["access"] = access.to_json();
+                    info["log_level"] = log::max_level().to_string().into();
+                    if let Some(sampler) = &server.sampler {
+                        info["sampling"] = sampler.policy();
+                    }
+                    info["pause"] = server.pause.to_json();
+                    async move { axum::Json(info) }
+                }
+            }),
+        )
+        .layer(axum::middleware::from_fn_with_state(access, access::guard))
+        .layer(tower_layer)
+        // Outermost, so requests turned away by the guard have IDs too.
+        .layer(axum::middleware::from_fn(request_id::propagate));
+    // I want the default to bind dual stack, but I don't see any obvious way to do it with one
+    // call.
+    let connection_limit = args.http_server.connection_limit();
+    let mut http_servers = vec![];
+    for listener in http_server::bind(&listen, activated, &args.http_server).await? {
+        let http_server = http_server::serve(
+            listener,
+            app.clone(),
+            &args.http_server,
+            connection_limit.clone(),
+        );
+        http_servers.push(Box::pin(http_server));
+    }
+    let http_server = future::select_all(http_servers).map(|(result, _, _)| result);
+    let term_sigs = pin!(handle_main_signals(commit_on_sigint)?);
+    #[cfg(unix)]
+    {
+        systemd::notify("READY=1");
+        if let Some(interval) = systemd::watchdog_interval() {
+            tokio::spawn(systemd::feed_watchdog(interval));
+        }
+    }
+    #[cfg(windows)]
+    win_service::set_state(
+        windows_service::service::ServiceState::Running,
+        windows_service::service::ServiceExitCode::Win32(0),
+    );
+    let either = future::select(http_server, term_sigs).await;
+    let result = either.factor_first().0;
+    #[cfg(unix)]
+    systemd::notify("STOPPING=1");
+    #[cfg(windows)]
+    win_service::set_state(
+        windows_service::service::ServiceState::StopPending,
+        windows_service::service::ServiceExitCode::Win32(0),
+    );
+    match server.shutdown().await {
+        Ok(()) => info!("shut down storage"),
+        Err(err) => error!(%err, "shutting down storage"),
+    }
+    result
+}
+
+/// Rereads the config file on SIGHUP and applies the settings that can change while serving.
+#[cfg(unix)]
+async fn reload_on_hangup(argv: Vec<OsString>, access: Arc<Access>) -> Result<()> {
+    let mut hangups = tokio::signal::unix::signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        reload_config(&argv, &access);
+    }
+    Ok(())
+}
+
+/// Windows has no SIGHUP, so the config file is reread when a service is sent paramchange.
+#[cfg(windows)]
+async fn reload_on_hangup(argv: Vec<OsString>, access: Arc<Access>) -> Result<()> {
+    loop {
+        win_service::reload_requested().await;
+        reload_config(&argv, &access);
+    }
+}
+
+/// Changes the log level until the config is reloaded or the server restarts. Without
+/// --log-level, levels more verbose than RUST_LOG's aren't logged.
+fn log_level_handler(level: &str) -> (StatusCode, String) {
+    match level.trim().parse::<log::LevelFilter>() {
+        Ok(level) => {
+            log::set_max_level(level);
+            info!(%level, "changed log level");
+            (StatusCode::OK, level.to_string())
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, format!("log level: {}", err)),
+    }
+}
+
+fn reload_config(argv: &[OsString], access: &Access) {
+    let args = match Args::load(argv.to_vec()) {
+        Ok(args) => args,
+        Err(err) => {
+            error!(
+                err = format!("{:#}", err),
+                "reloading config, keeping the old one"
+            );
+            return;
+        }
+    };
+    if let Some(level) = args.log_level {
+        log::set_max_level(level);
+    }
+    access.reload(args.access);
+    info!("reloaded config");
+}
+
+#[cfg(unix)]
+fn handle_main_signals(commit_on_sigint: bool) -> Result<impl Future<Output = Result<()>>> {
+    let mut signals = vec![];
+    if !commit_on_sigint {
+        signals.push(Box::pin(signal("SIGINT", SignalKind::interrupt())?));
+    }
+    for (name, kind) in [
+        // What about the fact this is normally for user detected errors?
+        ("SIGQUIT", SignalKind::quit()),
+        ("SIGTERM", SignalKind::terminate()),
+    ] {
+        signals.push(Box::pin(signal(name, kind)?));
+    }
+    Ok(async move {
+        let signal_name = future::select_all(signals).await.0 .0;
+        warn!(signal_name, "received terminating main signal");
+        Ok(())
+    })
+}
+
+/// Ctrl+C, unless it commits, Ctrl+Break, the console closing or the system shutting down, or the
+/// service being stopped. Windows gives the console ones only a few seconds to finish up.
+#[cfg(windows)]
+fn handle_main_signals(commit_on_sigint: bool) -> Result<impl Future<Output = Result<()>>> {
+    use tokio::signal::windows;
+
+    type Signal = std::pin::Pin<Box<dyn Future<Output = &'static str> + Send>>;
+    let mut signals: Vec<Signal> = vec![Box::pin(async {
+        win_service::stopped().await;
+        "service stop"
+    })];
+    if !commit_on_sigint {
+        let mut ctrl_c = windows::ctrl_c()?;
+        signals.push(Box::pin(async move {
+            ctrl_c.recv().await;
+            "CTRL_C"
+        }));
+    }
+    let mut ctrl_break = windows::ctrl_break()?;
+    signals.push(Box::pin(async move {
+        ctrl_break.recv().await;
+        "CTRL_BREAK"
+    }));
+    let mut ctrl_close = windows::ctrl_close()?;
+    signals.push(Box::pin(async move {
+        ctrl_close.recv().await;
+        "CTRL_CLOSE"
+    }));
+    let mut ctrl_shutdown = windows::ctrl_shutdown()?;
+    signals.push(Box::pin(async move {
+        ctrl_shutdown.recv().await;
+        "CTRL_SHUTDOWN"
+    }));
+    Ok(async move {
+        let signal_name = future::select_all(signals).await.0;
+        warn!(signal_name, "received terminating main signal");
+        Ok(())
+    })
+}
+
+#[cfg(unix)]
+fn signal(name: &str, kind: SignalKind) -> Result<impl Future<Output = (&str, Option<()>)>> {
+    let mut signal = tokio::signal::unix::signal(kind)?;
+    Ok(async move { signal.recv().map(|maybe_sig| (name, maybe_sig)).await })
+}
+
+async fn log_commit(conn: &mut (impl Connection + ?Sized)) -> Result<()> {
+    let res = conn.commit().await;
+    match &res {
+        Ok(()) => info!("committed"),
+        Err(err) => error!(%err, "committing"),
+    };
+    res
+}
+
+/// Serves events into storage over HTTP. Built with [Server::builder], and served with
+/// [Server::router].
+pub struct Server {
+    db_conn: Arc<ConnectionPool>,
+    pipeline: Pipeline,
+    legacy_encoding: LegacyEncoding,
+    stream_tokens: StreamTokens,
+    enricher: Option<Enricher>,
+    limits: EventLimits,
+    retention_stats: RetentionStats,
+    rollup_stats: RollupStats,
+    anomaly_stats: AnomalyStats,
+    sampler: Option<Sampler>,
+    routes: Option<Routes>,
+    /// Storage events can be routed to, by name.
+    sinks: HashMap<String, Sink>,
+    loki_streams: OpenStreams<loki::Labels>,
+    /// Orders requests writing to the same stream.
+    stream_queues: StreamQueues,
+    /// What to fall back to when the storage in use stops working.
+    fallback: Option<Fallback>,
+    /// Leaves out the endpoints that write to storage.
+    read_only: bool,
+    /// The endpoints under /ingest, by name.
+    ingest: HashMap<String, Arc<IngestEndpoint>>,
+    /// Holds off storing events while the storage is being worked on.
+    pause: Arc<Pause>,
+    /// None unless duplicate requests are looked for.
+    dedup: Option<Dedup>,
+}
+
+/// The request events came in, for enriching and routing them.
+struct Origin {
+    /// None unless enrichment is enabled.
+    source: Option<Source>,
+    headers: HeaderMap,
+    /// The ingest endpoint the events were posted to, if not /.
+    ingest: Option<Arc<IngestEndpoint>>,
+    /// When the same request was first received, if this is a duplicate to tag.
+    duplicate_of: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Origin {
+    /// What's stored with each event in its collector field.
+    fn collector(&self) -> Option<serde_json::Value> {
+        let mut collector = self.source.as_ref().map(Source::event);
+        if let Some(duplicate_of) = self.duplicate_of {
+            collector.get_or_insert_with(|| serde_json::json!({}))["duplicate_of"] =
+                duplicate_of.to_rfc3339().into();
+        }
+        collector
+    }
+}
+
+/// Request bodies compressed with this `Content-Encoding` are decompressed as they arrive. Others
+/// are refused.
+const GZIP_ENCODING: &str = "gzip";
+
+/// Decompresses a gzip body a chunk at a time, so it's never all in memory.
+fn gunzip_stream(
+    body: impl Stream<Item = Result<Bytes, axum::Error>> + Send + Unpin + 'static,
+) -> BoxStream<'static, Result<Bytes, axum::Error>> {
+    let decoder = Some(flate2::write::GzDecoder::new(vec![]));
+    futures::stream::unfold((body, decoder), |(mut body, mut decoder)| async move {
+        let gz = decoder.as_mut()?;
+        let written = match body.next().await {
+            Some(Ok(bytes)) => gz.write_all(&bytes).and_then(|()| gz.flush()),
+            Some(Err(err)) => return Some((Err(err), (body, None))),
+            None => {
+                let finished = gz.try_finish();
+                let decoded = std::mem::take(gz.get_mut());
+                return Some((
+                    finished.map(|()| decoded.into()).map_err(axum::Error::new),
+                    (body, None),
+                ));
+            }
+        };
+        let decoded = std::mem::take(gz.get_mut());
+        match written {
+            Ok(()) => Some((Ok(decoded.into()), (body, decoder))),
+            Err(err) => Some((Err(axum::Error::new(err)), (body, None))),
+        }
+    })
+    .boxed()
+}
+
+/// Finds where JSON values end as a body arrives, looking at each byte once however the body is
+/// chunked. Only strings and brackets are followed, so values are checked properly once they're
+/// split off.
+#[derive(Debug, Default)]
+struct ValueEnds {
+    /// How much of the buffer has been looked at.
+    scanned: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// In a number or literal outside any object or array, which ends at the first byte that
+    /// can't be part of it.
+    in_scalar: bool,
+}
+
+impl ValueEnds {
+    /// Where the next complete value in `bytes` ends, including any whitespace before it. `bytes`
+    /// must be what was given last time with more on the end, less the values returned, which
+    /// the caller splits off.
+    fn next(&mut self, bytes: &[u8]) -> Option<usize> {
+        while let Some(&byte) = bytes.get(self.scanned) {
+            if self.in_scalar {
+                if byte.is_ascii_whitespace() || b"\"[]{},:".contains(&byte) {
+                    self.in_scalar = false;
+                    return Some(std::mem::take(&mut self.scanned));
+                }
+                self.scanned += 1;
+                continue;
+            }
+            self.scanned += 1;
+            if self.in_string {
+                match (self.escaped, byte) {
+                    (true, _) => self.escaped = false,
+                    (false, b'\\') => self.escaped = true,
+                    (false, b'"') => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return Some(std::mem::take(&mut self.scanned));
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 1 => self.depth -= 1,
+                b'}' | b']' if self.depth == 1 => {
+                    self.depth = 0;
+                    return Some(std::mem::take(&mut self.scanned));
+                }
+                _ if byte.is_ascii_whitespace() || self.depth > 0 => {}
+                // Can't start a value, so it's left to the parser to say so.
+                b'}' | b']' | b',' | b':' => return Some(std::mem::take(&mut self.scanned)),
+                _ => self.in_scalar = true,
+            }
+        }
+        None
+    }
+}
+
+async fn iter_json_stream<F>(
+    mut body_data_stream: impl Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+    // Stop buffering an incomplete value once it's longer than this.
+    max_value_bytes: Option<usize>,
+    mut on_payload: impl FnMut(Bytes) -> F,
+) -> Result<(), SubmitError>
+where
+    F: Future<Output = Result<()>>,
+{
+    // Only what's left of the body after the complete values split off it.
+    let mut bytes = BytesMut::new();
+    let mut value_ends = ValueEnds::default();
+    // Bytes of the body split off the buffer, so offsets are from the start of the body.
+    let mut drained = 0;
+    // Where the next value starts, past any whitespace.
+    let value_offset = |drained: usize, bytes: &[u8]| {
+        let whitespace = bytes.iter().take_while(|byte| byte.is_ascii_whitespace());
+        (drained + whitespace.count()) as u64
+    };
+    let mut finished = false;
+    while !finished {
+        match body_data_stream.next().await {
+            Some(Err(err)) => {
+                return Err(SubmitError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "body_read_failed",
+                    anyhow::Error::from(err).context("error in body data stream"),
+                ));
+            }
+            Some(Ok(new_bytes)) => bytes.extend_from_slice(&new_bytes),
+            None => finished = true,
+        }
+        loop {
+            let payload = match value_ends.next(&bytes) {
+                Some(end) => bytes.split_to(end).freeze(),
+                // Whatever's left at the end could only be a number or literal.
+                None if finished && !bytes.iter().all(u8::is_ascii_whitespace) => {
+                    value_ends = ValueEnds::default();
+                    bytes.split().freeze()
+                }
+                None => break,
+            };
+            let offset = value_offset(drained, &payload);
+            drained += payload.len();
+            // Checked without allocating anything.
+            match serde_json::from_slice::<serde::de::IgnoredAny>(&payload) {
+                Err(err) if err.is_eof() => {
+                    return Err(SubmitError::new(
+                        StatusCode::BAD_REQUEST,
+                        "truncated_json",
+                        anyhow!(err),
+                    )
+                    .at(offset));
+                }
+                Err(err) => {
+                    error!(?err, "error deserializing json value");
+                    return Err(SubmitError::new(
+                        StatusCode::BAD_REQUEST,
+                        "malformed_json",
+                        anyhow!(err).context("deserializing json value"),
+                    )
+                    .at(offset));
+                }
+                Ok(serde::de::IgnoredAny) => {}
+            }
+            if let Err(err) = on_payload(payload).await {
+                return Err(SubmitError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "storage_error",
+                    err.context("handling payload"),
+                ));
+            }
+        }
+        trace!(drained, "split payloads off to offset");
+        if let Some(max) = max_value_bytes {
+            if let Err(err) = LimitExceeded::check("max_event_bytes", max, bytes.len()) {
+                return Err(SubmitError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "event_too_large",
+                    err.into(),
+                )
+                .at(value_offset(drained, &bytes)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads all of a body that's held in memory before its events are stored, refusing it once it's
+/// over [MAX_BUFFERED_REQUEST_BYTES].
+async fn read_buffered_body(
+    mut body_data_stream: impl Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+) -> Result<Bytes, SubmitError> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = body_data_stream.next().await {
+        let chunk = chunk.map_err(|err| {
+            SubmitError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "body_read_failed",
+                anyhow::Error::from(err).context("error in body data stream"),
+            )
+        })?;
+        if body.len() + chunk.len() > MAX_BUFFERED_REQUEST_BYTES {
+            return Err(SubmitError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request_too_large",
+                anyhow!("request body is over {} bytes", MAX_BUFFERED_REQUEST_BYTES),
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+/// Why a POST stopped before the end of its body, reported to the client with the events stored
+/// up to then.
+#[derive(Debug)]
+struct SubmitError {
+    status_code: StatusCode,
+    /// Stable and machine-readable, like "malformed_json".
+    code: &'static str,
+    err: anyhow::Error,
+    /// Where the value that couldn't be read starts in the body, after decompressing.
+    offset: Option<u64>,
+}
+
+impl SubmitError {
+    fn new(status_code: StatusCode, code: &'static str, err: anyhow::Error) -> Self {
+        Self {
+            status_code,
+            code,
+            err,
+            offset: None,
+        }
+    }
+
+    fn at(self, offset: u64) -> Self {
+        Self {
+            offset: Some(offset),
+            ..self
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "code": self.code,
+            "message": format!("{:#}", self.err),
+            "offset": self.offset,
+        })
+    }
+}
+
+/// An event that's been through the pipeline, and where it's stored.
+struct ProcessedEvent<'a, 'p> {
+    payload: Cow<'p, str>,
+    client_datetime: Option<chrono::DateTime<chrono::Utc>>,
+    /// None for the server's storage.
+    sink: Option<&'a Sink>,
+}
+
+impl ProcessedEvent<'_, '_> {
+    /// Stores the event in a sink it's routed to, copying its stream from `main` if need be.
+    async fn store_in_sink(
+        &self,
+        sink: &Sink,
+        main: &mut (dyn Connection + Send),
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        event_id: Option<&str>,
+        collector: Option<serde_json::Value>,
+    ) -> Result<()> {
+        let event = ImportedEvent {
+            stream_id,
+            stream_event_index,
+            insert_datetime: chrono::Utc::now(),
+            revision: 0,
+            payload: serde_json::from_str(&self.payload)?,
+            event_id: event_id.map(str::to_owned),
+            collector,
+            client_datetime: self.client_datetime,
+        };
+        sink.store(main, event)
+            .await
+            .context("inserting payload into sink")
+    }
+}
+
+/// Context for errors from the storage itself, after which a batch can only be rolled back.
+#[derive(Debug)]
+struct StorageFailed;
+
+impl std::fmt::Display for StorageFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("inserting payload into store")
+    }
+}
+
+/// How a POST's events were stored, as far as storage holding them back goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum BatchOutcome {
+    /// Each event was stored as it was read.
+    #[default]
+    Unbatched,
+    /// The events were stored together at the end of the body.
+    Committed,
+    /// None of the events were stored.
+    RolledBack,
+}
+
+/// An event from a POSTed batch that couldn't be stored.
+struct EventFailure {
+    stream_event_index: StreamEventIndex,
+    err: anyhow::Error,
+}
+
+impl EventFailure {
+    fn limit_exceeded(&self) -> Option<&LimitExceeded> {
+        self.err.downcast_ref()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "index": self.stream_event_index,
+            "error": format!("{:#}", self.err),
+        });
+        let fields = match (
+            self.limit_exceeded(),
+            self.err.downcast_ref::<SchemaViolation>(),
+        ) {
+            (Some(limit_exceeded), _) => limit_exceeded.to_json(),
+            (None, Some(schema_violation)) => schema_violation.to_json(),
+            (None, None) => return json,
+        };
+        json.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        json
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SubmitParams {
+    /// Close the stream once the body has been stored.
+    #[serde(default)]
+    close: bool,
+    /// Store all of the body's events or none of them.
+    #[serde(default)]
+    strict: bool,
+}
+
+/// Events from a request handed to storage at a time. Those that fail in storage that doesn't
+/// hold them back are all reported as failed, as which of them were stored isn't known.
+const EVENTS_PER_INSERT: usize = 1000;
+
+/// The largest strict request body read, after decompressing. Strict bodies are held in memory
+/// until they've all been checked.
+const MAX_STRICT_REQUEST_BYTES: usize = 16 << 20;
+
+/// The largest body read whole before it's stored, after decompressing, for storage that stores a
+/// request's events together or when checking for duplicate requests.
+const MAX_BUFFERED_REQUEST_BYTES: usize = 64 << 20;
+
+#[derive(serde::Deserialize)]
+struct WebsocketParams {
+    #[serde(default)]
+    acks: AckFormat,
+}
+
+/// What a websocket's acknowledgements say.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AckFormat {
+    /// The count of events stored from the connection so far.
+    #[default]
+    Count,
+    /// A JSON object with the count, and the highest index up to which the stream's events are
+    /// all flushed to storage, so a client can stop keeping them for retries.
+    Watermark,
+}
+
+impl AckFormat {
+    fn acknowledgement(self, count: u64, committed_stream_event_index: StreamEventIndex) -> String {
+        match self {
+            AckFormat::Count => count.to_string(),
+            AckFormat::Watermark => serde_json::json!({
+                "count": count,
+                "committed_stream_event_index": committed_stream_event_index,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Websocket acknowledgements are sent at least this often while events keep arriving.
+const ACK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How often partitioned storage is checked for partitions to create. Partitions are made
+/// periods ahead, so this only has to be well under a day.
+const PARTITION_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(serde::Deserialize)]
+struct SamplingParams {
+    /// The ingest endpoint whose sampling to change, rather than the server's.
+    ingest: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct PauseParams {
+    /// Seconds to tell clients to wait before retrying.
+    retry_after: Option<u64>,
+    /// Seconds to wait for what's being stored to finish.
+    timeout: Option<u64>,
+}
+
+/// How long pausing waits for what's being stored by default.
+const PAUSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// What an /admin storage request does to each connection.
+#[derive(Clone, Copy, Debug)]
+enum StorageAction {
+    Flush,
+    Commit,
+    Rotate,
+}
+
+#[derive(serde::Deserialize)]
+struct ReviseParams {
+    /// The stream event index of the event being corrected.
+    index: StreamEventIndex,
+    /// Must be higher than the stored event's revision.
+    revision: EventRevision,
+}
+
+#[derive(serde::Deserialize)]
+struct MergeStreamsParams {
+    from: u32,
+    into: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct SplitStreamParams {
+    stream_id: u32,
+    /// RFC 3339 time. Events inserted from then on go to the new stream.
+    at: String,
+}
+
+/// Picks the format for `/api/export`. The rest of the query string is the view to export.
+#[derive(serde::Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// Query parameters selecting stored events. Times are RFC 3339.
+#[derive(serde::Deserialize)]
+struct EventSelectionParams {
+    stream_id: Option<u32>,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+impl EventSelectionParams {
+    fn selection(&self) -> Result<EventSelection> {
+        let parse = |datetime: &Option<String>| -> Result<_> {
+            datetime
+                .as_deref()
+                .map(|datetime| {
+                    chrono::DateTime::parse_from_rfc3339(datetime)
+                        .map(|datetime| datetime.to_utc())
+                        .with_context(|| format!("parsing time {:?}", datetime))
+                })
+                .transpose()
+        };
+        Ok(EventSelection {
+            stream_id: self.stream_id.map(StreamId),
+            since: parse(&self.since)?,
+            until: parse(&self.until)?,
+        })
+    }
+}
+
+/// Bounds on the start of a rollup's buckets, as RFC 3339 times.
+#[derive(serde::Deserialize)]
+struct RollupParams {
+    since: Option<String>,
+    until: Option<String>,
+}
+
+/// Events whose payloads' fields are offered to Grafana.
+const GRAFANA_SEARCH_SAMPLE: usize = 100;
+
+/// Streams listed when the request doesn't say how many.
+const DEFAULT_STREAMS_LIMIT: usize = 100;
+
+#[derive(serde::Deserialize)]
+struct StreamsParams {
+    limit: Option<usize>,
+    /// Comma separated `key:value` pairs. Only streams with all of those labels are listed.
+    labels: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CompressionReportParams {
+    limit: Option<usize>,
+}
+
+enum StreamRetry {
+    More,
+    Stop,
+}
+
+#[derive(Debug)]
+enum Error {
+    Recv(axum::Error),
+    Handle(anyhow::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recv(err) => Display::fmt(err, f),
+            Handle(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl Server {
+    /// The stream is opened before upgrading so its token can be returned in the response headers.
+    async fn websocket_upgrade(
+        self: Arc<Self>,
+        mut ws_upgrade: WebSocketUpgrade,
+        remote_addr: Option<SocketAddr>,
+        headers: &HeaderMap,
+        acks: AckFormat,
+    ) -> Response {
+        // Taken first, so a fallback while the stream's opened makes the token no good rather
+        // than good for the wrong storage.
+        let generation = self.storage_generation();
+        // The turn's only held to resume the stream, so the connection can't hold up requests.
+        let (stream_id, last_stream_event_index, _) =
+            match self.open_stream(headers, self.sampler.as_ref()).await {
+                Err((err, code)) => {
+                    error!(?err, "opening stream");
+                    return (code, format!("{:#}", err)).into_response();
+                }
+                Ok(ok) => ok,
+            };
+        let stream_token = self.stream_tokens.issue(stream_id, generation);
+        let origin = self.origin(remote_addr, headers);
+        if let Some(max_event_bytes) = self.limits.max_event_bytes {
+            ws_upgrade = ws_upgrade.max_message_size(max_event_bytes);
+        }
+        // The connection is handled in a task of its own, still as part of this request.
+        let request_id = RequestId::current();
+        let mut response = ws_upgrade.on_upgrade(move |ws| async move {
+            let handler = self.websocket_handler(
+                ws,
+                stream_id,
+                generation,
+                last_stream_event_index,
+                &origin,
+                acks,
+            );
+            match request_id {
+                Some(request_id) => request_id.scope(handler).await,
+                None => handler.await,
+            }
+        });
+        response
+            .headers_mut()
+            .insert(STREAM_TOKEN_HEADER, stream_token.parse().unwrap());
+        response
+    }
+
+    async fn websocket_handler(
+        &self,
+        websocket: WebSocket,
+        stream_id: StreamId,
+        generation: u64,
+        last_stream_event_index: StreamEventIndex,
+        origin: &Origin,
+        acks: AckFormat,
+    ) {
+        if let Err(err) = self
+            .websocket_handler_err(
+                websocket,
+                stream_id,
+                generation,
+                last_stream_event_index,
+                origin,
+                acks,
+            )
+            .await
+        {
+            match err {
+                Recv(err) => {
+                    debug!(?err, "receiving message");
+                }
+                Handle(err) => {
+                    error!(?err, "handling message");
+                }
+            }
+        }
+    }
+
+    async fn handle_message(
+        &self,
+        message: Message,
+        stream_id: StreamId,
+        generation: u64,
+        last_stream_event_index: &AtomicU64,
+        origin: &Origin,
+    ) -> Result<StreamRetry> {
+        match message {
+            Message::Close(reason) => {
+                debug!(%stream_id, ?reason, "websocket closed");
+                // Not sure if we should act on this or let the next recv return None?
+                Ok(StreamRetry::More)
+            }
+            // Pings and pongs are apparently are handled for us by the library.
+            Message::Ping(_) | Message::Pong(_) => Ok(StreamRetry::More),
+            // That should leave text and binary types, which we won't discriminate.
+            Message::Binary(vec) if vec.is_empty() => Ok(StreamRetry::Stop),
+            _ => {
+                let _in_flight = self.pause.enter().await;
+                if self.storage_generation() != generation {
+                    bail!(
+                        "fell back to other storage since stream {} was opened",
+                        stream_id
+                    );
+                }
+                let data = message.into_data();
+                let payload = self
+                    .legacy_encoding
+                    .decode(&data)
+                    .context("converting payload to text")?;
+                let event_id = payload_event_id(&payload);
+                let stream_event_index =
+                    last_stream_event_index.fetch_add(1, Ordering::Relaxed) + 1;
+                self.insert_event(
+                    &payload,
+                    stream_id,
+                    stream_event_index,
+                    event_id.as_deref(),
+                    origin,
+                    None,
+                    None,
+                )
+                .await
+                .context("inserting event")?;
+                Ok(StreamRetry::More)
+            }
+        }
+    }
+
+    /// Only returns receive errors. Logs acknowledgement errors (but still returns).
+    async fn websocket_handler_err(
+        &self,
+        mut websocket: WebSocket,
+        stream_id: StreamId,
+        generation: u64,
+        last_stream_event_index: StreamEventIndex,
+        origin: &Origin,
+        acks: AckFormat,
+    ) -> Result<(), Error> {
+        // TODO: Flush streams
+        let mut total_events = 0;
+        // Shared with each message handling future, which can't hold a mutable borrow.
+        let last_stream_event_index = AtomicU64::new(last_stream_event_index);
+        let last_stream_event_index = &last_stream_event_index;
+        let result = loop {
+            let (batch_count, last_recv_result) = Self::receive_consecutive_websocket_messages(
+                &mut websocket,
+                |message| async move {
+                    // TODO: Take db_conn lock on first event.
+                    self.handle_message(
+                        message,
+                        stream_id,
+                        generation,
+                        last_stream_event_index,
+                        origin,
+                    )
+                    .await
+                },
+            )
+            .await;
+            info!(batch_count, %stream_id, "inserted consecutive payloads");
+            if batch_count != 0 {
+                // Just flush the events.
+                self.db_conn
+                    .lock()
+                    .await
+                    .flush()
+                    .await
+                    .context("flushing consecutive payloads")
+                    .map_err(Handle)?;
+                total_events += batch_count;
+                // Every index up to the last handed out is stored, as failing to store an event
+                // ends the connection.
+                let acknowledgement = acks.acknowledgement(
+                    total_events,
+                    last_stream_event_index.load(Ordering::Relaxed),
+                );
+                if let Err(err) = Self::acknowledge_inserted(&mut websocket, acknowledgement).await
+                {
+                    // Report the acknowledgment error, which is pretty important, and return with
+                    // whatever the recv result was.
+                    error!(?err, "acknowledging received");
+                    break last_recv_result.map(|_| ());
+                }
+            }
+            match last_recv_result {
+                Err(err) => {
+                    break Err(err);
+                }
+                Ok(StreamRetry::Stop) => {
+                    break Ok(());
+                }
+                Ok(StreamRetry::More) => {}
+            }
+        };
+        match &result {
+            Ok(()) => {
+                info!(%stream_id, total_events, "stream ended");
+                self.close_stream(stream_id)
+                    .await
+                    .context("closing stream")
+                    .map_err(Handle)?;
+            }
+            Err(err) => {
+                info!(%stream_id, total_events, %err, "stream ended");
+            }
+        }
+        result
+    }
+
+    async fn receive_consecutive_websocket_messages<F>(
+        websocket: &mut WebSocket,
+        mut handle: impl FnMut(Message) -> F,
+    ) -> (u64, Result<StreamRetry, Error>)
+    where
+        F: Future<Output = Result<StreamRetry>>,
+    {
+        let mut count = 0;
+        let mut first_received = None;
+        let result = loop {
+            // Clients sending without a break still get acknowledgements.
+            if first_received
+                .is_some_and(|first: std::time::Instant| first.elapsed() >= ACK_INTERVAL)
+            {
+                break Ok(StreamRetry::More);
+            }
+            let mut nonblocking = poll_fn(|_cx| {
+                if count == 0 {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+            .fuse();
+            let option_recv = select_biased! {
+                a = websocket.recv().fuse() => a,
+                () = nonblocking => {
+                    assert!(count > 0);
+                    break Ok(StreamRetry::More);
+                }
+            };
+            match match option_recv {
+                Some(Ok(message)) => match handle(message).await {
+                    Ok(more) => {
+                        count += 1;
+                        first_received.get_or_insert_with(std::time::Instant::now);
+                        more
+                    }
+                    Err(err) => break Err(Handle(err)),
+                },
+                Some(Err(err)) => {
+                    break Err(Recv(err));
+                }
+                None => break Ok(StreamRetry::Stop),
+            } {
+                StreamRetry::More => {}
+                StreamRetry::Stop => break Ok(StreamRetry::Stop),
+            }
+        };
+        (count, result)
+    }
+
+    async fn acknowledge_inserted(
+        websocket: &mut WebSocket,
+        acknowledgement: String,
+    ) -> Result<(), axum::Error> {
+        websocket.send(Message::Text(acknowledgement)).await
+    }
+
+    /// Responds with the count of events stored. If some couldn't be, or the body couldn't all be
+    /// read, it's a JSON object with how many were `accepted`, the events that `failed`, the
+    /// `error` that stopped the request, and whether the events were stored together, `atomic`,
+    /// in which case none were if the request failed.
+    async fn post_handler(
+        &self,
+        req: axum::http::Request<axum::body::Body>,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut payloads_inserted = 0;
+        let mut payloads_stored = 0;
+        let mut stream_id = None;
+        let failures = std::sync::Mutex::new(vec![]);
+        let mut batch_outcome = BatchOutcome::Unbatched;
+        let generation = self.storage_generation();
+        let result = self
+            .submit(
+                req,
+                &mut stream_id,
+                &mut payloads_inserted,
+                &mut payloads_stored,
+                &failures,
+                &mut batch_outcome,
+            )
+            .await;
+        let mut headers = HeaderMap::new();
+        if let Some(stream_id) = stream_id {
+            let stream_token = self.stream_tokens.issue(stream_id, generation);
+            headers.insert(STREAM_TOKEN_HEADER, stream_token.parse().unwrap());
+        }
+        let atomic = batch_outcome != BatchOutcome::Unbatched;
+        headers.insert(ATOMIC_HEADER, atomic.to_string().parse().unwrap());
+        let failures = failures.into_inner().unwrap();
+        // Sampled out and dropped events weren't stored, so they aren't accepted.
+        let payloads_accepted = match batch_outcome {
+            BatchOutcome::RolledBack => 0,
+            _ => payloads_stored,
+        };
+        let status_code = match &result {
+            Ok(()) if failures.is_empty() => {
+                info!(payloads_inserted, "submit handled ok");
+                return (StatusCode::OK, headers, format!("{}", payloads_inserted));
+            }
+            // Earlier events were stored, so report what made it in and let the client retry
+            // only the failures.
+            Ok(()) => {
+                warn!(
+                    payloads_accepted,
+                    payloads_failed = failures.len(),
+                    "submit handled with failed events"
+                );
+                if failures
+                    .iter()
+                    .any(|failure| failure.limit_exceeded().is_some())
+                {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else {
+                    StatusCode::MULTI_STATUS
+                }
+            }
+            Err(err) => {
+                error!(
+                    err = format!("{:#}", err.err),
+                    code = err.code,
+                    offset = err.offset,
+                    payloads_accepted,
+                    "submit failed"
+                );
+                err.status_code
+            }
+        };
+        let body = serde_json::json!({
+            "accepted": payloads_accepted,
+            "failed": failures
+                .iter()
+                .map(|failure| failure.to_json())
+                .collect::<Vec<_>>(),
+            "error": result.err().as_ref().map(SubmitError::to_json),
+            "atomic": atomic,
+            "request_id": RequestId::current().as_ref().map(RequestId::as_str),
+        });
+        (status_code, headers, body.to_string())
+    }
+
+    /// Resumes the stream named by a stream token in the headers, or creates a new one. Returns
+    /// the stream, the last event index used in it, and the turn to write to it, which waits for
+    /// earlier requests resuming the stream to be done.
+    async fn open_stream(
+        &self,
+        headers: &HeaderMap,
+        sampler: Option<&Sampler>,
+    ) -> Result<(StreamId, StreamEventIndex, StreamTurn<'_>), (anyhow::Error, StatusCode)> {
+        let Some(stream_token) = headers.get(STREAM_TOKEN_HEADER) else {
+            return match self.new_stream(headers, sampler).await {
+                Ok(stream_id) => Ok((stream_id, 0, self.stream_queues.wait_turn(stream_id).await)),
+                Err(err) => Err((
+                    err.context("creating new stream"),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )),
+            };
+        };
+        let stream_id = stream_token
+            .to_str()
+            .map_err(anyhow::Error::from)
+            .and_then(|stream_token| {
+                let generation = self.storage_generation();
+                self.stream_tokens.verify(stream_token, generation)
+            })
+            .map_err(|err| (err.context("checking stream token"), StatusCode::FORBIDDEN))?;
+        let turn = self.stream_queues.wait_turn(stream_id).await;
+        let last_stream_event_index = self
+            .db_conn
+            .lock()
+            .await
+            .resume_stream(stream_id)
+            .await
+            .map_err(|err| {
+                let status_code = match err.downcast_ref::<StreamNotFound>() {
+                    Some(_) => StatusCode::NOT_FOUND,
+                    None => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (err.context("resuming stream"), status_code)
+            })?;
+        info!(%stream_id, last_stream_event_index, "resumed stream");
+        Ok((stream_id, last_stream_event_index, turn))
+    }
+
+    /// Reruns stored events through the current pipeline so processing changes apply
+    /// retroactively. Storage is locked for the duration.
+    async fn reprocess_handler(&self, params: EventSelectionParams) -> (StatusCode, String) {
+        let selection = match params.selection() {
+            Ok(selection) => selection,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)),
+        };
+        info!(?selection, "reprocessing events");
+        let rewrite = |payload: &str| self.pipeline.reprocess(payload);
+        let result = self
+            .db_conn
+            .lock()
+            .await
+            .rewrite_events(&selection, &rewrite)
+            .await;
+        match result {
+            Ok(updated) => {
+                info!(updated, "reprocessed events");
+                (
+                    StatusCode::OK,
+                    serde_json::json!({ "updated": updated }).to_string(),
+                )
+            }
+            Err(err) => {
+                error!(?err, "reprocessing events");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    async fn view_handler(&self, params: ViewParams) -> Response {
+        let query = match params.query() {
+            Ok(query) => query,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        match self.db_conn.lock().await.query_events(&query).await {
+            Ok(events) => {
+                // The end cursor is for reading events inserted later, and a full page may have
+                // more after it already.
+                let end_cursor = events
+                    .last()
+                    .and_then(|last| EventCursor::after_json(last).ok())
+                    .map(|cursor| encode_cursor(&cursor));
+                let next_cursor = end_cursor.as_ref().filter(|_| events.len() == query.limit);
+                axum::Json(serde_json::json!({
+                    "events": events,
+                    "next_cursor": next_cursor,
+                    "end_cursor": end_cursor,
+                }))
+                .into_response()
+            }
+            Err(err) => {
+                error!(?err, ?query, "querying events");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response()
+            }
+        }
+    }
+
+    /// Lists the most recently started streams, for the UI.
+    async fn streams_handler(&self, params: StreamsParams) -> Response {
+        let limit = params.limit.unwrap_or(DEFAULT_STREAMS_LIMIT);
+        let labels = match params.labels.as_deref().map(parse_labels).transpose() {
+            Ok(labels) => labels.unwrap_or_default(),
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        let result = self
+            .db_conn
+            .lock()
+            .await
+            .recent_streams(limit, &labels)
+            .await;
+        match result {
+            Ok(streams) => axum::Json(serde_json::json!({ "streams": streams })).into_response(),
+            Err(err) => {
+                error!(?err, "listing streams");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response()
+            }
+        }
+    }
+
+    /// Exports the events in a view, with their streams, as a file in the requested format.
+    async fn export_handler(&self, format: ExportFormat, params: ViewParams) -> Response {
+        let query = match params.query_or_limit(export::DEFAULT_EXPORT_LIMIT) {
+            Ok(query) => query,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        let formatter = format.formatter();
+        let mut body = Vec::new();
+        let mut conn = self.db_conn.lock().await;
+        if let Err(err) = export::export(&mut **conn, &query, &*formatter, &mut body).await {
+            error!(?err, ?query, ?format, "exporting events");
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response();
+        }
+        drop(conn);
+        let disposition = format!(
+            "attachment; filename=\"events.{}\"",
+            formatter.file_extension()
+        );
+        (
+            [
+                (
+                    axum::http::header::CONTENT_TYPE,
+                    formatter.content_type().to_owned(),
+                ),
+                (axum::http::header::CONTENT_DISPOSITION, disposition),
+            ],
+            body,
+        )
+            .into_response()
+    }
+
+    /// The body is the query string of the view to link to.
+    async fn create_link_handler(&self, query: String) -> (StatusCode, String) {
+        let query = query.trim().trim_start_matches('?');
+        if let Err(err) = ViewParams::from_query(query).and_then(|params| params.query()) {
+            return (StatusCode::BAD_REQUEST, format!("{:#}", err));
+        }
+        let link_id = views::link_id(query);
+        match self.db_conn.lock().await.save_link(&link_id, query).await {
+            Ok(()) => (
+                StatusCode::OK,
+                serde_json::json!({ "link": format!("/l/{}", link_id) }).to_string(),
+            ),
+            Err(err) => {
+                error!(?err, "saving link");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    /// Redirects to the linked view in the UI, or describes it.
+    async fn resolve_link_handler(&self, link_id: &str, redirect: bool) -> Response {
+        let query = match self.db_conn.lock().await.load_link(link_id).await {
+            Ok(Some(query)) => query,
+            Ok(None) => return (StatusCode::NOT_FOUND, "no such link").into_response(),
+            Err(err) => {
+                error!(?err, link_id, "loading link");
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response();
+            }
+        };
+        let ui = format!("{}?{}", UI_PATH, query);
+        if redirect {
+            return axum::response::Redirect::to(&ui).into_response();
+        }
+        axum::Json(serde_json::json!({ "query": query, "ui": ui })).into_response()
+    }
+
+    async fn merge_streams_handler(&self, params: MergeStreamsParams) -> (StatusCode, String) {
+        let (from, into) = (StreamId(params.from), StreamId(params.into));
+        let result = self.db_conn.lock().await.merge_streams(from, into).await;
+        match result {
+            Ok(events) => {
+                info!(%from, %into, events, "merged streams");
+                (
+                    StatusCode::OK,
+                    serde_json::json!({ "events": events }).to_string(),
+                )
+            }
+            Err(err) => {
+                error!(?err, %from, %into, "merging streams");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    async fn split_stream_handler(&self, params: SplitStreamParams) -> (StatusCode, String) {
+        let stream_id = StreamId(params.stream_id);
+        let at = match chrono::DateTime::parse_from_rfc3339(&params.at) {
+            Ok(at) => at.to_utc(),
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("parsing time: {}", err)),
+        };
+        let result = self.db_conn.lock().await.split_stream(stream_id, at).await;
+        match result {
+            Ok((new_stream_id, events)) => {
+                info!(%stream_id, %new_stream_id, events, "split stream");
+                (
+                    StatusCode::OK,
+                    serde_json::json!({ "stream_id": new_stream_id.0, "events": events })
+                        .to_string(),
+                )
+            }
+            Err(err) => {
+                error!(?err, %stream_id, "splitting stream");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    async fn prune_periodically(&self, retention: RetentionArgs) {
+        if retention.policy().is_none() {
+            return;
+        }
+        let mut interval = tokio::time::interval(retention.prune_interval);
+        loop {
+            interval.tick().await;
+            let _in_flight = self.pause.enter().await;
+            // The policy is recomputed each time as age limits are relative to now.
+            let policy = retention.policy().unwrap();
+            let result = self.db_conn.lock().await.prune(&policy).await;
+            self.retention_stats.record(&result);
+            match result {
+                Ok(pruned) => info!(?policy, ?pruned, "pruned storage"),
+                Err(err) => error!(?err, "pruning storage"),
+            }
+        }
+    }
+
+    /// Which storage in the fallback chain is in use. Stream IDs from one aren't good in another.
+    fn storage_generation(&self) -> u64 {
+        self.fallback
+            .as_ref()
+            .map_or(0, |fallback| fallback.active() as u64)
+    }
+
+    /// Falls back to the next storage once the one in use keeps failing its checks.
+    async fn fall_back_periodically(&self) {
+        if let Some(fallback) = &self.fallback {
+            fallback
+                .check_periodically(&self.db_conn, &self.pause, || self.stream_queues.clear())
+                .await;
+        }
+    }
+
+    /// Keeps partitioned storage the configured number of partitions ahead as time passes.
+    async fn create_partitions_periodically(&self) {
+        let mut interval = tokio::time::interval(PARTITION_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let _in_flight = self.pause.enter().await;
+            match self.db_conn.lock().await.create_partitions().await {
+                Ok(0) => {}
+                Ok(created) => info!(created, "created partitions"),
+                Err(err) => error!(?err, "creating partitions"),
+            }
+        }
+    }
+
+    async fn mark_stale_periodically(&self, stale: StaleArgs) {
+        let Some(stale_after) = stale.stale_after else {
+            return;
+        };
+        let mut interval = tokio::time::interval(stale.stale_check_interval);
+        loop {
+            interval.tick().await;
+            let _in_flight = self.pause.enter().await;
+            let quiet_since = chrono::Utc::now() - stale_after;
+            let result = self
+                .db_conn
+                .lock()
+                .await
+                .mark_stale_streams(quiet_since)
+                .await;
+            match result {
+                Ok(StaleStreams {
+                    marked: 0,
+                    revived: 0,
+                }) => {}
+                Ok(streams) => info!(?streams, "updated stale streams"),
+                Err(err) => error!(?err, "marking stale streams"),
+            }
+        }
+    }
+
+    async fn roll_up_periodically(&self, rollups: RollupArgs) {
+        if rollups.rollup.is_empty() {
+            return;
+        }
+        let mut interval = tokio::time::interval(rollups.rollup_interval);
+        loop {
+            interval.tick().await;
+            let _in_flight = self.pause.enter().await;
+            self.roll_up(&rollups.rollup).await;
+        }
+    }
+
+    /// Brings each rollup up to date, logging failures.
+    async fn roll_up(&self, rollups: &[Rollup]) {
+        for rollup in rollups {
+            let result = self.db_conn.lock().await.roll_up(rollup).await;
+            self.rollup_stats.record(&result);
+            match result {
+                Ok(rows) => debug!(%rollup, rows, "updated rollup"),
+                Err(err) => error!(?err, %rollup, "updating rollup"),
+            }
+        }
+    }
+
+    async fn rollup_handler(&self, name: &str, params: RollupParams) -> Response {
+        let selection = EventSelectionParams {
+            stream_id: None,
+            since: params.since,
+            until: params.until,
+        }
+        .selection();
+        let selection = match selection {
+            Ok(selection) => selection,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        match self
+            .db_conn
+            .lock()
+            .await
+            .read_rollup(name, &selection)
+            .await
+        {
+            Ok(rows) => {
+                let rows: Vec<_> = rows.iter().map(RollupRow::to_json).collect();
+                axum::Json(serde_json::json!({ "rows": rows })).into_response()
+            }
+            Err(err) => {
+                error!(?err, name, "reading rollup");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response()
+            }
+        }
+    }
+
+    /// Offers series for Grafana's JSON datasource to chart, from events of the last hour.
+    async fn grafana_search_handler(&self, search: grafana::SearchRequest) -> Response {
+        let query = EventQuery {
+            selection: EventSelection {
+                since: Some(chrono::Utc::now() - chrono::Duration::hours(1)),
+                ..Default::default()
+            },
+            limit: GRAFANA_SEARCH_SAMPLE,
+            ..Default::default()
+        };
+        match self.db_conn.lock().await.query_events(&query).await {
+            Ok(events) => {
+                axum::Json(grafana::search_response(&search.target, &events)).into_response()
+            }
+            Err(err) => {
+                error!(?err, "sampling events for grafana");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response()
+            }
+        }
+    }
+
+    /// Reads each of a Grafana panel's targets as time series over the dashboard's range.
+    async fn grafana_query_handler(&self, request: grafana::QueryRequest) -> Response {
+        let selection = match request.range.selection() {
+            Ok(selection) => selection,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        let bucket = request.bucket(&selection);
+        let mut responses = vec![];
+        let mut conn = self.db_conn.lock().await;
+        for target in &request.targets {
+            let Some(text) = target.target.as_deref().filter(|_| !target.hide) else {
+                continue;
+            };
+            let parsed = match text.parse() {
+                Ok(parsed) => parsed,
+                Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+            };
+            let result = match parsed {
+                grafana::Target::Series(aggregate) => conn
+                    .read_series(&SeriesQuery {
+                        selection: selection.clone(),
+                        aggregate,
+                        bucket,
+                    })
+                    .await
+                    .map(|points| vec![grafana::series_response(text, &points)]),
+                grafana::Target::Rollup(name) => conn
+                    .read_rollup(&name, &selection)
+                    .await
+                    .map(|rows| grafana::rollup_responses(&name, &rows)),
+            };
+            match result {
+                Ok(series) => responses.extend(series),
+                Err(err) => {
+                    error!(?err, target = text, "querying for grafana");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+                        .into_response();
+                }
+            }
+        }
+        axum::Json(responses).into_response()
+    }
+
+    /// Marks the events of a UI view, given as the annotation's query, on Grafana's charts.
+    async fn grafana_annotations_handler(&self, request: grafana::AnnotationsRequest) -> Response {
+        let view = request.annotation["query"].as_str().unwrap_or_default();
+        let query = ViewParams::from_query(view.trim_start_matches('?'))
+            .and_then(|params| params.query())
+            .and_then(|query| {
+                let range = request.range.selection()?;
+                Ok(EventQuery {
+                    selection: EventSelection {
+                        since: range.since,
+                        until: range.until,
+                        ..query.selection
+                    },
+                    ..query
+                })
+            });
+        let query = match query {
+            Ok(query) => query,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        let annotations = match self.db_conn.lock().await.query_events(&query).await {
+            Ok(events) => events
+                .iter()
+                .map(|event| grafana::annotation_response(&request.annotation, event))
+                .collect::<Result<Vec<_>>>(),
+            Err(err) => Err(err),
+        };
+        match annotations {
+            Ok(annotations) => axum::Json(annotations).into_response(),
+            Err(err) => {
+                error!(?err, ?query, "querying annotations for grafana");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response()
+            }
+        }
+    }
+
+    async fn compression_report_handler(&self, params: CompressionReportParams) -> Response {
+        let limit = params.limit.unwrap_or(10);
+        match self.db_conn.lock().await.compression_report(limit) {
+            Some(report) => axum::Json(report).into_response(),
+            None => (
+                StatusCode::NOT_FOUND,
+                "storage does not track compression statistics",
+            )
+                .into_response(),
+        }
+    }
+
+    /// Time storage calls spent blocking their thread, for each connection.
+    async fn blocking_report_handler(&self) -> Response {
+        let mut reports = vec![];
+        for conn in self.db_conn.all() {
+            match conn.lock().await.blocking_report() {
+                Some(report) => reports.push(report),
+                None => {
+                    return (StatusCode::NOT_FOUND, "storage calls don't block").into_response()
+                }
+            }
+        }
+        axum::Json(reports).into_response()
+    }
+
+    /// Flushes, commits or rotates the storage now, rather than when it would on its own. Sinks
+    /// are flushed and committed too, but only the main storage is rotated.
+    async fn storage_action_handler(&self, action: StorageAction) -> (StatusCode, String) {
+        match self.storage_action(action).await {
+            Ok(()) => {
+                info!(?action, "ran storage action");
+                (StatusCode::OK, String::new())
+            }
+            Err(err) => {
+                error!(?err, ?action, "running storage action");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    async fn storage_action(&self, action: StorageAction) -> Result<()> {
+        for conn in self.db_conn.all() {
+            let mut conn = conn.lock().await;
+            match action {
+                StorageAction::Flush => conn.flush().await?,
+                StorageAction::Commit => conn.commit().await?,
+                StorageAction::Rotate => conn.rotate().await?,
+            }
+        }
+        for (name, sink) in &self.sinks {
+            let result = match action {
+                StorageAction::Flush => sink.flush().await,
+                StorageAction::Commit => sink.commit().await,
+                StorageAction::Rotate => continue,
+            };
+            result.with_context(|| format!("sink {}", name))?;
+        }
+        Ok(())
+    }
+
+    /// Stops storing events for maintenance of the storage underneath the server: HTTP requests
+    /// that would write get 503 with a Retry-After, other listeners and periodic tasks wait, and
+    /// once what's in flight has finished the storage is committed. Stays paused until resumed,
+    /// even if draining timed out or committing failed.
+    async fn pause_handler(
+        &self,
+        params: PauseParams,
+    ) -> (StatusCode, axum::Json<serde_json::Value>) {
+        let retry_after = params
+            .retry_after
+            .map_or(pause::DEFAULT_RETRY_AFTER, std::time::Duration::from_secs);
+        let timeout = params
+            .timeout
+            .map_or(PAUSE_TIMEOUT, std::time::Duration::from_secs);
+        if !self.pause.pause(retry_after, timeout).await {
+            warn!(?timeout, "paused without draining");
+            return (
+                StatusCode::GATEWAY_TIMEOUT,
+                axum::Json(serde_json::json!({"paused": true, "drained": false})),
+            );
+        }
+        if let Err(err) = self.storage_action(StorageAction::Commit).await {
+            error!(?err, "committing storage after pausing");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                axum::Json(
+                    serde_json::json!({"paused": true, "drained": true, "error": format!("{:#}", err)}),
+                ),
+            );
+        }
+        info!("paused");
+        (
+            StatusCode::OK,
+            axum::Json(serde_json::json!({"paused": true, "drained": true})),
+        )
+    }
+
+    /// Replaces the sampling policy of the server, or of an ingest endpoint that samples its own
+    /// way, with one given as JSON in the form /stats/sampling's streams record it. Sampling can
+    /// only be changed if it was enabled on startup.
+    fn sampling_handler(&self, params: SamplingParams, body: &[u8]) -> (StatusCode, String) {
+        let sampler = match &params.ingest {
+            None => self.sampler.as_ref(),
+            Some(name) => match self.ingest.get(name) {
+                Some(ingest) => ingest.sampler.as_ref(),
+                None => {
+                    return (
+                        StatusCode::NOT_FOUND,
+                        format!("no ingest endpoint {:?}", name),
+                    )
+                }
+            },
+        };
+        let Some(sampler) = sampler else {
+            return (
+                StatusCode::CONFLICT,
+                "sampling wasn't enabled on startup".to_owned(),
+            );
+        };
+        let args = serde_json::from_slice(body)
+            .map_err(anyhow::Error::from)
+            .and_then(SamplingArgs::from_json);
+        match args {
+            Ok(args) => {
+                sampler.set_policy(args);
+                let policy = sampler.policy();
+                info!(%policy, ingest = ?params.ingest, "changed sampling");
+                (StatusCode::OK, policy.to_string())
+            }
+            Err(err) => (StatusCode::BAD_REQUEST, format!("{:#}", err)),
+        }
+    }
+
+    /// For requests that act on an existing stream, which must be identified by its token.
+    fn token_stream_id(&self, headers: &HeaderMap) -> Result<StreamId, (StatusCode, String)> {
+        let Some(stream_token) = headers.get(STREAM_TOKEN_HEADER) else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("missing {} header", STREAM_TOKEN_HEADER),
+            ));
+        };
+        stream_token
+            .to_str()
+            .map_err(anyhow::Error::from)
+            .and_then(|stream_token| {
+                let generation = self.storage_generation();
+                self.stream_tokens.verify(stream_token, generation)
+            })
+            .map_err(|err| (StatusCode::FORBIDDEN, format!("{:#}", err)))
+    }
+
+    async fn revise_handler(
+        &self,
+        params: ReviseParams,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> (StatusCode, String) {
+        let stream_id = match self.token_stream_id(headers) {
+            Ok(stream_id) => stream_id,
+            Err(err) => return err,
+        };
+        let payload = match self.legacy_encoding.decode(body) {
+            Ok(payload) => payload,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)),
+        };
+        if let Err(err) = self.limits.check(&payload) {
+            return (StatusCode::PAYLOAD_TOO_LARGE, err.to_json().to_string());
+        }
+        let payload = match self.pipeline.process(&payload) {
+            Ok(payload) => payload,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)),
+        };
+        let ReviseParams { index, revision } = params;
+        let result = self
+            .db_conn
+            .lock()
+            .await
+            .revise_event(stream_id, index, revision, &payload)
+            .await;
+        match result {
+            Ok(Revised::Superseded) => {
+                info!(%stream_id, index, revision, "revised event");
+                (StatusCode::OK, String::new())
+            }
+            Ok(Revised::NotFound) => (
+                StatusCode::NOT_FOUND,
+                format!("no event {} in stream {}", index, stream_id),
+            ),
+            Ok(Revised::Stale { latest }) => (
+                StatusCode::CONFLICT,
+                format!("event is already at revision {}", latest),
+            ),
+            Err(err) => {
+                error!(?err, %stream_id, index, "revising event");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    /// Checks the request's stream token is for the stream in its path, which is named by its
+    /// UUID or, for streams from before they had them, its integer ID.
+    async fn check_token_for(
+        &self,
+        path_stream_id: &str,
+        headers: &HeaderMap,
+    ) -> Result<StreamId, (StatusCode, String)> {
+        let token_stream_id = self.token_stream_id(headers)?;
+        let stream_id = match path_stream_id.parse() {
+            Ok(stream_id) => StreamId(stream_id),
+            Err(_) => {
+                let stream_uuid: StreamUuid = path_stream_id
+                    .parse()
+                    .map_err(|err| (StatusCode::BAD_REQUEST, format!("{:#}", err)))?;
+                let found = self.db_conn.lock().await.find_stream(stream_uuid).await;
+                found.map_err(|err| (StatusCode::NOT_FOUND, format!("{:#}", err)))?
+            }
+        };
+        if token_stream_id != stream_id {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("stream token isn't for stream {}", path_stream_id),
+            ));
+        }
+        Ok(stream_id)
+    }
+
+    /// Sets the labels in the body, a JSON object of strings, on the stream the request's token is
+    /// for. Labels given null are removed.
+    async fn patch_stream_labels_handler(
+        &self,
+        path_stream_id: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> (StatusCode, String) {
+        let stream_id = match self.check_token_for(path_stream_id, headers).await {
+            Ok(stream_id) => stream_id,
+            Err(err) => return err,
+        };
+        let labels: BTreeMap<String, Option<String>> = match serde_json::from_slice(body) {
+            Ok(labels) => labels,
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("labels should be an object of strings or nulls: {}", err),
+                )
+            }
+        };
+        let result = self
+            .db_conn
+            .lock()
+            .await
+            .set_stream_labels(stream_id, &labels)
+            .await;
+        match result {
+            Ok(()) => {
+                info!(%stream_id, "labelled stream");
+                (StatusCode::OK, String::new())
+            }
+            Err(err) => {
+                error!(?err, %stream_id, "labelling stream");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    /// Merges the body, a JSON merge patch, into the headers of the stream the request's token is
+    /// for.
+    async fn patch_stream_handler(
+        &self,
+        path_stream_id: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> (StatusCode, String) {
+        let stream_id = match self.check_token_for(path_stream_id, headers).await {
+            Ok(stream_id) => stream_id,
+            Err(err) => return err,
+        };
+        let patch: serde_json::Value = match serde_json::from_slice(body) {
+            Ok(patch) => patch,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("parsing patch: {}", err)),
+        };
+        if !patch.is_object() {
+            return (
+                StatusCode::BAD_REQUEST,
+                "patch must be a JSON object".to_owned(),
+            );
+        }
+        let result = self
+            .db_conn
+            .lock()
+            .await
+            .update_stream_headers(stream_id, &patch)
+            .await;
+        match result {
+            Ok(()) => {
+                info!(%stream_id, "updated stream headers");
+                (StatusCode::OK, String::new())
+            }
+            Err(err) => {
+                error!(?err, %stream_id, "updating stream headers");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    /// Closes the stream named by the stream token header.
+    async fn close_stream_handler(&self, headers: &HeaderMap) -> (StatusCode, String) {
+        let stream_id = match self.token_stream_id(headers) {
+            Ok(stream_id) => stream_id,
+            Err(err) => return err,
+        };
+        match self.close_stream(stream_id).await {
+            Ok(()) => (StatusCode::OK, String::new()),
+            Err(err) => {
+                error!(?err, %stream_id, "closing stream");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        }
+    }
+
+    async fn close_stream(&self, stream_id: StreamId) -> Result<()> {
+        let mut conn = self.db_conn.lock().await;
+        conn.close_stream(stream_id).await?;
+        for (name, sink) in &self.sinks {
+            if let Err(err) = sink.close_stream(&mut **conn, stream_id).await {
+                warn!(?err, %stream_id, sink = name, "closing stream in sink");
+            }
+        }
+        // Streams posted to an ingest endpoint sampling its own way were sampled by it.
+        let sampler = self
+            .ingest
+            .values()
+            .filter_map(|ingest| ingest.sampler.as_ref())
+            .find(|sampler| sampler.tracks(stream_id))
+            .or(self.sampler.as_ref());
+        if let Some(sampler) = sampler {
+            let sampling = sampler.finish(stream_id);
+            if let Err(err) = conn.record_sampling(stream_id, &sampling).await {
+                warn!(?err, %stream_id, "recording sampling");
+            }
+        }
+        info!(%stream_id, "closed stream");
+        Ok(())
+    }
+
+    /// Starts a stream recording the sampling policy its events will be sampled by.
+    async fn new_stream(
+        &self,
+        headers: &HeaderMap,
+        sampler: Option<&Sampler>,
+    ) -> anyhow::Result<StreamId> {
+        self.new_stream_with(headers_to_json_value(headers)?, headers, sampler)
+            .await
+    }
+
+    /// Starts a stream whose headers aren't the request's, which may still say when the client
+    /// thinks it is.
+    async fn new_stream_with(
+        &self,
+        headers_value: serde_json::Value,
+        headers: &HeaderMap,
+        sampler: Option<&Sampler>,
+    ) -> anyhow::Result<StreamId> {
+        let mut conn = self.db_conn.lock().await;
+        let stream_id = conn.new_stream(headers_value).await?;
+        info!(%stream_id, "started new stream");
+        if let Some(client_now) = header_datetime(headers, CLIENT_NOW_HEADER) {
+            let clock_skew = chrono::Utc::now() - client_now;
+            if let Err(err) = conn.record_clock_skew(stream_id, clock_skew).await {
+                warn!(?err, %stream_id, "recording clock skew");
+            }
+        }
+        if let Some(sampler) = sampler {
+            if let Err(err) = conn.record_sampling(stream_id, &sampler.policy()).await {
+                warn!(?err, %stream_id, "recording sampling");
+            }
+        }
+        if let Some(labels) = headers.get(STREAM_LABELS_HEADER) {
+            let labels = labels
+                .to_str()
+                .map_err(anyhow::Error::from)
+                .and_then(parse_labels)
+                .map(|labels| {
+                    labels
+                        .into_iter()
+                        .map(|(key, value)| (key, Some(value)))
+                        .collect()
+                });
+            let result = match labels {
+                Ok(labels) => conn.set_stream_labels(stream_id, &labels).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!(?err, %stream_id, "labelling stream");
+            }
+        }
+        Ok(stream_id)
+    }
+
+    /// Stores an event at an index handed out for it, recording an anomaly if the index ends up
+    /// with nothing stored at it. Events dropped by sampling aren't anomalies, since the stream
+    /// records its sampling policy. `batch` is the storage connection, if the caller has it
+    /// locked for a batch.
+    #[allow(clippy::too_many_arguments)]
+    async fn insert_event(
+        &self,
+        payload: &str,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        event_id: Option<&str>,
+        origin: &Origin,
+        client_datetime: Option<chrono::DateTime<chrono::Utc>>,
+        mut batch: Option<&mut Box<dyn Connection + Send>>,
+    ) -> Result<()> {
+        if let Some(sampler) = self.sampler_for(origin.ingest.as_deref()) {
+            if let Err(dropped) = sampler.sample(stream_id, stream_event_index, payload) {
+                debug!(%stream_id, stream_event_index, ?dropped, "dropped event");
+                return Ok(());
+            }
+        }
+        let result = self
+            .store_event(
+                payload,
+                stream_id,
+                stream_event_index,
+                event_id,
+                origin,
+                client_datetime,
+                batch.as_deref_mut(),
+            )
+            .await;
+        self.record_outcome(stream_id, stream_event_index, event_id, &result, batch)
+            .await;
+        result.map(drop)
+    }
+
+    /// Records an anomaly for an event that wasn't stored, or was a duplicate of one that was.
+    async fn record_outcome(
+        &self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        event_id: Option<&str>,
+        result: &Result<Option<Inserted>>,
+        batch: Option<&mut Box<dyn Connection + Send>>,
+    ) {
+        let (kind, details) = match result {
+            Ok(None | Some(Inserted::Stored)) => return,
+            Ok(Some(Inserted::Duplicate)) => (AnomalyKind::Duplicate, event_id.map(str::to_owned)),
+            Err(err) => (AnomalyKind::Gap, Some(format!("{:#}", err))),
+        };
+        let anomaly = StreamAnomaly {
+            stream_id,
+            stream_event_index,
+            kind,
+            details,
+        };
+        let recorded = match batch {
+            Some(conn) => conn.record_anomaly(&anomaly).await,
+            None => self.db_conn.lock().await.record_anomaly(&anomaly).await,
+        };
+        if let Err(err) = &recorded {
+            warn!(?err, ?anomaly, "recording stream anomaly");
+        }
+        self.anomaly_stats.record(kind, recorded.is_ok());
+    }
+
+    /// Checks an event against the limits and runs it through the pipeline, working out where
+    /// it's stored. None if the transform script dropped it.
+    fn process_event<'a, 'p>(
+        &'a self,
+        payload: &'p str,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        origin: &Origin,
+        client_datetime: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Option<ProcessedEvent<'a, 'p>>> {
+        match &origin.ingest {
+            Some(ingest) => ingest.check(payload)?,
+            None => self.limits.check(payload)?,
+        }
+        // Taken before processing, which may rewrite the payload's timestamps.
+        let client_datetime = payload_timestamp(payload).or(client_datetime);
+        let (payload, route) = self.pipeline.route(payload).context("processing payload")?;
+        let ingest_sink = origin
+            .ingest
+            .as_ref()
+            .and_then(|ingest| ingest.sink.as_ref());
+        let route = match (route, ingest_sink, &self.routes) {
+            // The endpoint's sink takes the place of the routing rules.
+            (Route::Store, Some(sink), _) => Route::Sink(sink.clone()),
+            (Route::Store, None, Some(routes)) => {
+                let value = serde_json::from_str(&payload)?;
+                match routes.route(&origin.headers, &value) {
+                    Some(sink) => Route::Sink(sink.to_owned()),
+                    None => Route::Store,
+                }
+            }
+            (route, _, _) => route,
+        };
+        let sink = match route {
+            Route::Store => None,
+            Route::Drop => {
+                debug!(%stream_id, stream_event_index, "transform script dropped event");
+                return Ok(None);
+            }
+            Route::Sink(name) => Some(
+                self.sinks
+                    .get(&name)
+                    .ok_or_else(|| anyhow!("sink {} isn't open", name))?,
+            ),
+        };
+        Ok(Some(ProcessedEvent {
+            payload,
+            client_datetime,
+            sink,
+        }))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn store_event(
+        &self,
+        payload: &str,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        event_id: Option<&str>,
+        origin: &Origin,
+        client_datetime: Option<chrono::DateTime<chrono::Utc>>,
+        batch: Option<&mut Box<dyn Connection + Send>>,
+    ) -> Result<Option<Inserted>> {
+        debug!(payload, event_id, "inserting payload into store");
+        let Some(event) = self.process_event(
+            payload,
+            stream_id,
+            stream_event_index,
+            origin,
+            client_datetime,
+        )?
+        else {
+            return Ok(None);
+        };
+        // Only scanned, not parsed into a tree, and then handed to storage as is. Done before
+        // locking the connection, which other requests are waiting on.
+        let raw_payload: &RawValue =
+            serde_json::from_str(&event.payload).context("reading processed payload")?;
+        let collector = origin.collector();
+        let mut locked;
+        let conn: &mut (dyn Connection + Send) = match batch {
+            Some(conn) => &mut **conn,
+            None => {
+                locked = self.db_conn.lock().await;
+                &mut **locked
+            }
+        };
+        if let Some(sink) = event.sink {
+            event
+                .store_in_sink(
+                    sink,
+                    conn,
+                    stream_id,
+                    stream_event_index,
+                    event_id,
+                    collector,
+                )
+                .await?;
+            return Ok(Some(Inserted::Stored));
+        }
+        conn.insert_event(
+            stream_id,
+            stream_event_index,
+            raw_payload,
+            event_id,
+            collector.as_ref(),
+            event.client_datetime,
+        )
+        .await
+        .map(Some)
+        .context(StorageFailed)
+    }
+
+    /// Stores a run of a request's payloads, handing those for the server's storage to it
+    /// together, and returns how many were stored or sent to a sink. Failures of single events are
+    /// collected into `failures` rather than aborting the request. Only the storage failing while
+    /// `batch` is open is returned, as the batch can't be committed then.
+    #[allow(clippy::too_many_arguments)]
+    async fn insert_batch_payloads(
+        &self,
+        payloads: &[Bytes],
+        stream_id: StreamId,
+        first_stream_event_index: StreamEventIndex,
+        event_id_prefix: Option<&str>,
+        origin: &Origin,
+        client_datetime: Option<chrono::DateTime<chrono::Utc>>,
+        mut batch: Option<&mut Box<dyn Connection + Send>>,
+        failures: &std::sync::Mutex<Vec<EventFailure>>,
+    ) -> Result<u64> {
+        let fail = |stream_event_index, err: anyhow::Error| {
+            error!(?err, stream_event_index, "inserting event from batch");
+            failures.lock().unwrap().push(EventFailure {
+                stream_event_index,
+                err,
+            });
+        };
+        let mut stored = 0;
+        let mut decoded = Vec::with_capacity(payloads.len());
+        for (payload, stream_event_index) in payloads.iter().zip(first_stream_event_index..) {
+            // sqlite needs to be given text.
+            match self.legacy_encoding.decode(payload) {
+                Ok(payload) => decoded.push((stream_event_index, payload)),
+                Err(err) => fail(stream_event_index, err.context("decoding payload text")),
+            }
+        }
+        let sampler = self.sampler_for(origin.ingest.as_deref());
+        let collector = origin.collector();
+        let mut processed = Vec::with_capacity(decoded.len());
+        for (stream_event_index, payload) in &decoded {
+            let stream_event_index = *stream_event_index;
+            if let Some(sampler) = sampler {
+                if let Err(dropped) = sampler.sample(stream_id, stream_event_index, payload) {
+                    debug!(%stream_id, stream_event_index, ?dropped, "dropped event");
+                    continue;
+                }
+            }
+            let event_id = payload_event_id(payload).or_else(|| {
+                event_id_prefix.map(|prefix| format!("{}:{}", prefix, stream_event_index))
+            });
+            debug!(
+                payload = &**payload,
+                event_id = event_id.as_deref(),
+                "inserting payload into store"
+            );
+            let event = self.process_event(
+                payload,
+                stream_id,
+                stream_event_index,
+                origin,
+                client_datetime,
+            );
+            let (event, sink) = match event {
+                Ok(Some(event)) => match event.sink {
+                    None => {
+                        processed.push((stream_event_index, event_id, event));
+                        continue;
+                    }
+                    Some(sink) => (event, sink),
+                },
+                Ok(None) => continue,
+                Err(err) => {
+                    self.record_outcome(
+                        stream_id,
+                        stream_event_index,
+                        event_id.as_deref(),
+                        &Err(anyhow!("{:#}", err)),
+                        batch.as_deref_mut(),
+                    )
+                    .await;
+                    fail(stream_event_index, err);
+                    continue;
+                }
+            };
+            let event_id = event_id.as_deref();
+            let collector = collector.clone();
+            let result = match batch.as_deref_mut() {
+                Some(conn) => {
+                    let stored = event.store_in_sink(
+                        sink,
+                        &mut **conn,
+                        stream_id,
+                        stream_event_index,
+                        event_id,
+                        collector,
+                    );
+                    stored.await
+                }
+                None => {
+                    let mut conn = self.db_conn.lock().await;
+                    let stored = event.store_in_sink(
+                        sink,
+                        &mut **conn,
+                        stream_id,
+                        stream_event_index,
+                        event_id,
+                        collector,
+                    );
+                    stored.await
+                }
+            };
+            let result = result.map(|()| Some(Inserted::Stored));
+            self.record_outcome(
+                stream_id,
+                stream_event_index,
+                event_id,
+                &result,
+                batch.as_deref_mut(),
+            )
+            .await;
+            match result {
+                Ok(_) => stored += 1,
+                Err(err) => fail(stream_event_index, err),
+            }
+        }
+        let mut events = Vec::with_capacity(processed.len());
+        for (stream_event_index, event_id, event) in &processed {
+            // Only scanned, not parsed into a tree, and then handed to storage as is.
+            match serde_json::from_str::<&RawValue>(&event.payload) {
+                Ok(payload) => events.push(NewEvent {
+                    stream_event_index: *stream_event_index,
+                    payload,
+                    event_id: event_id.as_deref(),
+                    collector: collector.as_ref(),
+                    client_datetime: event.client_datetime,
+                }),
+                Err(err) => {
+                    let err = anyhow!(err).context("reading processed payload");
+                    self.record_outcome(
+                        stream_id,
+                        *stream_event_index,
+                        event_id.as_deref(),
+                        &Err(anyhow!("{:#}", err)),
+                        batch.as_deref_mut(),
+                    )
+                    .await;
+                    fail(*stream_event_index, err);
+                }
+            }
+        }
+        let mut events = &events[..];
+        while !events.is_empty() {
+            let inserted = match batch.as_deref_mut() {
+                Some(conn) => conn.insert_events(stream_id, events).await,
+                None => {
+                    self.db_conn
+                        .lock()
+                        .await
+                        .insert_events(stream_id, events)
+                        .await
+                }
+            };
+            let (inserted, failed) = match inserted {
+                Ok(inserted) => (inserted, None),
+                Err(err) if batch.is_some() => return Err(err.context(StorageFailed)),
+                // The rest of the run is tried again after the event that failed.
+                Err(err) => match err.downcast::<InsertedBefore>() {
+                    Ok(InsertedBefore { inserted, err }) => (inserted, Some(err)),
+                    // Some of the events may have been stored, but which isn't known, so they're
+                    // all reported as failed.
+                    Err(err) => {
+                        let err = format!("{:#}", err.context(StorageFailed));
+                        for event in events {
+                            self.record_outcome(
+                                stream_id,
+                                event.stream_event_index,
+                                event.event_id,
+                                &Err(anyhow!("{}", err)),
+                                None,
+                            )
+                            .await;
+                            fail(event.stream_event_index, anyhow!("{}", err));
+                        }
+                        return Ok(stored);
+                    }
+                },
+            };
+            let done = inserted.len();
+            for (event, inserted) in events.iter().zip(inserted) {
+                if inserted == Inserted::Stored {
+                    stored += 1;
+                }
+                self.record_outcome(
+                    stream_id,
+                    event.stream_event_index,
+                    event.event_id,
+                    &Ok(Some(inserted)),
+                    batch.as_deref_mut(),
+                )
+                .await;
+            }
+            events = &events[done..];
+            if let Some(err) = failed {
+                let event = &events[0];
+                let err = err.context(StorageFailed);
+                self.record_outcome(
+                    stream_id,
+                    event.stream_event_index,
+                    event.event_id,
+                    &Err(anyhow!("{:#}", err)),
+                    None,
+                )
+                .await;
+                fail(event.stream_event_index, err);
+                events = &events[1..];
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Decodes a payload from a batch and stores it, with its own event ID or one made from the
+    /// prefix and its index.
+    #[allow(clippy::too_many_arguments)]
+    async fn insert_batch_payload(
+        &self,
+        payload: &[u8],
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        event_id_prefix: Option<&str>,
+        origin: &Origin,
+        client_datetime: Option<chrono::DateTime<chrono::Utc>>,
+        batch: Option<&mut Box<dyn Connection + Send>>,
+    ) -> Result<()> {
+        // sqlite needs to be given text.
+        let payload = self
+            .legacy_encoding
+            .decode(payload)
+            .context("decoding payload text")?;
+        let event_id = payload_event_id(&payload)
+            .or_else(|| event_id_prefix.map(|prefix| format!("{}:{}", prefix, stream_event_index)));
+        self.insert_event(
+            &payload,
+            stream_id,
+            stream_event_index,
+            event_id.as_deref(),
+            origin,
+            client_datetime,
+            batch,
+        )
+        .await
+    }
+
+    /// Reads an OTLP/JSON request body, possibly gzipped, along with where it's from. Protobuf
+    /// requests are refused, as are bodies over [otlp::MAX_OTLP_REQUEST_BYTES].
+    async fn read_otlp_request(
+        &self,
+        req: axum::http::Request<axum::body::Body>,
+    ) -> Result<(Origin, serde_json::Value), Response> {
+        let is_json = req
+            .headers()
+            .get(axum::http::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        if !is_json {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "only OTLP/JSON is supported; configure the exporter for http/json",
+            )
+                .into_response());
+        }
+        let gzipped = match req.headers().get(axum::http::header::CONTENT_ENCODING) {
+            None => false,
+            Some(encoding) if encoding == GZIP_ENCODING => true,
+            Some(_) => {
+                return Err((
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported content encoding",
+                )
+                    .into_response())
+            }
+        };
+        let remote_addr = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        let origin = self.origin(remote_addr, req.headers());
+        let body = req.into_body().into_data_stream();
+        let body = match gzipped {
+            true => axum::body::Body::from_stream(gunzip_stream(body)),
+            false => axum::body::Body::from_stream(body),
+        };
+        let body = axum::body::to_bytes(body, otlp::MAX_OTLP_REQUEST_BYTES)
+            .await
+            .map_err(|err| (StatusCode::PAYLOAD_TOO_LARGE, format!("{:#}", err)).into_response())?;
+        let request = serde_json::from_slice(&body)
+            .map_err(|err| (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response())?;
+        Ok((origin, request))
+    }
+
+    /// Stores spans from an OTLP/JSON ExportTraceServiceRequest. Each resource gets a stream,
+    /// closed once its spans are stored, and each span is an event identified by its trace and
+    /// span IDs. Spans that can't be stored are reported to the exporter as rejected.
+    async fn otlp_traces_handler(&self, req: axum::http::Request<axum::body::Body>) -> Response {
+        let (origin, request) = match self.read_otlp_request(req).await {
+            Ok(read) => read,
+            Err(response) => return response,
+        };
+        let resources = match otlp::resource_spans(&request) {
+            Ok(resources) => resources,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        let mut rejected = 0;
+        let mut last_err = None;
+        for resource in resources {
+            let stream_id = match self
+                .new_stream_with(resource.headers, &origin.headers, self.sampler.as_ref())
+                .await
+            {
+                Ok(stream_id) => stream_id,
+                Err(err) => {
+                    error!(?err, "creating stream for spans");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+                        .into_response();
+                }
+            };
+            for (span, stream_event_index) in resource.spans.iter().zip(1..) {
+                let event_id = format!(
+                    "{}:{}",
+                    span["trace_id"].as_str().unwrap_or_default(),
+                    span["span_id"].as_str().unwrap_or_default()
+                );
+                if let Err(err) = self
+                    .insert_event(
+                        &span.to_string(),
+                        stream_id,
+                        stream_event_index,
+                        Some(&event_id),
+                        &origin,
+                        otlp::span_start(span),
+                        None,
+                    )
+                    .await
+                {
+                    error!(?err, %stream_id, stream_event_index, "inserting span");
+                    rejected += 1;
+                    last_err = Some(format!("{:#}", err));
+                }
+            }
+            if let Err(err) = self.close_stream(stream_id).await {
+                warn!(?err, %stream_id, "closing span stream");
+            }
+        }
+        axum::Json(otlp::export_response("rejectedSpans", rejected, last_err)).into_response()
+    }
+
+    /// Stores the data points of an OTLP/JSON ExportMetricsServiceRequest in storage's metric
+    /// points table. A batch is stored whole or rejected whole.
+    async fn otlp_metrics_handler(&self, req: axum::http::Request<axum::body::Body>) -> Response {
+        let request = match self.read_otlp_request(req).await {
+            Ok((_, request)) => request,
+            Err(response) => return response,
+        };
+        let points = match otlp::metric_points(&request) {
+            Ok(points) => points,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        let result = self
+            .db_conn
+            .lock()
+            .await
+            .insert_metric_points(&points)
+            .await;
+        let response = match result {
+            Ok(_) => otlp::export_response("rejectedDataPoints", 0, None),
+            Err(err) => {
+                error!(?err, "inserting metric points");
+                let rejected = points.len() as u64;
+                otlp::export_response("rejectedDataPoints", rejected, Some(format!("{:#}", err)))
+            }
+        };
+        axum::Json(response).into_response()
+    }
+
+    /// Stores each syslog datagram received on the socket as an event in its sender's stream.
+    async fn serve_syslog_udp(&self, socket: tokio::net::UdpSocket, streams: &OpenStreams<IpAddr>) {
+        let mut buf = vec![0; syslog::MAX_SYSLOG_MESSAGE_BYTES];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((len, peer)) => {
+                    let message = String::from_utf8_lossy(&buf[..len]);
+                    self.syslog_event(streams, peer, &message).await;
+                }
+                Err(err) => warn!(?err, "receiving syslog datagram"),
+            }
+        }
+    }
+
+    /// Stores the syslog messages sent over each connection accepted, as events in the stream of
+    /// the host connecting.
+    async fn serve_syslog_tcp(
+        self: Arc<Self>,
+        listener: tokio::net::TcpListener,
+        streams: Arc<OpenStreams<IpAddr>>,
+    ) {
+        loop {
+            let (conn, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(?err, "accepting syslog connection");
+                    continue;
+                }
+            };
+            let server = Arc::clone(&self);
+            let streams = Arc::clone(&streams);
+            tokio::spawn(async move {
+                let mut reader = tokio::io::BufReader::new(conn);
+                loop {
+                    match syslog::read_frame(&mut reader).await {
+                        Ok(Some(message)) => server.syslog_event(&streams, peer, &message).await,
+                        Ok(None) => break,
+                        Err(err) => {
+                            warn!(?err, %peer, "reading syslog, closing connection");
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    async fn syslog_event(&self, streams: &OpenStreams<IpAddr>, peer: SocketAddr, message: &str) {
+        // Some senders separate frames with blank lines.
+        if message.trim().is_empty() {
+            return;
+        }
+        let payload = syslog::parse(message, chrono::Utc::now());
+        let _in_flight = self.pause.enter().await;
+        // Hosts sending to a dual-stack socket over IPv4 appear as IPv6-mapped addresses.
+        let host = peer.ip().to_canonical();
+        let headers = HeaderMap::new();
+        let next = streams
+            .next_indexes(host, self.storage_generation(), 1, || {
+                self.new_stream_with(
+                    syslog::stream_headers(host),
+                    &headers,
+                    self.sampler.as_ref(),
+                )
+            })
+            .await;
+        let (stream_id, stream_event_index) = match next {
+            Ok(next) => next,
+            Err(err) => {
+                error!(?err, %host, "creating syslog stream");
+                return;
+            }
+        };
+        let origin = self.origin(Some(peer), &headers);
+        if let Err(err) = self
+            .insert_event(
+                &payload.to_string(),
+                stream_id,
+                stream_event_index,
+                None,
+                &origin,
+                None,
+                None,
+            )
+            .await
+        {
+            warn!(?err, %stream_id, stream_event_index, "inserting syslog message");
+        }
+    }
+
+    async fn serve_fluent_forward(self: Arc<Self>, listener: tokio::net::TcpListener) {
+        loop {
+            let (conn, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(?err, "accepting fluent forward connection");
+                    continue;
+                }
+            };
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = server.fluent_forward_connection(conn, peer).await {
+                    warn!(?err, %peer, "fluent forward connection");
+                }
+            });
+        }
+    }
+
+    /// Stores the events sent over a forward protocol connection, each tag's in a stream that's
+    /// closed with the connection. Messages are acknowledged once all their events are stored.
+    /// Otherwise the connection is closed, for the sender to retry.
+    async fn fluent_forward_connection(
+        &self,
+        mut conn: tokio::net::TcpStream,
+        peer: SocketAddr,
+    ) -> Result<()> {
+        let headers = HeaderMap::new();
+        let origin = self.origin(Some(peer), &headers);
+        let mut streams: HashMap<String, (StreamId, StreamEventIndex)> = HashMap::new();
+        let mut generation = self.storage_generation();
+        let mut buf = vec![];
+        let result = async {
+            while let Some(message) = forward::read_message(&mut conn, &mut buf).await? {
+                let message = forward::parse_message(message)?;
+                let _in_flight = self.pause.enter().await;
+                // The streams are in storage that's been fallen back from.
+                if self.storage_generation() != generation {
+                    streams.clear();
+                    generation = self.storage_generation();
+                }
+                let (stream_id, last_index) = match streams.entry(message.tag) {
+                    hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    hash_map::Entry::Vacant(entry) => {
+                        let headers_value = forward::stream_headers(entry.key());
+                        let stream_id = self
+                            .new_stream_with(headers_value, &headers, self.sampler.as_ref())
+                            .await?;
+                        entry.insert((stream_id, 0))
+                    }
+                };
+                for (time, record) in message.entries {
+                    *last_index += 1;
+                    self.insert_event(
+                        &record.to_string(),
+                        *stream_id,
+                        *last_index,
+                        None,
+                        &origin,
+                        time,
+                        None,
+                    )
+                    .await?;
+                }
+                if let Some(chunk) = message.chunk {
+                    conn.write_all(&forward::ack(&chunk)).await?;
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        for (stream_id, _) in streams.into_values() {
+            if let Err(err) = self.close_stream(stream_id).await {
+                warn!(?err, %stream_id, "closing fluent forward stream");
+            }
+        }
+        result
+    }
+
+    /// Stores the log lines of a Loki push request, as snappy-compressed protobuf or JSON. Each
+    /// label set gets a stream, kept open for later requests with the same labels. A request
+    /// whose lines can't all be stored fails, for the client to retry.
+    async fn loki_push_handler(&self, req: axum::http::Request<axum::body::Body>) -> Response {
+        let content_type = req
+            .headers()
+            .get(axum::http::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("application/x-protobuf");
+        let is_json = content_type.starts_with("application/json");
+        if !is_json && !content_type.starts_with("application/x-protobuf") {
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "push requests must be application/x-protobuf or application/json",
+            )
+                .into_response();
+        }
+        // Protobuf is always snappy-compressed, whatever the encoding header says.
+        let gzipped = match req.headers().get(axum::http::header::CONTENT_ENCODING) {
+            Some(encoding) if is_json && encoding == GZIP_ENCODING => true,
+            Some(encoding) if is_json && encoding != "identity" => {
+                return (
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported content encoding",
+                )
+                    .into_response()
+            }
+            _ => false,
+        };
+        let remote_addr = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        let origin = self.origin(remote_addr, req.headers());
+        let body = req.into_body().into_data_stream();
+        let body = match gzipped {
+            true => axum::body::Body::from_stream(gunzip_stream(body)),
+            false => axum::body::Body::from_stream(body),
+        };
+        let body = match axum::body::to_bytes(body, loki::MAX_LOKI_REQUEST_BYTES).await {
+            Ok(body) => body,
+            Err(err) => {
+                return (StatusCode::PAYLOAD_TOO_LARGE, format!("{:#}", err)).into_response()
+            }
+        };
+        let streams = match is_json {
+            true => loki::streams_from_json(&body),
+            false => snappy::decompress(&body, loki::MAX_LOKI_REQUEST_BYTES)
+                .and_then(|body| loki::streams_from_protobuf(&body)),
+        };
+        let streams = match streams {
+            Ok(streams) => streams,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        };
+        let mut last_err = None;
+        for stream in streams
+            .into_iter()
+            .filter(|stream| !stream.entries.is_empty())
+        {
+            let headers_value = loki::stream_headers(&stream.labels);
+            let next = self
+                .loki_streams
+                .next_indexes(
+                    stream.labels,
+                    self.storage_generation(),
+                    stream.entries.len() as u64,
+                    || self.new_stream_with(headers_value, &origin.headers, self.sampler.as_ref()),
+                )
+                .await;
+            let (stream_id, first_index) = match next {
+                Ok(next) => next,
+                Err(err) => {
+                    error!(?err, "creating stream for loki labels");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+                        .into_response();
+                }
+            };
+            for (entry, stream_event_index) in stream.entries.iter().zip(first_index..) {
+                if let Err(err) = self
+                    .insert_event(
+                        &entry.payload().to_string(),
+                        stream_id,
+                        stream_event_index,
+                        None,
+                        &origin,
+                        entry.time,
+                        None,
+                    )
+                    .await
+                {
+                    error!(?err, %stream_id, stream_event_index, "inserting loki entry");
+                    last_err = Some(err);
+                }
+            }
+        }
+        match last_err {
+            None => StatusCode::NO_CONTENT.into_response(),
+            Some(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)).into_response(),
+        }
+    }
+
+    /// Stores the metrics in each statsd datagram received on the socket, or adds them to the
+    /// current window if aggregating.
+    async fn serve_statsd_udp(&self, socket: tokio::net::UdpSocket, statsd: &Statsd) {
+        let mut buf = vec![0; statsd::MAX_STATSD_PACKET_BYTES];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!(?err, "receiving statsd datagram");
+                    continue;
+                }
+            };
+            let packet = String::from_utf8_lossy(&buf[..len]);
+            let host = peer.ip().to_canonical();
+            let (points, errors) = statsd.receive(host, &packet, chrono::Utc::now());
+            for err in errors {
+                debug!(?err, %host, "dropped statsd metric");
+            }
+            self.insert_statsd_points(&points).await;
+        }
+    }
+
+    async fn flush_statsd_periodically(&self, statsd: &Statsd, interval: std::time::Duration) {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick is immediate, and there's nothing to flush yet.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let points = statsd.flush(chrono::Utc::now());
+            self.insert_statsd_points(&points).await;
+        }
+    }
+
+    async fn insert_statsd_points(&self, points: &[MetricPoint]) {
+        if points.is_empty() {
+            return;
+        }
+        let _in_flight = self.pause.enter().await;
+        let result = self.db_conn.lock().await.insert_metric_points(points).await;
+        if let Err(err) = result {
+            error!(
+                ?err,
+                points = points.len(),
+                "inserting statsd metric points"
+            );
+        }
+    }
+
+    /// Serves the methods of the native gRPC service.
+    async fn grpc_handler(
+        self: Arc<Self>,
+        method: &str,
+        req: axum::http::Request<axum::body::Body>,
+    ) -> Response {
+        if !grpc::is_grpc(req.headers()) {
+            return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+        }
+        let remote_addr = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        let origin = self.origin(remote_addr, req.headers());
+        let mut messages = grpc::MessageReader::new(req.into_body().into_data_stream());
+        let answer = match method {
+            "AppendEvents" => {
+                let acknowledgements = futures::stream::unfold(
+                    (self, origin, messages, String::new()),
+                    |(server, origin, mut messages, mut stream_token)| async move {
+                        let acknowledgement = match messages.next().await? {
+                            Ok(message) => {
+                                server
+                                    .grpc_append_events(&origin, &message, &mut stream_token)
+                                    .await
+                            }
+                            Err(status) => Err(status),
+                        };
+                        Some((acknowledgement, (server, origin, messages, stream_token)))
+                    },
+                );
+                return grpc::response(acknowledgements);
+            }
+            "Submit" => {
+                async {
+                    let append = grpc::Append::decode(&messages.unary().await?)
+                        .map_err(grpc::Status::invalid_argument)?;
+                    let (stream_id, acknowledgement) = self.grpc_append(&origin, &append).await?;
+                    if append.close {
+                        self.close_stream(stream_id).await.map_err(|err| {
+                            grpc::Status::new(
+                                grpc::Code::Internal,
+                                format!("{:#}", err.context("closing stream")),
+                            )
+                        })?;
+                    }
+                    Ok(acknowledgement.encode())
+                }
+                .await
+            }
+            "OpenStream" => {
+                async {
+                    let stream_token = grpc::decode_stream_token(&messages.unary().await?)
+                        .map_err(grpc::Status::invalid_argument)?;
+                    let generation = self.storage_generation();
+                    let (stream_id, last_stream_event_index, _) =
+                        self.grpc_open_stream(&origin, &stream_token).await?;
+                    let response = grpc::Acknowledgement {
+                        stream_token: self.stream_tokens.issue(stream_id, generation),
+                        last_stream_event_index,
+                        ..Default::default()
+                    };
+                    Ok(response.encode())
+                }
+                .await
+            }
+            "CloseStream" => {
+                async {
+                    let stream_token = grpc::decode_stream_token(&messages.unary().await?)
+                        .map_err(grpc::Status::invalid_argument)?;
+                    let generation = self.storage_generation();
+                    let stream_id = self
+                        .stream_tokens
+                        .verify(&stream_token, generation)
+                        .map_err(|err| {
+                            grpc::Status::new(
+                                grpc::Code::PermissionDenied,
+                                format!("{:#}", err.context("checking stream token")),
+                            )
+                        })?;
+                    self.close_stream(stream_id).await.map_err(|err| {
+                        grpc::Status::new(
+                            grpc::Code::Internal,
+                            format!("{:#}", err.context("closing stream")),
+                        )
+                    })?;
+                    Ok(vec![])
+                }
+                .await
+            }
+            _ => Err(grpc::Status::new(
+                grpc::Code::Unimplemented,
+                format!("unknown method {}/{}", grpc::SERVICE, method),
+            )),
+        };
+        grpc::response(futures::stream::iter([answer]))
+    }
+
+    /// Opens the stream for a gRPC request as [Self::open_stream] does for HTTP, with the
+    /// request's token, if it has one.
+    async fn grpc_open_stream(
+        &self,
+        origin: &Origin,
+        stream_token: &str,
+    ) -> Result<(StreamId, StreamEventIndex, StreamTurn<'_>), grpc::Status> {
+        let mut headers = origin.headers.clone();
+        if !stream_token.is_empty() {
+            let stream_token = axum::http::HeaderValue::from_str(stream_token).map_err(|err| {
+                grpc::Status::new(
+                    grpc::Code::InvalidArgument,
+                    format!("reading stream token: {}", err),
+                )
+            })?;
+            headers.insert(STREAM_TOKEN_HEADER, stream_token);
+        }
+        self.open_stream(&headers, self.sampler.as_ref())
+            .await
+            .map_err(|(err, status_code)| {
+                let code = match status_code {
+                    StatusCode::FORBIDDEN => grpc::Code::PermissionDenied,
+                    StatusCode::NOT_FOUND => grpc::Code::NotFound,
+                    _ => grpc::Code::Internal,
+                };
+                grpc::Status::new(code, format!("{:#}", err.context("opening stream")))
+            })
+    }
+
+    /// Stores the events of a gRPC request in the stream it opens, acknowledging them once
+    /// they're flushed. Events that can't be stored are listed, and don't stop the rest.
+    async fn grpc_append(
+        &self,
+        origin: &Origin,
+        append: &grpc::Append,
+    ) -> Result<(StreamId, grpc::Acknowledgement), grpc::Status> {
+        let generation = self.storage_generation();
+        let (stream_id, mut stream_event_index, _turn) =
+            self.grpc_open_stream(origin, &append.stream_token).await?;
+        let mut accepted = 0;
+        let mut failed = vec![];
+        for event in &append.events {
+            stream_event_index += 1;
+            let result = match serde_json::from_str::<serde::de::IgnoredAny>(event) {
+                Ok(_) => {
+                    self.insert_batch_payload(
+                        event.as_bytes(),
+                        stream_id,
+                        stream_event_index,
+                        None,
+                        origin,
+                        None,
+                        None,
+                    )
+                    .await
+                }
+                Err(err) => Err(anyhow!(err).context("parsing event")),
+            };
+            match result {
+                Ok(()) => accepted += 1,
+                Err(err) => {
+                    error!(?err, %stream_id, stream_event_index, "inserting event from grpc");
+                    failed.push((stream_event_index, format!("{:#}", err)));
+                }
+            }
+        }
+        self.db_conn.lock().await.flush().await.map_err(|err| {
+            grpc::Status::new(
+                grpc::Code::Internal,
+                format!("{:#}", err.context("flushing events")),
+            )
+        })?;
+        let acknowledgement = grpc::Acknowledgement {
+            stream_token: self.stream_tokens.issue(stream_id, generation),
+            last_stream_event_index: stream_event_index,
+            accepted,
+            failed,
+        };
+        Ok((stream_id, acknowledgement))
+    }
+
+    /// Handles one request of an `AppendEvents` call. The first opens the stream, which the rest
+    /// add to whatever token they have.
+    async fn grpc_append_events(
+        &self,
+        origin: &Origin,
+        message: &[u8],
+        stream_token: &mut String,
+    ) -> Result<Vec<u8>, grpc::Status> {
+        let mut append = grpc::Append::decode(message).map_err(grpc::Status::invalid_argument)?;
+        if !stream_token.is_empty() {
+            append.stream_token = stream_token.clone();
+        }
+        let (_, acknowledgement) = self.grpc_append(origin, &append).await?;
+        *stream_token = acknowledgement.stream_token.clone();
+        Ok(acknowledgement.encode())
+    }
+
+    fn origin(&self, remote_addr: Option<SocketAddr>, headers: &HeaderMap) -> Origin {
+        Origin {
+            source: self
+                .enricher
+                .as_ref()
+                .map(|enricher| enricher.source(remote_addr, headers, RequestId::current())),
+            headers: headers.clone(),
+            ingest: None,
+            duplicate_of: None,
+        }
+    }
+
+    /// The limits for events from the origin.
+    fn event_limits(&self, origin: &Origin) -> EventLimits {
+        match &origin.ingest {
+            Some(ingest) => ingest.limits,
+            None => self.limits,
+        }
+    }
+
+    /// The sampler for events from the origin, if they're sampled.
+    fn sampler_for<'a>(&'a self, ingest: Option<&'a IngestEndpoint>) -> Option<&'a Sampler> {
+        ingest
+            .and_then(|ingest| ingest.sampler.as_ref())
+            .or(self.sampler.as_ref())
+    }
+
+    /// Reads the whole of a strict request's body, checking that every event in it can be read,
+    /// decoded, is within the limits and matches the ingest endpoint's schema, so that none are
+    /// stored if any can't be.
+    async fn read_strict_body(
+        &self,
+        origin: &Origin,
+        body_data_stream: BoxStream<'static, Result<Bytes, axum::Error>>,
+    ) -> Result<Bytes, SubmitError> {
+        let body = axum::body::to_bytes(
+            axum::body::Body::from_stream(body_data_stream),
+            MAX_STRICT_REQUEST_BYTES,
+        )
+        .await
+        .map_err(|err| {
+            SubmitError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request_too_large",
+                anyhow!(err).context("reading strict request body"),
+            )
+        })?;
+        let mut offset = 0;
+        let mut index = 0;
+        let mut rejected = None;
+        let chunks = futures::stream::iter([Ok(body.clone())]);
+        let max_event_bytes = self.event_limits(origin).max_event_bytes;
+        iter_json_stream(chunks, max_event_bytes, |payload| {
+            let value_offset = offset
+                + payload
+                    .iter()
+                    .take_while(|b| b.is_ascii_whitespace())
+                    .count();
+            offset += payload.len();
+            index += 1;
+            if rejected.is_none() {
+                let checked = self
+                    .legacy_encoding
+                    .decode(&payload)
+                    .context("decoding payload text")
+                    .and_then(|payload| match &origin.ingest {
+                        Some(ingest) => ingest.check_event(&payload),
+                        None => Ok(self.limits.check(&payload)?),
+                    });
+                if let Err(err) = checked {
+                    rejected = Some((index, value_offset, err));
+                }
+            }
+            std::future::ready(Ok(()))
+        })
+        .await?;
+        let Some((index, offset, err)) = rejected else {
+            return Ok(body);
+        };
+        let status_code = match err.downcast_ref::<LimitExceeded>() {
+            Some(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            None => StatusCode::BAD_REQUEST,
+        };
+        let code = match err.downcast_ref::<SchemaViolation>() {
+            Some(_) => "schema_violation",
+            None => "event_rejected",
+        };
+        let err = err.context(format!("event {} of strict request", index));
+        Err(SubmitError::new(status_code, code, err).at(offset as u64))
+    }
+
+    /// Returns when the same request was first stored within the window, if it's a duplicate to
+    /// tag. Duplicates to reject are an error. Requests are let through if the storage can't keep
+    /// hashes, as losing events is worse than storing them twice.
+    async fn find_duplicate(
+        &self,
+        dedup: Dedup,
+        hash: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, SubmitError> {
+        let since = chrono::Utc::now() - dedup.window;
+        let result = self
+            .db_conn
+            .lock()
+            .await
+            .find_request_hash(hash, since)
+            .await;
+        let first = match result {
+            Ok(first) => first,
+            Err(err) => {
+                warn!(?err, "finding request hash");
+                return Ok(None);
+            }
+        };
+        match (first, dedup.action) {
+            (None, _) => Ok(None),
+            (Some(first), DedupAction::Reject) => Err(SubmitError::new(
+                StatusCode::CONFLICT,
+                "duplicate_request",
+                anyhow!("the same request was received at {}", first.to_rfc3339()),
+            )),
+            (Some(first), DedupAction::Tag) => {
+                info!(%first, "storing duplicate request");
+                Ok(Some(first))
+            }
+        }
+    }
+
+    /// Stores the events of a POST body in the stream it opens or resumes, as they're read.
+    /// Events that can't be stored are collected into `failures`, and don't stop the rest. If the
+    /// storage can batch writes, the body's read first, and the events are stored together, or not
+    /// at all if the body can't all be read, storage fails, or a strict request has failures.
+    async fn submit(
+        &self,
+        req: axum::http::Request<axum::body::Body>,
+        opened_stream_id: &mut Option<StreamId>,
+        payloads_inserted: &mut u64,
+        payloads_stored: &mut u64,
+        failures: &std::sync::Mutex<Vec<EventFailure>>,
+        batch_outcome: &mut BatchOutcome,
+    ) -> Result<(), SubmitError> {
+        let gzipped = match req.headers().get(axum::http::header::CONTENT_ENCODING) {
+            None => false,
+            Some(encoding) if encoding == GZIP_ENCODING => true,
+            Some(encoding) => {
+                return Err(SubmitError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported_content_encoding",
+                    anyhow!("unsupported content encoding {:?}", encoding),
+                ));
+            }
+        };
+        let Query(params) = Query::<SubmitParams>::try_from_uri(req.uri()).map_err(|err| {
+            SubmitError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                anyhow!(err).context("parsing query parameters"),
+            )
+        })?;
+        let event_id_prefix = req
+            .headers()
+            .get(EVENT_ID_HEADER)
+            .map(|value| value.to_str().map(str::to_owned))
+            .transpose()
+            .map_err(|err| {
+                SubmitError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_header",
+                    anyhow!(err).context("reading event id header"),
+                )
+            })?;
+        let event_id_prefix = event_id_prefix.as_deref();
+        let client_datetime = header_datetime(req.headers(), EVENT_TIMESTAMP_HEADER);
+        let remote_addr = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        let mut origin = self.origin(remote_addr, req.headers());
+        origin.ingest = req.extensions().get::<Arc<IngestEndpoint>>().cloned();
+        let path = req.uri().path().to_owned();
+        // Saved once the request's events are stored, so that failed requests can be retried.
+        let mut request_hash = None;
+        let body_data_stream = req.into_body().into_data_stream();
+        let mut body_data_stream = match gzipped {
+            true => gunzip_stream(body_data_stream),
+            false => body_data_stream.boxed(),
+        };
+        if params.strict {
+            // Checked before the stream's opened, so a rejected request leaves nothing behind.
+            let body = self.read_strict_body(&origin, body_data_stream).await?;
+            body_data_stream = futures::stream::iter([Ok(body)]).boxed();
+        }
+        let mut hasher = self.dedup.map(|_| {
+            let stream_token = origin.headers.get(STREAM_TOKEN_HEADER);
+            RequestHasher::new(&path, stream_token.map(|token| token.as_bytes()))
+        });
+        let max_value_bytes = self.event_limits(&origin).max_event_bytes;
+        // Bodies stored together are read whole before a connection's taken, so a slow client
+        // can't hold a batch open, and so are bodies checked for duplicates, which have to be
+        // known to be before anything's stored. Others are stored as they're read.
+        let buffered = self.dedup.is_some() || self.db_conn.lock().await.batches();
+        let mut payloads = vec![];
+        let mut parsed = Ok(());
+        if buffered {
+            let body_data_stream = (&mut body_data_stream).inspect(|chunk| {
+                if let (Some(hasher), Ok(bytes)) = (hasher.as_mut(), chunk) {
+                    hasher.update(bytes);
+                }
+            });
+            let body = read_buffered_body(body_data_stream).await?;
+            let chunks = futures::stream::iter([Ok(body)]);
+            parsed = iter_json_stream(chunks, max_value_bytes, |payload| {
+                payloads.push(payload);
+                future::ready(Ok(()))
+            })
+            .await;
+            // A body that couldn't all be read isn't the same as any other.
+            if let (Some(dedup), Some(hasher), Ok(())) = (self.dedup, hasher, &parsed) {
+                let hash = hasher.finish();
+                origin.duplicate_of = self.find_duplicate(dedup, &hash).await?;
+                request_hash = Some(hash);
+            }
+        }
+        let origin = &origin;
+        // Held until the events are stored, so later requests for the stream index theirs after.
+        let sampler = self.sampler_for(origin.ingest.as_deref());
+        let (stream_id, mut stream_event_index, _turn) = self
+            .open_stream(&origin.headers, sampler)
+            .await
+            .map_err(|(err, code)| {
+                let error_code = match code {
+                    StatusCode::FORBIDDEN => "invalid_stream_token",
+                    StatusCode::NOT_FOUND => "stream_not_found",
+                    _ => "storage_error",
+                };
+                SubmitError::new(code, error_code, err.context("opening stream"))
+            })?;
+        *opened_stream_id = Some(stream_id);
+        let storage_error = |err: anyhow::Error| {
+            SubmitError::new(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", err)
+        };
+        if !buffered {
+            // Events are stored in runs of those read while the last run was being stored.
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(EVENTS_PER_INSERT);
+            let read = async move {
+                let sender = &sender;
+                iter_json_stream(body_data_stream, max_value_bytes, |payload| async move {
+                    sender
+                        .send(payload)
+                        .await
+                        .map_err(|_| anyhow!("stopped storing events"))
+                })
+                .await
+            };
+            let store = async {
+                let mut run = Vec::with_capacity(EVENTS_PER_INSERT);
+                while receiver.recv_many(&mut run, EVENTS_PER_INSERT).await != 0 {
+                    // Only storage failing in a batch is returned, and there's no batch.
+                    if let Ok(stored) = self
+                        .insert_batch_payloads(
+                            &run,
+                            stream_id,
+                            stream_event_index + 1,
+                            event_id_prefix,
+                            origin,
+                            client_datetime,
+                            None,
+                            failures,
+                        )
+                        .await
+                    {
+                        *payloads_stored += stored;
+                    }
+                    *payloads_inserted += run.len() as u64;
+                    stream_event_index += run.len() as StreamEventIndex;
+                    run.clear();
+                }
+            };
+            let (parsed, ()) = future::join(read, store).await;
+            return self
+                .finish_submit(stream_id, parsed, request_hash, params.close)
+                .await;
+        }
+        let mut conn = self.db_conn.lock().await;
+        let batched = conn
+            .begin_batch()
+            .await
+            .map_err(|err| storage_error(err.context("beginning batch")))?;
+        // Other requests wait for the batch, as their writes would otherwise be part of it.
+        let mut batch = batched.then_some(conn);
+        let last_stream_event_index = stream_event_index;
+        let mut result = Ok(());
+        for chunk in payloads.chunks(EVENTS_PER_INSERT) {
+            let inserted = self
+                .insert_batch_payloads(
+                    chunk,
+                    stream_id,
+                    stream_event_index + 1,
+                    event_id_prefix,
+                    origin,
+                    client_datetime,
+                    batch.as_deref_mut(),
+                    failures,
+                )
+                .await;
+            *payloads_inserted += chunk.len() as u64;
+            stream_event_index += chunk.len() as StreamEventIndex;
+            match inserted {
+                Ok(stored) => *payloads_stored += stored,
+                Err(err) => {
+                    result = Err(storage_error(err.context("handling payload")));
+                    break;
+                }
+            }
+        }
+        let result = result.and(parsed);
+        if let Some(mut conn) = batch {
+            let rejected = params.strict && !failures.lock().unwrap().is_empty();
+            *batch_outcome = BatchOutcome::RolledBack;
+            if result.is_err() || rejected {
+                conn.rollback_batch()
+                    .await
+                    .map_err(|err| storage_error(err.context("rolling back batch")))?;
+            } else if let Err(err) = conn.commit_batch().await {
+                // Some storage stays in the transaction when committing fails.
+                if let Err(err) = conn.rollback_batch().await {
+                    warn!(?err, "rolling back batch after failed commit");
+                }
+                return Err(storage_error(err.context("committing batch")));
+            } else {
+                *batch_outcome = BatchOutcome::Committed;
+            }
+        }
+        result?;
+        if *batch_outcome == BatchOutcome::RolledBack {
+            let failures = failures.lock().unwrap();
+            let failure = &failures[0];
+            let status_code = match failure.limit_exceeded() {
+                Some(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                None => StatusCode::BAD_REQUEST,
+            };
+            let err = anyhow!("{:#}", failure.err).context(format!(
+                "event {} of strict request",
+                failure.stream_event_index - last_stream_event_index
+            ));
+            return Err(SubmitError::new(status_code, "event_rejected", err));
+        }
+        self.finish_submit(stream_id, Ok(()), request_hash, params.close)
+            .await
+    }
+
+    /// Saves the hash of a request whose body was all read, and closes its stream if asked to.
+    async fn finish_submit(
+        &self,
+        stream_id: StreamId,
+        parsed: Result<(), SubmitError>,
+        request_hash: Option<String>,
+        close: bool,
+    ) -> Result<(), SubmitError> {
+        parsed?;
+        if let Some(hash) = request_hash {
+            let result = self.db_conn.lock().await.save_request_hash(&hash).await;
+            if let Err(err) = result {
+                warn!(?err, "saving request hash");
+            }
+        }
+        if close {
+            self.close_stream(stream_id).await.map_err(|err| {
+                SubmitError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "storage_error",
+                    err.context("closing stream"),
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn headers_to_json_value(headers: &HeaderMap) -> serde_json::Result<serde_json::Value> {
+    // This converts duplicate header values to an array, and seems to leave single header values
+    // alone. This is needed to fix JSON containing backslashes for some values when those should be
+    // valid objects.
+    http_serde::header_map::serialize(headers, serde_json::value::Serializer)
+}
+
+/// Header supplying event IDs for a POST. Each event gets the header value suffixed with its index
+/// in the request body, so a retried request produces the same IDs.
+const EVENT_ID_HEADER: &str = "x-event-id";
+
+/// When the events of a POST happened by the client's clock, as RFC 3339, for payloads without a
+/// timestamp field of their own.
+const EVENT_TIMESTAMP_HEADER: &str = "x-event-timestamp";
+
+/// Set on POST responses to "true" if the events were stored together, so that none were if the
+/// request failed, or "false" if they were stored as they were read.
+const ATOMIC_HEADER: &str = "x-atomic";
+
+/// The client's clock as RFC 3339, sent when starting a stream. The difference from the server's
+/// clock is recorded on the stream as `clock_skew_ms`.
+const CLIENT_NOW_HEADER: &str = "x-client-now";
+
+/// Labels for a stream, sent when starting it, as comma separated `key:value` pairs.
+const STREAM_LABELS_HEADER: &str = "x-stream-labels";
+
+/// Parses comma separated `key:value` pairs of stream labels.
+fn parse_labels(text: &str) -> Result<Vec<(String, String)>> {
+    text.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (key, value) = pair
+                .split_once(':')
+                .ok_or_else(|| anyhow!("label {:?} should be key:value", pair))?;
+            Ok((key.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// Unparseable values are ignored, as the headers only add information.
+fn header_datetime(headers: &HeaderMap, name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let value = headers.get(name)?.to_str().ok()?;
+    parse_timestamp(&serde_json::Value::String(value.to_owned()))
+}
+
+/// Clients can put an `event_id` field at the top level of payloads to have retries deduplicated.
+fn payload_event_id(payload: &str) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct EventIdField {
+        event_id: Option<serde_json::Value>,
+    }
+    // Payloads needn't be objects, in which case there's no ID.
+    let field: EventIdField = serde_json::from_str(payload).ok()?;
+    match field.event_id? {
+        serde_json::Value::String(event_id) => Some(event_id),
+        serde_json::Value::Number(event_id) => Some(event_id.to_string()),
+        _ => None,
+    }
+}
+
+#[allow(dead_code)]
+fn sqlite_local_datetime_now_string() -> String {
+    chrono::Local::now().to_rfc3339_opts(SecondsFormat::Millis, false)
+}
+
+// enum Payload {
+//     Binary,
+//     Text,
+//     Json,
+// }
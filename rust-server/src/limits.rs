@@ -0,0 +1,105 @@
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+
+/// Per-event limits, so one runaway producer can't store events that break everything reading
+/// them. Unset limits aren't checked.
+#[derive(Clone, Copy, Debug, Default, clap::Args, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventLimits {
+    /// Largest event accepted, in serialized bytes.
+    #[arg(long)]
+    pub max_event_bytes: Option<usize>,
+    /// Deepest nesting of objects and arrays accepted in an event.
+    #[arg(long)]
+    pub max_event_depth: Option<usize>,
+    /// Most elements accepted in any array in an event.
+    #[arg(long)]
+    pub max_array_length: Option<usize>,
+}
+
+impl EventLimits {
+    pub(crate) fn check(&self, payload: &str) -> Result<(), LimitExceeded> {
+        if let Some(max) = self.max_event_bytes {
+            LimitExceeded::check("max_event_bytes", max, payload.len())?;
+        }
+        if self.max_event_depth.is_none() && self.max_array_length.is_none() {
+            return Ok(());
+        }
+        // Payloads that don't parse are left for insertion to reject.
+        let Ok(value) = serde_json::from_str::<Value>(payload) else {
+            return Ok(());
+        };
+        let shape = Shape::of(&value);
+        if let Some(max) = self.max_event_depth {
+            LimitExceeded::check("max_event_depth", max, shape.depth)?;
+        }
+        if let Some(max) = self.max_array_length {
+            LimitExceeded::check("max_array_length", max, shape.longest_array)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Shape {
+    depth: usize,
+    longest_array: usize,
+}
+
+impl Shape {
+    fn of(value: &Value) -> Self {
+        let children: Box<dyn Iterator<Item = &Value>> = match value {
+            Value::Array(array) => Box::new(array.iter()),
+            Value::Object(object) => Box::new(object.values()),
+            _ => return Self::default(),
+        };
+        let mut shape = Self {
+            depth: 1,
+            longest_array: value.as_array().map_or(0, Vec::len),
+        };
+        for child in children.map(Self::of) {
+            shape.depth = shape.depth.max(child.depth + 1);
+            shape.longest_array = shape.longest_array.max(child.longest_array);
+        }
+        shape
+    }
+}
+
+/// An event that was rejected for exceeding one of the [EventLimits].
+#[derive(Debug)]
+pub(crate) struct LimitExceeded {
+    limit: &'static str,
+    max: usize,
+    actual: usize,
+}
+
+impl LimitExceeded {
+    pub(crate) fn check(limit: &'static str, max: usize, actual: usize) -> Result<(), Self> {
+        if actual > max {
+            return Err(Self { limit, max, actual });
+        }
+        Ok(())
+    }
+
+    /// The fields added to error responses for clients to act on.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "code": "event_limit_exceeded",
+            "limit": self.limit,
+            "max": self.max,
+            "actual": self.actual,
+        })
+    }
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "event exceeds {} of {} ({})",
+            self.limit, self.max, self.actual
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
@@ -0,0 +1,211 @@
+use crate::protobuf::{fields, WireValue};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// The largest Loki push request body read, and the most a snappy body can decompress to.
+pub(crate) const MAX_LOKI_REQUEST_BYTES: usize = 16 << 20;
+
+/// A label set, which is a stream to Loki.
+pub(crate) type Labels = BTreeMap<String, String>;
+
+/// A stream's entries from a push request.
+#[derive(Debug, PartialEq)]
+pub(crate) struct LokiStream {
+    pub labels: Labels,
+    pub entries: Vec<LokiEntry>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct LokiEntry {
+    pub time: Option<DateTime<Utc>>,
+    pub line: String,
+    pub structured_metadata: Map<String, Value>,
+}
+
+impl LokiEntry {
+    pub(crate) fn payload(&self) -> Value {
+        let mut payload = json!({ "line": self.line });
+        if !self.structured_metadata.is_empty() {
+            payload["structured_metadata"] = self.structured_metadata.clone().into();
+        }
+        payload
+    }
+}
+
+/// The headers of a label set's stream.
+pub(crate) fn stream_headers(labels: &Labels) -> Value {
+    json!({ "loki": labels })
+}
+
+/// Parses a JSON push request, `{"streams": [{"stream": {labels}, "values": [[nanoseconds,
+/// line, metadata?]]}]}`, or the older `{"streams": [{"labels": "{...}", "entries": [{"ts":
+/// time, "line": line}]}]}`.
+pub(crate) fn streams_from_json(body: &[u8]) -> Result<Vec<LokiStream>> {
+    #[derive(serde::Deserialize)]
+    struct Request {
+        streams: Vec<Stream>,
+    }
+    #[derive(serde::Deserialize)]
+    struct Stream {
+        stream: Option<Labels>,
+        #[serde(default)]
+        values: Vec<Vec<Value>>,
+        labels: Option<String>,
+        #[serde(default)]
+        entries: Vec<OldEntry>,
+    }
+    #[derive(serde::Deserialize)]
+    struct OldEntry {
+        #[serde(alias = "timestamp")]
+        ts: Option<String>,
+        line: String,
+    }
+    let request: Request = serde_json::from_slice(body)?;
+    request
+        .streams
+        .into_iter()
+        .map(|stream| {
+            let labels = match (stream.stream, stream.labels) {
+                (Some(labels), _) => labels,
+                (None, Some(labels)) => parse_labels(&labels)?,
+                (None, None) => Labels::new(),
+            };
+            let mut entries = vec![];
+            for value in stream.values {
+                let [time, line, rest @ ..] = &value[..] else {
+                    bail!("value has no line");
+                };
+                let time = time
+                    .as_str()
+                    .and_then(|nanos| nanos.parse().ok())
+                    .map(DateTime::from_timestamp_nanos)
+                    .context("value's time isn't a string of nanoseconds")?;
+                let line = line.as_str().context("value's line isn't a string")?;
+                let structured_metadata = match rest.first() {
+                    Some(Value::Object(metadata)) => metadata.clone(),
+                    _ => Map::new(),
+                };
+                entries.push(LokiEntry {
+                    time: Some(time),
+                    line: line.to_owned(),
+                    structured_metadata,
+                });
+            }
+            for entry in stream.entries {
+                let time = entry
+                    .ts
+                    .map(|ts| DateTime::parse_from_rfc3339(&ts).map(|ts| ts.to_utc()))
+                    .transpose()
+                    .context("entry's ts isn't an RFC 3339 time")?;
+                entries.push(LokiEntry {
+                    time,
+                    line: entry.line,
+                    structured_metadata: Map::new(),
+                });
+            }
+            Ok(LokiStream { labels, entries })
+        })
+        .collect()
+}
+
+/// Parses a protobuf push request, once decompressed.
+pub(crate) fn streams_from_protobuf(body: &[u8]) -> Result<Vec<LokiStream>> {
+    let mut streams = vec![];
+    for field in fields(body) {
+        if let (1, stream) = field? {
+            streams.push(stream_from_protobuf(stream.bytes()?)?);
+        }
+    }
+    Ok(streams)
+}
+
+/// A StreamAdapter: labels = 1, entries = 2.
+fn stream_from_protobuf(message: &[u8]) -> Result<LokiStream> {
+    let mut labels = Labels::new();
+    let mut entries = vec![];
+    for field in fields(message) {
+        match field? {
+            (1, value) => labels = parse_labels(&value.string()?)?,
+            (2, value) => entries.push(entry_from_protobuf(value.bytes()?)?),
+            _ => {}
+        }
+    }
+    Ok(LokiStream { labels, entries })
+}
+
+/// An EntryAdapter: timestamp = 1, line = 2, structuredMetadata = 3.
+fn entry_from_protobuf(message: &[u8]) -> Result<LokiEntry> {
+    let mut entry = LokiEntry {
+        time: None,
+        line: String::new(),
+        structured_metadata: Map::new(),
+    };
+    for field in fields(message) {
+        match field? {
+            (1, value) => entry.time = timestamp(value)?,
+            (2, value) => entry.line = value.string()?,
+            (3, value) => {
+                let (mut name, mut pair_value) = (String::new(), String::new());
+                for field in fields(value.bytes()?) {
+                    match field? {
+                        (1, value) => name = value.string()?,
+                        (2, value) => pair_value = value.string()?,
+                        _ => {}
+                    }
+                }
+                entry.structured_metadata.insert(name, pair_value.into());
+            }
+            _ => {}
+        }
+    }
+    Ok(entry)
+}
+
+/// A google.protobuf.Timestamp: seconds = 1, nanos = 2.
+fn timestamp(value: WireValue) -> Result<Option<DateTime<Utc>>> {
+    let (mut secs, mut nanos) = (0, 0);
+    for field in fields(value.bytes()?) {
+        match field? {
+            (1, value) => secs = value.varint()? as i64,
+            (2, value) => nanos = value.varint()? as u32,
+            _ => {}
+        }
+    }
+    Ok(DateTime::from_timestamp(secs, nanos))
+}
+
+/// Parses a label set as Prometheus writes it, like `{job="api", env="prod"}`.
+pub(crate) fn parse_labels(text: &str) -> Result<Labels> {
+    let mut rest = text
+        .trim()
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .with_context(|| format!("labels {:?} aren't in braces", text))?;
+    let mut labels = Labels::new();
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        if rest.is_empty() {
+            return Ok(labels);
+        }
+        let (name, after) = rest
+            .split_once("=\"")
+            .with_context(|| format!("label in {:?} has no value", text))?;
+        let mut value = String::new();
+        let mut chars = after.char_indices();
+        rest = loop {
+            match chars.next() {
+                Some((index, '"')) => break &after[index + 1..],
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, escaped)) => value.push(escaped),
+                    None => bail!("labels {:?} end in an escape", text),
+                },
+                Some((_, c)) => value.push(c),
+                None => bail!("label value in {:?} isn't closed", text),
+            }
+        };
+        labels.insert(name.trim().to_owned(), value);
+    }
+}
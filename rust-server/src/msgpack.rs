@@ -0,0 +1,273 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+/// A decoded MessagePack value.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Ext(i8, Vec<u8>),
+}
+
+/// Values nested deeper than this are refused, rather than risk the stack.
+const MAX_DEPTH: usize = 64;
+
+enum DecodeError {
+    /// The buffer ends partway through the value.
+    Incomplete,
+    Invalid(anyhow::Error),
+}
+
+/// Decodes the value at the start of `buf`, and how many bytes it took. None if `buf` ends before
+/// the value does.
+pub(crate) fn decode(buf: &[u8]) -> Result<Option<(Value, usize)>> {
+    let mut decoder = Decoder { buf, pos: 0 };
+    match decoder.value(0) {
+        Ok(value) => Ok(Some((value, decoder.pos))),
+        Err(DecodeError::Incomplete) => Ok(None),
+        Err(DecodeError::Invalid(err)) => Err(err),
+    }
+}
+
+/// Encodes a string.
+pub(crate) fn encode_str(out: &mut Vec<u8>, text: &str) {
+    let len = text.len();
+    match len {
+        0..32 => out.push(0xa0 | len as u8),
+        32..0x100 => out.extend([0xd9, len as u8]),
+        0x100..0x10000 => {
+            out.push(0xda);
+            out.extend((len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0xdb);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+    out.extend(text.as_bytes());
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.pos.checked_add(len).ok_or(DecodeError::Incomplete)?;
+        let taken = self.buf.get(self.pos..end).ok_or(DecodeError::Incomplete)?;
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize, DecodeError> {
+        Ok(u16::from_be_bytes(self.take_array()?).into())
+    }
+
+    fn u32(&mut self) -> Result<usize, DecodeError> {
+        Ok(u32::from_be_bytes(self.take_array()?) as usize)
+    }
+
+    fn str(&mut self, len: usize) -> Result<Value, DecodeError> {
+        Ok(Value::Str(
+            String::from_utf8_lossy(self.take(len)?).into_owned(),
+        ))
+    }
+
+    fn ext(&mut self, len: usize) -> Result<Value, DecodeError> {
+        let ext_type = self.u8()? as i8;
+        Ok(Value::Ext(ext_type, self.take(len)?.to_vec()))
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> Result<Value, DecodeError> {
+        // The length isn't trusted for allocating until the items are there.
+        let mut items = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> Result<Value, DecodeError> {
+        let mut entries = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            entries.push((self.value(depth + 1)?, self.value(depth + 1)?));
+        }
+        Ok(Value::Map(entries))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::Invalid(anyhow!(
+                "values are nested more than {} deep",
+                MAX_DEPTH
+            )));
+        }
+        let marker = self.u8()?;
+        let value = match marker {
+            0x00..=0x7f => Value::UInt(marker.into()),
+            0x80..=0x8f => self.map((marker & 0x0f).into(), depth)?,
+            0x90..=0x9f => self.array((marker & 0x0f).into(), depth)?,
+            0xa0..=0xbf => self.str((marker & 0x1f).into())?,
+            0xc0 => Value::Nil,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4 => {
+                let len = self.u8()?.into();
+                Value::Bin(self.take(len)?.to_vec())
+            }
+            0xc5 => {
+                let len = self.u16()?;
+                Value::Bin(self.take(len)?.to_vec())
+            }
+            0xc6 => {
+                let len = self.u32()?;
+                Value::Bin(self.take(len)?.to_vec())
+            }
+            0xc7 => {
+                let len = self.u8()?.into();
+                self.ext(len)?
+            }
+            0xc8 => {
+                let len = self.u16()?;
+                self.ext(len)?
+            }
+            0xc9 => {
+                let len = self.u32()?;
+                self.ext(len)?
+            }
+            0xca => Value::Float(f32::from_be_bytes(self.take_array()?).into()),
+            0xcb => Value::Float(f64::from_be_bytes(self.take_array()?)),
+            0xcc => Value::UInt(self.u8()?.into()),
+            0xcd => Value::UInt(self.u16()? as u64),
+            0xce => Value::UInt(self.u32()? as u64),
+            0xcf => Value::UInt(u64::from_be_bytes(self.take_array()?)),
+            0xd0 => Value::Int(i8::from_be_bytes(self.take_array()?).into()),
+            0xd1 => Value::Int(i16::from_be_bytes(self.take_array()?).into()),
+            0xd2 => Value::Int(i32::from_be_bytes(self.take_array()?).into()),
+            0xd3 => Value::Int(i64::from_be_bytes(self.take_array()?)),
+            0xd4 => self.ext(1)?,
+            0xd5 => self.ext(2)?,
+            0xd6 => self.ext(4)?,
+            0xd7 => self.ext(8)?,
+            0xd8 => self.ext(16)?,
+            0xd9 => {
+                let len = self.u8()?.into();
+                self.str(len)?
+            }
+            0xda => {
+                let len = self.u16()?;
+                self.str(len)?
+            }
+            0xdb => {
+                let len = self.u32()?;
+                self.str(len)?
+            }
+            0xdc => {
+                let len = self.u16()?;
+                self.array(len, depth)?
+            }
+            0xdd => {
+                let len = self.u32()?;
+                self.array(len, depth)?
+            }
+            0xde => {
+                let len = self.u16()?;
+                self.map(len, depth)?
+            }
+            0xdf => {
+                let len = self.u32()?;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => Value::Int((marker as i8).into()),
+            0xc1 => {
+                return Err(DecodeError::Invalid(anyhow!(
+                    "0xc1 isn't a MessagePack type"
+                )))
+            }
+        };
+        Ok(value)
+    }
+}
+
+impl Value {
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// The value of a key of a map.
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(entry_key, _)| entry_key.as_str() == Some(key))
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// A time, as Fluentd sends: whole seconds, or an EventTime extension of seconds and
+    /// nanoseconds.
+    pub(crate) fn event_time(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Value::UInt(secs) => DateTime::from_timestamp(i64::try_from(*secs).ok()?, 0),
+            Value::Int(secs) => DateTime::from_timestamp(*secs, 0),
+            Value::Float(secs) => DateTime::from_timestamp_micros((secs * 1e6) as i64),
+            Value::Ext(0, data) if data.len() == 8 => {
+                let secs = u32::from_be_bytes(data[..4].try_into().unwrap());
+                let nanos = u32::from_be_bytes(data[4..].try_into().unwrap());
+                DateTime::from_timestamp(secs.into(), nanos)
+            }
+            _ => None,
+        }
+    }
+
+    /// As JSON. Binary is taken to be text, map keys that aren't strings are written as JSON,
+    /// and EventTimes become RFC 3339 times. Other extensions are null.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Nil => serde_json::Value::Null,
+            Value::Bool(value) => json!(value),
+            Value::Int(value) => json!(value),
+            Value::UInt(value) => json!(value),
+            Value::Float(value) => json!(value),
+            Value::Str(text) => json!(text),
+            Value::Bin(bytes) => json!(String::from_utf8_lossy(bytes)),
+            Value::Array(items) => items.iter().map(Value::to_json).collect(),
+            Value::Map(entries) => entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Value::Str(key) => key.clone(),
+                        key => key.to_json().to_string(),
+                    };
+                    (key, value.to_json())
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            Value::Ext(..) => match self.event_time() {
+                Some(time) => json!(time.to_rfc3339()),
+                None => serde_json::Value::Null,
+            },
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use telemetry_storage::{StreamEventIndex, StreamId};
+use tokio::sync::Mutex;
+
+/// The stream of each sender that doesn't say when it's done, like a syslog host or a Loki label
+/// set, and the index of its last event. The streams are left open. They're kept along with the
+/// storage generation they're in, and forgotten once it changes.
+pub(crate) struct OpenStreams<K>(Mutex<Generation<K>>);
+
+impl<K> Default for OpenStreams<K> {
+    fn default() -> Self {
+        Self(Mutex::new(Generation {
+            generation: 0,
+            streams: HashMap::new(),
+        }))
+    }
+}
+
+/// The senders' streams in one storage generation.
+struct Generation<K> {
+    generation: u64,
+    streams: HashMap<K, (StreamId, StreamEventIndex)>,
+}
+
+impl<K: Eq + Hash> OpenStreams<K> {
+    /// Hands out the next `count` event indexes in the sender's stream, returning the first,
+    /// starting a stream with `new_stream` if the sender doesn't have one yet in the storage of
+    /// `generation`.
+    pub(crate) async fn next_indexes<F>(
+        &self,
+        key: K,
+        generation: u64,
+        count: u64,
+        new_stream: impl FnOnce() -> F,
+    ) -> Result<(StreamId, StreamEventIndex)>
+    where
+        F: Future<Output = Result<StreamId>>,
+    {
+        let mut open = self.0.lock().await;
+        if open.generation != generation {
+            open.streams.clear();
+            open.generation = generation;
+        }
+        let (stream_id, last_index) = match open.streams.get(&key) {
+            Some(&(stream_id, last_index)) => (stream_id, last_index),
+            None => (new_stream().await?, 0),
+        };
+        open.streams.insert(key, (stream_id, last_index + count));
+        Ok((stream_id, last_index + 1))
+    }
+}
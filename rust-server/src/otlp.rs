@@ -0,0 +1,281 @@
+use crate::Storage;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use telemetry_storage::MetricPoint;
+
+/// The largest OTLP request body read, after decompressing. Spans are stored separately, so this
+/// only bounds what's held in memory while they are.
+pub(crate) const MAX_OTLP_REQUEST_BYTES: usize = 16 << 20;
+
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct OtlpArgs {
+    /// Extracts the trace_id and span_id of spans received at /v1/traces into indexed columns of
+    /// SQLite and Postgres storage, as with --extract-field.
+    #[arg(long)]
+    pub otlp_trace_columns: bool,
+}
+
+impl OtlpArgs {
+    pub(crate) fn apply(&self, storage: &mut Storage) -> Result<()> {
+        if !self.otlp_trace_columns {
+            return Ok(());
+        }
+        let extract = match storage {
+            Storage::Sqlite(open) => open.extract_mut(),
+            Storage::Postgres(open) => &mut open.extract,
+            _ => bail!("--otlp-trace-columns needs SQLite or Postgres storage"),
+        };
+        for column in ["trace_id", "span_id"] {
+            extract.add(format!("{}=/{}", column, column).parse()?);
+        }
+        Ok(())
+    }
+}
+
+/// A resource's spans from an OTLP/JSON ExportTraceServiceRequest, as stream headers and event
+/// payloads.
+pub(crate) struct ResourceSpans {
+    pub headers: Value,
+    pub spans: Vec<Value>,
+}
+
+/// Splits an OTLP/JSON traces request into its resources. Each resource's attributes become its
+/// stream's headers, and each span an event.
+pub(crate) fn resource_spans(request: &Value) -> Result<Vec<ResourceSpans>> {
+    let Some(resources) = request.get("resourceSpans") else {
+        return Ok(vec![]);
+    };
+    resources
+        .as_array()
+        .context("resourceSpans isn't an array")?
+        .iter()
+        .map(|resource| {
+            let attributes = attributes(&resource["resource"]["attributes"]);
+            let mut spans = vec![];
+            for scope_spans in array(&resource["scopeSpans"]) {
+                let scope = &scope_spans["scope"];
+                for span in array(&scope_spans["spans"]) {
+                    spans.push(span_payload(span, scope)?);
+                }
+            }
+            Ok(ResourceSpans {
+                headers: json!({ "otlp": "traces", "resource": attributes }),
+                spans,
+            })
+        })
+        .collect()
+}
+
+fn span_payload(span: &Value, scope: &Value) -> Result<Value> {
+    let trace_id = span["traceId"].as_str().context("span has no traceId")?;
+    let span_id = span["spanId"].as_str().context("span has no spanId")?;
+    let start = nanos(&span["startTimeUnixNano"]);
+    let end = nanos(&span["endTimeUnixNano"]);
+    let events: Vec<_> = array(&span["events"])
+        .map(|event| {
+            json!({
+                "time": nanos(&event["timeUnixNano"]).map(rfc3339),
+                "name": event["name"],
+                "attributes": attributes(&event["attributes"]),
+            })
+        })
+        .collect();
+    let links: Vec<_> = array(&span["links"])
+        .map(|link| {
+            json!({
+                "trace_id": link["traceId"].as_str().map(str::to_ascii_lowercase),
+                "span_id": link["spanId"].as_str().map(str::to_ascii_lowercase),
+                "attributes": attributes(&link["attributes"]),
+            })
+        })
+        .collect();
+    Ok(json!({
+        "type": "span",
+        "trace_id": trace_id.to_ascii_lowercase(),
+        "span_id": span_id.to_ascii_lowercase(),
+        "parent_span_id": span["parentSpanId"]
+            .as_str()
+            .filter(|parent| !parent.is_empty())
+            .map(str::to_ascii_lowercase),
+        "name": span["name"],
+        "kind": span_kind(&span["kind"]),
+        "start_time": start.map(rfc3339),
+        "end_time": end.map(rfc3339),
+        "duration_s": start.zip(end).map(|(start, end)| (end - start) as f64 / 1e9),
+        "attributes": attributes(&span["attributes"]),
+        "status": {
+            "code": status_code(&span["status"]["code"]),
+            "message": span["status"]["message"],
+        },
+        "events": events,
+        "links": links,
+        "scope": {"name": scope["name"], "version": scope["version"]},
+    }))
+}
+
+/// Flattens an OTLP/JSON metrics request into data points. Histograms and summaries become
+/// NAME.count and NAME.sum points, and summaries also a NAME point for each quantile, with the
+/// quantile as an attribute. Points without a time are taken to be from now.
+pub(crate) fn metric_points(request: &Value) -> Result<Vec<MetricPoint>> {
+    let now = Utc::now();
+    let mut points = vec![];
+    for resource in array(&request["resourceMetrics"]) {
+        let resource_attributes = attributes(&resource["resource"]["attributes"]);
+        for scope_metrics in array(&resource["scopeMetrics"]) {
+            for metric in array(&scope_metrics["metrics"]) {
+                let name = metric["name"].as_str().context("metric has no name")?;
+                for (naming, data_point, value) in data_point_values(metric) {
+                    let mut point_attributes = attributes(&data_point["attributes"]);
+                    let name = match naming {
+                        MetricValue::Quantile(quantile) => {
+                            point_attributes["quantile"] = quantile.into();
+                            name.to_owned()
+                        }
+                        MetricValue::Suffix(suffix) => format!("{}{}", name, suffix),
+                    };
+                    let Some(value) = value else {
+                        continue;
+                    };
+                    points.push(MetricPoint {
+                        name,
+                        resource: resource_attributes.clone(),
+                        attributes: point_attributes,
+                        datetime: nanos(&data_point["timeUnixNano"])
+                            .map(DateTime::from_timestamp_nanos)
+                            .unwrap_or(now),
+                        value,
+                    });
+                }
+            }
+        }
+    }
+    Ok(points)
+}
+
+/// How a data point's value is named.
+enum MetricValue {
+    /// Appended to the metric's name.
+    Suffix(&'static str),
+    /// A summary's quantile, named as the metric.
+    Quantile(f64),
+}
+
+/// Each value of each data point of a metric, whatever its type.
+fn data_point_values(metric: &Value) -> Vec<(MetricValue, &Value, Option<f64>)> {
+    let mut values = vec![];
+    for kind in ["gauge", "sum"] {
+        for data_point in array(&metric[kind]["dataPoints"]) {
+            let value = match &data_point["asDouble"] {
+                Value::Null => int(&data_point["asInt"]).map(|int| int as f64),
+                double => double.as_f64(),
+            };
+            values.push((MetricValue::Suffix(""), data_point, value));
+        }
+    }
+    for kind in ["histogram", "exponentialHistogram", "summary"] {
+        for data_point in array(&metric[kind]["dataPoints"]) {
+            let count = int(&data_point["count"]).map(|count| count as f64);
+            values.push((MetricValue::Suffix(".count"), data_point, count));
+            let sum = data_point["sum"].as_f64();
+            values.push((MetricValue::Suffix(".sum"), data_point, sum));
+            for quantile in array(&data_point["quantileValues"]) {
+                let Some(at) = quantile["quantile"].as_f64() else {
+                    continue;
+                };
+                let value = quantile["value"].as_f64();
+                values.push((MetricValue::Quantile(at), data_point, value));
+            }
+        }
+    }
+    values
+}
+
+/// When the span started, for the event's client datetime.
+pub(crate) fn span_start(payload: &Value) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(payload["start_time"].as_str()?)
+        .ok()
+        .map(|start| start.to_utc())
+}
+
+fn array(value: &Value) -> impl Iterator<Item = &Value> {
+    value.as_array().into_iter().flatten()
+}
+
+/// 64-bit integers are strings in OTLP/JSON, but some exporters send numbers.
+fn int(value: &Value) -> Option<i64> {
+    match value {
+        Value::String(text) => text.parse().ok(),
+        value => value.as_i64(),
+    }
+}
+
+fn nanos(value: &Value) -> Option<i64> {
+    int(value).filter(|nanos| *nanos > 0)
+}
+
+fn rfc3339(nanos: i64) -> String {
+    DateTime::from_timestamp_nanos(nanos).to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+}
+
+/// Key-value lists, as of attributes, as an object.
+fn attributes(list: &Value) -> Value {
+    array(list)
+        .filter_map(|pair| Some((pair["key"].as_str()?.to_owned(), any_value(&pair["value"]))))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+/// An AnyValue as plain JSON. Bytes stay base64.
+fn any_value(value: &Value) -> Value {
+    let Some((kind, inner)) = value.as_object().and_then(|object| object.iter().next()) else {
+        return Value::Null;
+    };
+    match kind.as_str() {
+        "intValue" => int(inner).map_or(Value::Null, Value::from),
+        "arrayValue" => array(&inner["values"]).map(any_value).collect(),
+        "kvlistValue" => attributes(&inner["values"]),
+        _ => inner.clone(),
+    }
+}
+
+/// An enum's number, or its name with the prefix, as some exporters send.
+fn enum_value(value: &Value, prefix: &str, names: &[&'static str]) -> Option<&'static str> {
+    match value {
+        Value::String(name) => {
+            let name = name.strip_prefix(prefix)?;
+            names
+                .iter()
+                .copied()
+                .find(|known| known.eq_ignore_ascii_case(name))
+        }
+        value => names.get(usize::try_from(value.as_u64()?).ok()?).copied(),
+    }
+}
+
+fn span_kind(kind: &Value) -> Option<&'static str> {
+    let names = [
+        "unspecified",
+        "internal",
+        "server",
+        "client",
+        "producer",
+        "consumer",
+    ];
+    enum_value(kind, "SPAN_KIND_", &names).filter(|kind| *kind != "unspecified")
+}
+
+fn status_code(code: &Value) -> &'static str {
+    enum_value(code, "STATUS_CODE_", &["unset", "ok", "error"]).unwrap_or("unset")
+}
+
+/// The response OTLP exporters expect, with how many spans or data points weren't stored, in
+/// `rejected_field`.
+pub(crate) fn export_response(rejected_field: &str, rejected: u64, error: Option<String>) -> Value {
+    match error {
+        None => json!({}),
+        Some(error) => json!({
+            "partialSuccess": {rejected_field: rejected.to_string(), "errorMessage": error},
+        }),
+    }
+}
@@ -0,0 +1,117 @@
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+/// What clients are told to wait before retrying while paused, unless the pause says otherwise.
+pub(crate) const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Holds off writes to storage while it's being worked on underneath the server. Everything that
+/// writes counts itself in flight while it does, so pausing can wait for what's started to finish.
+pub(crate) struct Pause {
+    paused: watch::Sender<bool>,
+    retry_after_secs: AtomicU64,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self {
+            paused: watch::Sender::new(false),
+            retry_after_secs: AtomicU64::new(DEFAULT_RETRY_AFTER.as_secs()),
+            in_flight: Default::default(),
+            idle: Default::default(),
+        }
+    }
+}
+
+/// Counts as in flight until dropped.
+pub(crate) struct InFlight<'a>(&'a Pause);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl Pause {
+    pub(crate) fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Counts the caller in flight, unless paused.
+    pub(crate) fn try_enter(&self) -> Option<InFlight<'_>> {
+        // Counted before checking, so that pausing either sees the count or is seen here.
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(self);
+        match self.is_paused() {
+            true => None,
+            false => Some(in_flight),
+        }
+    }
+
+    /// Waits until not paused, and counts the caller in flight.
+    pub(crate) async fn enter(&self) -> InFlight<'_> {
+        let mut paused = self.paused.subscribe();
+        loop {
+            if let Some(in_flight) = self.try_enter() {
+                return in_flight;
+            }
+            // Only fails if the sender's gone, which it isn't while self is borrowed.
+            let _ = paused.wait_for(|paused| !paused).await;
+        }
+    }
+
+    /// Stops new writes, and waits up to the timeout for those in flight to finish. Returns
+    /// whether they did.
+    pub(crate) async fn pause(&self, retry_after: Duration, timeout: Duration) -> bool {
+        self.retry_after_secs
+            .store(retry_after.as_secs(), Ordering::Relaxed);
+        self.paused.send_replace(true);
+        tokio::time::timeout(timeout, async {
+            loop {
+                let idle = self.idle.notified();
+                if self.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    pub(crate) fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "paused": self.is_paused(),
+            "in_flight": self.in_flight.load(Ordering::SeqCst),
+        })
+    }
+}
+
+/// Middleware turning requests away with 503 Service Unavailable and a Retry-After while paused,
+/// and counting them in flight otherwise.
+pub(crate) async fn guard(State(pause): State<Arc<Pause>>, req: Request, next: Next) -> Response {
+    let Some(_in_flight) = pause.try_enter() else {
+        let retry_after = pause.retry_after_secs.load(Ordering::Relaxed);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            "paused for maintenance",
+        )
+            .into_response();
+    };
+    next.run(req).await
+}
@@ -0,0 +1,135 @@
+mod normalize;
+mod redact;
+mod transform;
+pub(crate) use normalize::*;
+pub(crate) use redact::Redact;
+pub use redact::RedactArgs;
+pub use transform::TransformArgs;
+pub(crate) use transform::{Route, Transform};
+
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+
+/// A stage that rewrites event payloads on their way into storage.
+pub(crate) trait Processor: Send + Sync {
+    /// Identifies the processor in pipeline tests.
+    fn name(&self) -> &'static str;
+    fn process(&self, payload: &mut serde_json::Value) -> Result<()>;
+    /// Counters of what the processor has done, if it keeps any.
+    fn stats(&self) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+/// The processors applied to every event before insertion, in order, and then the transform
+/// script if there is one.
+#[derive(Default)]
+pub(crate) struct Pipeline {
+    processors: Vec<Box<dyn Processor>>,
+    transform: Option<Transform>,
+}
+
+impl Pipeline {
+    /// Redaction goes first, so its rules are written against payloads as clients send them. The
+    /// script goes last, so it sees payloads as they'd be stored.
+    pub(crate) fn new(
+        redact: Option<Redact>,
+        normalize: bool,
+        transform: Option<Transform>,
+    ) -> Self {
+        let mut pipeline = Self::default();
+        if let Some(redact) = redact {
+            pipeline.push(redact);
+        }
+        if normalize {
+            pipeline.push(Normalize);
+        }
+        pipeline.transform = transform;
+        pipeline
+    }
+
+    pub(crate) fn push(&mut self, processor: impl Processor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    fn run(&self, value: &mut serde_json::Value) -> Result<Route> {
+        for processor in &self.processors {
+            processor.process(value)?;
+        }
+        match &self.transform {
+            Some(transform) => transform.apply(value),
+            None => Ok(Route::Store),
+        }
+    }
+
+    /// Returns the payload to store, and where. Payloads are passed through untouched when there's
+    /// nothing to do, to avoid reparsing them.
+    pub(crate) fn route<'a>(&self, payload: &'a str) -> Result<(Cow<'a, str>, Route)> {
+        if self.processors.is_empty() && self.transform.is_none() {
+            return Ok((Cow::Borrowed(payload), Route::Store));
+        }
+        let mut value: serde_json::Value = serde_json::from_str(payload)?;
+        let route = self.run(&mut value)?;
+        Ok((Cow::Owned(value.to_string()), route))
+    }
+
+    /// Like [Self::route], for payloads that can only be stored in place, like revisions.
+    pub(crate) fn process<'a>(&self, payload: &'a str) -> Result<Cow<'a, str>> {
+        match self.route(payload)? {
+            (payload, Route::Store) => Ok(payload),
+            (_, Route::Drop) => bail!("dropped by the transform script"),
+            (_, Route::Sink(sink)) => bail!("routed to sink {} by the transform script", sink),
+        }
+    }
+
+    /// Like [Self::route], but always parses the payload, and also returns the names of the
+    /// processors that changed it.
+    pub(crate) fn trace(
+        &self,
+        payload: &str,
+    ) -> Result<(serde_json::Value, Vec<&'static str>, Route)> {
+        let mut value: serde_json::Value = serde_json::from_str(payload)?;
+        let mut changed_by = vec![];
+        for processor in &self.processors {
+            let before = value.clone();
+            processor.process(&mut value)?;
+            if value != before {
+                changed_by.push(processor.name());
+            }
+        }
+        let mut route = Route::Store;
+        if let Some(transform) = &self.transform {
+            let before = value.clone();
+            route = transform.apply(&mut value)?;
+            if value != before {
+                changed_by.push(transform.name());
+            }
+        }
+        Ok((value, changed_by, route))
+    }
+
+    /// The counters of each processor that keeps them, by name.
+    pub(crate) fn stats(&self) -> serde_json::Value {
+        let stats = self
+            .processors
+            .iter()
+            .filter_map(|processor| Some((processor.name().to_owned(), processor.stats()?)))
+            .chain(
+                self.transform
+                    .iter()
+                    .map(|transform| (transform.name().to_owned(), transform.stats())),
+            )
+            .collect();
+        serde_json::Value::Object(stats)
+    }
+
+    /// Reruns a stored payload through the processors. Returns None if they leave it unchanged.
+    /// Only rewrites apply, since the event's already stored: a script dropping or routing it
+    /// leaves it where it is.
+    pub(crate) fn reprocess(&self, payload: &str) -> Result<Option<String>> {
+        let original: serde_json::Value = serde_json::from_str(payload)?;
+        let mut value = original.clone();
+        self.run(&mut value)?;
+        Ok((value != original).then(|| value.to_string()))
+    }
+}
@@ -0,0 +1,133 @@
+use super::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Number, Value};
+
+/// Field names that are taken to hold timestamps.
+const TIMESTAMP_FIELDS: &[&str] = &["time", "timestamp", "ts", "datetime"];
+const TIMESTAMP_FIELD_SUFFIXES: &[&str] = &["_time", "_timestamp", "_at"];
+
+/// Field name suffixes denoting units, what they're renamed to, and the factor that converts to the
+/// canonical unit.
+const UNIT_SUFFIXES: &[(&str, &str, f64)] = &[
+    ("_ns", "_s", 1e-9),
+    ("_us", "_s", 1e-6),
+    ("_ms", "_s", 1e-3),
+    ("_kb", "_bytes", 1e3),
+    ("_mb", "_bytes", 1e6),
+    ("_gb", "_bytes", 1e9),
+    ("_kib", "_bytes", 1024.),
+    ("_mib", "_bytes", 1024. * 1024.),
+    ("_gib", "_bytes", 1024. * 1024. * 1024.),
+];
+
+/// Converts recognized timestamp fields to RFC 3339 UTC, and unit-suffixed numeric fields to
+/// seconds or bytes, so queries don't need to know each producer's conventions.
+pub(crate) struct Normalize;
+
+impl Processor for Normalize {
+    fn name(&self) -> &'static str {
+        "normalize"
+    }
+    fn process(&self, payload: &mut Value) -> Result<()> {
+        normalize_value(payload);
+        Ok(())
+    }
+}
+
+fn normalize_value(value: &mut Value) {
+    match value {
+        Value::Object(object) => normalize_object(object),
+        Value::Array(array) => array.iter_mut().for_each(normalize_value),
+        _ => {}
+    }
+}
+
+fn normalize_object(object: &mut Map<String, Value>) {
+    let mut renames = vec![];
+    for (key, value) in object.iter_mut() {
+        let lower_key = key.to_lowercase();
+        if is_timestamp_field(&lower_key) {
+            if let Some(timestamp) = parse_timestamp(value) {
+                *value = Value::String(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+            }
+            continue;
+        }
+        if let Some(rename) = unit_rename(key, &lower_key, value) {
+            renames.push(rename);
+            continue;
+        }
+        normalize_value(value);
+    }
+    for (old_key, new_key, new_value) in renames {
+        // Don't clobber a field the producer already supplied in canonical units.
+        if object.contains_key(&new_key) {
+            continue;
+        }
+        object.remove(&old_key);
+        object.insert(new_key, new_value);
+    }
+}
+
+fn is_timestamp_field(lower_key: &str) -> bool {
+    TIMESTAMP_FIELDS.contains(&lower_key)
+        || TIMESTAMP_FIELD_SUFFIXES
+            .iter()
+            .any(|suffix| lower_key.ends_with(suffix))
+}
+
+/// When the payload says its event happened, from a top-level timestamp field, for storing
+/// alongside the time the server received it.
+pub(crate) fn payload_timestamp(payload: &str) -> Option<DateTime<Utc>> {
+    // The names in TIMESTAMP_FIELDS, in order of preference.
+    #[derive(serde::Deserialize)]
+    struct TimestampFields {
+        timestamp: Option<Value>,
+        time: Option<Value>,
+        ts: Option<Value>,
+        datetime: Option<Value>,
+    }
+    let fields: TimestampFields = serde_json::from_str(payload).ok()?;
+    [fields.timestamp, fields.time, fields.ts, fields.datetime]
+        .iter()
+        .flatten()
+        .find_map(parse_timestamp)
+}
+
+pub(crate) fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .or_else(|_| DateTime::parse_from_rfc2822(s))
+            .ok()
+            .map(|timestamp| timestamp.with_timezone(&Utc)),
+        // Epoch numbers. Anything too big to be seconds in this era is taken to be milliseconds.
+        Value::Number(number) => {
+            let number = number.as_f64()?;
+            let millis = if number.abs() < 1e11 {
+                number * 1e3
+            } else {
+                number
+            };
+            DateTime::from_timestamp_millis(millis as i64)
+        }
+        _ => None,
+    }
+}
+
+fn unit_rename(key: &str, lower_key: &str, value: &Value) -> Option<(String, String, Value)> {
+    let number = value.as_f64()?;
+    let (suffix, canonical_suffix, factor) = UNIT_SUFFIXES
+        .iter()
+        .find(|(suffix, _, _)| lower_key.ends_with(suffix))?;
+    let stem = &key[..key.len() - suffix.len()];
+    let converted = number * factor;
+    let new_value = if converted.fract() == 0.0 && converted.abs() < i64::MAX as f64 {
+        Value::Number((converted as i64).into())
+    } else {
+        Value::Number(Number::from_f64(converted)?)
+    };
+    Some((
+        key.to_owned(),
+        format!("{}{}", stem, canonical_suffix),
+        new_value,
+    ))
+}
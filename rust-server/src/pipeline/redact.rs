@@ -0,0 +1,298 @@
+use super::*;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What redacted values and matches are replaced with.
+const MASK: &str = "[REDACTED]";
+
+/// Rules for scrubbing sensitive values out of payloads before they're stored. Pointers are JSON
+/// pointers like "/user/email", where a "*" segment matches every member of an object or element
+/// of an array.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct RedactArgs {
+    /// Replace the value at this JSON pointer with "[REDACTED]".
+    #[arg(long, value_parser = parse_pointer)]
+    redact_mask: Vec<Pointer>,
+    /// Remove the value at this JSON pointer.
+    #[arg(long, value_parser = parse_pointer)]
+    redact_remove: Vec<Pointer>,
+    /// Replace matches of this regex in any string value with "[REDACTED]".
+    #[arg(long)]
+    redact_pattern: Vec<Regex>,
+    /// Built-in patterns to replace in any string value, comma-separated.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    redact_builtin: Vec<BuiltinPattern>,
+}
+
+impl RedactArgs {
+    /// None if there are no rules.
+    pub(crate) fn redactor(&self) -> Option<Redact> {
+        let mut patterns: Vec<Pattern> = self
+            .redact_pattern
+            .iter()
+            .map(|regex| Pattern {
+                regex: regex.clone(),
+                check: None,
+            })
+            .collect();
+        patterns.extend(self.redact_builtin.iter().map(|builtin| builtin.pattern()));
+        if self.redact_mask.is_empty() && self.redact_remove.is_empty() && patterns.is_empty() {
+            return None;
+        }
+        Some(Redact {
+            mask: self.redact_mask.clone(),
+            remove: self.redact_remove.clone(),
+            patterns,
+            stats: Default::default(),
+        })
+    }
+
+    /// Parses a single value of one of the flags, for config files to check.
+    pub(crate) fn check(flag: &str, value: &str) -> Result<()> {
+        match flag {
+            "redact-mask" | "redact-remove" => parse_pointer(value).map(drop),
+            "redact-pattern" => Ok(Regex::new(value).map(drop)?),
+            _ => <BuiltinPattern as clap::ValueEnum>::from_str(value, false)
+                .map(drop)
+                .map_err(|_| anyhow::anyhow!("unknown built-in pattern {:?}", value)),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "mask": self.redact_mask.iter().map(|pointer| &pointer.source).collect::<Vec<_>>(),
+            "remove": self.redact_remove.iter().map(|pointer| &pointer.source).collect::<Vec<_>>(),
+            "patterns": self.redact_pattern.iter().map(Regex::as_str).collect::<Vec<_>>(),
+            "builtin": self.redact_builtin.iter().map(|builtin| builtin.name()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub(crate) enum BuiltinPattern {
+    Email,
+    Ipv4,
+    Ipv6,
+    /// "Bearer" followed by a token, as in Authorization headers.
+    Bearer,
+    /// JSON web tokens.
+    Jwt,
+}
+
+impl BuiltinPattern {
+    fn name(self) -> &'static str {
+        match self {
+            BuiltinPattern::Email => "email",
+            BuiltinPattern::Ipv4 => "ipv4",
+            BuiltinPattern::Ipv6 => "ipv6",
+            BuiltinPattern::Bearer => "bearer",
+            BuiltinPattern::Jwt => "jwt",
+        }
+    }
+
+    fn pattern(self) -> Pattern {
+        let (regex, check): (_, Option<MatchCheck>) = match self {
+            BuiltinPattern::Email => (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+", None),
+            BuiltinPattern::Ipv4 => (
+                r"\b(?:(?:25[0-5]|2[0-4][0-9]|1?[0-9]?[0-9])\.){3}(?:25[0-5]|2[0-4][0-9]|1?[0-9]?[0-9])\b",
+                None,
+            ),
+            // Too loose by itself, since it matches times and Rust paths, so candidates are
+            // checked by parsing.
+            BuiltinPattern::Ipv6 => (r"[0-9A-Fa-f:]*:[0-9A-Fa-f:]*", Some(is_ipv6)),
+            BuiltinPattern::Bearer => (r"(?i)\bbearer\s+[A-Za-z0-9._~+/-]+=*", None),
+            BuiltinPattern::Jwt => (r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*", None),
+        };
+        Pattern {
+            regex: Regex::new(regex).unwrap(),
+            check,
+        }
+    }
+}
+
+/// An address with at least three groups, so "::" and "::1" aren't taken for one.
+fn is_ipv6(candidate: &str) -> bool {
+    candidate.parse::<std::net::Ipv6Addr>().is_ok()
+        && candidate
+            .split(':')
+            .filter(|group| !group.is_empty())
+            .count()
+            >= 3
+}
+
+/// Matches it rejects are left alone.
+type MatchCheck = fn(&str) -> bool;
+
+#[derive(Clone, Debug)]
+struct Pattern {
+    regex: Regex,
+    check: Option<MatchCheck>,
+}
+
+/// A JSON pointer, split into unescaped segments.
+#[derive(Clone, Debug)]
+pub(crate) struct Pointer {
+    source: String,
+    segments: Vec<String>,
+}
+
+fn parse_pointer(source: &str) -> Result<Pointer> {
+    let Some(rest) = source.strip_prefix('/') else {
+        anyhow::bail!("JSON pointer must start with /");
+    };
+    let segments = rest
+        .split('/')
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect();
+    Ok(Pointer {
+        source: source.to_owned(),
+        segments,
+    })
+}
+
+/// Running totals of what redaction has changed since the server started.
+#[derive(Default)]
+struct RedactionStats {
+    /// Events with anything redacted.
+    events: AtomicU64,
+    masked: AtomicU64,
+    removed: AtomicU64,
+    pattern_matches: AtomicU64,
+}
+
+/// Masks or removes values at pointers, and then masks pattern matches in what's left.
+pub(crate) struct Redact {
+    mask: Vec<Pointer>,
+    remove: Vec<Pointer>,
+    patterns: Vec<Pattern>,
+    stats: RedactionStats,
+}
+
+impl Processor for Redact {
+    fn name(&self) -> &'static str {
+        "redact"
+    }
+
+    fn process(&self, payload: &mut Value) -> Result<()> {
+        let mut masked = 0;
+        for pointer in &self.mask {
+            masked += at_pointer(payload, &pointer.segments, &mut |parent, key| {
+                match parent {
+                    Value::Object(object) => object.get_mut(key),
+                    Value::Array(array) => key.parse().ok().and_then(|i: usize| array.get_mut(i)),
+                    _ => None,
+                }
+                .map(|value| *value = Value::String(MASK.to_owned()))
+                .is_some()
+            });
+        }
+        let mut removed = 0;
+        for pointer in &self.remove {
+            removed += at_pointer(
+                payload,
+                &pointer.segments,
+                &mut |parent, key| match parent {
+                    Value::Object(object) => object.remove(key).is_some(),
+                    Value::Array(array) => match key.parse::<usize>() {
+                        Ok(index) if index < array.len() => {
+                            array.remove(index);
+                            true
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                },
+            );
+        }
+        let mut pattern_matches = 0;
+        if !self.patterns.is_empty() {
+            for_each_string(payload, &mut |s| {
+                for pattern in &self.patterns {
+                    let mut matches = 0;
+                    let replaced = pattern.regex.replace_all(s, |captures: &regex::Captures| {
+                        let found = &captures[0];
+                        if pattern.check.is_some_and(|check| !check(found)) {
+                            return found.to_owned();
+                        }
+                        matches += 1;
+                        MASK.to_owned()
+                    });
+                    if matches > 0 {
+                        *s = replaced.into_owned();
+                        pattern_matches += matches;
+                    }
+                }
+            });
+        }
+        let stats = &self.stats;
+        if masked + removed + pattern_matches > 0 {
+            stats.events.fetch_add(1, Ordering::Relaxed);
+        }
+        stats.masked.fetch_add(masked, Ordering::Relaxed);
+        stats.removed.fetch_add(removed, Ordering::Relaxed);
+        stats
+            .pattern_matches
+            .fetch_add(pattern_matches, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn stats(&self) -> Option<Value> {
+        let stats = &self.stats;
+        Some(json!({
+            "events": stats.events.load(Ordering::Relaxed),
+            "masked": stats.masked.load(Ordering::Relaxed),
+            "removed": stats.removed.load(Ordering::Relaxed),
+            "pattern_matches": stats.pattern_matches.load(Ordering::Relaxed),
+        }))
+    }
+}
+
+/// Calls `f` with the parent and last segment of each value the pointer reaches, returning how
+/// many calls returned true. A "*" segment goes through every child.
+fn at_pointer(
+    value: &mut Value,
+    segments: &[String],
+    f: &mut dyn FnMut(&mut Value, &str) -> bool,
+) -> u64 {
+    let [first, rest @ ..] = segments else {
+        return 0;
+    };
+    if rest.is_empty() {
+        if first != "*" {
+            return f(value, first) as u64;
+        }
+        // Removing shifts array elements, so go from the end.
+        let keys: Vec<String> = match value {
+            Value::Object(object) => object.keys().cloned().collect(),
+            Value::Array(array) => (0..array.len()).rev().map(|i| i.to_string()).collect(),
+            _ => vec![],
+        };
+        return keys.iter().map(|key| f(value, key) as u64).sum();
+    }
+    let children: Vec<&mut Value> = match (value, first.as_str()) {
+        (Value::Object(object), "*") => object.values_mut().collect(),
+        (Value::Array(array), "*") => array.iter_mut().collect(),
+        (Value::Object(object), key) => object.get_mut(key).into_iter().collect(),
+        (Value::Array(array), key) => key
+            .parse()
+            .ok()
+            .and_then(|i: usize| array.get_mut(i))
+            .into_iter()
+            .collect(),
+        _ => vec![],
+    };
+    children
+        .into_iter()
+        .map(|child| at_pointer(child, rest, f))
+        .sum()
+}
+
+fn for_each_string(value: &mut Value, f: &mut dyn FnMut(&mut String)) {
+    match value {
+        Value::String(s) => f(s),
+        Value::Array(array) => array.iter_mut().for_each(|v| for_each_string(v, f)),
+        Value::Object(object) => object.values_mut().for_each(|v| for_each_string(v, f)),
+        _ => {}
+    }
+}
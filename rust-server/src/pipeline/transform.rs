@@ -0,0 +1,243 @@
+use super::*;
+use anyhow::{anyhow, bail, Context};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use wasmi::{Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Most memory a script can grow to.
+const MAX_SCRIPT_MEMORY_BYTES: usize = 64 << 20;
+
+/// A WebAssembly module that can rewrite, drop or route each event, for rules too specific to
+/// build into the server.
+///
+/// The module exports its `memory`, `alloc(len: i32) -> i32`, and `transform(ptr: i32, len: i32)
+/// -> i64`. The server allocates room for the payload's JSON, writes it there, and calls
+/// `transform`, which returns the address of its output in the high 32 bits and its length in the
+/// low 32. The output is JSON: `{"payload": ...}` stores a rewritten payload, with a `"sink"` to
+/// store it in that --sink instead, and `{"drop": true}` drops the event. Empty output stores the
+/// event as it was. If the module exports `dealloc(ptr: i32, len: i32)`, it's called to free the
+/// input and output.
+#[derive(Clone, clap::Args)]
+pub struct TransformArgs {
+    /// WebAssembly module to run each event through, as a .wasm file or .wat text, after the
+    /// other processors.
+    #[arg(long, value_parser = load_script)]
+    transform_script: Option<Script>,
+    /// Fuel a script can use per event, roughly one per instruction. Events that run out fail to
+    /// store.
+    #[arg(long, default_value_t = 10_000_000)]
+    transform_fuel: u64,
+}
+
+/// A compiled script, shared by every clone of the args.
+#[derive(Clone)]
+pub(crate) struct Script {
+    path: PathBuf,
+    engine: Engine,
+    module: Arc<Module>,
+}
+
+fn load_script(path: &str) -> Result<Script> {
+    let wasm = wat::parse_file(path).with_context(|| format!("reading {}", path))?;
+    let mut config = wasmi::Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, &wasm).with_context(|| format!("compiling {}", path))?;
+    for export in ["memory", "alloc", "transform"] {
+        if module.get_export(export).is_none() {
+            bail!("{} doesn't export {:?}", path, export);
+        }
+    }
+    Ok(Script {
+        path: path.into(),
+        engine,
+        module: Arc::new(module),
+    })
+}
+
+impl TransformArgs {
+    /// None if there's no script.
+    pub(crate) fn transform(&self) -> Option<Transform> {
+        let script = self.transform_script.clone()?;
+        Some(Transform {
+            script,
+            fuel: self.transform_fuel,
+            instance: Mutex::new(None),
+            stats: Default::default(),
+        })
+    }
+
+    /// Checks a --transform-script, for config files.
+    pub(crate) fn check(path: &str) -> Result<()> {
+        load_script(path).map(drop)
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "script": self.transform_script.as_ref().map(|script| &script.path),
+            "fuel": self.transform_fuel,
+        })
+    }
+}
+
+/// What a script decided to do with an event.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Route {
+    Store,
+    Drop,
+    /// Store in the named sink instead.
+    Sink(String),
+}
+
+struct Running {
+    store: Store<StoreLimits>,
+    instance: Instance,
+    memory: Memory,
+}
+
+#[derive(Default)]
+struct TransformStats {
+    rewritten: AtomicU64,
+    dropped: AtomicU64,
+    routed: Mutex<BTreeMap<String, u64>>,
+    errors: AtomicU64,
+}
+
+/// Runs events through a script, one at a time.
+pub(crate) struct Transform {
+    script: Script,
+    fuel: u64,
+    /// Started on first use, and again after a script fails, in case it was left in a bad state.
+    instance: Mutex<Option<Running>>,
+    stats: TransformStats,
+}
+
+impl Transform {
+    pub(crate) fn name(&self) -> &'static str {
+        "transform"
+    }
+
+    fn start(&self) -> Result<Running> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_SCRIPT_MEMORY_BYTES)
+            .build();
+        let mut store = Store::new(&self.script.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel).map_err(wasmi::Error::from)?;
+        let linker = Linker::new(&self.script.engine);
+        let instance = linker
+            .instantiate(&mut store, &self.script.module)?
+            .start(&mut store)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| anyhow!("script doesn't export its memory"))?;
+        Ok(Running {
+            store,
+            instance,
+            memory,
+        })
+    }
+
+    /// Rewrites the payload in place, and returns where it goes.
+    pub(crate) fn apply(&self, payload: &mut Value) -> Result<Route> {
+        let mut instance = self.instance.lock().unwrap();
+        let running = match &mut *instance {
+            Some(running) => running,
+            none => none.insert(self.start().context("starting transform script")?),
+        };
+        let output = call(running, self.fuel, payload.to_string().as_bytes());
+        let output = match output {
+            Ok(output) => output,
+            Err(err) => {
+                *instance = None;
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                return Err(err.context("running transform script"));
+            }
+        };
+        drop(instance);
+        let route = self.parse_output(&output, payload);
+        if route.is_err() {
+            self.stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        route
+    }
+
+    fn parse_output(&self, output: &[u8], payload: &mut Value) -> Result<Route> {
+        if output.is_empty() {
+            return Ok(Route::Store);
+        }
+        let mut output: Value =
+            serde_json::from_slice(output).context("parsing transform script output")?;
+        if output["drop"] == true {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(Route::Drop);
+        }
+        let Some(new_payload) = output.get_mut("payload").map(Value::take) else {
+            bail!("transform script output has no payload");
+        };
+        if new_payload != *payload {
+            self.stats.rewritten.fetch_add(1, Ordering::Relaxed);
+            *payload = new_payload;
+        }
+        let Some(sink) = output.get("sink") else {
+            return Ok(Route::Store);
+        };
+        let Some(sink) = sink.as_str() else {
+            bail!("transform script output's sink isn't a string: {}", sink);
+        };
+        *self
+            .stats
+            .routed
+            .lock()
+            .unwrap()
+            .entry(sink.to_owned())
+            .or_default() += 1;
+        Ok(Route::Sink(sink.to_owned()))
+    }
+
+    pub(crate) fn stats(&self) -> Value {
+        let stats = &self.stats;
+        json!({
+            "rewritten": stats.rewritten.load(Ordering::Relaxed),
+            "dropped": stats.dropped.load(Ordering::Relaxed),
+            "routed": *stats.routed.lock().unwrap(),
+            "errors": stats.errors.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Passes the input to the script's transform function, and returns a copy of its output.
+fn call(running: &mut Running, fuel: u64, input: &[u8]) -> Result<Vec<u8>> {
+    let Running {
+        store,
+        instance,
+        memory,
+    } = running;
+    store.set_fuel(fuel).map_err(wasmi::Error::from)?;
+    let len = i32::try_from(input.len()).context("payload too big for script")?;
+    let alloc = instance.get_typed_func::<i32, i32>(&*store, "alloc")?;
+    let transform = instance.get_typed_func::<(i32, i32), i64>(&*store, "transform")?;
+    let dealloc = instance
+        .get_typed_func::<(i32, i32), ()>(&*store, "dealloc")
+        .ok();
+    let input_ptr = alloc.call(&mut *store, len)?;
+    memory
+        .write(&mut *store, input_ptr as u32 as usize, input)
+        .map_err(wasmi::Error::from)?;
+    let result = transform.call(&mut *store, (input_ptr, len))?;
+    let (output_ptr, output_len) = ((result >> 32) as u32, result as u32);
+    let mut output = vec![0; output_len as usize];
+    memory
+        .read(&*store, output_ptr as usize, &mut output)
+        .map_err(wasmi::Error::from)?;
+    if let Some(dealloc) = dealloc {
+        dealloc.call(&mut *store, (input_ptr, len))?;
+        if output_len > 0 {
+            dealloc.call(&mut *store, (output_ptr as i32, output_len as i32))?;
+        }
+    }
+    Ok(output)
+}
@@ -0,0 +1,83 @@
+use crate::pipeline::Route;
+use crate::routing::Routes;
+use crate::{iter_json_stream, payload_event_id, Args};
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Runs sample payloads through ingestion with the rest of the command line's settings, and prints
+/// a JSON line for each event saying what would be stored, and where. Routes on headers don't
+/// match, as there aren't any. Nothing is stored.
+#[derive(Clone, clap::Args)]
+pub(crate) struct PipelineTestArgs {
+    /// Files of payloads, one after another as in a request body.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+}
+
+impl PipelineTestArgs {
+    pub(crate) async fn run(&self, args: &Args, out: &mut impl Write) -> Result<()> {
+        let pipeline = args.pipeline();
+        let routes = args.routing.routes();
+        for path in &self.files {
+            let body =
+                std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+            let mut payloads = vec![];
+            let chunks = futures::stream::iter([Ok(Bytes::from(body))]);
+            iter_json_stream(chunks, args.limits.max_event_bytes, |payload| {
+                payloads.push(payload);
+                std::future::ready(Ok(()))
+            })
+            .await
+            .map_err(|err| err.err)
+            .with_context(|| format!("splitting {} into payloads", path.display()))?;
+            for (index, payload) in payloads.iter().enumerate() {
+                let mut line = json!({"file": path, "index": index + 1});
+                match process(args, &pipeline, routes.as_ref(), payload) {
+                    Ok((event_id, payload, changed_by, route)) => {
+                        line["event_id"] = json!(event_id);
+                        line["changed_by"] = json!(changed_by);
+                        line["payload"] = payload;
+                        line["stored"] = json!(route != Route::Drop);
+                        if let Route::Sink(sink) = route {
+                            line["sink"] = json!(sink);
+                        }
+                    }
+                    Err(err) => {
+                        line["stored"] = json!(false);
+                        line["rejected"] = json!(format!("{:#}", err));
+                    }
+                }
+                serde_json::to_writer(&mut *out, &line)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The steps ingestion takes with a payload before storing it.
+fn process(
+    args: &Args,
+    pipeline: &crate::Pipeline,
+    routes: Option<&Routes>,
+    payload: &[u8],
+) -> Result<(Option<String>, Value, Vec<&'static str>, Route)> {
+    let payload = args
+        .legacy_encoding
+        .decode(payload)
+        .context("decoding payload text")?;
+    let event_id = payload_event_id(&payload);
+    args.limits.check(&payload)?;
+    let (payload, changed_by, mut route) =
+        pipeline.trace(&payload).context("processing payload")?;
+    if let (Route::Store, Some(routes)) = (&route, routes) {
+        if let Some(sink) = routes.route(&HeaderMap::new(), &payload) {
+            route = Route::Sink(sink.to_owned());
+        }
+    }
+    Ok((event_id, payload, changed_by, route))
+}
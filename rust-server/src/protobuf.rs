@@ -0,0 +1,124 @@
+use anyhow::{bail, Result};
+
+/// A field's value off the wire. Which type it is depends on the message's schema.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum WireValue<'a> {
+    Varint(u64),
+    /// Strings, bytes, nested messages and packed repeated fields.
+    Bytes(&'a [u8]),
+    /// Fixed-width numbers, which no message read here has, so they're only skipped.
+    Fixed,
+}
+
+impl<'a> WireValue<'a> {
+    pub(crate) fn bytes(self) -> Result<&'a [u8]> {
+        match self {
+            WireValue::Bytes(bytes) => Ok(bytes),
+            value => bail!("expected a length-delimited field, not {:?}", value),
+        }
+    }
+
+    pub(crate) fn string(self) -> Result<String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    pub(crate) fn varint(self) -> Result<u64> {
+        match self {
+            WireValue::Varint(value) => Ok(value),
+            value => bail!("expected a varint field, not {:?}", value),
+        }
+    }
+}
+
+/// The fields of an encoded message, in order, by field number. Fields of known numbers are
+/// interpreted by the caller, and the rest skipped, as protobuf parsers do.
+pub(crate) fn fields(mut message: &[u8]) -> impl Iterator<Item = Result<(u32, WireValue<'_>)>> {
+    std::iter::from_fn(move || {
+        if message.is_empty() {
+            return None;
+        }
+        let field = (|| {
+            let key = varint(&mut message)?;
+            let number = u32::try_from(key >> 3)?;
+            let value = match key & 7 {
+                0 => WireValue::Varint(varint(&mut message)?),
+                1 => take(&mut message, 8).map(|_| WireValue::Fixed)?,
+                2 => {
+                    let len = usize::try_from(varint(&mut message)?)?;
+                    WireValue::Bytes(take(&mut message, len)?)
+                }
+                5 => take(&mut message, 4).map(|_| WireValue::Fixed)?,
+                wire_type => bail!("unsupported wire type {}", wire_type),
+            };
+            Ok((number, value))
+        })();
+        if field.is_err() {
+            // Nothing after a bad field can be read.
+            message = &[];
+        }
+        Some(field)
+    })
+}
+
+/// Reads a base 128 varint, as protobuf and snappy use.
+pub(crate) fn varint(input: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = take(input, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint is too long")
+}
+
+/// Takes the next `len` bytes of the input.
+pub(crate) fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        bail!("input is truncated");
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+/// Builds an encoded message, field by field.
+#[derive(Default)]
+pub(crate) struct MessageBuilder(Vec<u8>);
+
+impl MessageBuilder {
+    pub(crate) fn varint(mut self, number: u32, value: u64) -> Self {
+        put_varint(&mut self.0, u64::from(number) << 3);
+        put_varint(&mut self.0, value);
+        self
+    }
+
+    pub(crate) fn bytes(mut self, number: u32, value: &[u8]) -> Self {
+        put_varint(&mut self.0, u64::from(number) << 3 | 2);
+        put_varint(&mut self.0, value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    pub(crate) fn string(self, number: u32, value: &str) -> Self {
+        self.bytes(number, value.as_bytes())
+    }
+
+    pub(crate) fn message(self, number: u32, message: MessageBuilder) -> Self {
+        self.bytes(number, &message.0)
+    }
+
+    pub(crate) fn build(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Appends a base 128 varint.
+pub(crate) fn put_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
@@ -0,0 +1,126 @@
+use crate::import::Imported;
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta};
+use std::collections::BTreeSet;
+use std::path::Path;
+use telemetry_storage::{Connection, EventCursor, ImportedStream, StreamId};
+use tracing::info;
+
+/// Events are copied in transactions of this many.
+const REPLICATE_BATCH_SIZE: usize = 1000;
+
+/// Each run starts this long before the last event copied, for events that were committed after
+/// it with an earlier insert time. Events that were already copied are skipped.
+const REPLICATION_OVERLAP: TimeDelta = TimeDelta::minutes(1);
+
+/// How far replication got, kept in a file between runs.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct ReplicationState {
+    /// The insert time of the last event copied, as RFC 3339.
+    replicated_until: Option<String>,
+    /// Streams that hadn't ended when they were copied, so their ends are still to come.
+    #[serde(default)]
+    open_streams: BTreeSet<u32>,
+}
+
+impl ReplicationState {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("reading {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// Replaces the file in one step, so an interrupted run leaves the last state whole.
+    fn save(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.as_file().sync_all()?;
+        file.persist(path)
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Copies the streams and events stored in `from` since the last run recorded in the state file,
+/// with their IDs and times, and the ends of streams copied before they ended. The state is saved
+/// after each batch, so an interrupted run carries on from there.
+pub(crate) async fn replicate(
+    from: &mut (dyn Connection + Send),
+    to: &mut (dyn Connection + Send),
+    state_path: &Path,
+) -> Result<Imported> {
+    let mut state = ReplicationState::load(state_path)?;
+    let mut cursor = match &state.replicated_until {
+        Some(until) => Some(EventCursor {
+            insert_datetime: DateTime::parse_from_rfc3339(until)
+                .with_context(|| format!("reading {}", state_path.display()))?
+                .to_utc()
+                - REPLICATION_OVERLAP,
+            stream_id: StreamId(0),
+            stream_event_index: 0,
+        }),
+        None => None,
+    };
+    let mut imported = Imported::default();
+    let mut copied_streams = BTreeSet::new();
+    loop {
+        let events = from
+            .events_after(cursor.as_ref(), REPLICATE_BATCH_SIZE)
+            .await?;
+        let Some(last) = events.last() else {
+            break;
+        };
+        cursor = Some(EventCursor::after(last));
+        let stream_ids: BTreeSet<u32> = events.iter().map(|event| event.stream_id.0).collect();
+        copy_streams(from, to, &stream_ids, &mut state).await?;
+        copied_streams.extend(stream_ids);
+        imported.add_events(to, &events).await?;
+        state.replicated_until = Some(last.insert_datetime.to_rfc3339());
+        state.save(state_path)?;
+    }
+    // Streams can end after their last event was copied.
+    let open_streams = state.open_streams.clone();
+    copy_streams(from, to, &open_streams, &mut state).await?;
+    copied_streams.extend(open_streams);
+    state.save(state_path)?;
+    imported.streams = copied_streams.len() as u64;
+    info!(
+        imported.streams,
+        imported.events,
+        imported.skipped_events,
+        open_streams = state.open_streams.len(),
+        "replicated"
+    );
+    Ok(imported)
+}
+
+/// Copies the streams, and notes which are still open. Streams no longer in `from` are forgotten.
+async fn copy_streams(
+    from: &mut (dyn Connection + Send),
+    to: &mut (dyn Connection + Send),
+    stream_ids: &BTreeSet<u32>,
+    state: &mut ReplicationState,
+) -> Result<()> {
+    if stream_ids.is_empty() {
+        return Ok(());
+    }
+    let stream_ids: Vec<StreamId> = stream_ids.iter().copied().map(StreamId).collect();
+    let streams: Vec<ImportedStream> = from.streams_by_id(&stream_ids).await?;
+    to.import_streams(&streams).await?;
+    for stream_id in stream_ids {
+        state.open_streams.remove(&stream_id.0);
+    }
+    for stream in streams {
+        if stream.end_datetime.is_none() {
+            state.open_streams.insert(stream.stream_id.0);
+        }
+    }
+    Ok(())
+}
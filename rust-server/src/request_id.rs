@@ -0,0 +1,90 @@
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+
+/// Returned with every response. Clients can send their own to have it used instead.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// W3C trace context header. Without a request ID header, the trace ID is used.
+const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Longest request ID taken from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// Identifies a request in log lines, its response and the events it stored.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RequestId(String);
+
+impl RequestId {
+    /// The client's ID for the request, if it sent a usable one.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header_value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        if let Some(request_id) = header_value(REQUEST_ID_HEADER).filter(|id| is_valid(id)) {
+            return Some(Self(request_id.to_owned()));
+        }
+        // version-traceid-parentid-flags
+        let trace_id = header_value(TRACEPARENT_HEADER)?.split('-').nth(1)?;
+        let is_trace_id = trace_id.len() == 32
+            && trace_id
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+            && trace_id.bytes().any(|byte| byte != b'0');
+        is_trace_id.then(|| Self(trace_id.to_owned()))
+    }
+
+    /// A random ID, shaped like a trace ID.
+    fn generate() -> Self {
+        Self(format!("{:032x}", rand::random::<u128>()))
+    }
+
+    /// The ID of the request being handled, if any.
+    pub(crate) fn current() -> Option<Self> {
+        REQUEST_ID.try_with(Clone::clone).ok()
+    }
+
+    /// Runs the future as part of this request, for work that's spawned off it.
+    pub(crate) fn scope<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        REQUEST_ID.scope(self, future)
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Client IDs end up in log lines, so they're kept to characters that can't break them up.
+fn is_valid(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:/".contains(&byte))
+}
+
+/// Middleware handling each request under the client's request ID, or a new one, and returning it
+/// in the response. Requests already given one by an outer layer keep it.
+pub(crate) async fn propagate(req: Request, next: Next) -> Response {
+    if RequestId::current().is_some() {
+        return next.run(req).await;
+    }
+    let request_id = RequestId::from_headers(req.headers()).unwrap_or_else(RequestId::generate);
+    let header_value = HeaderValue::from_str(request_id.as_str()).unwrap();
+    let mut response = request_id.scope(next.run(req)).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value);
+    response
+}
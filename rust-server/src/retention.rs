@@ -0,0 +1,88 @@
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use telemetry_storage::{Pruned, RetentionPolicy};
+
+#[derive(Clone, clap::Args)]
+pub(crate) struct RetentionArgs {
+    /// Prune events older than this, like "30days" or "12h".
+    #[arg(long, value_parser = humantime::parse_duration)]
+    retain_for: Option<Duration>,
+    /// Prune the oldest events beyond this many.
+    #[arg(long)]
+    retain_max_events: Option<u64>,
+    /// Prune the oldest events once their payloads take more than this many bytes.
+    #[arg(long)]
+    retain_max_bytes: Option<u64>,
+    /// How often to prune.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1h")]
+    pub prune_interval: Duration,
+}
+
+impl RetentionArgs {
+    /// None if nothing is limited.
+    pub(crate) fn policy(&self) -> Option<RetentionPolicy> {
+        if self.retain_for.is_none()
+            && self.retain_max_events.is_none()
+            && self.retain_max_bytes.is_none()
+        {
+            return None;
+        }
+        Some(RetentionPolicy {
+            before: self
+                .retain_for
+                .map(|retain_for| chrono::Utc::now() - retain_for),
+            max_events: self.retain_max_events,
+            max_bytes: self.retain_max_bytes,
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "retain_for": self.retain_for.map(|d| humantime::format_duration(d).to_string()),
+            "retain_max_events": self.retain_max_events,
+            "retain_max_bytes": self.retain_max_bytes,
+            "prune_interval": humantime::format_duration(self.prune_interval).to_string(),
+        })
+    }
+}
+
+/// Running totals of what pruning has removed since the server started.
+#[derive(Default)]
+pub(crate) struct RetentionStats {
+    runs: AtomicU64,
+    failures: AtomicU64,
+    events: AtomicU64,
+    streams: AtomicU64,
+    files: AtomicU64,
+    partitions: AtomicU64,
+}
+
+impl RetentionStats {
+    pub(crate) fn record(&self, result: &anyhow::Result<Pruned>) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(pruned) => {
+                self.events.fetch_add(pruned.events, Ordering::Relaxed);
+                self.streams.fetch_add(pruned.streams, Ordering::Relaxed);
+                self.files.fetch_add(pruned.files, Ordering::Relaxed);
+                self.partitions
+                    .fetch_add(pruned.partitions, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "runs": self.runs.load(Ordering::Relaxed),
+            "failures": self.failures.load(Ordering::Relaxed),
+            "events_pruned": self.events.load(Ordering::Relaxed),
+            "streams_pruned": self.streams.load(Ordering::Relaxed),
+            "files_pruned": self.files.load(Ordering::Relaxed),
+            "partitions_pruned": self.partitions.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use telemetry_storage::Rollup;
+
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct RollupArgs {
+    /// Keeps a count of events in the rollups table, as NAME=GROUP/BUCKET, where GROUP is
+    /// "stream" or "payload.<field>" and BUCKET is a duration, a minute if left out. Like
+    /// "per_stream=stream/1m" or "levels=payload.level/5m". Can be repeated.
+    #[arg(long, value_parser = Rollup::from_str)]
+    pub rollup: Vec<Rollup>,
+    /// How often to update rollups.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1m")]
+    pub rollup_interval: Duration,
+}
+
+impl RollupArgs {
+    /// Checks that no two rollups have the same name.
+    pub(crate) fn check_names(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for rollup in &self.rollup {
+            if !names.insert(&rollup.name) {
+                bail!("rollup {:?} is given more than once", rollup.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "rollups": self.rollup.iter().map(Rollup::to_string).collect::<Vec<_>>(),
+            "rollup_interval": humantime::format_duration(self.rollup_interval).to_string(),
+        })
+    }
+}
+
+/// Running totals of rollup updates since the server started.
+#[derive(Default)]
+pub(crate) struct RollupStats {
+    runs: AtomicU64,
+    failures: AtomicU64,
+    rows: AtomicU64,
+}
+
+impl RollupStats {
+    pub(crate) fn record(&self, result: &Result<u64>) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(rows) => {
+                self.rows.fetch_add(*rows, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "runs": self.runs.load(Ordering::Relaxed),
+            "failures": self.failures.load(Ordering::Relaxed),
+            "rows_written": self.rows.load(Ordering::Relaxed),
+        })
+    }
+}
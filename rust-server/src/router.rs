@@ -0,0 +1,559 @@
+use super::*;
+
+/// Settings for a [Server], which start as the command line's defaults.
+pub struct ServerBuilder {
+    db_conns: Vec<Box<dyn Connection + Send>>,
+    normalize: bool,
+    redact: Option<Redact>,
+    transform: Option<Transform>,
+    routes: Option<Routes>,
+    sinks: HashMap<String, Sink>,
+    legacy_encoding: LegacyEncoding,
+    stream_token_secret: Option<String>,
+    enricher: Option<Enricher>,
+    limits: EventLimits,
+    sampler: Option<Sampler>,
+    fallback: Option<Fallback>,
+    read_only: bool,
+    ingest: IngestArgs,
+    dedup: Option<Dedup>,
+}
+
+impl ServerBuilder {
+    /// Converts timestamps to RFC 3339 UTC and unit-suffixed fields to seconds and bytes.
+    pub fn normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Masks or removes sensitive values before they're stored, ahead of normalizing.
+    pub fn redact(mut self, redact: &RedactArgs) -> Self {
+        self.redact = redact.redactor();
+        self
+    }
+
+    /// Runs each event through a script after the other processors.
+    pub fn transform(mut self, transform: &TransformArgs) -> Self {
+        self.transform = transform.transform();
+        self
+    }
+
+    /// Stores events matching the rules in sinks instead.
+    pub fn routing(mut self, routing: &RoutingArgs) -> Self {
+        self.routes = routing.routes();
+        self
+    }
+
+    /// Another connection to the same storage. Requests use whichever connection is free, so they
+    /// only wait for each other once all of them are in use.
+    pub fn connection(mut self, db_conn: Box<dyn Connection + Send>) -> Self {
+        self.db_conns.push(db_conn);
+        self
+    }
+
+    /// Storage that routing rules and the transform script can send events to by name.
+    pub fn sink(mut self, name: impl Into<String>, db_conn: Box<dyn Connection + Send>) -> Self {
+        self.sinks.insert(name.into(), Sink::new(db_conn));
+        self
+    }
+
+    pub fn legacy_encoding(mut self, legacy_encoding: LegacyEncoding) -> Self {
+        self.legacy_encoding = legacy_encoding;
+        self
+    }
+
+    /// Key for signing stream tokens, so they survive a restart.
+    pub fn stream_token_secret(mut self, secret: impl Into<String>) -> Self {
+        self.stream_token_secret = Some(secret.into());
+        self
+    }
+
+    pub fn enrich(mut self, enrich: &EnrichArgs) -> Self {
+        self.enricher = enrich.enricher();
+        self
+    }
+
+    pub fn limits(mut self, limits: EventLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Drops some of each stream's events before they're stored.
+    pub fn sampling(mut self, sampling: &SamplingArgs) -> Self {
+        self.sampler = sampling.sampler();
+        self
+    }
+
+    /// Endpoints under /ingest taking events with their own limits, schema, sampling and sink.
+    /// Limits they don't set are the server's.
+    pub fn ingest(mut self, ingest: &IngestArgs) -> Self {
+        self.ingest = ingest.clone();
+        self
+    }
+
+    /// Looks for POSTs repeating one received within a window, and rejects or tags them.
+    pub fn dedup(mut self, dedup: &DedupArgs) -> Self {
+        self.dedup = dedup.dedup();
+        self
+    }
+
+    /// Serves only the endpoints that query and export what's stored, for storage opened without
+    /// writing to it.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Storage to fall back to when the storage the connections are to keeps failing its checks.
+    pub(crate) fn fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    pub fn build(self) -> Arc<Server> {
+        let ingest = self.ingest.endpoints(self.limits);
+        Arc::new(Server {
+            db_conn: Arc::new(ConnectionPool::new(self.db_conns)),
+            pipeline: Pipeline::new(self.redact, self.normalize, self.transform),
+            legacy_encoding: self.legacy_encoding,
+            stream_tokens: StreamTokens::new(self.stream_token_secret.as_deref()),
+            enricher: self.enricher,
+            limits: self.limits,
+            retention_stats: Default::default(),
+            rollup_stats: Default::default(),
+            anomaly_stats: Default::default(),
+            sampler: self.sampler,
+            routes: self.routes,
+            sinks: self.sinks,
+            loki_streams: Default::default(),
+            stream_queues: Default::default(),
+            fallback: self.fallback,
+            read_only: self.read_only,
+            ingest,
+            pause: Default::default(),
+            dedup: self.dedup,
+        })
+    }
+}
+
+impl Server {
+    /// Starts building a server that stores events in the connection.
+    pub fn builder(db_conn: Box<dyn Connection + Send>) -> ServerBuilder {
+        ServerBuilder {
+            db_conns: vec![db_conn],
+            normalize: false,
+            redact: None,
+            transform: None,
+            routes: None,
+            sinks: HashMap::new(),
+            legacy_encoding: LegacyEncoding::default(),
+            stream_token_secret: None,
+            enricher: None,
+            limits: EventLimits::default(),
+            sampler: None,
+            fallback: None,
+            read_only: false,
+            ingest: Default::default(),
+            dedup: None,
+        }
+    }
+
+    /// The server's endpoints, with paths relative to wherever the router is nested. The admin
+    /// endpoints are included, and have no auth of their own, so guard them with a layer. The
+    /// short links under /l redirect to the UI at /ui, which is served from the binary. Each
+    /// response has an `x-request-id` header. A read-only server only has the endpoints that
+    /// query and export what's stored.
+    pub fn router(self: &Arc<Self>) -> axum::Router {
+        let router = axum::Router::new()
+            .route(
+                "/api/events",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<ViewParams>| async move { server.view_handler(params).await }
+                }),
+            )
+            .route(
+                "/api/streams",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<StreamsParams>| async move {
+                        server.streams_handler(params).await
+                    }
+                }),
+            )
+            .route(UI_PATH, axum::routing::get(views::ui_handler))
+            .route(
+                "/api/export",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |Query(export): Query<ExportParams>, Query(params): Query<ViewParams>| async move {
+                        server.export_handler(export.format, params).await
+                    }
+                }),
+            )
+            .route(
+                "/api/links/:link_id",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |Path(link_id): Path<String>| async move {
+                        server.resolve_link_handler(&link_id, false).await
+                    }
+                }),
+            )
+            .route(
+                "/api/rollups/:name",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |Path(name): Path<String>, Query(params): Query<RollupParams>| async move {
+                        server.rollup_handler(&name, params).await
+                    }
+                }),
+            )
+            // Grafana's JSON datasource checks the URL it's given answers.
+            .route("/grafana", axum::routing::get(|| async { "ok" }))
+            .route("/grafana/", axum::routing::get(|| async { "ok" }))
+            .route(
+                "/grafana/search",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |body: Option<axum::Json<grafana::SearchRequest>>| async move {
+                        let search = body.map(|axum::Json(search)| search).unwrap_or_default();
+                        server.grafana_search_handler(search).await
+                    }
+                }),
+            )
+            .route(
+                "/grafana/query",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |axum::Json(request): axum::Json<grafana::QueryRequest>| async move {
+                        server.grafana_query_handler(request).await
+                    }
+                }),
+            )
+            .route(
+                "/grafana/annotations",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |axum::Json(request): axum::Json<grafana::AnnotationsRequest>| async move {
+                        server.grafana_annotations_handler(request).await
+                    }
+                }),
+            )
+            .route(
+                "/l/:link_id",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |Path(link_id): Path<String>| async move {
+                        server.resolve_link_handler(&link_id, true).await
+                    }
+                }),
+            )
+            .route(
+                "/admin/log-level",
+                axum::routing::put(|level: String| async move { log_level_handler(&level) }),
+            )
+            .route(
+                "/admin/sampling",
+                axum::routing::put({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<SamplingParams>, body: Bytes| async move {
+                        server.sampling_handler(params, &body)
+                    }
+                }),
+            )
+            .route(
+                "/stats/retention",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { axum::Json(server.retention_stats.to_json()) }
+                }),
+            )
+            .route(
+                "/stats/rollups",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { axum::Json(server.rollup_stats.to_json()) }
+                }),
+            )
+            .route(
+                "/stats/pipeline",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { axum::Json(server.pipeline.stats()) }
+                }),
+            )
+            .route(
+                "/stats/anomalies",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { axum::Json(server.anomaly_stats.to_json()) }
+                }),
+            )
+            .route(
+                "/stats/sampling",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { axum::Json(server.sampler.as_ref().map(Sampler::to_json)) }
+                }),
+            )
+            .route(
+                "/stats/ingest",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move {
+                        let stats: BTreeMap<_, _> = server
+                            .ingest
+                            .iter()
+                            .map(|(name, ingest)| (name.clone(), ingest.to_json()))
+                            .collect();
+                        axum::Json(stats)
+                    }
+                }),
+            )
+            .route(
+                "/stats/storage",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { axum::Json(server.fallback.as_ref().map(Fallback::to_json)) }
+                }),
+            )
+            .route(
+                "/stats/routing",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { axum::Json(server.routes.as_ref().map(Routes::to_json)) }
+                }),
+            )
+            .route(
+                "/stats/compression",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<CompressionReportParams>| async move {
+                        server.compression_report_handler(params).await
+                    }
+                }),
+            )
+            .route(
+                "/stats/blocking",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    || async move { server.blocking_report_handler().await }
+                }),
+            );
+        match self.read_only {
+            true => router,
+            false => router.merge(self.write_router()),
+        }
+        .layer(axum::middleware::from_fn(request_id::propagate))
+    }
+
+    /// The endpoints that store events or change what's stored, left out in read-only mode and
+    /// turned away while paused.
+    fn write_router(self: &Arc<Self>) -> axum::Router {
+        axum::Router::new()
+            .route(
+                "/",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    move |body| async move { server.post_handler(body).await }
+                }),
+            )
+            .route(
+                "/ingest/:name",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |Path(name): Path<String>, mut req: axum::extract::Request| async move {
+                        let Some(ingest) = server.ingest.get(&name) else {
+                            let body = format!("no ingest endpoint {:?}", name);
+                            return (StatusCode::NOT_FOUND, HeaderMap::new(), body);
+                        };
+                        req.extensions_mut().insert(Arc::clone(ingest));
+                        server.post_handler(req).await
+                    }
+                }),
+            )
+            .route(
+                "/",
+                axum::routing::get({
+                    let server = Arc::clone(self);
+                    |ws_upgrade: WebSocketUpgrade,
+                     connect_info: Option<ConnectInfo<SocketAddr>>,
+                     Query(params): Query<WebsocketParams>,
+                     headers: HeaderMap| async move {
+                        let remote_addr = connect_info.map(|ConnectInfo(addr)| addr);
+                        server
+                            .websocket_upgrade(ws_upgrade, remote_addr, &headers, params.acks)
+                            .await
+                    }
+                }),
+            )
+            .route(
+                "/v1/traces",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    move |req| async move { server.otlp_traces_handler(req).await }
+                }),
+            )
+            .route(
+                "/loki/api/v1/push",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    move |req| async move { server.loki_push_handler(req).await }
+                }),
+            )
+            .route(
+                "/v1/metrics",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    move |req| async move { server.otlp_metrics_handler(req).await }
+                }),
+            )
+            .route(
+                "/streams/close",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |headers: HeaderMap| async move { server.close_stream_handler(&headers).await }
+                }),
+            )
+            .route(
+                "/streams/revise",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<ReviseParams>, headers: HeaderMap, body: Bytes| async move {
+                        server.revise_handler(params, &headers, &body).await
+                    }
+                }),
+            )
+            .route(
+                "/streams/:stream_id",
+                axum::routing::patch({
+                    let server = Arc::clone(self);
+                    |Path(stream_id): Path<String>, headers: HeaderMap, body: Bytes| async move {
+                        server.patch_stream_handler(&stream_id, &headers, &body).await
+                    }
+                }),
+            )
+            .route(
+                "/streams/:stream_id/labels",
+                axum::routing::patch({
+                    let server = Arc::clone(self);
+                    |Path(stream_id): Path<String>, headers: HeaderMap, body: Bytes| async move {
+                        server.patch_stream_labels_handler(&stream_id, &headers, &body).await
+                    }
+                }),
+            )
+            .route(
+                "/admin/flush",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    || async move { server.storage_action_handler(StorageAction::Flush).await }
+                }),
+            )
+            .route(
+                "/admin/commit",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    || async move { server.storage_action_handler(StorageAction::Commit).await }
+                }),
+            )
+            .route(
+                "/admin/rotate",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    || async move { server.storage_action_handler(StorageAction::Rotate).await }
+                }),
+            )
+            .route(
+                "/admin/reprocess",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<EventSelectionParams>| async move {
+                        server.reprocess_handler(params).await
+                    }
+                }),
+            )
+            .route(
+                "/api/links",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |query: String| async move { server.create_link_handler(query).await }
+                }),
+            )
+            .route(
+                &format!("/{}/:method", grpc::SERVICE),
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |Path(method): Path<String>, req| async move {
+                        server.grpc_handler(&method, req).await
+                    }
+                }),
+            )
+            .route(
+                "/grpc.reflection.v1.ServerReflection/ServerReflectionInfo",
+                axum::routing::post(grpc::reflection_handler),
+            )
+            .route(
+                "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+                axum::routing::post(grpc::reflection_handler),
+            )
+            .route(
+                "/admin/streams/merge",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<MergeStreamsParams>| async move {
+                        server.merge_streams_handler(params).await
+                    }
+                }),
+            )
+            .route(
+                "/admin/streams/split",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<SplitStreamParams>| async move {
+                        server.split_stream_handler(params).await
+                    }
+                }),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                Arc::clone(&self.pause),
+                pause::guard,
+            ))
+            // After the layer, so that a paused server can be resumed.
+            .route(
+                "/admin/pause",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    |Query(params): Query<PauseParams>| async move {
+                        server.pause_handler(params).await
+                    }
+                }),
+            )
+            .route(
+                "/admin/resume",
+                axum::routing::post({
+                    let server = Arc::clone(self);
+                    || async move { server.pause.resume() }
+                }),
+            )
+    }
+
+    /// Finishes up storage once serving's done. Nothing is stored after this.
+    pub async fn shutdown(&self) -> Result<()> {
+        for sink in self.sinks.values() {
+            sink.shutdown().await?;
+        }
+        for conn in self.db_conn.all() {
+            conn.lock().await.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+/// The ingest endpoints and the rest of the server's API storing into the connection with the
+/// default settings, for mounting in another axum application like
+/// `app.nest("/telemetry", telemetry::router(conn))`. Use [Server::builder] to change the
+/// settings, or to shut the storage down afterwards.
+pub fn router(db_conn: Box<dyn Connection + Send>) -> axum::Router {
+    Server::builder(db_conn).build().router()
+}
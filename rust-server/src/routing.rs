@@ -0,0 +1,195 @@
+use crate::Storage;
+use anyhow::{bail, Context, Result};
+use axum::http::{HeaderMap, HeaderName};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Sends events to other storage by their content, so one endpoint can fill several backends.
+/// Events that no rule matches go to the main storage.
+#[derive(Clone, Default, clap::Args)]
+pub struct RoutingArgs {
+    /// Storage events can be routed to by name, as NAME=STORAGE_URI. Can be repeated.
+    #[arg(long, value_parser = parse_sink)]
+    sink: Vec<(String, Storage)>,
+    /// Stores events matching a condition in a --sink instead, as SINK:CONDITION, like
+    /// 'crashes:payload.kind == "crash"' or 'metrics:header.x-source != "app"'. The first rule
+    /// that matches is used. Can be repeated.
+    #[arg(long, value_parser = Rule::from_str)]
+    route: Vec<Rule>,
+}
+
+fn parse_sink(text: &str) -> Result<(String, Storage)> {
+    let (name, uri) = text
+        .split_once('=')
+        .context("sink must be NAME=STORAGE_URI")?;
+    Ok((name.to_owned(), Storage::from_str(uri)?))
+}
+
+impl RoutingArgs {
+    /// The storage for each sink, to be opened when serving.
+    pub(crate) fn sinks(&self) -> &[(String, Storage)] {
+        &self.sink
+    }
+
+    /// None if there are no rules.
+    pub(crate) fn routes(&self) -> Option<Routes> {
+        if self.route.is_empty() {
+            return None;
+        }
+        Some(Routes {
+            rules: self.route.clone(),
+            stats: Default::default(),
+        })
+    }
+
+    /// Checks that every rule routes to a sink that was given.
+    pub(crate) fn check_sinks(&self) -> Result<()> {
+        for rule in &self.route {
+            if !self.sink.iter().any(|(name, _)| *name == rule.sink) {
+                bail!("route {} is to unknown sink {:?}", rule, rule.sink);
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a single value of one of the flags, for config files to check.
+    pub(crate) fn check(flag: &str, value: &str) -> Result<()> {
+        match flag {
+            "sink" => parse_sink(value).map(drop),
+            _ => Rule::from_str(value).map(drop),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "sinks": self.sink.iter().map(|(name, storage)| (name, storage.info())).collect::<BTreeMap<_, _>>(),
+            "routes": self.route.iter().map(Rule::to_string).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Sends events matching a condition to a sink.
+#[derive(Clone, Debug)]
+pub(crate) struct Rule {
+    sink: String,
+    field: Field,
+    equal: bool,
+    value: Value,
+}
+
+#[derive(Clone, Debug)]
+enum Field {
+    /// A JSON pointer into the payload.
+    Payload(String),
+    Header(HeaderName),
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (sink, condition) = text
+            .split_once(':')
+            .context("route must be SINK:CONDITION")?;
+        // The first operator, as the value could contain one.
+        let Some(at) = ["==", "!="]
+            .iter()
+            .filter_map(|op| condition.find(op))
+            .min()
+        else {
+            bail!("route condition must compare with == or !=");
+        };
+        let (field, equal, value) = (
+            &condition[..at],
+            &condition[at..at + 2] == "==",
+            &condition[at + 2..],
+        );
+        let field = match field.trim().split_once('.') {
+            Some(("payload", path)) => Field::Payload(
+                path.split('.')
+                    .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+                    .collect(),
+            ),
+            Some(("header", name)) => Field::Header(
+                HeaderName::from_str(name).with_context(|| format!("header name {:?}", name))?,
+            ),
+            _ => bail!("route condition must be on payload.<field> or header.<name>"),
+        };
+        let value = serde_json::from_str(value.trim())
+            .with_context(|| format!("route value {} must be JSON", value.trim()))?;
+        Ok(Rule {
+            sink: sink.trim().to_owned(),
+            field,
+            equal,
+            value,
+        })
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let field = match &self.field {
+            Field::Payload(pointer) => format!(
+                "payload{}",
+                pointer
+                    .replace('/', ".")
+                    .replace("~1", "/")
+                    .replace("~0", "~")
+            ),
+            Field::Header(name) => format!("header.{}", name),
+        };
+        let op = if self.equal { "==" } else { "!=" };
+        write!(f, "{}:{} {} {}", self.sink, field, op, self.value)
+    }
+}
+
+impl Rule {
+    fn matches(&self, headers: &HeaderMap, payload: &Value) -> bool {
+        let found = match &self.field {
+            Field::Payload(pointer) => payload.pointer(pointer) == Some(&self.value),
+            Field::Header(name) => {
+                let expected = match &self.value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                headers
+                    .get_all(name)
+                    .iter()
+                    .any(|value| value.to_str().ok() == Some(expected.as_str()))
+            }
+        };
+        found == self.equal
+    }
+}
+
+/// Picks a sink for each event by the first rule it matches, counting events routed to each.
+pub(crate) struct Routes {
+    rules: Vec<Rule>,
+    stats: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Routes {
+    /// The sink for the event, or None to store it in the main storage. Headers are the request's
+    /// that the event came in.
+    pub(crate) fn route(&self, headers: &HeaderMap, payload: &Value) -> Option<&str> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(headers, payload))?;
+        *self
+            .stats
+            .lock()
+            .unwrap()
+            .entry(rule.sink.clone())
+            .or_default() += 1;
+        Some(&rule.sink)
+    }
+
+    /// Events routed to each sink since the server started.
+    pub(crate) fn to_json(&self) -> Value {
+        json!(*self.stats.lock().unwrap())
+    }
+}
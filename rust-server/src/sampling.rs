@@ -0,0 +1,261 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Instant;
+use telemetry_storage::{StreamEventIndex, StreamId};
+
+/// Drops some of each stream's events before they're stored, for high volume telemetry that isn't
+/// worth keeping all of. The policy applies to each stream separately.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct SamplingArgs {
+    /// Keep one in this many of each stream's events, by stream event index.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    sample_every: Option<u64>,
+    /// Top-level payload field whose value picks a --sample-rate, like "level".
+    #[arg(long)]
+    sample_field: Option<String>,
+    /// Chance of keeping an event whose --sample-field has a value, like "debug=0.01". Values
+    /// match case-insensitively. Events with other values fall back to --sample-every. Can be
+    /// repeated.
+    #[arg(long, value_parser = parse_rate, requires = "sample_field")]
+    sample_rate: Vec<(String, f64)>,
+    /// Most events to keep from each stream per second, after sampling. Short bursts of up to a
+    /// second's worth are allowed.
+    #[arg(long)]
+    throttle_events_per_second: Option<f64>,
+}
+
+fn parse_rate(text: &str) -> Result<(String, f64)> {
+    let (value, rate) = text
+        .rsplit_once('=')
+        .context("sample rate must be VALUE=RATE, like debug=0.01")?;
+    let rate: f64 = rate.parse().context("sample rate")?;
+    if !(0.0..=1.0).contains(&rate) {
+        bail!("sample rate must be between 0 and 1");
+    }
+    Ok((value.to_lowercase(), rate))
+}
+
+impl SamplingArgs {
+    /// None if every event is kept.
+    pub(crate) fn sampler(&self) -> Option<Sampler> {
+        if self.sample_every.is_none()
+            && self.sample_rate.is_empty()
+            && self.throttle_events_per_second.is_none()
+        {
+            return None;
+        }
+        Some(Sampler {
+            args: RwLock::new(self.clone()),
+            streams: Default::default(),
+            stats: Default::default(),
+        })
+    }
+
+    /// Sets one of the flags by its name without dashes, like "sample_every", for sampling given
+    /// other than by flags.
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "sample_every" => match value.parse()? {
+                0 => bail!("sample_every must be at least 1"),
+                every => self.sample_every = Some(every),
+            },
+            "sample_field" => self.sample_field = Some(value.to_owned()),
+            "sample_rate" => self.sample_rate.push(parse_rate(value)?),
+            "throttle_events_per_second" => self.throttle_events_per_second = Some(value.parse()?),
+            _ => bail!("unknown setting {}", key),
+        }
+        Ok(())
+    }
+
+    /// Checks settings given by [Self::set] fit together once they're all set, as clap does for
+    /// flags.
+    pub(crate) fn check(&self) -> Result<()> {
+        if !self.sample_rate.is_empty() && self.sample_field.is_none() {
+            bail!("sample_rate needs sample_field");
+        }
+        Ok(())
+    }
+
+    /// Reads settings in the form [Self::to_json] gives them, for changing them while serving.
+    pub(crate) fn from_json(value: Value) -> Result<Self> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Policy {
+            every: Option<u64>,
+            field: Option<String>,
+            #[serde(default)]
+            rates: BTreeMap<String, f64>,
+            throttle_events_per_second: Option<f64>,
+        }
+        let policy: Policy = serde_json::from_value(value)?;
+        let mut args = Self::default();
+        if let Some(every) = policy.every {
+            args.set("sample_every", &every.to_string())?;
+        }
+        args.sample_field = policy.field;
+        for (value, rate) in policy.rates {
+            args.set("sample_rate", &format!("{}={}", value, rate))?;
+        }
+        args.throttle_events_per_second = policy.throttle_events_per_second;
+        args.check()?;
+        Ok(args)
+    }
+
+    fn burst(&self) -> f64 {
+        self.throttle_events_per_second.unwrap_or(0.0).max(1.0)
+    }
+
+    /// The --sample-rate for the payload's --sample-field value, if it has one.
+    fn field_rate(&self, payload: &str) -> Option<f64> {
+        if self.sample_rate.is_empty() {
+            return None;
+        }
+        let field = self.sample_field.as_deref()?;
+        let payload: Value = serde_json::from_str(payload).ok()?;
+        let value = match payload.get(field)? {
+            Value::String(s) => s.to_lowercase(),
+            other => other.to_string(),
+        };
+        self.sample_rate
+            .iter()
+            .find(|(rate_value, _)| *rate_value == value)
+            .map(|(_, rate)| *rate)
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let rates: Map<String, Value> = self
+            .sample_rate
+            .iter()
+            .map(|(value, rate)| (value.clone(), json!(rate)))
+            .collect();
+        json!({
+            "every": self.sample_every,
+            "field": self.sample_field,
+            "rates": rates,
+            "throttle_events_per_second": self.throttle_events_per_second,
+        })
+    }
+}
+
+/// Why an event wasn't stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Dropped {
+    /// By --sample-every or --sample-rate.
+    Sampled,
+    Throttled,
+}
+
+#[derive(Default)]
+struct Counts<T> {
+    kept: T,
+    sampled: T,
+    throttled: T,
+}
+
+struct StreamSampling {
+    counts: Counts<u64>,
+    /// Token bucket for throttling, refilled as time passes.
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Decides which events to keep, and counts what it drops overall and per stream.
+pub(crate) struct Sampler {
+    /// Can be changed while serving, with [Self::set_policy].
+    args: RwLock<SamplingArgs>,
+    streams: std::sync::Mutex<HashMap<StreamId, StreamSampling>>,
+    stats: Counts<AtomicU64>,
+}
+
+impl Sampler {
+    pub(crate) fn sample(
+        &self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        payload: &str,
+    ) -> Result<(), Dropped> {
+        let args = self.args.read().unwrap();
+        let sampled_in = match args.field_rate(payload) {
+            Some(rate) => rand::random::<f64>() < rate,
+            None => args
+                .sample_every
+                .is_none_or(|every| stream_event_index.saturating_sub(1).is_multiple_of(every)),
+        };
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams.entry(stream_id).or_insert_with(|| StreamSampling {
+            counts: Default::default(),
+            tokens: args.burst(),
+            refilled: Instant::now(),
+        });
+        let result = if !sampled_in {
+            Err(Dropped::Sampled)
+        } else if let Some(per_second) = args.throttle_events_per_second {
+            let now = Instant::now();
+            let elapsed = now.duration_since(stream.refilled).as_secs_f64();
+            stream.tokens = (stream.tokens + elapsed * per_second).min(args.burst());
+            stream.refilled = now;
+            if stream.tokens >= 1.0 {
+                stream.tokens -= 1.0;
+                Ok(())
+            } else {
+                Err(Dropped::Throttled)
+            }
+        } else {
+            Ok(())
+        };
+        let (count, stat) = match result {
+            Ok(()) => (&mut stream.counts.kept, &self.stats.kept),
+            Err(Dropped::Sampled) => (&mut stream.counts.sampled, &self.stats.sampled),
+            Err(Dropped::Throttled) => (&mut stream.counts.throttled, &self.stats.throttled),
+        };
+        *count += 1;
+        stat.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    /// The policy, recorded on streams when they start so readers know events are missing on
+    /// purpose.
+    pub(crate) fn policy(&self) -> Value {
+        self.args.read().unwrap().to_json()
+    }
+
+    /// Replaces the policy for events from now on. Streams already started keep the policy they
+    /// recorded, though what was done to them is recorded with the new one when they close.
+    pub(crate) fn set_policy(&self, args: SamplingArgs) {
+        *self.args.write().unwrap() = args;
+    }
+
+    /// The policy with what it did to the stream, recorded when the stream closes. Counts are
+    /// only for events this server received since it started.
+    pub(crate) fn finish(&self, stream_id: StreamId) -> Value {
+        let counts = self
+            .streams
+            .lock()
+            .unwrap()
+            .remove(&stream_id)
+            .map(|stream| stream.counts)
+            .unwrap_or_default();
+        let mut policy = self.policy();
+        policy["kept"] = counts.kept.into();
+        policy["sampled"] = counts.sampled.into();
+        policy["throttled"] = counts.throttled.into();
+        policy
+    }
+
+    /// Whether the sampler has seen the stream's events since the stream or the server started.
+    pub(crate) fn tracks(&self, stream_id: StreamId) -> bool {
+        self.streams.lock().unwrap().contains_key(&stream_id)
+    }
+
+    /// Totals since the server started.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "kept": self.stats.kept.load(Ordering::Relaxed),
+            "sampled": self.stats.sampled.load(Ordering::Relaxed),
+            "throttled": self.stats.throttled.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use telemetry_storage::{Connection, ImportedEvent, StreamId};
+use tokio::sync::Mutex;
+
+/// Storage that routing rules or a transform script send events to. Streams are copied over with
+/// their IDs the first time one of their events is routed, as replication does, so a sink's events
+/// keep their streams.
+pub(crate) struct Sink {
+    conn: Mutex<Box<dyn Connection + Send>>,
+    /// Streams copied to the sink so far.
+    streams: std::sync::Mutex<HashSet<StreamId>>,
+}
+
+impl Sink {
+    pub(crate) fn new(conn: Box<dyn Connection + Send>) -> Self {
+        Self {
+            conn: Mutex::new(conn),
+            streams: Default::default(),
+        }
+    }
+
+    /// Stores the event, copying its stream from `main` first if the sink hasn't got it.
+    pub(crate) async fn store(
+        &self,
+        main: &mut (dyn Connection + Send),
+        event: ImportedEvent,
+    ) -> Result<()> {
+        let stream_id = event.stream_id;
+        let copied = self.streams.lock().unwrap().contains(&stream_id);
+        let mut conn = self.conn.lock().await;
+        if !copied {
+            let streams = main.streams_by_id(&[stream_id]).await?;
+            conn.import_streams(&streams)
+                .await
+                .context("copying stream to sink")?;
+            self.streams.lock().unwrap().insert(stream_id);
+        }
+        conn.import_events(&[event]).await?;
+        Ok(())
+    }
+
+    /// Copies a closed stream's end to the sink, if it has the stream.
+    pub(crate) async fn close_stream(
+        &self,
+        main: &mut (dyn Connection + Send),
+        stream_id: StreamId,
+    ) -> Result<()> {
+        if !self.streams.lock().unwrap().remove(&stream_id) {
+            return Ok(());
+        }
+        let streams = main.streams_by_id(&[stream_id]).await?;
+        self.conn.lock().await.import_streams(&streams).await
+    }
+
+    pub(crate) async fn flush(&self) -> Result<()> {
+        self.conn.lock().await.flush().await
+    }
+
+    pub(crate) async fn commit(&self) -> Result<()> {
+        self.conn.lock().await.commit().await
+    }
+
+    pub(crate) async fn shutdown(&self) -> Result<()> {
+        self.conn.lock().await.shutdown().await
+    }
+}
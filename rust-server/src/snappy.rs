@@ -0,0 +1,68 @@
+use crate::protobuf::{take, varint};
+use anyhow::{bail, Context, Result};
+
+/// Decompresses a snappy block, as Loki push requests are compressed. The framed format, for
+/// streams, isn't supported. Blocks that would decompress to more than `max_len` bytes are
+/// refused.
+pub(crate) fn decompress(block: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let mut input = block;
+    let len = varint(&mut input).context("reading uncompressed length")?;
+    let len = usize::try_from(len)?;
+    if len > max_len {
+        bail!("decompresses to {} bytes, more than {}", len, max_len);
+    }
+    let mut out = Vec::with_capacity(len);
+    while let Some((&tag, rest)) = input.split_first() {
+        input = rest;
+        let (copy_len, offset) = match tag & 3 {
+            0 => {
+                let literal_len = match usize::from(tag >> 2) {
+                    short @ 0..60 => short,
+                    long => little_endian(take(&mut input, long - 59)?),
+                } + 1;
+                out.extend_from_slice(take(&mut input, literal_len)?);
+                if out.len() > len {
+                    bail!("literal runs past the uncompressed length");
+                }
+                continue;
+            }
+            1 => {
+                let low = take(&mut input, 1)?[0];
+                (
+                    usize::from((tag >> 2) & 7) + 4,
+                    usize::from(tag >> 5) << 8 | usize::from(low),
+                )
+            }
+            2 => (
+                usize::from(tag >> 2) + 1,
+                little_endian(take(&mut input, 2)?),
+            ),
+            _ => (
+                usize::from(tag >> 2) + 1,
+                little_endian(take(&mut input, 4)?),
+            ),
+        };
+        if offset == 0 || offset > out.len() {
+            bail!("copy offset {} is out of range", offset);
+        }
+        if out.len() + copy_len > len {
+            bail!("copy runs past the uncompressed length");
+        }
+        // Copies can overlap what they write, repeating a run.
+        let start = out.len() - offset;
+        for index in start..start + copy_len {
+            out.push(out[index]);
+        }
+    }
+    if out.len() != len {
+        bail!("decompressed to {} bytes, not {}", out.len(), len);
+    }
+    Ok(out)
+}
+
+fn little_endian(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | usize::from(*byte))
+}
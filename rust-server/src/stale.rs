@@ -0,0 +1,22 @@
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[derive(Clone, clap::Args)]
+pub(crate) struct StaleArgs {
+    /// Mark open streams with no events for this long as stale, like "10m". Clients that send
+    /// heartbeats while idle are never marked, so stale streams are from agents that have died.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub stale_after: Option<Duration>,
+    /// How often to look for stale streams.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1m")]
+    pub stale_check_interval: Duration,
+}
+
+impl StaleArgs {
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "stale_after": self.stale_after.map(|d| humantime::format_duration(d).to_string()),
+            "stale_check_interval": humantime::format_duration(self.stale_check_interval).to_string(),
+        })
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use telemetry_storage::MetricPoint;
+
+/// The largest statsd datagram read. Longer ones are truncated, losing their last metrics.
+pub(crate) const MAX_STATSD_PACKET_BYTES: usize = 64 << 10;
+
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct StatsdArgs {
+    /// Address to receive statsd and DogStatsD metrics on over UDP, like "[::]:8125". They're
+    /// stored as metric points, which needs SQLite or Postgres storage. Can be repeated.
+    #[arg(long)]
+    pub statsd_udp: Vec<SocketAddr>,
+    /// Aggregate statsd metrics over windows this long, like "10s", storing a point for each
+    /// metric, tag set and sending host per window instead of one per sample.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub statsd_flush_interval: Option<Duration>,
+}
+
+impl StatsdArgs {
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "udp": self.statsd_udp,
+            "flush_interval": self
+                .statsd_flush_interval
+                .map(|interval| humantime::format_duration(interval).to_string()),
+        })
+    }
+}
+
+/// A value from a statsd line.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Sample {
+    /// Already scaled up by its sample rate.
+    Counter(f64),
+    /// A gauge's value, or a change to it if signed.
+    Gauge {
+        value: f64,
+        delta: bool,
+    },
+    /// Timers, histograms and distributions, with their sample rate.
+    Timer {
+        value: f64,
+        rate: f64,
+    },
+    Set(String),
+}
+
+/// A metric from a statsd line, with its DogStatsD tags. Lines with several values, as
+/// DogStatsD sends, give a metric for each.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Metric {
+    pub name: String,
+    pub tags: Value,
+    pub sample: Sample,
+}
+
+/// Parses a line like `name:value|type|@rate|#tag:value,tag`. Other DogStatsD fields, like
+/// container IDs, are ignored.
+pub(crate) fn parse_line(line: &str) -> Result<Vec<Metric>> {
+    if line.starts_with("_e{") || line.starts_with("_sc|") {
+        bail!("DogStatsD events and service checks aren't supported");
+    }
+    let mut fields = line.split('|');
+    let (name, values) = fields
+        .next()
+        .and_then(|field| field.split_once(':'))
+        .context("metric has no value")?;
+    if name.is_empty() {
+        bail!("metric has no name");
+    }
+    let kind = fields.next().context("metric has no type")?;
+    let mut rate = 1.0;
+    let mut tags = Map::new();
+    for field in fields {
+        if let Some(given) = field.strip_prefix('@') {
+            rate = given
+                .parse()
+                .ok()
+                .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                .with_context(|| format!("sample rate {:?} isn't in (0, 1]", given))?;
+        } else if let Some(given) = field.strip_prefix('#') {
+            for tag in given.split(',').filter(|tag| !tag.is_empty()) {
+                match tag.split_once(':') {
+                    Some((key, value)) => tags.insert(key.to_owned(), value.into()),
+                    None => tags.insert(tag.to_owned(), true.into()),
+                };
+            }
+        }
+    }
+    let number = |value: &str| -> Result<f64> {
+        value
+            .parse()
+            .with_context(|| format!("metric value {:?} isn't a number", value))
+    };
+    values
+        .split(':')
+        .map(|value| {
+            let sample = match kind {
+                "c" => Sample::Counter(number(value)? / rate),
+                "g" => Sample::Gauge {
+                    value: number(value)?,
+                    delta: value.starts_with(['+', '-']),
+                },
+                "ms" | "h" | "d" => Sample::Timer {
+                    value: number(value)?,
+                    rate,
+                },
+                "s" => Sample::Set(value.to_owned()),
+                kind => bail!("unknown metric type {:?}", kind),
+            };
+            Ok(Metric {
+                name: name.to_owned(),
+                tags: tags.clone().into(),
+                sample,
+            })
+        })
+        .collect()
+}
+
+/// A metric from a host, and its tags as text, for telling metrics apart.
+type Key = (IpAddr, String, String);
+
+/// What's been received of a metric in the current window.
+enum Window {
+    Counter(f64),
+    Gauge,
+    Timer {
+        count: f64,
+        sum: f64,
+        min: f64,
+        max: f64,
+    },
+    Set(HashSet<String>),
+}
+
+#[derive(Default)]
+struct State {
+    /// The last value of each gauge, which signed samples change.
+    gauges: HashMap<Key, f64>,
+    window: BTreeMap<Key, (Value, Window)>,
+}
+
+/// Turns statsd packets into metric points, as they arrive, or for each window if aggregating.
+#[derive(Default)]
+pub(crate) struct Statsd {
+    aggregate: bool,
+    state: std::sync::Mutex<State>,
+}
+
+impl Statsd {
+    pub(crate) fn new(args: &StatsdArgs) -> Self {
+        Self {
+            aggregate: args.statsd_flush_interval.is_some(),
+            state: Default::default(),
+        }
+    }
+
+    /// The points to store for a packet's metrics right away, with the lines that couldn't be
+    /// parsed. None are stored right away when aggregating.
+    pub(crate) fn receive(
+        &self,
+        host: IpAddr,
+        packet: &str,
+        now: DateTime<Utc>,
+    ) -> (Vec<MetricPoint>, Vec<anyhow::Error>) {
+        let mut state = self.state.lock().unwrap();
+        let mut points = vec![];
+        let mut errors = vec![];
+        for line in packet.lines().filter(|line| !line.trim().is_empty()) {
+            let metrics = match parse_line(line) {
+                Ok(metrics) => metrics,
+                Err(err) => {
+                    errors.push(err.context(format!("parsing {:?}", line)));
+                    continue;
+                }
+            };
+            for metric in metrics {
+                let key = (host, metric.name.clone(), metric.tags.to_string());
+                let sample = match metric.sample {
+                    Sample::Gauge { value, delta } => {
+                        let gauge = state.gauges.entry(key.clone()).or_default();
+                        *gauge = if delta { *gauge + value } else { value };
+                        Sample::Gauge {
+                            value: *gauge,
+                            delta: false,
+                        }
+                    }
+                    sample => sample,
+                };
+                if self.aggregate {
+                    state.add(key, metric.tags, sample);
+                    continue;
+                }
+                let value = match sample {
+                    Sample::Counter(value)
+                    | Sample::Gauge { value, .. }
+                    | Sample::Timer { value, .. } => value,
+                    Sample::Set(_) => 1.0,
+                };
+                points.push(point(host, metric.name, metric.tags, now, value));
+            }
+        }
+        (points, errors)
+    }
+
+    /// The points for the window ending now, starting a new one. Timers have NAME.count,
+    /// NAME.sum, NAME.min and NAME.max points, and sets a point with how many distinct values
+    /// they had.
+    pub(crate) fn flush(&self, now: DateTime<Utc>) -> Vec<MetricPoint> {
+        let mut state = self.state.lock().unwrap();
+        let window = std::mem::take(&mut state.window);
+        let mut points = vec![];
+        for (key, (tags, window)) in window {
+            let (host, name, _) = &key;
+            let mut push = |suffix: &str, value| {
+                let name = format!("{}{}", name, suffix);
+                points.push(point(*host, name, tags.clone(), now, value));
+            };
+            match window {
+                Window::Counter(sum) => push("", sum),
+                Window::Gauge => push("", state.gauges[&key]),
+                Window::Timer {
+                    count,
+                    sum,
+                    min,
+                    max,
+                } => {
+                    push(".count", count);
+                    push(".sum", sum);
+                    push(".min", min);
+                    push(".max", max);
+                }
+                Window::Set(members) => push("", members.len() as f64),
+            }
+        }
+        points
+    }
+}
+
+impl State {
+    fn add(&mut self, key: Key, tags: Value, sample: Sample) {
+        let (_, window) = self.window.entry(key).or_insert_with(|| {
+            let window = match &sample {
+                Sample::Counter(_) => Window::Counter(0.0),
+                Sample::Gauge { .. } => Window::Gauge,
+                Sample::Timer { value, .. } => Window::Timer {
+                    count: 0.0,
+                    sum: 0.0,
+                    min: *value,
+                    max: *value,
+                },
+                Sample::Set(_) => Window::Set(HashSet::new()),
+            };
+            (tags, window)
+        });
+        match (window, sample) {
+            (Window::Counter(sum), Sample::Counter(value)) => *sum += value,
+            (
+                Window::Timer {
+                    count,
+                    sum,
+                    min,
+                    max,
+                },
+                Sample::Timer { value, rate },
+            ) => {
+                *count += 1.0 / rate;
+                *sum += value / rate;
+                *min = min.min(value);
+                *max = max.max(value);
+            }
+            (Window::Set(members), Sample::Set(member)) => {
+                members.insert(member);
+            }
+            // Gauges are read when flushed, and a metric sent as another type in the same
+            // window is dropped.
+            _ => {}
+        }
+    }
+}
+
+fn point(host: IpAddr, name: String, tags: Value, now: DateTime<Utc>, value: f64) -> MetricPoint {
+    MetricPoint {
+        name,
+        resource: json!({"host": host.to_string()}),
+        attributes: tags,
+        datetime: now,
+        value,
+    }
+}
@@ -0,0 +1,104 @@
+use crate::Storage;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::ffi::OsString;
+use std::str::FromStr;
+use url::form_urlencoded;
+
+/// Query parameters of postgres URIs that are for us rather than the postgres connection.
+const POSTGRES_OPTIONS: &[&str] = &[
+    "tls",
+    "tls_root_cert_path",
+    "password_file",
+    "schema_path",
+    "db_schema",
+    "extract_field",
+    "query_indexes",
+    "full_text_search",
+    "durability",
+    "fsync_interval",
+    "partition_events",
+    "partitions_ahead",
+    "timescale",
+    "timescale_chunk_interval",
+    "timescale_compress_after",
+];
+
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct StorageCommand {
+    #[command(subcommand)]
+    storage: Storage,
+}
+
+/// Parses URIs like `sqlite://telemetry.db?rotate_size=1000000`, `jsonfiles://./out` or
+/// `postgres://user@host/db?tls=require&schema_path=sql/postgres.sql`. The location is the
+/// database file or output directory, or the postgres connection string, and other options of the
+/// storage's subcommand go in the query string.
+impl FromStr for Storage {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let (scheme, rest) = uri
+            .split_once("://")
+            .with_context(|| format!("storage URI {:?} has no scheme", uri))?;
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut options: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        let mut command: Vec<OsString> = vec![];
+        match scheme {
+            "sqlite" | "duckdb" => {
+                let subcommand = match scheme {
+                    "sqlite" => "sqlite",
+                    _ => "duck-db",
+                };
+                command.push(subcommand.into());
+                if !location.is_empty() {
+                    command.extend(["--db-path".into(), location.into()]);
+                }
+            }
+            "jsonfiles" => {
+                command.push("json-files".into());
+                if !location.is_empty() {
+                    command.extend(["--output-dir".into(), location.into()]);
+                }
+            }
+            "memory" => {
+                if !location.is_empty() {
+                    bail!("memory storage URI {:?} can't have a location", uri);
+                }
+                command.push("memory".into());
+            }
+            "postgres" | "postgresql" => {
+                command.push("postgres".into());
+                let (ours, theirs) = options
+                    .into_iter()
+                    .partition(|(key, _)| POSTGRES_OPTIONS.contains(&key.as_str()));
+                options = ours;
+                let mut conn_str = format!("{}://{}", scheme, location);
+                if !theirs.is_empty() {
+                    conn_str += "?";
+                    conn_str += &form_urlencoded::Serializer::new(String::new())
+                        .extend_pairs(theirs)
+                        .finish();
+                }
+                command.extend(["--conn-str".into(), conn_str.into()]);
+            }
+            _ => bail!("no storage for URI scheme {:?}", scheme),
+        }
+        for (key, value) in options {
+            match (key.as_str(), value.as_str()) {
+                ("tls", "require") => command.push("--use-tls".into()),
+                ("tls", "disable") => {}
+                ("tls", _) => bail!("tls must be require or disable"),
+                // Flags without a value, like `?query_indexes`.
+                (_, "") => command.push(format!("--{}", key.replace('_', "-")).into()),
+                _ => command.extend([format!("--{}", key.replace('_', "-")).into(), value.into()]),
+            }
+        }
+        let parsed = StorageCommand::try_parse_from(command)
+            .with_context(|| format!("storage URI {:?}", uri))?;
+        Ok(parsed.storage)
+    }
+}
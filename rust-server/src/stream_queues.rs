@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use telemetry_storage::StreamId;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// A queue for each stream being written to by requests, so requests resuming the same stream take
+/// turns in the order they arrived. Each request's events then get the indexes after those of the
+/// request before it, and are stored in index order.
+#[derive(Default)]
+pub(crate) struct StreamQueues(std::sync::Mutex<HashMap<StreamId, Arc<Mutex<()>>>>);
+
+impl StreamQueues {
+    /// Waits for the requests ahead in the stream's queue to finish.
+    pub(crate) async fn wait_turn(&self, stream_id: StreamId) -> StreamTurn<'_> {
+        let queue = Arc::clone(self.0.lock().unwrap().entry(stream_id).or_default());
+        StreamTurn {
+            queues: self,
+            stream_id,
+            guard: Some(queue.lock_owned().await),
+        }
+    }
+
+    /// Forgets every stream's queue, for when the stream IDs are for other storage from now on.
+    /// Requests already waiting keep their places in the old queues.
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// A request's turn to write to a stream, until it's dropped.
+pub(crate) struct StreamTurn<'a> {
+    queues: &'a StreamQueues,
+    stream_id: StreamId,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for StreamTurn<'_> {
+    fn drop(&mut self) {
+        let mut queues = self.queues.0.lock().unwrap();
+        drop(self.guard.take());
+        // Nothing else is waiting if only the map refers to the queue.
+        if queues
+            .get(&self.stream_id)
+            .is_some_and(|queue| Arc::strong_count(queue) == 1)
+        {
+            queues.remove(&self.stream_id);
+        }
+    }
+}
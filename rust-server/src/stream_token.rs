@@ -0,0 +1,66 @@
+use anyhow::{anyhow, bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use telemetry_storage::StreamId;
+
+/// Returned when a stream is created, and sent back by clients to resume it.
+pub(crate) const STREAM_TOKEN_HEADER: &str = "x-stream-token";
+
+/// Bytes of the MAC kept in tokens.
+const MAC_LEN: usize = 16;
+
+/// Signs stream IDs so clients can resume the streams they were given, and only those. Tokens are
+/// signed for the storage generation the stream is in, so they stop working once the server falls
+/// back to other storage, where the same ID could be another stream.
+pub(crate) struct StreamTokens {
+    key: Vec<u8>,
+}
+
+impl StreamTokens {
+    /// Without a secret, tokens are only good for the life of the process.
+    pub(crate) fn new(secret: Option<&str>) -> Self {
+        let key = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
+        Self { key }
+    }
+
+    fn mac(&self, stream_id: StreamId, generation: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(&stream_id.0.to_be_bytes());
+        // Left out for the main storage, so its tokens are the same as before there was a fallback.
+        if generation != 0 {
+            mac.update(&generation.to_be_bytes());
+        }
+        mac
+    }
+
+    pub(crate) fn issue(&self, stream_id: StreamId, generation: u64) -> String {
+        let tag = self.mac(stream_id, generation).finalize().into_bytes();
+        let hex: String = tag[..MAC_LEN]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}.{}", stream_id, hex)
+    }
+
+    pub(crate) fn verify(&self, token: &str, generation: u64) -> Result<StreamId> {
+        let (stream_id, hex) = token
+            .split_once('.')
+            .ok_or_else(|| anyhow!("malformed stream token"))?;
+        let stream_id =
+            StreamId(u32::from_str_radix(stream_id, 16).context("parsing token stream id")?);
+        if hex.len() != MAC_LEN * 2 || !hex.is_ascii() {
+            bail!("malformed stream token signature");
+        }
+        let tag = (0..MAC_LEN)
+            .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .context("parsing token signature")?;
+        self.mac(stream_id, generation)
+            .verify_truncated_left(&tag)
+            .map_err(|_| anyhow!("bad stream token signature"))?;
+        Ok(stream_id)
+    }
+}
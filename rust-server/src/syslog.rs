@@ -0,0 +1,263 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use serde_json::{json, Map, Value};
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// The longest syslog message read. Longer UDP datagrams are truncated, and TCP connections
+/// sending longer messages are closed.
+pub(crate) const MAX_SYSLOG_MESSAGE_BYTES: usize = 64 << 10;
+
+#[derive(Clone, Default, clap::Args)]
+pub(crate) struct SyslogArgs {
+    /// Address to receive RFC 5424 or RFC 3164 syslog messages on over UDP, like "[::]:514". Can
+    /// be repeated.
+    #[arg(long)]
+    pub syslog_udp: Vec<SocketAddr>,
+    /// Address to receive syslog messages on over TCP, newline or octet-count framed. Can be
+    /// repeated.
+    #[arg(long)]
+    pub syslog_tcp: Vec<SocketAddr>,
+}
+
+impl SyslogArgs {
+    pub(crate) fn to_json(&self) -> Value {
+        json!({"udp": self.syslog_udp, "tcp": self.syslog_tcp})
+    }
+}
+
+/// The headers of a remote host's stream.
+pub(crate) fn stream_headers(host: IpAddr) -> Value {
+    json!({"syslog": {"host": host.to_string()}})
+}
+
+/// Reads a message from a TCP connection, framed as RFC 6587 describes: by a leading octet count,
+/// or otherwise by a trailing newline. None at the end of the connection.
+pub(crate) async fn read_frame(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<String>> {
+    let Some(&first) = reader.fill_buf().await?.first() else {
+        return Ok(None);
+    };
+    let mut frame = vec![];
+    if first.is_ascii_digit() {
+        let mut count = vec![];
+        (&mut *reader).take(8).read_until(b' ', &mut count).await?;
+        let count = std::str::from_utf8(&count)?.trim_end();
+        let len: usize = count
+            .parse()
+            .with_context(|| format!("parsing octet count {:?}", count))?;
+        if len > MAX_SYSLOG_MESSAGE_BYTES {
+            bail!("message of {} bytes is too long", len);
+        }
+        frame.resize(len, 0);
+        reader.read_exact(&mut frame).await?;
+    } else {
+        let limit = MAX_SYSLOG_MESSAGE_BYTES as u64 + 1;
+        (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut frame)
+            .await?;
+        if frame.len() > MAX_SYSLOG_MESSAGE_BYTES {
+            bail!("message is longer than {} bytes", MAX_SYSLOG_MESSAGE_BYTES);
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&frame).into_owned()))
+}
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// A syslog message as an event payload. RFC 5424 messages keep their structured data, as an
+/// object of each element's parameters. Anything not recognizably RFC 5424 is parsed as RFC 3164
+/// as far as it goes, with the rest as its message. RFC 3164 timestamps have no year or zone, so
+/// they're taken to be UTC in the last year.
+pub(crate) fn parse(message: &str, now: DateTime<Utc>) -> Value {
+    let message = message.trim_end_matches(['\r', '\n', '\0']);
+    // RFC 3164 says relays give messages without a priority the priority 13, user.notice.
+    let (priority, rest) = priority(message).unwrap_or((13, message));
+    let mut payload = json!({
+        "type": "syslog",
+        "facility": FACILITIES[usize::from(priority / 8)],
+        "severity": SEVERITIES[usize::from(priority % 8)],
+    });
+    let fields = match rest.strip_prefix("1 ").and_then(rfc5424) {
+        Some(fields) => fields,
+        None => rfc3164(rest, now),
+    };
+    payload.as_object_mut().unwrap().extend(fields);
+    payload
+}
+
+/// The `<PRI>` a message starts with, and what follows it.
+fn priority(message: &str) -> Option<(u8, &str)> {
+    let (priority, rest) = message.strip_prefix('<')?.split_once('>')?;
+    if priority.is_empty() || priority.len() > 3 || !priority.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((
+        priority.parse().ok().filter(|priority| *priority < 192)?,
+        rest,
+    ))
+}
+
+/// RFC 5424's `-` for no value.
+fn nil(field: &str) -> Value {
+    match field {
+        "-" => Value::Null,
+        field => field.into(),
+    }
+}
+
+fn rfc5424(rest: &str) -> Option<Map<String, Value>> {
+    let mut header = rest.splitn(6, ' ');
+    let mut field = || header.next();
+    let (timestamp, hostname, app_name, procid, msgid) =
+        (field()?, field()?, field()?, field()?, field()?);
+    let (structured_data, message) = structured_data(field()?)?;
+    let timestamp = match timestamp {
+        "-" => None,
+        timestamp => Some(DateTime::parse_from_rfc3339(timestamp).ok()?.to_utc()),
+    };
+    let message = message.strip_prefix(' ').unwrap_or(message);
+    let message = message.strip_prefix('\u{feff}').unwrap_or(message);
+    let Value::Object(fields) = json!({
+        "format": "rfc5424",
+        "timestamp": timestamp.map(|timestamp| timestamp.to_rfc3339()),
+        "hostname": nil(hostname),
+        "app_name": nil(app_name),
+        "procid": nil(procid),
+        "msgid": nil(msgid),
+        "structured_data": structured_data,
+        "message": (!message.is_empty()).then_some(message),
+    }) else {
+        unreachable!()
+    };
+    Some(fields)
+}
+
+/// Parses `-` or `[id name="value" ...]...`, returning the elements by ID and what follows.
+/// Parameters given more than once in an element are kept as arrays of their values.
+fn structured_data(text: &str) -> Option<(Value, &str)> {
+    if let Some(rest) = text.strip_prefix('-') {
+        return Some((Value::Null, rest));
+    }
+    let mut elements = Map::new();
+    let mut rest = text.strip_prefix('[')?;
+    loop {
+        let id_end = rest.find([' ', ']'])?;
+        let id = &rest[..id_end];
+        rest = &rest[id_end..];
+        let mut params = Map::new();
+        while let Some(param) = rest.strip_prefix(' ') {
+            let (name, value) = param.split_once("=\"")?;
+            let (value, after) = param_value(value)?;
+            rest = after;
+            match params.get_mut(name) {
+                None => {
+                    params.insert(name.to_owned(), value.into());
+                }
+                Some(Value::Array(values)) => values.push(value.into()),
+                Some(first) => *first = json!([first.take(), value]),
+            }
+        }
+        rest = rest.strip_prefix(']')?;
+        elements.insert(id.to_owned(), params.into());
+        match rest.strip_prefix('[') {
+            Some(next) => rest = next,
+            None => return Some((elements.into(), rest)),
+        }
+    }
+}
+
+/// A parameter value up to its closing quote, unescaped, and what follows the quote.
+fn param_value(text: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[index + 1..])),
+            '\\' => {
+                let (_, escaped) = chars.next()?;
+                if !matches!(escaped, '"' | '\\' | ']') {
+                    value.push('\\');
+                }
+                value.push(escaped);
+            }
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Parses `Mmm dd hh:mm:ss hostname tag[pid]: message`, leaving whatever doesn't fit as the
+/// message.
+fn rfc3164(rest: &str, now: DateTime<Utc>) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert("format".into(), "rfc3164".into());
+    let mut message = rest;
+    if let Some((timestamp, after)) = rest.get(..15).zip(rest.get(15..)) {
+        if let Some(timestamp) = rfc3164_timestamp(timestamp, now) {
+            fields.insert("timestamp".into(), timestamp.to_rfc3339().into());
+            message = after.trim_start_matches(' ');
+            if let Some((hostname, after)) = message.split_once(' ') {
+                fields.insert("hostname".into(), hostname.into());
+                message = after;
+            }
+        }
+    }
+    if let Some((tag, after)) = message
+        .split_once(": ")
+        .filter(|(tag, _)| !tag.contains(' '))
+    {
+        let (app_name, procid) = match tag.strip_suffix(']').and_then(|tag| tag.split_once('[')) {
+            Some((app_name, procid)) => (app_name, Some(procid)),
+            None => (tag, None),
+        };
+        fields.insert("app_name".into(), app_name.into());
+        fields.insert("procid".into(), procid.into());
+        message = after;
+    }
+    fields.insert("message".into(), message.into());
+    fields
+}
+
+fn rfc3164_timestamp(timestamp: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let in_year = |year: i32| {
+        NaiveDateTime::parse_from_str(&format!("{} {}", year, timestamp), "%Y %b %e %H:%M:%S")
+            .ok()
+            .map(|timestamp| timestamp.and_utc())
+    };
+    let timestamp = in_year(now.year())?;
+    // Allow for senders' clocks being ahead, but not a year's worth.
+    if timestamp - now > chrono::Duration::days(1) {
+        return in_year(now.year() - 1);
+    }
+    Some(timestamp)
+}
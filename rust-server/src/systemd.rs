@@ -0,0 +1,102 @@
+use anyhow::{bail, Context, Result};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use tracing::*;
+
+/// The first file descriptor systemd passes sockets from, SD_LISTEN_FDS_START.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the sockets systemd passed with socket activation, with their FileDescriptorName=
+/// names, which default to the socket unit's name. Empty if the server wasn't socket activated,
+/// or the sockets were meant for another process. The variables are cleared so that child
+/// processes don't take them too.
+pub(crate) fn take_listen_fds() -> Result<Vec<(String, OwnedFd)>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(vec![]);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        debug!(pid, "ignoring sockets passed to another process");
+        return Ok(vec![]);
+    }
+    let count: RawFd = fds
+        .parse()
+        .with_context(|| format!("LISTEN_FDS {:?} isn't a number", fds))?;
+    let names: Vec<_> = match &names {
+        Some(names) => names.split(':').map(str::to_owned).collect(),
+        None => vec![],
+    };
+    if !names.is_empty() && names.len() != count as usize {
+        bail!(
+            "LISTEN_FDNAMES has {} names for {} sockets",
+            names.len(),
+            count
+        );
+    }
+    Ok((0..count)
+        .map(|index| {
+            let name = names.get(index as usize).cloned().unwrap_or_default();
+            // systemd hands these over for this process to own.
+            let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START + index) };
+            (name, fd)
+        })
+        .collect())
+}
+
+/// Tells the service manager about the server's state, like "READY=1", if it's waiting to hear.
+/// Does nothing when not run by systemd with Type=notify.
+pub(crate) fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(err) = notify_socket(&socket.to_string_lossy(), state) {
+        warn!(err = format!("{:#}", err), state, "notifying systemd");
+    }
+}
+
+/// Sends a notification to the socket at the path, or in the abstract namespace if it starts
+/// with "@".
+pub(crate) fn notify_socket(socket: &str, state: &str) -> Result<()> {
+    let conn = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            conn.send_to_addr(state.as_bytes(), &addr)
+        }
+        // Only Linux has an abstract namespace.
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => bail!("{} is in the abstract namespace", socket),
+        None => conn.send_to(state.as_bytes(), socket),
+    }
+    .with_context(|| format!("sending to {}", socket))?;
+    Ok(())
+}
+
+/// How often to tell systemd the server's alive, half its WatchdogSec=, if it's watching.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Pings the watchdog for as long as the runtime's running tasks, so a wedged server gets
+/// restarted.
+pub(crate) async fn feed_watchdog(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
@@ -0,0 +1,417 @@
+//! Tests against Postgres servers in containers, including over TLS with a generated CA. They need
+//! Docker, so run them with `cargo test -- --ignored`.
+
+use super::*;
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use std::time::{Duration, Instant};
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
+
+/// Images as their names and tags.
+const POSTGRES: (&str, &str) = ("postgres", "16-alpine");
+const TIMESCALE: (&str, &str) = ("timescale/timescaledb", "latest-pg16");
+const CERTS_DIR: &str = "/certs";
+
+/// The role that logs in with a client certificate rather than a password.
+const CERT_USER: &str = "certified";
+
+/// A CA, a server certificate for localhost and a client certificate for [CERT_USER] signed by it,
+/// as PEM.
+struct Certs {
+    ca_cert: String,
+    server_cert: String,
+    server_key: String,
+    client_cert: String,
+    client_key: String,
+}
+
+impl Certs {
+    fn generate() -> anyhow::Result<Self> {
+        let ca_key = KeyPair::generate()?;
+        let mut ca_params = CertificateParams::new(vec![])?;
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "telemetry test CA");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key)?;
+        let server_key = KeyPair::generate()?;
+        let server_cert = CertificateParams::new(vec!["localhost".to_owned()])?.signed_by(
+            &server_key,
+            &ca_cert,
+            &ca_key,
+        )?;
+        let client_key = KeyPair::generate()?;
+        let mut client_params = CertificateParams::new(vec![])?;
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, CERT_USER);
+        let client_cert = client_params.signed_by(&client_key, &ca_cert, &ca_key)?;
+        Ok(Self {
+            ca_cert: ca_cert.pem(),
+            server_cert: server_cert.pem(),
+            server_key: server_key.serialize_pem(),
+            client_cert: client_cert.pem(),
+            client_key: client_key.serialize_pem(),
+        })
+    }
+}
+
+/// Starts Postgres from an image of it, serving TLS with the server certificate if given, and then
+/// letting [CERT_USER] in by its client certificate. Returns the container, which stops when
+/// dropped, and a connection string for it.
+async fn start_postgres(
+    (name, tag): (&str, &str),
+    certs: Option<&Certs>,
+) -> anyhow::Result<(ContainerAsync<GenericImage>, String)> {
+    let image = GenericImage::new(name, tag)
+        .with_exposed_port(5432.tcp())
+        .with_wait_for(WaitFor::message_on_stderr(
+            "database system is ready to accept connections",
+        ))
+        .with_entrypoint("sh");
+    let mut command = "exec docker-entrypoint.sh postgres".to_owned();
+    if certs.is_some() {
+        // Postgres won't use a key that other users can read, and copied files belong to root.
+        command = format!(
+            "install -o postgres -m 600 {dir}/server.key /var/lib/postgresql/server.key && \
+            {command} -c ssl=on -c ssl_cert_file={dir}/server.crt \
+            -c ssl_key_file=/var/lib/postgresql/server.key -c ssl_ca_file={dir}/ca.crt \
+            -c hba_file={dir}/pg_hba.conf",
+            dir = CERTS_DIR,
+        );
+    }
+    let mut request = image
+        .with_env_var("POSTGRES_PASSWORD", "postgres")
+        .with_cmd(["-c".to_owned(), command]);
+    if let Some(certs) = certs {
+        request = request
+            .with_copy_to(
+                format!("{}/server.crt", CERTS_DIR),
+                certs.server_cert.clone().into_bytes(),
+            )
+            .with_copy_to(
+                format!("{}/server.key", CERTS_DIR),
+                certs.server_key.clone().into_bytes(),
+            )
+            .with_copy_to(
+                format!("{}/ca.crt", CERTS_DIR),
+                certs.ca_cert.clone().into_bytes(),
+            )
+            .with_copy_to(
+                format!("{}/pg_hba.conf", CERTS_DIR),
+                format!(
+                    "local all all trust\n\
+                    hostssl all {} all cert\n\
+                    host all all all scram-sha-256\n",
+                    CERT_USER
+                )
+                .into_bytes(),
+            );
+    }
+    let container = request.start().await?;
+    let port = container.get_host_port_ipv4(5432.tcp()).await?;
+    let conn_str = format!(
+        "host=localhost hostaddr=127.0.0.1 port={} user=postgres password=postgres",
+        port
+    );
+    Ok((container, conn_str))
+}
+
+/// Opens storage, retrying while the server finishes starting up. The entrypoint runs a temporary
+/// server to initialize the database first, which logs the same ready message.
+async fn open_when_ready(opener: &PostgresOpener) -> anyhow::Result<Postgres> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        match opener.clone().open().await {
+            Ok(conn) => return Ok(conn),
+            Err(err) if Instant::now() < deadline => {
+                debug!(%err, "waiting for postgres");
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_container_postgres_batch_and_reopen() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let (_container, conn_str) = start_postgres(POSTGRES, None).await?;
+    let opener = PostgresOpener {
+        indexes: PostgresIndexArgs {
+            query_indexes: true,
+        },
+        full_text: FullTextArgs {
+            full_text_search: true,
+        },
+        ..postgres_opener(&conn_str)
+    };
+    let conn = open_when_ready(&opener).await?;
+    let server = Server::builder(Box::new(conn))
+        .stream_token_secret("secret")
+        .build();
+    let req = axum::http::Request::post("/").body(axum::body::Body::from(
+        r#"{"event_id": "a"} {"event_id": "b"} {"event_id": "a"} {"c": 3}"#,
+    ))?;
+    let (status_code, headers, body) = server.post_handler(req).await;
+    assert_eq!(status_code, StatusCode::OK, "{}", body);
+    let stream_id = server
+        .stream_tokens
+        .verify(headers.get(STREAM_TOKEN_HEADER).unwrap().to_str()?, 0)?;
+
+    // Opening again replays the schema over the existing tables.
+    let mut conn = opener.clone().open().await?;
+    assert_eq!(conn.resume_stream(stream_id).await?, 4);
+    let (client, connection) =
+        tokio_postgres::connect(opener.conn_str.as_deref().unwrap(), NoTls).await?;
+    tokio::spawn(connection);
+    let count: i64 = client
+        .query_one("SELECT count(*) FROM events", &[])
+        .await?
+        .get(0);
+    // The repeated event ID was dropped.
+    assert_eq!(count, 3);
+    let indexes: i64 = client
+        .query_one(
+            "SELECT count(*) FROM pg_indexes WHERE indexname IN \
+            ('events_payload_gin', 'events_insert_datetime_brin')",
+            &[],
+        )
+        .await?
+        .get(0);
+    assert_eq!(indexes, 2);
+    // Events can straddle a bucket boundary, so only totals are certain.
+    let per_stream: Rollup = "per_stream=stream".parse()?;
+    conn.roll_up(&per_stream).await?;
+    let rows = conn.read_rollup("per_stream", &Default::default()).await?;
+    assert_eq!(rows[0].group, Some(stream_id.0.to_string()));
+    assert_eq!(rows.iter().map(|row| row.count).sum::<u64>(), 3);
+    let by_id: Rollup = "by_id=payload.event_id/1h".parse()?;
+    conn.roll_up(&by_id).await?;
+    let rows = conn.read_rollup("by_id", &Default::default()).await?;
+    assert!(rows.iter().any(|row| row.group.is_none()));
+    let points = conn
+        .read_series(&SeriesQuery {
+            selection: Default::default(),
+            aggregate: "max:c".parse()?,
+            bucket: Duration::from_secs(3600),
+        })
+        .await?;
+    assert_eq!(points.iter().map(|point| point.value).sum::<f64>(), 3.0);
+    let metric_point = MetricPoint {
+        name: "queue.depth".to_owned(),
+        resource: json!({"service.name": "checkout"}),
+        attributes: json!({}),
+        datetime: chrono::Utc::now(),
+        value: 2.5,
+    };
+    assert_eq!(conn.insert_metric_points(&[metric_point]).await?, 1);
+
+    let mut batches = vec![];
+    let query = EventQuery {
+        limit: 10,
+        ..Default::default()
+    };
+    conn.snapshot_batches(&query, 2, &mut |snapshot| {
+        batches.push((snapshot.events.len(), snapshot.streams.len()));
+        Ok(())
+    })
+    .await?;
+    assert_eq!(batches, [(2, 1), (1, 1)]);
+    let found = conn
+        .query_events(&EventQuery {
+            limit: 10,
+            text: Some("b".to_owned()),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0]["payload"]["event_id"], "b");
+
+    // Imported streams keep their IDs, and new streams are numbered after them.
+    let imported = StreamId(1000);
+    conn.import_streams(&[ImportedStream {
+        stream_id: imported,
+        headers: json!({}),
+        start_datetime: chrono::Utc::now(),
+        end_datetime: None,
+        event_count: None,
+        clock_skew_ms: None,
+        stream_uuid: None,
+    }])
+    .await?;
+    let event = ImportedEvent {
+        stream_id: imported,
+        stream_event_index: 1,
+        insert_datetime: chrono::Utc::now(),
+        revision: 0,
+        payload: json!({}),
+        event_id: None,
+        collector: None,
+        client_datetime: None,
+    };
+    assert_eq!(conn.import_events(std::slice::from_ref(&event)).await?, 1);
+    assert_eq!(conn.import_events(&[event]).await?, 0);
+    assert!(conn.new_stream(json!({})).await?.0 > 1000);
+
+    let events = conn.events_after(None, 10).await?;
+    assert_eq!(events.len(), 4);
+    let after_first = EventCursor::after(&events[0]);
+    assert_eq!(
+        conn.events_after(Some(&after_first), 10).await?,
+        events[1..]
+    );
+    let streams = conn.streams_by_id(&[imported, StreamId(999)]).await?;
+    assert_eq!(streams.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_container_postgres_tls_root_cert() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let certs = Certs::generate()?;
+    let (_container, conn_str) = start_postgres(POSTGRES, Some(&certs)).await?;
+    let dir = tempfile::tempdir()?;
+    let ca_path = dir.path().join("ca.crt");
+    std::fs::write(&ca_path, &certs.ca_cert)?;
+    let opener = PostgresOpener {
+        tls_root_cert_path: Some(ca_path.to_str().unwrap().to_owned()),
+        use_tls: true,
+        ..postgres_opener(&format!("{} sslmode=verify-full", conn_str))
+    };
+    let mut conn = open_when_ready(&opener).await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    conn.insert_event(stream_id, 1, raw("{}"), None, None, None)
+        .await?;
+    assert_eq!(conn.resume_stream(stream_id).await?, 1);
+
+    // The generated CA isn't trusted without the root cert path.
+    let untrusted = PostgresOpener {
+        tls_root_cert_path: None,
+        ..opener.clone()
+    };
+    assert!(untrusted.clone().open().await.is_err());
+    opener.check().await?;
+    assert!(untrusted.check().await.is_err());
+
+    // The certificate is for localhost, so only verify-ca accepts it for the address.
+    let by_address = conn_str.replace("host=localhost hostaddr=127.0.0.1", "host=127.0.0.1");
+    let verify_full = PostgresOpener {
+        conn_str: Some(format!("{} sslmode=verify-full", by_address)),
+        ..opener.clone()
+    };
+    assert!(verify_full.check().await.is_err());
+    let verify_ca = PostgresOpener {
+        conn_str: Some(format!("{} sslmode=verify-ca", by_address)),
+        ..opener.clone()
+    };
+    verify_ca.check().await?;
+    // require doesn't check the certificate without a root cert to check it against.
+    let require = PostgresOpener {
+        conn_str: Some(format!("{} sslmode=require", conn_str)),
+        ..untrusted
+    };
+    require.check().await?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_container_postgres_client_cert() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let certs = Certs::generate()?;
+    let (_container, conn_str) = start_postgres(POSTGRES, Some(&certs)).await?;
+    open_when_ready(&postgres_opener(&conn_str)).await?;
+    let (client, conn) = tokio_postgres::connect(&conn_str, tokio_postgres::NoTls).await?;
+    tokio::spawn(conn);
+    client
+        .batch_execute(&format!(
+            "CREATE ROLE {user} LOGIN; CREATE DATABASE {user} OWNER {user}",
+            user = CERT_USER
+        ))
+        .await?;
+
+    let dir = tempfile::tempdir()?;
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+    std::fs::write(path("ca.crt"), &certs.ca_cert)?;
+    std::fs::write(path("client.crt"), &certs.client_cert)?;
+    std::fs::write(path("client.key"), &certs.client_key)?;
+    let by_cert = conn_str.replace("user=postgres password=postgres", "user=certified");
+    let opener = postgres_opener(&format!(
+        "{} sslmode=verify-full sslrootcert='{}' sslcert='{}' sslkey='{}'",
+        by_cert,
+        path("ca.crt"),
+        path("client.crt"),
+        path("client.key")
+    ));
+    assert_eq!(opener.info()["tls_client_cert_path"], path("client.crt"));
+    let mut conn = opener.clone().open().await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    conn.insert_event(stream_id, 1, raw("{}"), None, None, None)
+        .await?;
+    assert_eq!(conn.resume_stream(stream_id).await?, 1);
+
+    // Without the client certificate, there's no password to fall back on.
+    let without_cert = postgres_opener(&format!(
+        "{} sslmode=verify-full sslrootcert='{}'",
+        by_cert,
+        path("ca.crt")
+    ));
+    assert!(without_cert.check().await.is_err());
+    // The key has to come with the certificate.
+    let without_key = postgres_opener(&format!(
+        "{} sslmode=verify-full sslrootcert='{}' sslcert='{}'",
+        by_cert,
+        path("ca.crt"),
+        path("client.crt")
+    ));
+    assert!(without_key.check().await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_container_timescale() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let (_container, conn_str) = start_postgres(TIMESCALE, None).await?;
+    let opener = postgres_opener(&conn_str);
+    let mut conn = open_when_ready(&opener).await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    conn.insert_event(stream_id, 1, raw("{}"), Some("a"), None, None)
+        .await?;
+
+    // The events already stored are moved into chunks.
+    let opener = PostgresOpener {
+        timescale: TimescaleArgs {
+            timescale: true,
+            timescale_chunk_interval: Duration::from_secs(60 * 60),
+            timescale_compress_after: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+        },
+        ..opener
+    };
+    let mut conn = opener.open().await?;
+    assert_eq!(
+        conn.insert_event(stream_id, 2, raw("{}"), Some("a"), None, None)
+            .await?,
+        Inserted::Duplicate
+    );
+    assert_eq!(
+        conn.insert_event(stream_id, 2, raw("{}"), Some("b"), None, None)
+            .await?,
+        Inserted::Stored
+    );
+
+    // Opening again keeps the hypertable, and its compression policy.
+    let mut conn = opener.open().await?;
+    let policy = RetentionPolicy {
+        before: Some(chrono::Utc::now() + chrono::Duration::hours(2)),
+        ..Default::default()
+    };
+    let pruned = conn.prune(&policy).await?;
+    assert_eq!((pruned.events, pruned.streams), (2, 1));
+    assert!(pruned.partitions > 0);
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use crate::EventSelectionParams;
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use telemetry_storage::{parse_datetime, EventCursor, EventQuery, StreamId};
+
+/// Where the UI is served. Links resolve to views under it.
+pub(crate) const UI_PATH: &str = "/ui";
+
+/// The UI's page, which does the rest in the browser with the API.
+pub(crate) async fn ui_handler() -> axum::response::Html<&'static str> {
+    axum::response::Html(include_str!("../ui/index.html"))
+}
+
+/// Events returned when a view doesn't say how many.
+const DEFAULT_VIEW_LIMIT: usize = 100;
+
+/// A slice of stored events as shown in the UI. Views are encoded entirely in URL query
+/// parameters, so any of them can be linked to. The admin commands take them as flags.
+#[derive(Clone, Default, serde::Deserialize, clap::Args)]
+pub(crate) struct ViewParams {
+    /// Only events from this stream.
+    #[arg(long)]
+    stream_id: Option<u32>,
+    /// Only events inserted at or after this RFC 3339 time.
+    #[arg(long)]
+    since: Option<String>,
+    /// Only events inserted before this RFC 3339 time.
+    #[arg(long)]
+    until: Option<String>,
+    /// Comma separated `field:value` pairs. Events are shown if their top-level payload fields
+    /// have those values.
+    #[arg(long)]
+    filter: Option<String>,
+    /// Words the payload must all contain, searched with the storage's full-text index. `q` in
+    /// URLs.
+    #[serde(rename = "q")]
+    #[arg(long)]
+    search: Option<String>,
+    /// At most this many events.
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Only events after the page this came with, as `next_cursor`.
+    #[arg(long)]
+    cursor: Option<String>,
+}
+
+impl ViewParams {
+    pub(crate) fn from_query(query: &str) -> Result<Self> {
+        let uri = format!("/?{}", query).parse()?;
+        let axum::extract::Query(params) = axum::extract::Query::try_from_uri(&uri)?;
+        Ok(params)
+    }
+
+    pub(crate) fn query(&self) -> Result<EventQuery> {
+        self.query_or_limit(DEFAULT_VIEW_LIMIT)
+    }
+
+    /// Like [Self::query], for callers with their own default limit.
+    pub(crate) fn query_or_limit(&self, default_limit: usize) -> Result<EventQuery> {
+        let selection = EventSelectionParams {
+            stream_id: self.stream_id,
+            since: self.since.clone(),
+            until: self.until.clone(),
+        }
+        .selection()?;
+        let filters = self
+            .filter
+            .iter()
+            .flat_map(|filter| filter.split(','))
+            .map(|pair| {
+                let (field, value) = pair
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("filter {:?} should be field:value", pair))?;
+                Ok((field.to_owned(), value.to_owned()))
+            })
+            .collect::<Result<_>>()?;
+        let after = self.cursor.as_deref().map(decode_cursor).transpose()?;
+        Ok(EventQuery {
+            selection,
+            filters,
+            limit: self.limit.unwrap_or(default_limit),
+            after,
+            text: self.search.clone(),
+        })
+    }
+}
+
+/// Short links are named by a hash of the view, so linking to the same view twice gives the same
+/// link.
+pub(crate) fn link_id(query: &str) -> String {
+    Sha256::digest(query.as_bytes())[..5]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Cursors are opaque to clients, and hex so they needn't be escaped in URLs. They hold the place
+/// after an event, so pages stay put as events are added, and each is read with the index.
+pub(crate) fn encode_cursor(cursor: &EventCursor) -> String {
+    format!(
+        "{},{},{}",
+        cursor.insert_datetime.to_rfc3339(),
+        cursor.stream_id.0,
+        cursor.stream_event_index
+    )
+    .bytes()
+    .map(|byte| format!("{:02x}", byte))
+    .collect()
+}
+
+fn decode_cursor(hex: &str) -> Result<EventCursor> {
+    let decode = || -> Result<EventCursor> {
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|at| {
+                Ok(u8::from_str_radix(
+                    hex.get(at..at + 2).context("odd length")?,
+                    16,
+                )?)
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        let text = String::from_utf8(bytes)?;
+        let mut parts = text.split(',');
+        let mut next = || parts.next().context("too few parts");
+        Ok(EventCursor {
+            insert_datetime: parse_datetime(next()?)?,
+            stream_id: StreamId(next()?.parse()?),
+            stream_event_index: next()?.parse()?,
+        })
+    };
+    decode().with_context(|| format!("invalid cursor {:?}", hex))
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::*;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_dispatcher;
+
+/// The name services running in their own process are registered under, which the service control
+/// manager doesn't check.
+const SERVICE_NAME: &str = "telemetry";
+
+/// How long the service control manager is told to wait for storage to be flushed on stop.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(30);
+
+/// The command line the service was installed with, for the service's thread to serve with.
+static ARGV: OnceLock<Vec<OsString>> = OnceLock::new();
+static STATUS: OnceLock<service_control_handler::ServiceStatusHandle> = OnceLock::new();
+static STOP: Notify = Notify::const_new();
+static RELOAD: Notify = Notify::const_new();
+
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+/// Whether the command line asks to run as a service, which has to be known before the server
+/// would otherwise start, as the service control manager waits to be connected to first.
+pub(crate) fn requested(argv: &[OsString]) -> bool {
+    argv.iter().any(|arg| arg == "--windows-service")
+}
+
+/// Serves as a Windows service, returning once it's stopped.
+pub(crate) fn dispatch(argv: Vec<OsString>) -> Result<()> {
+    ARGV.set(argv).expect("the service is only dispatched once");
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("connecting to the service control manager")?;
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let exit_code = match run_service() {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(err) => {
+            error!(err = format!("{:#}", err), "serving as a windows service");
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+    set_state(ServiceState::Stopped, exit_code);
+}
+
+fn run_service() -> Result<()> {
+    let status = service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown | ServiceControl::Preshutdown => {
+            STOP.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::ParamChange => {
+            RELOAD.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let _ = STATUS.set(status);
+    set_state(ServiceState::StartPending, ServiceExitCode::Win32(0));
+    let argv = ARGV.get().expect("dispatched with a command line").clone();
+    tokio::runtime::Runtime::new()?.block_on(crate::run(argv))
+}
+
+/// Tells the service control manager what state the service is in, if running as one.
+pub(crate) fn set_state(state: ServiceState, exit_code: ServiceExitCode) {
+    let Some(status) = STATUS.get() else {
+        return;
+    };
+    let controls_accepted = match state {
+        ServiceState::Running => {
+            ServiceControlAccept::STOP
+                | ServiceControlAccept::SHUTDOWN
+                | ServiceControlAccept::PARAM_CHANGE
+        }
+        _ => ServiceControlAccept::empty(),
+    };
+    let wait_hint = match state {
+        ServiceState::StartPending | ServiceState::StopPending => STOP_WAIT_HINT,
+        _ => Duration::ZERO,
+    };
+    let result = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    });
+    if let Err(err) = result {
+        warn!(%err, ?state, "setting service status");
+    }
+}
+
+/// Waits for the service control manager to stop the service, which never happens when not
+/// running as one.
+pub(crate) async fn stopped() {
+    STOP.notified().await
+}
+
+/// Waits for the service control manager to ask for the config to be reread, with `sc control
+/// <name> paramchange`.
+pub(crate) async fn reload_requested() {
+    RELOAD.notified().await
+}
@@ -0,0 +1,71 @@
+use crate::import::list_files;
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::io::Write;
+use std::path::PathBuf;
+use telemetry_storage::{read_json_lines, EncryptionArgs};
+
+/// zstd's own default dictionary size.
+const DEFAULT_DICTIONARY_BYTES: usize = 112640;
+
+/// Trains a zstd dictionary on the lines in JSON files storage output, for its
+/// --compression-dictionary, and prints as JSON how much better it compresses them.
+#[derive(Clone, clap::Args)]
+pub(crate) struct ZstdTrainArgs {
+    /// Files to learn from. Directories are read for their ".json.zst" files.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Where to write the dictionary.
+    #[arg(long, short)]
+    output: PathBuf,
+    /// The largest the dictionary can be, in bytes.
+    #[arg(long, default_value_t = DEFAULT_DICTIONARY_BYTES)]
+    max_size: usize,
+    /// Lines to learn from, at most, taken from the first files.
+    #[arg(long, default_value_t = 100000)]
+    max_samples: usize,
+    /// The key encrypted files (ending in ".enc") were written with.
+    #[command(flatten)]
+    encryption: EncryptionArgs,
+}
+
+impl ZstdTrainArgs {
+    pub(crate) fn run(&self, out: &mut impl Write) -> Result<()> {
+        let key = self.encryption.key()?;
+        let mut samples = vec![];
+        'files: for path in list_files(&self.files)? {
+            for line in read_json_lines(&path, key.as_ref(), None)? {
+                if samples.len() >= self.max_samples {
+                    break 'files;
+                }
+                let mut sample = serde_json::to_vec(&line?)?;
+                sample.push(b'\n');
+                samples.push(sample);
+            }
+        }
+        if samples.is_empty() {
+            bail!("no lines to train on");
+        }
+        let dictionary = zstd::dict::from_samples(&samples, self.max_size)
+            .context("training dictionary, which needs plenty of samples")?;
+        std::fs::write(&self.output, &dictionary)
+            .with_context(|| format!("writing {}", self.output.display()))?;
+        // Each line on its own, as events are often flushed a few at a time.
+        let mut compressor = zstd::bulk::Compressor::with_dictionary(0, &dictionary)?;
+        let (mut compressed, mut with_dictionary) = (0, 0);
+        for sample in &samples {
+            compressed += zstd::bulk::compress(sample, 0)?.len();
+            with_dictionary += compressor.compress(sample)?.len();
+        }
+        let line = json!({
+            "output": self.output,
+            "samples": samples.len(),
+            "dictionary_bytes": dictionary.len(),
+            "sample_bytes": samples.iter().map(Vec::len).sum::<usize>(),
+            "compressed_bytes": compressed,
+            "compressed_with_dictionary_bytes": with_dictionary,
+        });
+        writeln!(out, "{}", line)?;
+        Ok(())
+    }
+}
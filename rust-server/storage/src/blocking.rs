@@ -0,0 +1,35 @@
+use super::*;
+use std::time::{Duration, Instant};
+
+/// How long calls into storage that blocks its thread, like SQLite waiting on a lock or an fsync,
+/// held the thread up.
+#[derive(Debug, Default)]
+pub(crate) struct BlockingStats {
+    calls: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl BlockingStats {
+    /// Runs a call that blocks, letting the async runtime move its other tasks off this thread
+    /// meanwhile. The runtime has to be multi-threaded.
+    pub(crate) fn run<T>(&mut self, call: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = tokio::task::block_in_place(call);
+        let blocked = start.elapsed();
+        self.calls += 1;
+        self.total += blocked;
+        self.max = self.max.max(blocked);
+        result
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let total_ms = self.total.as_secs_f64() * 1000.;
+        json!({
+            "calls": self.calls,
+            "total_ms": total_ms,
+            "mean_ms": total_ms / self.calls.max(1) as f64,
+            "max_ms": self.max.as_secs_f64() * 1000.,
+        })
+    }
+}
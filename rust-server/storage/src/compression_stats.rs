@@ -0,0 +1,134 @@
+use super::*;
+use std::sync::Mutex;
+
+/// Every nth event of a stream is compressed on its own to estimate how well the stream's payloads
+/// compress without help from surrounding data.
+const SAMPLE_EVERY: u64 = 16;
+const SAMPLE_LEVEL: i32 = 3;
+
+/// Payloads with more entropy than this (bits per byte) are nearly incompressible.
+const HIGH_ENTROPY: f64 = 7.0;
+/// Standalone compression ratios below this are considered poor.
+const POOR_RATIO: f64 = 2.0;
+/// Dictionaries only pay off for payloads smaller than this.
+const DICTIONARY_MAX_PAYLOAD: f64 = 4096.;
+
+#[derive(Default)]
+struct StreamCompression {
+    events: u64,
+    raw_bytes: u64,
+    sampled_raw_bytes: u64,
+    sampled_compressed_bytes: u64,
+    byte_counts: Vec<u64>,
+}
+
+impl StreamCompression {
+    fn entropy(&self) -> f64 {
+        let total = self.byte_counts.iter().sum::<u64>() as f64;
+        self.byte_counts
+            .iter()
+            .filter(|&&count| count != 0)
+            .map(|&count| {
+                let p = count as f64 / total;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    fn standalone_ratio(&self) -> Option<f64> {
+        if self.sampled_compressed_bytes == 0 {
+            return None;
+        }
+        Some(self.sampled_raw_bytes as f64 / self.sampled_compressed_bytes as f64)
+    }
+
+    fn recommendation(&self) -> Option<&'static str> {
+        let mean_payload = self.raw_bytes as f64 / self.events as f64;
+        if self.entropy() >= HIGH_ENTROPY {
+            return Some(
+                "payloads are close to incompressible: check for embedded base64 or \
+                already-compressed data, and consider a binary format",
+            );
+        }
+        match self.standalone_ratio() {
+            Some(ratio) if ratio < POOR_RATIO && mean_payload < DICTIONARY_MAX_PAYLOAD => {
+                Some("small payloads compress poorly on their own: train a zstd dictionary on them with \
+                    zstd-train")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tracks how compressible each open stream's payloads are in the file backends. Samples are
+/// compressed on a blocking thread, so the connection isn't held up by them.
+#[derive(Default)]
+pub(crate) struct CompressionStats {
+    streams: Arc<Mutex<HashMap<StreamId, StreamCompression>>>,
+}
+
+impl CompressionStats {
+    pub(crate) fn record(&self, stream_id: StreamId, payload: &[u8]) {
+        let sample = {
+            let mut streams = self.streams.lock().unwrap();
+            let stream = streams.entry(stream_id).or_default();
+            if stream.byte_counts.is_empty() {
+                stream.byte_counts = vec![0; 256];
+            }
+            for &byte in payload {
+                stream.byte_counts[byte as usize] += 1;
+            }
+            let sample = stream.events.is_multiple_of(SAMPLE_EVERY);
+            stream.events += 1;
+            stream.raw_bytes += payload.len() as u64;
+            sample
+        };
+        if !sample {
+            return;
+        }
+        let streams = self.streams.clone();
+        let payload = payload.to_vec();
+        let compress = move || match zstd::bulk::compress(&payload, SAMPLE_LEVEL) {
+            Ok(compressed) => {
+                // The stream may have closed meanwhile.
+                if let Some(stream) = streams.lock().unwrap().get_mut(&stream_id) {
+                    stream.sampled_raw_bytes += payload.len() as u64;
+                    stream.sampled_compressed_bytes += compressed.len() as u64;
+                }
+            }
+            Err(err) => warn!(%stream_id, %err, "sampling compression"),
+        };
+        tokio::task::spawn_blocking(compress);
+    }
+
+    /// Drops a stream that's closed or forgotten, so only open streams are tracked.
+    pub(crate) fn forget(&self, stream_id: StreamId) {
+        self.streams.lock().unwrap().remove(&stream_id);
+    }
+
+    /// Reports the streams with the worst standalone compression ratios first.
+    pub(crate) fn report(&self, limit: usize) -> serde_json::Value {
+        let streams = self.streams.lock().unwrap();
+        let mut streams: Vec<_> = streams.iter().collect();
+        streams.sort_by(|(_, a), (_, b)| {
+            let a = a.standalone_ratio().unwrap_or(f64::INFINITY);
+            let b = b.standalone_ratio().unwrap_or(f64::INFINITY);
+            a.total_cmp(&b)
+        });
+        let streams: Vec<_> = streams
+            .into_iter()
+            .take(limit)
+            .map(|(stream_id, stream)| {
+                json!({
+                    "stream_id": stream_id.0,
+                    "events": stream.events,
+                    "raw_bytes": stream.raw_bytes,
+                    "entropy_bits_per_byte": stream.entropy(),
+                    "standalone_compression_ratio": stream.standalone_ratio(),
+                    "recommendation": stream.recommendation(),
+                })
+            })
+            .collect();
+        json!({ "streams": streams })
+    }
+}
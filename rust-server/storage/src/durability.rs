@@ -0,0 +1,105 @@
+use super::*;
+use std::time::{Duration, Instant};
+
+/// How far writes get toward disk before they count as stored, trading what a crash of the machine
+/// can lose for write throughput. None of them lose anything if only the server process dies.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Durability {
+    /// Every commit is fsynced before it's acknowledged.
+    FsyncEveryCommit,
+    /// Writes are fsynced at most every --fsync-interval, so that much can be lost.
+    FsyncInterval,
+    /// Writing back to disk is left to the operating system.
+    OsBuffered,
+}
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct DurabilityArgs {
+    /// What a power loss or OS crash can lose. SQLite sets the synchronous pragma, Postgres
+    /// synchronous_commit for the session, and JSON files fsync the file being written. Without it,
+    /// SQLite and Postgres keep their own settings, and JSON files are left to the OS.
+    #[arg(long, value_enum)]
+    pub durability: Option<Durability>,
+    /// How often --durability fsync-interval fsyncs, on the first flush after it's passed.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    pub fsync_interval: Duration,
+}
+
+impl DurabilityArgs {
+    /// How the storage's writes get to disk, after `what` is stored, for startup info.
+    pub(crate) fn info(&self, what: &str) -> serde_json::Value {
+        let how = match self.durability {
+            None => return json!(what),
+            Some(Durability::FsyncEveryCommit) => "fsynced on every commit".to_owned(),
+            Some(Durability::FsyncInterval) => format!(
+                "fsynced every {}",
+                humantime::format_duration(self.fsync_interval)
+            ),
+            Some(Durability::OsBuffered) => "left to the OS to write back".to_owned(),
+        };
+        json!(format!("{}, {}", what, how))
+    }
+
+    /// SQLite's own fsyncs are all or nothing, so fsyncing on an interval is done by
+    /// [FsyncSchedule].
+    pub(crate) fn sqlite_synchronous(&self) -> Option<&'static str> {
+        self.durability.map(|durability| match durability {
+            Durability::FsyncEveryCommit => "full",
+            Durability::FsyncInterval | Durability::OsBuffered => "off",
+        })
+    }
+
+    /// Postgres flushes asynchronous commits on the server's wal_writer_delay, not the interval
+    /// here.
+    pub(crate) fn postgres_synchronous_commit(&self) -> Option<&'static str> {
+        self.durability.map(|durability| match durability {
+            Durability::FsyncEveryCommit => "on",
+            Durability::FsyncInterval | Durability::OsBuffered => "off",
+        })
+    }
+
+    /// When storage that doesn't fsync for itself should, with `default` if no durability was
+    /// given.
+    pub(crate) fn fsync_schedule(&self, default: Durability) -> FsyncSchedule {
+        let every = match self.durability.unwrap_or(default) {
+            Durability::FsyncEveryCommit => Some(Duration::ZERO),
+            Durability::FsyncInterval => Some(self.fsync_interval),
+            Durability::OsBuffered => None,
+        };
+        FsyncSchedule { every, last: None }
+    }
+}
+
+/// Paces explicit fsyncs of storage files.
+#[derive(Clone, Debug)]
+pub(crate) struct FsyncSchedule {
+    /// Never fsyncs if unset.
+    every: Option<Duration>,
+    last: Option<Instant>,
+}
+
+impl FsyncSchedule {
+    pub(crate) fn never() -> Self {
+        Self {
+            every: None,
+            last: None,
+        }
+    }
+
+    /// Whether files get fsynced at all, like when they're finished.
+    pub(crate) fn enabled(&self) -> bool {
+        self.every.is_some()
+    }
+
+    /// Whether to fsync now, counting it as done if so.
+    pub(crate) fn due(&mut self) -> bool {
+        let Some(every) = self.every else {
+            return false;
+        };
+        if self.last.is_some_and(|last| last.elapsed() < every) {
+            return false;
+        }
+        self.last = Some(Instant::now());
+        true
+    }
+}
@@ -0,0 +1,253 @@
+use super::*;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Starts every encrypted file, ahead of the random nonce prefix.
+const MAGIC: &[u8; 8] = b"TLMAES1\n";
+/// Plaintext bytes per sealed chunk, so files are encrypted and decrypted without holding them in
+/// memory.
+const CHUNK_BYTES: usize = 64 << 10;
+/// What finished JSON files get appended to their names once encrypted.
+pub const ENCRYPTED_SUFFIX: &str = ".enc";
+
+/// Where the key for encrypting storage at rest comes from. Without either, nothing's encrypted.
+#[derive(Clone, Default, clap::Args)]
+pub struct EncryptionArgs {
+    /// Environment variable holding the key to encrypt storage at rest with, as 64 hex digits.
+    #[arg(long)]
+    encryption_key_env: Option<String>,
+    /// Shell command that prints the encryption key as 64 hex digits, like one asking a KMS to
+    /// decrypt it. It's run each time the storage is opened.
+    #[arg(long, conflicts_with = "encryption_key_env")]
+    encryption_key_command: Option<String>,
+}
+
+impl EncryptionArgs {
+    /// None if encryption isn't enabled.
+    pub fn key(&self) -> Result<Option<EncryptionKey>> {
+        let hex = if let Some(var) = &self.encryption_key_env {
+            std::env::var(var).with_context(|| format!("reading encryption key from ${}", var))?
+        } else if let Some(script) = &self.encryption_key_command {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(script)
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .context("running encryption key command")?;
+            if !output.status.success() {
+                bail!("encryption key command failed: {}", output.status);
+            }
+            String::from_utf8(output.stdout).context("encryption key command output")?
+        } else {
+            return Ok(None);
+        };
+        EncryptionKey::from_hex(hex.trim()).map(Some)
+    }
+
+    /// Where the key comes from, for startup info. The key and command are left out.
+    pub fn info(&self) -> serde_json::Value {
+        match (&self.encryption_key_env, &self.encryption_key_command) {
+            (Some(_), _) => json!("env"),
+            (None, Some(_)) => json!("command"),
+            (None, None) => json!(null),
+        }
+    }
+}
+
+/// An AES-256 key. Files are sealed with AES-GCM, and SQLite databases are keyed with it raw.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let mut key = [0; 32];
+        if hex.len() != key.len() * 2 || !hex.is_ascii() {
+            bail!("encryption key must be 64 hex digits");
+        }
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)
+                .context("encryption key must be 64 hex digits")?;
+        }
+        Ok(Self(key))
+    }
+
+    fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn aead(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).unwrap())
+    }
+}
+
+/// Chunks are numbered so they can't be reordered or dropped, and the last is marked so a file
+/// can't be cut short without it showing.
+fn chunk_nonce(prefix: &[u8; 8], counter: u32) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Reads until `buf` is full or the input ends, returning how much was read.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Replaces a finished file with an encrypted copy named with [ENCRYPTED_SUFFIX], returning the
+/// new path. The copy is synced before the original is removed.
+pub(crate) fn encrypt_file(key: &EncryptionKey, path: &Path) -> Result<PathBuf> {
+    let mut encrypted_path = path.as_os_str().to_owned();
+    encrypted_path.push(ENCRYPTED_SUFFIX);
+    let encrypted_path = PathBuf::from(encrypted_path);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut out = tempfile::NamedTempFile::new_in(dir).context("opening encrypted file")?;
+    let mut input = std::fs::File::open(path)?;
+    let aead = key.aead();
+    let prefix: [u8; 8] = rand::random();
+    out.write_all(MAGIC)?;
+    out.write_all(&prefix)?;
+    let mut chunk = vec![0; CHUNK_BYTES];
+    let mut len = read_full(&mut input, &mut chunk)?;
+    let mut next = vec![0; CHUNK_BYTES];
+    for counter in 0u32.. {
+        let next_len = match len {
+            CHUNK_BYTES => read_full(&mut input, &mut next)?,
+            _ => 0,
+        };
+        let last = next_len == 0;
+        let mut sealed = chunk[..len].to_vec();
+        aead.seal_in_place_append_tag(
+            chunk_nonce(&prefix, counter),
+            Aad::from([last as u8]),
+            &mut sealed,
+        )
+        .map_err(|_| anyhow!("encrypting {}", path.display()))?;
+        out.write_all(&[last as u8])?;
+        out.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        out.write_all(&sealed)?;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+    }
+    out.as_file().sync_all()?;
+    out.persist(&encrypted_path)?;
+    std::fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+    Ok(encrypted_path)
+}
+
+/// Decrypts what [encrypt_file] wrote as it's read. Errors if the file was tampered with or cut
+/// short.
+pub struct DecryptingReader<R> {
+    inner: R,
+    aead: LessSafeKey,
+    prefix: [u8; 8],
+    counter: u32,
+    plaintext: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(key: &EncryptionKey, mut inner: R) -> Result<Self> {
+        let mut header = [0; 16];
+        if read_full(&mut inner, &mut header)? != header.len() || &header[..8] != MAGIC {
+            bail!("not an encrypted telemetry file");
+        }
+        Ok(Self {
+            inner,
+            aead: key.aead(),
+            prefix: header[8..].try_into().unwrap(),
+            counter: 0,
+            plaintext: vec![],
+            pos: 0,
+            finished: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+        let mut header = [0; 5];
+        if read_full(&mut self.inner, &mut header)? != header.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "encrypted file is cut short",
+            ));
+        }
+        // The last chunk flag is sealed in with the chunk, so it can't be changed either.
+        let last = header[0];
+        let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+        if len > CHUNK_BYTES + AES_256_GCM.tag_len() {
+            return Err(Error::new(ErrorKind::InvalidData, "corrupt encrypted file"));
+        }
+        let mut sealed = vec![0; len];
+        self.inner.read_exact(&mut sealed)?;
+        let opened = self
+            .aead
+            .open_in_place(
+                chunk_nonce(&self.prefix, self.counter),
+                Aad::from([last]),
+                &mut sealed,
+            )
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "wrong key or corrupt encrypted file",
+                )
+            })?
+            .len();
+        sealed.truncate(opened);
+        self.plaintext = sealed;
+        self.finished = last != 0;
+        self.pos = 0;
+        self.counter += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = buf.len().min(self.plaintext.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plaintext[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Keys a SQLite connection before anything's read from it. Errors unless SQLite was built with
+/// SQLCipher, since plain SQLite ignores the key and would store everything unencrypted.
+pub(super) fn key_sqlite(conn: &rusqlite::Connection, key: &EncryptionKey) -> Result<()> {
+    use rusqlite::OptionalExtension;
+    conn.pragma_update(None, "key", format!("x'{}'", key.to_hex()))?;
+    let cipher_version: Option<String> = conn
+        .query_row("pragma cipher_version", [], |row| row.get(0))
+        .optional()?;
+    if cipher_version.is_none() {
+        bail!("encrypting SQLite needs a build with the sqlcipher feature");
+    }
+    conn.query_row("select count(*) from sqlite_master", [], |_| Ok(()))
+        .context("reading encrypted database, which needs the key it was created with")?;
+    Ok(())
+}
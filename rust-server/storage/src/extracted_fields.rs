@@ -0,0 +1,252 @@
+use super::*;
+use rusqlite::OptionalExtension;
+use std::str::FromStr;
+
+/// A payload field copied into a column of its own on the events table, with an index, for
+/// queries that would be slow on the JSON.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtractedField {
+    column: String,
+    /// A JSON pointer, like "/device/id".
+    pointer: String,
+}
+
+impl FromStr for ExtractedField {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (column, pointer) = text
+            .split_once('=')
+            .context("extracted field must be COLUMN=POINTER, like level=/level")?;
+        let mut chars = column.chars();
+        let valid_column = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+            && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_column {
+            bail!(
+                "column name {:?} must be lowercase letters, digits and underscores",
+                column
+            );
+        }
+        if !pointer.starts_with('/') {
+            bail!("{:?} isn't a JSON pointer to a field, like /level", pointer);
+        }
+        if pointer.contains('"') {
+            bail!("JSON pointer {:?} can't have a double quote", pointer);
+        }
+        Ok(Self {
+            column: column.to_owned(),
+            pointer: pointer.to_owned(),
+        })
+    }
+}
+
+impl ExtractedField {
+    fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.pointer[1..]
+            .split('/')
+            .map(|key| key.replace("~1", "/").replace("~0", "~"))
+    }
+
+    /// The field as an SQLite JSON path. Keys of digits are taken as array indexes.
+    fn sqlite_path(&self) -> String {
+        let mut path = "$".to_owned();
+        for key in self.keys() {
+            if !key.is_empty() && key.bytes().all(|b| b.is_ascii_digit()) {
+                path += &format!("[{}]", key);
+            } else {
+                path += &format!(".\"{}\"", key.replace('\'', "''"));
+            }
+        }
+        path
+    }
+
+    /// The field as a Postgres text array for `#>>`.
+    fn postgres_path(&self) -> String {
+        let keys: Vec<String> = self
+            .keys()
+            .map(|key| format!("'{}'", key.replace('\'', "''")))
+            .collect();
+        format!("ARRAY[{}]::text[]", keys.join(", "))
+    }
+
+    fn index(&self) -> String {
+        format!("events_extracted_{}", self.column)
+    }
+}
+
+/// Payload fields to keep in columns of the events table. Columns are added when the storage is
+/// opened, and recorded in the extracted_fields table with their pointer. A column whose pointer
+/// changes is dropped and added again, and one no longer extracted is left as it is.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct ExtractArgs {
+    /// Copies a payload field into an indexed column of the events table, as COLUMN=POINTER, like
+    /// "level=/level" or "device_id=/device/id". Can be repeated.
+    #[arg(long, value_parser = ExtractedField::from_str)]
+    extract_field: Vec<ExtractedField>,
+}
+
+/// What's already done about a field.
+enum Existing {
+    /// Nothing in the way.
+    None,
+    /// Extracted before, from this pointer.
+    Extracted(String),
+    /// A column that wasn't extracted, like one of the schema's.
+    Column,
+}
+
+impl ExtractArgs {
+    /// Extracts the field too, for embedders with fields of their own, unless its column is
+    /// already given.
+    pub fn add(&mut self, field: ExtractedField) {
+        if !self
+            .extract_field
+            .iter()
+            .any(|given| given.column == field.column)
+        {
+            self.extract_field.push(field);
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        self.extract_field
+            .iter()
+            .map(|field| (field.column.clone(), json!(field.pointer)))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// Adds and indexes the columns, as virtual generated columns.
+    pub(crate) fn apply_sqlite(&self, tx: &rusqlite::Transaction) -> Result<()> {
+        if self.extract_field.is_empty() {
+            return Ok(());
+        }
+        tx.execute_batch(
+            "create table if not exists extracted_fields(\
+                column_name text not null primary key, pointer text not null) strict",
+        )?;
+        for field in &self.extract_field {
+            let recorded: Option<String> = tx
+                .query_row(
+                    "select pointer from extracted_fields where column_name = ?",
+                    [&field.column],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let column_exists: bool = tx.query_row(
+                "select exists(select 1 from pragma_table_xinfo('events') where name = ?)",
+                [&field.column],
+                |row| row.get(0),
+            )?;
+            match existing(recorded, column_exists) {
+                Existing::Extracted(pointer) if pointer == field.pointer => {}
+                Existing::Column => bail!("events already has a column {}", field.column),
+                existing => {
+                    if let Existing::Extracted(_) = existing {
+                        tx.execute_batch(&format!(
+                            "drop index if exists \"{}\"; alter table events drop column \"{}\";",
+                            field.index(),
+                            field.column
+                        ))?;
+                    }
+                    tx.execute_batch(&format!(
+                        "alter table events add column \"{}\" any \
+                        generated always as (payload ->> '{}') virtual",
+                        field.column,
+                        field.sqlite_path()
+                    ))?;
+                    tx.execute(
+                        "insert or replace into extracted_fields values (?, ?)",
+                        [&field.column, &field.pointer],
+                    )?;
+                    debug!(?field, "extracted payload field into column");
+                }
+            }
+            tx.execute_batch(&format!(
+                "create index if not exists \"{}\" on events(\"{}\")",
+                field.index(),
+                field.column
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Adds and indexes the columns, as stored generated columns of text. Adding one rewrites
+    /// the events table.
+    pub(crate) async fn apply_postgres(&self, client: &mut Client) -> Result<()> {
+        if self.extract_field.is_empty() {
+            return Ok(());
+        }
+        let tx = client.transaction().await?;
+        tx.batch_execute(
+            "CREATE TABLE IF NOT EXISTS extracted_fields(\
+                column_name TEXT PRIMARY KEY, pointer TEXT NOT NULL)",
+        )
+        .await?;
+        for field in &self.extract_field {
+            let recorded: Option<String> = tx
+                .query_opt(
+                    "SELECT pointer FROM extracted_fields WHERE column_name = $1",
+                    &[&field.column],
+                )
+                .await?
+                .map(|row| row.get(0));
+            let column_exists: bool = tx
+                .query_one(
+                    "SELECT EXISTS(SELECT 1 FROM information_schema.columns \
+                    WHERE table_schema = current_schema() AND table_name = 'events' \
+                    AND column_name = $1)",
+                    &[&field.column],
+                )
+                .await?
+                .get(0);
+            match existing(recorded, column_exists) {
+                Existing::Extracted(pointer) if pointer == field.pointer => {}
+                Existing::Column => bail!("events already has a column {}", field.column),
+                existing => {
+                    if let Existing::Extracted(_) = existing {
+                        // Its index goes with it.
+                        tx.batch_execute(&format!(
+                            "ALTER TABLE events DROP COLUMN \"{}\"",
+                            field.column
+                        ))
+                        .await?;
+                    }
+                    tx.batch_execute(&format!(
+                        "ALTER TABLE events ADD COLUMN \"{}\" TEXT \
+                        GENERATED ALWAYS AS (payload #>> {}) STORED",
+                        field.column,
+                        field.postgres_path()
+                    ))
+                    .await?;
+                    tx.execute(
+                        "INSERT INTO extracted_fields VALUES ($1, $2) \
+                        ON CONFLICT (column_name) DO UPDATE SET pointer = excluded.pointer",
+                        &[&field.column, &field.pointer],
+                    )
+                    .await?;
+                    debug!(?field, "extracted payload field into column");
+                }
+            }
+            tx.batch_execute(&format!(
+                "CREATE INDEX IF NOT EXISTS \"{}\" ON events(\"{}\")",
+                field.index(),
+                field.column
+            ))
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+fn existing(recorded: Option<String>, column_exists: bool) -> Existing {
+    match (recorded, column_exists) {
+        (Some(pointer), true) => Existing::Extracted(pointer),
+        (None, true) => Existing::Column,
+        // A recorded column that's since been dropped by hand is added again.
+        (_, false) => Existing::None,
+    }
+}
@@ -0,0 +1,56 @@
+use super::*;
+use std::path::Path;
+
+/// Tells external shippers when a JSON file is finished, so they don't have to poll the output
+/// directory. Runs in the background, and failures are only logged.
+#[derive(Debug, Default)]
+pub(super) struct FileClosedHook {
+    /// Run with `sh -c`, with the file's path as `$1` and its table as `$2`.
+    pub command: Option<String>,
+    /// Gets a POST with a JSON object of the file's `path` and `table`.
+    pub webhook: Option<reqwest::Url>,
+    pub client: reqwest::Client,
+}
+
+impl FileClosedHook {
+    pub(super) fn run(&self, table: &str, path: &Path) {
+        if self.command.is_none() && self.webhook.is_none() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!(?path, "no runtime to run file closed hook");
+            return;
+        };
+        // Shippers might not share the working directory.
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+        if let Some(script) = &self.command {
+            let mut command = tokio::process::Command::new("sh");
+            command
+                .arg("-c")
+                .arg(script)
+                .arg("sh")
+                .arg(&path)
+                .arg(table);
+            let path = path.clone();
+            runtime.spawn(async move {
+                match command.status().await {
+                    Ok(status) if status.success() => debug!(?path, "ran file closed command"),
+                    Ok(status) => warn!(?path, %status, "file closed command failed"),
+                    Err(err) => error!(?path, %err, "running file closed command"),
+                }
+            });
+        }
+        if let Some(webhook) = &self.webhook {
+            let request = self
+                .client
+                .post(webhook.clone())
+                .json(&json!({"path": path, "table": table}));
+            runtime.spawn(async move {
+                let response = request.send().await.and_then(|r| r.error_for_status());
+                if let Err(err) = response {
+                    warn!(?path, %err, "calling file closed webhook");
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use super::*;
+
+/// A full-text index of payloads, for [EventQuery::text]. Once created it's kept up to date on
+/// insert, and left in place if later not asked for.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct FullTextArgs {
+    /// Indexes the text of payloads, keys and values, when the storage is opened, for searching
+    /// events by words they contain. SQLite keeps an FTS5 table, and Postgres a tsvector column,
+    /// which rewrites the table when added.
+    #[arg(long)]
+    pub full_text_search: bool,
+}
+
+impl FullTextArgs {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!(self.full_text_search)
+    }
+
+    /// Creates the FTS5 table, filled from the events already there, and the triggers that keep it
+    /// up to date. It holds no copy of the payloads, and matches events by rowid, which nothing
+    /// changes as the database is never vacuumed.
+    pub(crate) fn apply_sqlite(&self, tx: &rusqlite::Transaction) -> Result<()> {
+        if !self.full_text_search {
+            return Ok(());
+        }
+        let exists: bool = tx.query_row(
+            "select exists(select 1 from sqlite_schema where type = 'table' and name = 'events_fts')",
+            [],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(());
+        }
+        tx.execute_batch(
+            "create virtual table events_fts using fts5(payload, content='', contentless_delete=1);
+            insert into events_fts(rowid, payload) select rowid, json(payload) from events;
+            create trigger events_fts_insert after insert on events begin
+                insert into events_fts(rowid, payload) values (new.rowid, json(new.payload));
+            end;
+            create trigger events_fts_update after update of payload on events begin
+                delete from events_fts where rowid = old.rowid;
+                insert into events_fts(rowid, payload) values (new.rowid, json(new.payload));
+            end;
+            create trigger events_fts_delete after delete on events begin
+                delete from events_fts where rowid = old.rowid;
+            end;",
+        )
+        .context("creating full-text index")?;
+        debug!("created full-text index");
+        Ok(())
+    }
+
+    /// Adds a generated tsvector column and a GIN index on it.
+    pub(crate) async fn apply_postgres(&self, client: &Client) -> Result<()> {
+        if !self.full_text_search {
+            return Ok(());
+        }
+        client
+            .batch_execute(
+                "ALTER TABLE events ADD COLUMN IF NOT EXISTS payload_tsv tsvector \
+                GENERATED ALWAYS AS (to_tsvector('simple', payload::text)) STORED;
+                CREATE INDEX IF NOT EXISTS events_payload_tsv ON events USING GIN (payload_tsv);",
+            )
+            .await
+            .context("creating full-text index")?;
+        Ok(())
+    }
+}
+
+/// The words of a search as an FTS5 query matching events with all of them, each quoted so that
+/// no query syntax applies.
+pub(crate) fn sqlite_match(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether a payload contains all of a search's words, ignoring case, for storage without an
+/// index. This is looser than the indexes, which only match whole words.
+pub(crate) fn payload_contains(payload: &str, text: &str) -> bool {
+    let payload = payload.to_lowercase();
+    text.split_whitespace()
+        .all(|word| payload.contains(&word.to_lowercase()))
+}
@@ -0,0 +1,80 @@
+use super::*;
+use std::path::Path;
+
+/// Inserted before ".json.zst" in the names of files finished by [recover_json_files].
+pub(crate) const RECOVERED_MARKER: &str = ".recovered";
+
+/// What [recover_json_files] salvaged.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RecoveredFiles {
+    pub files: u64,
+    /// Complete zstd frames kept. Each ends on a line, as frames end when events are flushed.
+    pub frames: u64,
+    /// Compressed bytes of cut-short frames that were dropped.
+    pub dropped_bytes: u64,
+}
+
+/// Finishes files left in the output directory by a process that didn't get to, keeping their
+/// complete frames. Without encryption a file is only known to be unfinished if its last frame was
+/// cut short, and with it any plain file is. Recovered files are renamed with [RECOVERED_MARKER],
+/// and encrypted if there's a key. Only one process should write to a directory, as its open files
+/// would look unfinished too.
+pub(crate) fn recover_json_files(
+    dir: &Path,
+    key: Option<&EncryptionKey>,
+) -> Result<RecoveredFiles> {
+    let mut recovered = RecoveredFiles::default();
+    if !dir.exists() {
+        return Ok(recovered);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(stem) = path
+            .to_str()
+            .and_then(|path| path.strip_suffix(".json.zst"))
+        else {
+            continue;
+        };
+        let data = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let (complete_len, frames) = complete_frames(&data);
+        if complete_len == data.len() && key.is_none() {
+            continue;
+        }
+        let stem = stem.strip_suffix(RECOVERED_MARKER).unwrap_or(stem);
+        let recovered_path = PathBuf::from(format!("{}{}.json.zst", stem, RECOVERED_MARKER));
+        // Written beside the original and renamed into place, so a crash now leaves it to retry.
+        let mut out = NamedTempFile::new_in(dir).context("opening recovered file")?;
+        out.write_all(&data[..complete_len])?;
+        out.as_file().sync_all()?;
+        out.persist(&recovered_path)?;
+        if recovered_path != path {
+            std::fs::remove_file(&path)?;
+        }
+        let dropped_bytes = (data.len() - complete_len) as u64;
+        let finished_path = match key {
+            Some(key) => encryption::encrypt_file(key, &recovered_path)?,
+            None => recovered_path,
+        };
+        info!(path = %finished_path.display(), frames, dropped_bytes, "recovered json file");
+        recovered.files += 1;
+        recovered.frames += frames;
+        recovered.dropped_bytes += dropped_bytes;
+    }
+    Ok(recovered)
+}
+
+/// The length of the complete zstd frames at the start of `data`, and how many there are.
+fn complete_frames(data: &[u8]) -> (usize, u64) {
+    let mut len = 0;
+    let mut frames = 0;
+    while len < data.len() {
+        match zstd::zstd_safe::find_frame_compressed_size(&data[len..]) {
+            Ok(size) => {
+                len += size;
+                frames += 1;
+            }
+            Err(_) => break,
+        }
+    }
+    (len, frames)
+}
@@ -0,0 +1,4033 @@
+//! The telemetry server's storage: streams of events, and the backends they're kept in. Storage is
+//! opened with a [StorageOpen], usually parsed from command line arguments, and used through the
+//! [Connection] trait.
+
+mod blocking;
+mod compression_stats;
+mod durability;
+mod encryption;
+mod extracted_fields;
+mod file_hook;
+mod full_text;
+mod json_recovery;
+mod memory;
+mod metric_points;
+mod migrations;
+mod openers;
+mod partitions;
+mod postgres_tls;
+mod query_indexes;
+mod rollups;
+mod rotating_sqlite;
+mod secrets;
+mod series;
+mod stream_id;
+#[cfg(test)]
+mod tests;
+mod timescale;
+mod tracing_layer;
+use blocking::BlockingStats;
+use compression_stats::CompressionStats;
+use durability::FsyncSchedule;
+pub use durability::{Durability, DurabilityArgs};
+pub use encryption::{DecryptingReader, EncryptionArgs, EncryptionKey, ENCRYPTED_SUFFIX};
+pub use extracted_fields::{ExtractArgs, ExtractedField};
+use file_hook::FileClosedHook;
+pub use full_text::FullTextArgs;
+pub use memory::Memory;
+pub use metric_points::MetricPoint;
+pub use openers::*;
+use partitions::EventsLayout;
+pub use partitions::{PartitionArgs, PartitionPeriod};
+pub use postgres_tls::SslMode;
+pub use query_indexes::{PostgresIndexArgs, SqliteIndexArgs};
+pub use rollups::{Rollup, RollupGroup, RollupRow};
+pub use rotating_sqlite::RotatingSqlite;
+pub use series::{SeriesAggregate, SeriesPoint, SeriesQuery};
+pub use stream_id::{StreamId, StreamUuid};
+pub use timescale::TimescaleArgs;
+pub use tracing_layer::{StorageLayer, StorageWriter};
+
+use anyhow::{anyhow, bail, Context, Result};
+pub use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rand::random;
+use serde_json::json;
+pub use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+use tokio_postgres::types::Json;
+use tokio_postgres::Client;
+use tracing::*;
+
+/// Stream headers as stored, a JSON object of the HTTP headers the stream was started with.
+pub type SerializedHeaders = serde_json::Value;
+
+/// Events are numbered within their stream from 1.
+pub type StreamEventIndex = u64;
+
+/// Which stored events an operation applies to. Unset fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct EventSelection {
+    pub stream_id: Option<StreamId>,
+    /// Inclusive lower bound on insert time.
+    pub since: Option<DateTime<Utc>>,
+    /// Exclusive upper bound on insert time.
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl EventSelection {
+    fn naive_bounds(&self) -> (Option<NaiveDateTime>, Option<NaiveDateTime>) {
+        (
+            self.since.map(|since| since.naive_utc()),
+            self.until.map(|until| until.naive_utc()),
+        )
+    }
+
+    fn text_bounds(&self) -> (Option<String>, Option<String>) {
+        (self.since.map(text_datetime), self.until.map(text_datetime))
+    }
+}
+
+/// Stored events to read back, oldest first.
+#[derive(Clone, Debug, Default)]
+pub struct EventQuery {
+    pub selection: EventSelection,
+    /// Top-level payload fields and the values they must have, compared as text.
+    pub filters: Vec<(String, String)>,
+    pub limit: usize,
+    /// Only events after this place, for reading a page at a time. Events are in [EventCursor]
+    /// order when this is supported.
+    pub after: Option<EventCursor>,
+    /// Only events whose payloads have all of these whitespace-separated words, searched with the
+    /// full-text index where there is one.
+    pub text: Option<String>,
+}
+
+impl EventQuery {
+    /// For storage that reads events in another order, so can't page by cursor, and has no
+    /// full-text index.
+    fn refuse_unsupported(&self) -> Result<()> {
+        if self.after.is_some() {
+            bail!("paging by cursor is not supported by this storage");
+        }
+        if self.search_text().is_some() {
+            bail!("full-text search is not supported by this storage");
+        }
+        Ok(())
+    }
+
+    /// The search text, if it has any words.
+    fn search_text(&self) -> Option<&str> {
+        self.text.as_deref().filter(|text| !text.trim().is_empty())
+    }
+}
+
+/// Selected events and the streams they belong to, read in one transaction, so every event's
+/// stream is there and every stream has events.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// As returned by [Connection::query_events].
+    pub events: Vec<serde_json::Value>,
+    /// Objects with the stream's ID, headers, start and end, ordered by ID.
+    pub streams: Vec<serde_json::Value>,
+}
+
+/// The distinct streams of events returned by [Connection::query_events], in order.
+fn event_stream_ids(events: &[serde_json::Value]) -> Result<Vec<i64>> {
+    let stream_ids: BTreeSet<i64> = events
+        .iter()
+        .map(|event| {
+            event["stream_id"]
+                .as_i64()
+                .context("event has no stream_id")
+        })
+        .collect::<Result<_>>()?;
+    Ok(stream_ids.into_iter().collect())
+}
+
+/// The text format of SQLite's `datetime('now')`, which DuckDB can also cast.
+fn text_datetime(datetime: DateTime<Utc>) -> String {
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Reads the datetimes storage writes, which are all UTC: RFC 3339 in JSON files, SQLite's text
+/// format, and Postgres's text and timestamp formats.
+pub fn parse_datetime(text: &str) -> Result<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(text) {
+        return Ok(datetime.to_utc());
+    }
+    if let Ok(datetime) = DateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f%#z") {
+        return Ok(datetime.to_utc());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(datetime.and_utc());
+        }
+    }
+    bail!("unrecognized datetime {:?}", text)
+}
+
+/// Limits on what storage keeps. Unset limits aren't applied.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    /// Events inserted before this are pruned.
+    pub before: Option<DateTime<Utc>>,
+    /// The oldest events beyond this many are pruned.
+    pub max_events: Option<u64>,
+    /// The oldest events are pruned until their payloads fit in this many bytes.
+    pub max_bytes: Option<u64>,
+}
+
+/// What a pass of pruning removed.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct Pruned {
+    pub events: u64,
+    pub streams: u64,
+    pub files: u64,
+    pub partitions: u64,
+}
+
+/// What a pass of stale stream detection changed.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct StaleStreams {
+    /// Open streams newly marked stale.
+    pub marked: u64,
+    /// Stale streams that have had events again.
+    pub revived: u64,
+}
+
+/// Why a stream event index has no stored event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnomalyKind {
+    /// The event was lost on its way into storage.
+    Gap,
+    /// The event repeated one already stored, and was dropped.
+    Duplicate,
+}
+
+impl AnomalyKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AnomalyKind::Gap => "gap",
+            AnomalyKind::Duplicate => "duplicate",
+        }
+    }
+}
+
+/// An index in a stream's event sequence that was handed out but has no event stored at it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamAnomaly {
+    pub stream_id: StreamId,
+    pub stream_event_index: StreamEventIndex,
+    pub kind: AnomalyKind,
+    /// Such as the error that lost the event, or the event ID it repeated.
+    pub details: Option<String>,
+}
+
+/// Totals over everything stored.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct StorageStats {
+    pub streams: u64,
+    /// Streams that were closed.
+    pub ended_streams: u64,
+    pub events: u64,
+    /// As the storage measures them, so they're only comparable within a backend.
+    pub payload_bytes: u64,
+    /// Insert times of the oldest and newest events, in the storage's own format.
+    pub first_insert_datetime: Option<String>,
+    pub last_insert_datetime: Option<String>,
+}
+
+/// Whether the payload's top-level fields have the filters' values, for storage that can't filter
+/// in its queries. Strings are compared as they are, and other values as JSON.
+fn payload_matches(payload: &serde_json::Value, filters: &[(String, String)]) -> bool {
+    filters.iter().all(|(field, value)| match &payload[field] {
+        serde_json::Value::Null => false,
+        serde_json::Value::String(text) => text == value,
+        other => value
+            .parse::<serde_json::Value>()
+            .is_ok_and(|value| value == *other),
+    })
+}
+
+/// Applies a JSON merge patch (RFC 7396): objects are merged key by key, null removes a key, and
+/// anything else replaces what was there.
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = json!({});
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        match value {
+            serde_json::Value::Null => {
+                target.remove(key);
+            }
+            value => merge_patch(target.entry(key).or_insert(json!(null)), value),
+        }
+    }
+}
+
+/// Returns a replacement for a stored payload, or None to leave it as is.
+pub type PayloadRewriter<'a> = &'a (dyn Fn(&str) -> Result<Option<String>> + Send + Sync);
+
+/// Events start at revision 0. Producers submit corrections with higher revisions.
+pub type EventRevision = u64;
+
+/// What became of an event given to [Connection::insert_event].
+#[derive(Debug, PartialEq)]
+pub enum Inserted {
+    Stored,
+    /// The stream already has an event with its ID, so it was dropped.
+    Duplicate,
+}
+
+/// The storage doesn't have the stream, as opposed to having failed to look for it.
+#[derive(Debug)]
+pub struct StreamNotFound(pub StreamId);
+
+impl std::fmt::Display for StreamNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream {} not found", self.0)
+    }
+}
+
+impl std::error::Error for StreamNotFound {}
+
+/// [Connection::insert_events] failing on an event after storing those before it one at a time,
+/// with what became of them. The events after it weren't tried.
+#[derive(Debug)]
+pub struct InsertedBefore {
+    pub inserted: Vec<Inserted>,
+    pub err: anyhow::Error,
+}
+
+impl std::fmt::Display for InsertedBefore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.err)
+    }
+}
+
+impl std::error::Error for InsertedBefore {}
+
+/// An event given to [Connection::insert_events], with what [Connection::insert_event] takes for
+/// each.
+#[derive(Debug, Clone, Copy)]
+pub struct NewEvent<'a> {
+    pub stream_event_index: StreamEventIndex,
+    pub payload: &'a RawValue,
+    pub event_id: Option<&'a str>,
+    pub collector: Option<&'a serde_json::Value>,
+    pub client_datetime: Option<DateTime<Utc>>,
+}
+
+impl<'a> NewEvent<'a> {
+    /// An event with only its index and payload.
+    pub fn new(stream_event_index: StreamEventIndex, payload: &'a RawValue) -> Self {
+        Self {
+            stream_event_index,
+            payload,
+            event_id: None,
+            collector: None,
+            client_datetime: None,
+        }
+    }
+}
+
+/// The outcome of submitting a revision of an event.
+#[derive(Debug, PartialEq)]
+pub enum Revised {
+    /// The revision replaced the stored event, which was moved to the event history.
+    Superseded,
+    /// There's no event at that index of the stream.
+    NotFound,
+    /// The stored event is already at this revision or a later one.
+    Stale { latest: EventRevision },
+}
+
+/// A stream read from elsewhere, like another storage's files, to be stored as it was.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportedStream {
+    pub stream_id: StreamId,
+    pub headers: serde_json::Value,
+    pub start_datetime: DateTime<Utc>,
+    pub end_datetime: Option<DateTime<Utc>>,
+    pub event_count: Option<u64>,
+    /// The server's clock minus the client's when the stream started, in milliseconds.
+    pub clock_skew_ms: Option<i64>,
+    pub stream_uuid: Option<StreamUuid>,
+}
+
+/// An event read from elsewhere, to be stored with its original insert time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportedEvent {
+    pub stream_id: StreamId,
+    pub stream_event_index: StreamEventIndex,
+    pub insert_datetime: DateTime<Utc>,
+    pub revision: EventRevision,
+    pub payload: serde_json::Value,
+    pub event_id: Option<String>,
+    pub collector: Option<serde_json::Value>,
+    pub client_datetime: Option<DateTime<Utc>>,
+}
+
+/// A place in events ordered by insert time, stream ID and stream event index, for reading them a
+/// batch at a time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventCursor {
+    pub insert_datetime: DateTime<Utc>,
+    pub stream_id: StreamId,
+    pub stream_event_index: StreamEventIndex,
+}
+
+impl EventCursor {
+    /// Just after the event.
+    pub fn after(event: &ImportedEvent) -> Self {
+        Self {
+            insert_datetime: event.insert_datetime,
+            stream_id: event.stream_id,
+            stream_event_index: event.stream_event_index,
+        }
+    }
+
+    /// Just after an event as returned by [Connection::query_events].
+    pub fn after_json(event: &serde_json::Value) -> Result<Self> {
+        let insert_datetime = event["insert_datetime"]
+            .as_str()
+            .context("event has no insert_datetime")?;
+        Ok(Self {
+            insert_datetime: parse_datetime(insert_datetime)?,
+            stream_id: StreamId(
+                event["stream_id"]
+                    .as_u64()
+                    .context("event has no stream_id")? as u32,
+            ),
+            stream_event_index: event["stream_event_index"]
+                .as_u64()
+                .context("event has no stream_event_index")?,
+        })
+    }
+}
+
+/// Events are read back in batches of this many when rewriting them, or filtering them outside the
+/// database.
+const READ_BATCH_SIZE: usize = 1000;
+
+/// Rows per statement when inserting events in bulk into Postgres, keeping well under its limit of
+/// 65535 parameters.
+const POSTGRES_INSERT_ROWS: usize = 1000;
+
+#[async_trait]
+pub trait Connection: Send {
+    async fn new_stream(&mut self, headers: SerializedHeaders) -> Result<StreamId>;
+    async fn insert_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        // Already checked to be JSON by the caller, so storage can hand it on without parsing it.
+        payload: &RawValue,
+        // Client-supplied identifier. Events repeating an ID already stored for the stream are
+        // dropped.
+        event_id: Option<&str>,
+        // Server-side metadata about where the event came from, when enrichment is enabled.
+        collector: Option<&serde_json::Value>,
+        // When the client says the event happened, by its own clock. The insert time is when the
+        // server received it.
+        client_datetime: Option<DateTime<Utc>>,
+    ) -> Result<Inserted>;
+    /// Stores a batch of a stream's events, returning what became of each in the same order.
+    /// Storage that can write them in fewer round trips than one at a time does. On error, events
+    /// before the one that failed may have been stored. One at a time, the error is an
+    /// [InsertedBefore] saying which.
+    async fn insert_events(
+        &mut self,
+        stream_id: StreamId,
+        events: &[NewEvent<'_>],
+    ) -> Result<Vec<Inserted>> {
+        let mut inserted = Vec::with_capacity(events.len());
+        for event in events {
+            let result = self
+                .insert_event(
+                    stream_id,
+                    event.stream_event_index,
+                    event.payload,
+                    event.event_id,
+                    event.collector,
+                    event.client_datetime,
+                )
+                .await;
+            match result {
+                Ok(one) => inserted.push(one),
+                Err(err) => return Err(InsertedBefore { inserted, err }.into()),
+            }
+        }
+        Ok(inserted)
+    }
+    /// Checks the stream exists so more events can be added to it, and returns the last stream
+    /// event index stored for it (0 if there are none).
+    async fn resume_stream(&mut self, _stream_id: StreamId) -> Result<StreamEventIndex> {
+        Err(anyhow!("resuming streams is not supported by this storage"))
+    }
+    /// The ID of the stream given the UUID.
+    async fn find_stream(&mut self, _stream_uuid: StreamUuid) -> Result<StreamId> {
+        Err(anyhow!(
+            "finding streams by uuid is not supported by this storage"
+        ))
+    }
+    /// Marks the stream as having ended cleanly, recording when and how many events it had.
+    async fn close_stream(&mut self, stream_id: StreamId) -> Result<()>;
+    /// Records how far the server's clock was ahead of the client's when the stream started, for
+    /// correcting the client's timestamps.
+    async fn record_clock_skew(
+        &mut self,
+        _stream_id: StreamId,
+        _clock_skew: chrono::TimeDelta,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "recording clock skew is not supported by this storage"
+        ))
+    }
+    /// Records how the server sampled a stream's events, replacing what was recorded before.
+    async fn record_sampling(
+        &mut self,
+        _stream_id: StreamId,
+        _sampling: &serde_json::Value,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "recording sampling is not supported by this storage"
+        ))
+    }
+    /// Changes a stream's headers with a JSON merge patch, for metadata that's only known after
+    /// the stream started.
+    async fn update_stream_headers(
+        &mut self,
+        _stream_id: StreamId,
+        _patch: &serde_json::Value,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "updating stream headers is not supported by this storage"
+        ))
+    }
+    /// Sets key-value labels on a stream, replacing the values of labels it already has. Labels
+    /// given None are removed.
+    async fn set_stream_labels(
+        &mut self,
+        _stream_id: StreamId,
+        _labels: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "labelling streams is not supported by this storage"
+        ))
+    }
+    /// Records a gap or duplicate in a stream's event indexes.
+    async fn record_anomaly(&mut self, _anomaly: &StreamAnomaly) -> Result<()> {
+        Err(anyhow!(
+            "recording stream anomalies is not supported by this storage"
+        ))
+    }
+    /// Replaces an event's payload with a later revision, keeping the previous one in the event
+    /// history.
+    async fn revise_event(
+        &mut self,
+        _stream_id: StreamId,
+        _stream_event_index: StreamEventIndex,
+        _revision: EventRevision,
+        _payload: &str,
+    ) -> Result<Revised> {
+        Err(anyhow!("revising events is not supported by this storage"))
+    }
+    /// Returns events as JSON objects with their stream, index, insert time and payload.
+    async fn query_events(&mut self, _query: &EventQuery) -> Result<Vec<serde_json::Value>> {
+        Err(anyhow!("querying events is not supported by this storage"))
+    }
+    /// Like [Self::query_events], but also reads the events' streams, without seeing changes made
+    /// in between.
+    async fn snapshot(&mut self, _query: &EventQuery) -> Result<Snapshot> {
+        Err(anyhow!("snapshots are not supported by this storage"))
+    }
+    /// Returns the most recently started streams with all of the given labels, newest first, as
+    /// in a [Snapshot].
+    async fn recent_streams(
+        &mut self,
+        _limit: usize,
+        _labels: &[(String, String)],
+    ) -> Result<Vec<serde_json::Value>> {
+        Err(anyhow!("listing streams is not supported by this storage"))
+    }
+    /// Like [Self::snapshot], but hands the events to `each` in batches of about `batch_size` as
+    /// they're read, with the streams of each batch's events, so they needn't all be in memory.
+    async fn snapshot_batches(
+        &mut self,
+        query: &EventQuery,
+        _batch_size: usize,
+        each: &mut (dyn FnMut(Snapshot) -> Result<()> + Send),
+    ) -> Result<()> {
+        each(self.snapshot(query).await?)
+    }
+    /// Counts what's stored.
+    async fn stats(&mut self) -> Result<StorageStats> {
+        Err(anyhow!("stats are not supported by this storage"))
+    }
+    /// Stores a short link to a UI view. Saving the same link again does nothing.
+    async fn save_link(&mut self, _link_id: &str, _query: &str) -> Result<()> {
+        Err(anyhow!("links are not supported by this storage"))
+    }
+    /// Returns the view query a short link was saved with.
+    async fn load_link(&mut self, _link_id: &str) -> Result<Option<String>> {
+        Err(anyhow!("links are not supported by this storage"))
+    }
+    /// Returns when a request with this hash was saved, forgetting those saved before `since`.
+    async fn find_request_hash(
+        &mut self,
+        _hash: &str,
+        _since: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        Err(anyhow!(
+            "duplicate request detection is not supported by this storage"
+        ))
+    }
+    /// Saves the hash of a request as received now, for finding duplicates of it. Saving the same
+    /// hash again does nothing.
+    async fn save_request_hash(&mut self, _hash: &str) -> Result<()> {
+        Err(anyhow!(
+            "duplicate request detection is not supported by this storage"
+        ))
+    }
+    /// Moves all of `from`'s events onto the end of `into`, and deletes `from`. Returns how many
+    /// events moved. Recorded in the audit log.
+    async fn merge_streams(&mut self, _from: StreamId, _into: StreamId) -> Result<u64> {
+        Err(anyhow!("merging streams is not supported by this storage"))
+    }
+    /// Moves the events inserted at or after `at` to a new stream with the same headers. Returns
+    /// the new stream and how many events moved. Recorded in the audit log.
+    async fn split_stream(
+        &mut self,
+        _stream_id: StreamId,
+        _at: DateTime<Utc>,
+    ) -> Result<(StreamId, u64)> {
+        Err(anyhow!(
+            "splitting streams is not supported by this storage"
+        ))
+    }
+    /// Deletes what's outside the retention policy. Streams are deleted once all their events
+    /// have been.
+    async fn prune(&mut self, _policy: &RetentionPolicy) -> Result<Pruned> {
+        Err(anyhow!("pruning is not supported by this storage"))
+    }
+    /// Creates partitions of the events table ahead of the events that will go in them. Returns
+    /// how many were created. Storage that isn't partitioned has none to create.
+    async fn create_partitions(&mut self) -> Result<u64> {
+        Ok(0)
+    }
+    /// Sets `stale_datetime` on open streams that started before `quiet_since` and have had no
+    /// events since, and clears it from those that have had events again.
+    async fn mark_stale_streams(&mut self, _quiet_since: DateTime<Utc>) -> Result<StaleStreams> {
+        Err(anyhow!(
+            "stale stream detection is not supported by this storage"
+        ))
+    }
+    /// Counts events into the rollup's buckets in the rollups table, counting its latest bucket
+    /// again for events inserted since. Returns how many rows were written.
+    async fn roll_up(&mut self, _rollup: &Rollup) -> Result<u64> {
+        Err(anyhow!("rollups are not supported by this storage"))
+    }
+    /// Reads the rows of a rollup whose buckets start within the selection's times, oldest first.
+    async fn read_rollup(
+        &mut self,
+        _name: &str,
+        _selection: &EventSelection,
+    ) -> Result<Vec<RollupRow>> {
+        Err(anyhow!("rollups are not supported by this storage"))
+    }
+    /// Stores metric data points, apart from events. Returns how many were stored.
+    async fn insert_metric_points(&mut self, _points: &[MetricPoint]) -> Result<u64> {
+        Err(anyhow!("metrics are not supported by this storage"))
+    }
+    /// Counts the selected events, or aggregates a payload field, in buckets of insert time,
+    /// oldest first.
+    async fn read_series(&mut self, _query: &SeriesQuery) -> Result<Vec<SeriesPoint>> {
+        Err(anyhow!("series are not supported by this storage"))
+    }
+    /// Passes the selected stored payloads through `rewrite`, storing any replacements. Returns
+    /// how many events were updated.
+    async fn rewrite_events(
+        &mut self,
+        _selection: &EventSelection,
+        _rewrite: PayloadRewriter<'_>,
+    ) -> Result<u64> {
+        Err(anyhow!("rewriting events is not supported by this storage"))
+    }
+    /// Reads up to `limit` events after the cursor, or from the first, in the cursor's order. They
+    /// have everything [Self::import_events] takes, so they can be copied to other storage.
+    async fn events_after(
+        &mut self,
+        _cursor: Option<&EventCursor>,
+        _limit: usize,
+    ) -> Result<Vec<ImportedEvent>> {
+        Err(anyhow!(
+            "reading events by cursor is not supported by this storage"
+        ))
+    }
+    /// Reads streams as [Self::import_streams] takes them. IDs that aren't stored are left out.
+    async fn streams_by_id(&mut self, _stream_ids: &[StreamId]) -> Result<Vec<ImportedStream>> {
+        Err(anyhow!(
+            "reading streams by ID is not supported by this storage"
+        ))
+    }
+    /// Stores streams with their own IDs and times. A stream that's already stored is taken to be
+    /// the same one, and only gains an end if it didn't have one.
+    async fn import_streams(&mut self, _streams: &[ImportedStream]) -> Result<()> {
+        Err(anyhow!("importing is not supported by this storage"))
+    }
+    /// Stores events with their own insert times, all or none. Events already stored at the same
+    /// or a later revision are skipped, and earlier revisions move to the event history as with
+    /// [Self::revise_event]. Returns how many were stored.
+    async fn import_events(&mut self, _events: &[ImportedEvent]) -> Result<u64> {
+        Err(anyhow!("importing is not supported by this storage"))
+    }
+    /// Starts holding writes back until [Self::commit_batch] stores them all at once, or
+    /// [Self::rollback_batch] discards them. Returns false if the storage can't, in which case
+    /// writes are stored as they're made and ending the batch does nothing.
+    async fn begin_batch(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+    /// Whether [Self::begin_batch] holds writes back, for callers that get ready differently when
+    /// it does.
+    fn batches(&self) -> bool {
+        false
+    }
+    async fn commit_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+    async fn rollback_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+    // Write stuff to disk
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    // Make data available to observers.
+    async fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Starts writing to new files now, rather than when they'd next be rotated.
+    async fn rotate(&mut self) -> Result<()> {
+        Err(anyhow!("rotating is not supported by this storage"))
+    }
+    /// Finishes up before the process exits. Nothing is written after this.
+    async fn shutdown(&mut self) -> Result<()> {
+        self.commit().await
+    }
+    /// Whether sigint should be hooked to trigger a commit. Some storage types buffer output and
+    /// need to be committed to ensure observability of data up to the point of the commit.
+    fn commit_on_sigint(&self) -> bool {
+        false
+    }
+    /// Per-stream compression statistics and recommendations, for storage that compresses its
+    /// output. Lists at most `limit` streams, worst first.
+    fn compression_report(&self, _limit: usize) -> Option<serde_json::Value> {
+        None
+    }
+    /// How long calls held up their thread, for storage whose calls block it.
+    fn blocking_report(&self) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+pub struct Postgres {
+    client: Client,
+    partitions: PartitionArgs,
+    /// Partitioned tables and hypertables leave event IDs to be kept unique by a trigger, rather
+    /// than an index ON CONFLICT can use.
+    layout: EventsLayout,
+}
+
+impl Postgres {
+    /// How inserts into events skip those repeating an event ID.
+    fn on_event_id_conflict(&self) -> &'static str {
+        match self.layout {
+            EventsLayout::Table => " ON CONFLICT (stream_id, event_id) DO NOTHING",
+            EventsLayout::Partitioned | EventsLayout::Hypertable => "",
+        }
+    }
+}
+
+#[async_trait]
+impl Connection for Postgres {
+    async fn new_stream(&mut self, headers_value: SerializedHeaders) -> Result<StreamId> {
+        let stmt = self
+            .client
+            .prepare(
+                "INSERT INTO streams (headers, start_datetime, stream_uuid) \
+                VALUES ($1, NOW(), $2::text::uuid) RETURNING stream_id",
+            )
+            .await?;
+        let stream_id: i32 = self
+            .client
+            .query_one(&stmt, &[&headers_value, &StreamUuid::now_v7().to_string()])
+            .await?
+            .get(0);
+        Ok(StreamId(stream_id as u32))
+    }
+
+    async fn insert_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        payload: &RawValue,
+        event_id: Option<&str>,
+        collector: Option<&serde_json::Value>,
+        client_datetime: Option<DateTime<Utc>>,
+    ) -> Result<Inserted> {
+        let stmt = self
+            .client
+            .prepare(&format!(
+                "INSERT INTO events \
+                (insert_datetime, stream_event_index, payload, stream_id, event_id, collector, \
+                client_datetime) \
+                VALUES (NOW(), $1, $2, $3, $4, $5, $6){}",
+                self.on_event_id_conflict()
+            ))
+            .await?;
+        let inserted = self
+            .client
+            .execute(
+                &stmt,
+                &[
+                    &(stream_event_index as i32),
+                    &Json(payload),
+                    &(stream_id.0 as i32),
+                    &event_id,
+                    &collector,
+                    &client_datetime.map(|client| client.naive_utc()),
+                ],
+            )
+            .await?;
+        if inserted == 0 {
+            debug!(%stream_id, event_id, "dropped duplicate event");
+            return Ok(Inserted::Duplicate);
+        }
+        Ok(Inserted::Stored)
+    }
+
+    async fn insert_events(
+        &mut self,
+        stream_id: StreamId,
+        events: &[NewEvent<'_>],
+    ) -> Result<Vec<Inserted>> {
+        let stream_id_param = stream_id.0 as i32;
+        let mut inserted = Vec::with_capacity(events.len());
+        for chunk in events.chunks(POSTGRES_INSERT_ROWS) {
+            let rows: Vec<_> = chunk
+                .iter()
+                .map(|event| {
+                    (
+                        event.stream_event_index as i32,
+                        Json(event.payload),
+                        event.client_datetime.map(|client| client.naive_utc()),
+                    )
+                })
+                .collect();
+            let mut sql = String::from(
+                "INSERT INTO events \
+                (insert_datetime, stream_event_index, payload, stream_id, event_id, collector, \
+                client_datetime) VALUES ",
+            );
+            let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> =
+                vec![&stream_id_param];
+            for (row, (event, (index, payload, client_datetime))) in
+                chunk.iter().zip(&rows).enumerate()
+            {
+                if row > 0 {
+                    sql.push_str(", ");
+                }
+                let first = params.len() + 1;
+                sql.push_str(&format!(
+                    "(NOW(), ${}, ${}, $1, ${}, ${}, ${})",
+                    first,
+                    first + 1,
+                    first + 2,
+                    first + 3,
+                    first + 4,
+                ));
+                params.extend([
+                    index as &(dyn tokio_postgres::types::ToSql + Sync),
+                    payload,
+                    &event.event_id,
+                    &event.collector,
+                    client_datetime,
+                ]);
+            }
+            sql.push_str(self.on_event_id_conflict());
+            sql.push_str(" RETURNING stream_event_index");
+            let stored: HashSet<i32> = self
+                .client
+                .query(&sql, &params)
+                .await?
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            for (event, (index, ..)) in chunk.iter().zip(&rows) {
+                inserted.push(match stored.contains(index) {
+                    true => Inserted::Stored,
+                    false => {
+                        debug!(%stream_id, event_id = event.event_id, "dropped duplicate event");
+                        Inserted::Duplicate
+                    }
+                });
+            }
+        }
+        Ok(inserted)
+    }
+
+    async fn resume_stream(&mut self, stream_id: StreamId) -> Result<StreamEventIndex> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT COALESCE(MAX(stream_event_index), 0) \
+                FROM streams LEFT JOIN events USING (stream_id) \
+                WHERE streams.stream_id = $1 GROUP BY streams.stream_id",
+                &[&(stream_id.0 as i32)],
+            )
+            .await?
+            .ok_or(StreamNotFound(stream_id))?;
+        Ok(row.get::<_, i32>(0) as StreamEventIndex)
+    }
+
+    async fn find_stream(&mut self, stream_uuid: StreamUuid) -> Result<StreamId> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT stream_id FROM streams WHERE stream_uuid = $1::text::uuid",
+                &[&stream_uuid.to_string()],
+            )
+            .await?
+            .ok_or_else(|| anyhow!("stream {} not found", stream_uuid))?;
+        Ok(StreamId(row.get::<_, i32>(0) as u32))
+    }
+
+    async fn close_stream(&mut self, stream_id: StreamId) -> Result<()> {
+        let updated = self
+            .client
+            .execute(
+                "UPDATE streams SET end_datetime = NOW(), \
+                event_count = stored_event_count \
+                WHERE stream_id = $1",
+                &[&(stream_id.0 as i32)],
+            )
+            .await?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+
+    async fn record_clock_skew(
+        &mut self,
+        stream_id: StreamId,
+        clock_skew: chrono::TimeDelta,
+    ) -> Result<()> {
+        let updated = self
+            .client
+            .execute(
+                "UPDATE streams SET clock_skew_ms = $2 WHERE stream_id = $1",
+                &[&(stream_id.0 as i32), &clock_skew.num_milliseconds()],
+            )
+            .await?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+
+    async fn record_sampling(
+        &mut self,
+        stream_id: StreamId,
+        sampling: &serde_json::Value,
+    ) -> Result<()> {
+        let updated = self
+            .client
+            .execute(
+                "UPDATE streams SET sampling = $2 WHERE stream_id = $1",
+                &[&(stream_id.0 as i32), sampling],
+            )
+            .await?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+
+    async fn update_stream_headers(
+        &mut self,
+        stream_id: StreamId,
+        patch: &serde_json::Value,
+    ) -> Result<()> {
+        let tx = self.client.transaction().await?;
+        let Some(row) = tx
+            .query_opt(
+                "SELECT headers FROM streams WHERE stream_id = $1 FOR UPDATE",
+                &[&(stream_id.0 as i32)],
+            )
+            .await?
+        else {
+            bail!("stream {} not found", stream_id);
+        };
+        let mut headers: serde_json::Value = row.get(0);
+        merge_patch(&mut headers, patch);
+        tx.execute(
+            "UPDATE streams SET headers = $2 WHERE stream_id = $1",
+            &[&(stream_id.0 as i32), &headers],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_stream_labels(
+        &mut self,
+        stream_id: StreamId,
+        labels: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        let stream_id_param = stream_id.0 as i32;
+        let tx = self.client.transaction().await?;
+        if tx
+            .query_opt(
+                "SELECT 1 FROM streams WHERE stream_id = $1",
+                &[&stream_id_param],
+            )
+            .await?
+            .is_none()
+        {
+            bail!("stream {} not found", stream_id);
+        }
+        for (key, value) in labels {
+            match value {
+                Some(value) => {
+                    tx.execute(
+                        "INSERT INTO stream_labels (stream_id, key, value) VALUES ($1, $2, $3) \
+                        ON CONFLICT (stream_id, key) DO UPDATE SET value = EXCLUDED.value",
+                        &[&stream_id_param, key, value],
+                    )
+                    .await?
+                }
+                None => {
+                    tx.execute(
+                        "DELETE FROM stream_labels WHERE stream_id = $1 AND key = $2",
+                        &[&stream_id_param, key],
+                    )
+                    .await?
+                }
+            };
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn revise_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        revision: EventRevision,
+        payload: &str,
+    ) -> Result<Revised> {
+        let payload_value: serde_json::Value = serde_json::from_str(payload)?;
+        let key: [&(dyn tokio_postgres::types::ToSql + Sync); 2] =
+            [&(stream_id.0 as i32), &(stream_event_index as i32)];
+        let tx = self.client.transaction().await?;
+        let Some(row) = tx
+            .query_opt(
+                "SELECT revision FROM events \
+                WHERE stream_id = $1 AND stream_event_index = $2 \
+                LIMIT 1 FOR UPDATE",
+                &key,
+            )
+            .await?
+        else {
+            return Ok(Revised::NotFound);
+        };
+        let latest = row.get::<_, i32>(0) as EventRevision;
+        if revision <= latest {
+            return Ok(Revised::Stale { latest });
+        }
+        tx.execute(
+            "INSERT INTO event_revisions \
+            (stream_id, stream_event_index, revision, insert_datetime, payload) \
+            SELECT stream_id, stream_event_index, revision, insert_datetime, payload FROM events \
+            WHERE stream_id = $1 AND stream_event_index = $2",
+            &key,
+        )
+        .await?;
+        tx.execute(
+            "UPDATE events SET revision = $3, payload = $4, insert_datetime = NOW() \
+            WHERE stream_id = $1 AND stream_event_index = $2",
+            &[key[0], key[1], &(revision as i32), &payload_value],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(Revised::Superseded)
+    }
+
+    async fn query_events(&mut self, query: &EventQuery) -> Result<Vec<serde_json::Value>> {
+        select_postgres_events(&self.client, query).await
+    }
+
+    async fn snapshot(&mut self, query: &EventQuery) -> Result<Snapshot> {
+        let tx = self
+            .client
+            .build_transaction()
+            .isolation_level(tokio_postgres::IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .await?;
+        let events = select_postgres_events(&tx, query).await?;
+        let streams = select_postgres_streams(&tx, &events).await?;
+        tx.commit().await?;
+        Ok(Snapshot { events, streams })
+    }
+
+    async fn recent_streams(
+        &mut self,
+        limit: usize,
+        labels: &[(String, String)],
+    ) -> Result<Vec<serde_json::Value>> {
+        let limit = limit as i64;
+        let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![&limit];
+        let mut conditions = vec!["TRUE".to_owned()];
+        for (key, value) in labels {
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM stream_labels WHERE stream_labels.stream_id = \
+                streams.stream_id AND key = ${} AND value = ${})",
+                params.len() + 1,
+                params.len() + 2
+            ));
+            params.extend([key as &(dyn tokio_postgres::types::ToSql + Sync), value]);
+        }
+        let rows = self
+            .client
+            .query(
+                &format!(
+                    "SELECT {} FROM streams WHERE {} ORDER BY stream_id DESC LIMIT $1",
+                    POSTGRES_STREAM_OBJECT,
+                    conditions.join(" AND ")
+                ),
+                &params,
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn snapshot_batches(
+        &mut self,
+        query: &EventQuery,
+        batch_size: usize,
+        each: &mut (dyn FnMut(Snapshot) -> Result<()> + Send),
+    ) -> Result<()> {
+        let tx = self
+            .client
+            .build_transaction()
+            .isolation_level(tokio_postgres::IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .await?;
+        let (sql, params) = postgres_events_statement(query);
+        let select = tx.prepare(&sql).await?;
+        let params: Vec<_> = params.iter().map(|param| param.as_ref() as _).collect();
+        let portal = tx.bind(&select, &params).await?;
+        loop {
+            let rows = tx.query_portal(&portal, batch_size as i32).await?;
+            if rows.is_empty() {
+                break;
+            }
+            let events: Vec<serde_json::Value> = rows.iter().map(|row| row.get(0)).collect();
+            let streams = select_postgres_streams(&tx, &events).await?;
+            each(Snapshot { events, streams })?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn stats(&mut self) -> Result<StorageStats> {
+        let row = self
+            .client
+            .query_one(
+                "SELECT (SELECT count(*) FROM streams), \
+                (SELECT count(*) FROM streams WHERE end_datetime IS NOT NULL), \
+                count(*), coalesce(sum(pg_column_size(payload)), 0)::bigint, \
+                min(insert_datetime)::text, max(insert_datetime)::text \
+                FROM events",
+                &[],
+            )
+            .await?;
+        Ok(StorageStats {
+            streams: row.get::<_, i64>(0) as u64,
+            ended_streams: row.get::<_, i64>(1) as u64,
+            events: row.get::<_, i64>(2) as u64,
+            payload_bytes: row.get::<_, i64>(3) as u64,
+            first_insert_datetime: row.get(4),
+            last_insert_datetime: row.get(5),
+        })
+    }
+
+    async fn record_anomaly(&mut self, anomaly: &StreamAnomaly) -> Result<()> {
+        self.client
+            .execute(
+                "INSERT INTO stream_anomalies \
+                (stream_id, stream_event_index, kind, detected_datetime, details) \
+                VALUES ($1, $2, $3, NOW(), $4)",
+                &[
+                    &(anomaly.stream_id.0 as i32),
+                    &(anomaly.stream_event_index as i32),
+                    &anomaly.kind.as_str(),
+                    &anomaly.details,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn begin_batch(&mut self) -> Result<bool> {
+        self.client.batch_execute("BEGIN").await?;
+        Ok(true)
+    }
+
+    fn batches(&self) -> bool {
+        true
+    }
+
+    async fn commit_batch(&mut self) -> Result<()> {
+        Ok(self.client.batch_execute("COMMIT").await?)
+    }
+
+    async fn rollback_batch(&mut self) -> Result<()> {
+        Ok(self.client.batch_execute("ROLLBACK").await?)
+    }
+
+    async fn save_link(&mut self, link_id: &str, query: &str) -> Result<()> {
+        self.client
+            .execute(
+                "INSERT INTO links (link_id, query, created_datetime) VALUES ($1, $2, NOW()) \
+                ON CONFLICT (link_id) DO NOTHING",
+                &[&link_id, &query],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn load_link(&mut self, link_id: &str) -> Result<Option<String>> {
+        let row = self
+            .client
+            .query_opt("SELECT query FROM links WHERE link_id = $1", &[&link_id])
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn find_request_hash(
+        &mut self,
+        hash: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        self.client
+            .execute(
+                "DELETE FROM request_hashes WHERE received_datetime < $1",
+                &[&since.naive_utc()],
+            )
+            .await?;
+        let row = self
+            .client
+            .query_opt(
+                "SELECT received_datetime FROM request_hashes WHERE hash = $1",
+                &[&hash],
+            )
+            .await?;
+        Ok(row.map(|row| row.get::<_, NaiveDateTime>(0).and_utc()))
+    }
+
+    async fn save_request_hash(&mut self, hash: &str) -> Result<()> {
+        self.client
+            .execute(
+                "INSERT INTO request_hashes (hash, received_datetime) VALUES ($1, $2) \
+                ON CONFLICT (hash) DO NOTHING",
+                &[&hash, &Utc::now().naive_utc()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn merge_streams(&mut self, from: StreamId, into: StreamId) -> Result<u64> {
+        if from == into {
+            bail!("can't merge a stream into itself");
+        }
+        let (from, into) = (from.0 as i32, into.0 as i32);
+        let tx = self.client.transaction().await?;
+        let index_offset: i32 = tx
+            .query_opt(
+                "SELECT COALESCE(MAX(stream_event_index), 0) \
+                FROM streams LEFT JOIN events USING (stream_id) \
+                WHERE streams.stream_id = $1 GROUP BY streams.stream_id",
+                &[&into],
+            )
+            .await?
+            .ok_or_else(|| anyhow!("stream {} not found", StreamId(into as u32)))?
+            .get(0);
+        let params: [&(dyn tokio_postgres::types::ToSql + Sync); 3] = [&from, &into, &index_offset];
+        tx.execute(
+            "UPDATE event_revisions SET stream_id = $2, stream_event_index = stream_event_index + $3 \
+            WHERE stream_id = $1",
+            &params,
+        )
+        .await?;
+        let moved = tx
+            .execute(
+                "UPDATE events SET stream_id = $2, stream_event_index = stream_event_index + $3 \
+                WHERE stream_id = $1",
+                &params,
+            )
+            .await
+            .context("moving events")?;
+        // The merged stream's headers would otherwise be lost.
+        let audited = tx
+            .execute(
+                "INSERT INTO audit_log (datetime, operation, details) \
+                SELECT NOW(), 'merge_streams', jsonb_build_object(\
+                    'from', $1::integer, 'into', $2::integer, 'events', $4::bigint, \
+                    'index_offset', $3::integer, 'from_headers', headers) \
+                FROM streams WHERE stream_id = $1",
+                &[&from, &into, &index_offset, &(moved as i64)],
+            )
+            .await?;
+        if audited == 0 {
+            bail!("stream {} not found", StreamId(from as u32));
+        }
+        tx.execute("DELETE FROM streams WHERE stream_id = $1", &[&from])
+            .await?;
+        tx.execute(
+            "UPDATE streams SET event_count = stored_event_count \
+            WHERE stream_id = $1 AND event_count IS NOT NULL",
+            &[&into],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(moved)
+    }
+
+    async fn split_stream(
+        &mut self,
+        stream_id: StreamId,
+        at: DateTime<Utc>,
+    ) -> Result<(StreamId, u64)> {
+        let old = stream_id.0 as i32;
+        let at_naive = at.naive_utc();
+        let tx = self.client.transaction().await?;
+        let new: i32 = tx
+            .query_opt(
+                "INSERT INTO streams \
+                    (headers, start_datetime, end_datetime, event_count, stream_uuid) \
+                SELECT headers, $2::timestamptz::text, end_datetime, event_count, $3::text::uuid \
+                FROM streams WHERE stream_id = $1 \
+                RETURNING stream_id",
+                &[&old, &at, &StreamUuid::now_v7().to_string()],
+            )
+            .await?
+            .ok_or_else(|| anyhow!("stream {} not found", stream_id))?
+            .get(0);
+        // The new stream's indexes start from 1.
+        let index_offset: i32 = tx
+            .query_one(
+                "SELECT COALESCE(MIN(stream_event_index) - 1, 0) FROM events \
+                WHERE stream_id = $1 AND insert_datetime >= $2",
+                &[&old, &at_naive],
+            )
+            .await?
+            .get(0);
+        let params: [&(dyn tokio_postgres::types::ToSql + Sync); 4] =
+            [&old, &at_naive, &new, &index_offset];
+        tx.execute(
+            "UPDATE event_revisions SET stream_id = $3, stream_event_index = stream_event_index - $4 \
+            WHERE stream_id = $1 AND stream_event_index IN (SELECT stream_event_index FROM events \
+                WHERE stream_id = $1 AND insert_datetime >= $2)",
+            &params,
+        )
+        .await?;
+        let moved = tx
+            .execute(
+                "UPDATE events SET stream_id = $3, stream_event_index = stream_event_index - $4 \
+                WHERE stream_id = $1 AND insert_datetime >= $2",
+                &params,
+            )
+            .await?;
+        tx.execute(
+            "UPDATE streams SET event_count = stored_event_count \
+            WHERE stream_id IN ($1, $2) AND event_count IS NOT NULL",
+            &[&old, &new],
+        )
+        .await?;
+        tx.execute(
+            "INSERT INTO audit_log (datetime, operation, details) \
+            VALUES (NOW(), 'split_stream', jsonb_build_object(\
+                'stream_id', $1::integer, 'at', $2::timestamptz, 'new_stream_id', $3::integer, \
+                'events', $4::bigint))",
+            &[&old, &at, &new, &(moved as i64)],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok((StreamId(new as u32), moved))
+    }
+
+    async fn prune(&mut self, policy: &RetentionPolicy) -> Result<Pruned> {
+        let layout = self.layout;
+        let tx = self.client.transaction().await?;
+        let mut pruned = Pruned::default();
+        if let Some(before) = policy.before {
+            let (partitions, events) = match layout {
+                EventsLayout::Table => (0, 0),
+                EventsLayout::Partitioned => {
+                    partitions::drop_partitions(&tx, before.naive_utc()).await?
+                }
+                EventsLayout::Hypertable => timescale::drop_chunks(&tx, before.naive_utc()).await?,
+            };
+            pruned.partitions += partitions;
+            pruned.events += events;
+        }
+        if let Some(before) = policy.before {
+            pruned.events += tx
+                .execute(
+                    "DELETE FROM events WHERE insert_datetime < $1",
+                    &[&before.naive_utc()],
+                )
+                .await?;
+        }
+        if let Some(max_events) = policy.max_events {
+            pruned.events += tx
+                .execute(
+                    "DELETE FROM events WHERE (tableoid, ctid) IN (SELECT tableoid, ctid \
+                    FROM events ORDER BY insert_datetime DESC, tableoid DESC, ctid DESC OFFSET $1)",
+                    &[&(max_events as i64)],
+                )
+                .await?;
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            pruned.events += tx
+                .execute(
+                    "DELETE FROM events WHERE (tableoid, ctid) IN (SELECT tableoid, ctid FROM \
+                    (SELECT tableoid, ctid, SUM(pg_column_size(payload)) \
+                        OVER (ORDER BY insert_datetime DESC, tableoid DESC, ctid DESC) AS total \
+                        FROM events) \
+                    AS newest WHERE total > $1)",
+                    &[&(max_bytes as i64)],
+                )
+                .await?;
+        }
+        tx.execute(
+            "DELETE FROM event_revisions WHERE NOT EXISTS (SELECT 1 FROM events \
+            WHERE events.stream_id = event_revisions.stream_id \
+            AND events.stream_event_index = event_revisions.stream_event_index)",
+            &[],
+        )
+        .await?;
+        // Streams that ended with events are only emptied by pruning. Open streams stay, since
+        // their next events still need them.
+        pruned.streams = tx
+            .execute(
+                "DELETE FROM streams WHERE NOT EXISTS \
+                (SELECT 1 FROM events WHERE events.stream_id = streams.stream_id) \
+                AND (end_datetime IS NOT NULL OR stale_datetime IS NOT NULL) \
+                AND (start_datetime::timestamptz < $1 OR event_count > 0)",
+                &[&policy.before],
+            )
+            .await?;
+        tx.commit().await?;
+        Ok(pruned)
+    }
+
+    async fn create_partitions(&mut self) -> Result<u64> {
+        self.partitions.create(&self.client).await
+    }
+
+    async fn mark_stale_streams(&mut self, quiet_since: DateTime<Utc>) -> Result<StaleStreams> {
+        let quiet_since = quiet_since.naive_utc();
+        let tx = self.client.transaction().await?;
+        let recent = "SELECT stream_id FROM events WHERE insert_datetime >= $1";
+        let revived = tx
+            .execute(
+                &format!(
+                    "UPDATE streams SET stale_datetime = NULL \
+                    WHERE stale_datetime IS NOT NULL AND end_datetime IS NULL \
+                    AND stream_id IN ({})",
+                    recent
+                ),
+                &[&quiet_since],
+            )
+            .await?;
+        let marked = tx
+            .execute(
+                &format!(
+                    "UPDATE streams SET stale_datetime = NOW() \
+                    WHERE stale_datetime IS NULL AND end_datetime IS NULL \
+                    AND start_datetime::timestamp < $1 AND stream_id NOT IN ({})",
+                    recent
+                ),
+                &[&quiet_since],
+            )
+            .await?;
+        tx.commit().await?;
+        Ok(StaleStreams { marked, revived })
+    }
+
+    async fn roll_up(&mut self, rollup: &Rollup) -> Result<u64> {
+        rollups::roll_up_postgres(&mut self.client, rollup).await
+    }
+
+    async fn read_rollup(
+        &mut self,
+        name: &str,
+        selection: &EventSelection,
+    ) -> Result<Vec<RollupRow>> {
+        rollups::read_rollup_postgres(&self.client, name, selection).await
+    }
+
+    async fn read_series(&mut self, query: &SeriesQuery) -> Result<Vec<SeriesPoint>> {
+        series::read_series_postgres(&self.client, query).await
+    }
+
+    async fn insert_metric_points(&mut self, points: &[MetricPoint]) -> Result<u64> {
+        metric_points::insert_metric_points_postgres(&mut self.client, points).await
+    }
+
+    async fn rewrite_events(
+        &mut self,
+        selection: &EventSelection,
+        rewrite: PayloadRewriter<'_>,
+    ) -> Result<u64> {
+        let (since, until) = selection.naive_bounds();
+        let stream_id = selection.stream_id.map(|stream_id| stream_id.0 as i32);
+        let tx = self.client.transaction().await?;
+        let select = tx
+            .prepare(
+                "SELECT tableoid, ctid::text, payload::text FROM events \
+                WHERE ($1::integer IS NULL OR stream_id = $1) \
+                AND ($2::timestamp IS NULL OR insert_datetime >= $2) \
+                AND ($3::timestamp IS NULL OR insert_datetime < $3)",
+            )
+            .await?;
+        let update = tx
+            .prepare(
+                "UPDATE events SET payload = $1::text::jsonb \
+                WHERE tableoid = $2 AND ctid = $3::text::tid",
+            )
+            .await?;
+        // The cursor reads from the transaction's snapshot, so it doesn't see the updates.
+        let portal = tx.bind(&select, &[&stream_id, &since, &until]).await?;
+        let mut updated = 0;
+        loop {
+            let rows = tx.query_portal(&portal, READ_BATCH_SIZE as i32).await?;
+            if rows.is_empty() {
+                break;
+            }
+            for row in rows {
+                // Row locations are only unique within a partition.
+                let tableoid: u32 = row.get(0);
+                let ctid: &str = row.get(1);
+                let payload: &str = row.get(2);
+                if let Some(new_payload) = rewrite(payload)? {
+                    tx.execute(&update, &[&new_payload, &tableoid, &ctid])
+                        .await?;
+                    updated += 1;
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    async fn import_streams(&mut self, streams: &[ImportedStream]) -> Result<()> {
+        if streams.is_empty() {
+            return Ok(());
+        }
+        let tx = self.client.transaction().await?;
+        // Start times are text, in the format NOW() is stored in.
+        let insert = tx
+            .prepare(
+                "INSERT INTO streams \
+                (stream_id, headers, start_datetime, end_datetime, event_count, clock_skew_ms, \
+                stream_uuid) \
+                VALUES ($1, $2, $3::timestamptz::text, $4, $5, $6, $7::text::uuid) \
+                ON CONFLICT (stream_id) DO UPDATE SET \
+                end_datetime = COALESCE(streams.end_datetime, EXCLUDED.end_datetime), \
+                event_count = COALESCE(streams.event_count, EXCLUDED.event_count), \
+                clock_skew_ms = COALESCE(streams.clock_skew_ms, EXCLUDED.clock_skew_ms), \
+                stream_uuid = COALESCE(streams.stream_uuid, EXCLUDED.stream_uuid)",
+            )
+            .await?;
+        for stream in streams {
+            tx.execute(
+                &insert,
+                &[
+                    &(stream.stream_id.0 as i32),
+                    &stream.headers,
+                    &stream.start_datetime,
+                    &stream.end_datetime.map(|end| end.naive_utc()),
+                    &stream.event_count.map(|count| count as i64),
+                    &stream.clock_skew_ms,
+                    &stream.stream_uuid.map(|uuid| uuid.to_string()),
+                ],
+            )
+            .await?;
+        }
+        // The IDs didn't come from the sequence, so new streams have to start after them.
+        tx.execute(
+            "SELECT setval(pg_get_serial_sequence('streams', 'stream_id'), GREATEST(\
+                MAX(stream_id), \
+                pg_sequence_last_value(pg_get_serial_sequence('streams', 'stream_id')::regclass), \
+                1)) \
+            FROM streams",
+            &[],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn import_events(&mut self, events: &[ImportedEvent]) -> Result<u64> {
+        let on_event_id_conflict = self.on_event_id_conflict();
+        let tx = self.client.transaction().await?;
+        let select = tx
+            .prepare(
+                "SELECT revision FROM events \
+                WHERE stream_id = $1 AND stream_event_index = $2 \
+                LIMIT 1 FOR UPDATE",
+            )
+            .await?;
+        let insert = tx
+            .prepare(&format!(
+                "INSERT INTO events \
+                (stream_id, stream_event_index, insert_datetime, revision, payload, event_id, \
+                collector, client_datetime) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8){}",
+                on_event_id_conflict
+            ))
+            .await?;
+        let keep_revision = tx
+            .prepare(
+                "INSERT INTO event_revisions \
+                (stream_id, stream_event_index, revision, insert_datetime, payload) \
+                SELECT stream_id, stream_event_index, revision, insert_datetime, payload FROM events \
+                WHERE stream_id = $1 AND stream_event_index = $2",
+            )
+            .await?;
+        let update = tx
+            .prepare(
+                "UPDATE events SET insert_datetime = $3, revision = $4, payload = $5 \
+                WHERE stream_id = $1 AND stream_event_index = $2",
+            )
+            .await?;
+        let mut imported = 0;
+        for event in events {
+            let stream_id = event.stream_id.0 as i32;
+            let stream_event_index = event.stream_event_index as i32;
+            let insert_datetime = event.insert_datetime.naive_utc();
+            let revision = event.revision as i32;
+            let latest = tx
+                .query_opt(&select, &[&stream_id, &stream_event_index])
+                .await?
+                .map(|row| row.get::<_, i32>(0));
+            imported += match latest {
+                None => {
+                    tx.execute(
+                        &insert,
+                        &[
+                            &stream_id,
+                            &stream_event_index,
+                            &insert_datetime,
+                            &revision,
+                            &event.payload,
+                            &event.event_id,
+                            &event.collector,
+                            &event.client_datetime.map(|client| client.naive_utc()),
+                        ],
+                    )
+                    .await?
+                }
+                Some(latest) if revision > latest => {
+                    tx.execute(&keep_revision, &[&stream_id, &stream_event_index])
+                        .await?;
+                    tx.execute(
+                        &update,
+                        &[
+                            &stream_id,
+                            &stream_event_index,
+                            &insert_datetime,
+                            &revision,
+                            &event.payload,
+                        ],
+                    )
+                    .await?
+                }
+                Some(_) => 0,
+            };
+        }
+        tx.commit().await?;
+        Ok(imported)
+    }
+
+    async fn events_after(
+        &mut self,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<Vec<ImportedEvent>> {
+        let columns = "SELECT stream_id, stream_event_index, insert_datetime, revision, payload, \
+            event_id, collector, client_datetime FROM events";
+        let order = "ORDER BY insert_datetime, stream_id, stream_event_index";
+        let limit = limit as i64;
+        // Separate statements, so the one with the cursor can use the index on the ordering.
+        let rows = match cursor {
+            None => {
+                self.client
+                    .query(&format!("{} {} LIMIT $1", columns, order), &[&limit])
+                    .await?
+            }
+            Some(cursor) => {
+                self.client
+                    .query(
+                        &format!(
+                        "{} WHERE (insert_datetime, stream_id, stream_event_index) > ($1, $2, $3) \
+                            {} LIMIT $4",
+                        columns, order
+                    ),
+                        &[
+                            &cursor.insert_datetime.naive_utc(),
+                            &(cursor.stream_id.0 as i32),
+                            &(cursor.stream_event_index as i32),
+                            &limit,
+                        ],
+                    )
+                    .await?
+            }
+        };
+        Ok(rows
+            .iter()
+            .map(|row| ImportedEvent {
+                stream_id: StreamId(row.get::<_, i32>(0) as u32),
+                stream_event_index: row.get::<_, i32>(1) as StreamEventIndex,
+                insert_datetime: row.get::<_, NaiveDateTime>(2).and_utc(),
+                revision: row.get::<_, i32>(3) as EventRevision,
+                payload: row.get(4),
+                event_id: row.get(5),
+                collector: row.get(6),
+                client_datetime: row
+                    .get::<_, Option<NaiveDateTime>>(7)
+                    .map(|client| client.and_utc()),
+            })
+            .collect())
+    }
+
+    async fn streams_by_id(&mut self, stream_ids: &[StreamId]) -> Result<Vec<ImportedStream>> {
+        let stream_ids: Vec<i32> = stream_ids.iter().map(|id| id.0 as i32).collect();
+        self.client
+            .query(
+                "SELECT stream_id, headers, start_datetime, end_datetime, event_count, \
+                clock_skew_ms, stream_uuid::text FROM streams WHERE stream_id = ANY($1) \
+                ORDER BY stream_id",
+                &[&stream_ids],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(ImportedStream {
+                    stream_id: StreamId(row.get::<_, i32>(0) as u32),
+                    headers: row.get(1),
+                    start_datetime: parse_datetime(row.get(2))?,
+                    end_datetime: row
+                        .get::<_, Option<NaiveDateTime>>(3)
+                        .map(|end| end.and_utc()),
+                    event_count: row.get::<_, Option<i64>>(4).map(|count| count as u64),
+                    clock_skew_ms: row.get(5),
+                    stream_uuid: row.get::<_, Option<&str>>(6).map(str::parse).transpose()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A statement parameter that can be held across awaits.
+type PostgresParam = Box<dyn tokio_postgres::types::ToSql + Send + Sync>;
+
+async fn select_postgres_events(
+    client: &impl tokio_postgres::GenericClient,
+    query: &EventQuery,
+) -> Result<Vec<serde_json::Value>> {
+    let (sql, params) = postgres_events_statement(query);
+    let params: Vec<_> = params.iter().map(|param| param.as_ref() as _).collect();
+    let rows = client.query(&sql, &params).await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Selects events as JSON objects for [Connection::query_events].
+fn postgres_events_statement(query: &EventQuery) -> (String, Vec<PostgresParam>) {
+    let (since, until) = query.selection.naive_bounds();
+    let stream_id = query
+        .selection
+        .stream_id
+        .map(|stream_id| stream_id.0 as i32);
+    let limit = query.limit as i64;
+    let mut params: Vec<PostgresParam> = vec![
+        Box::new(stream_id),
+        Box::new(since),
+        Box::new(until),
+        Box::new(limit),
+    ];
+    let mut sql = "SELECT json_build_object(\
+        'stream_id', stream_id, 'stream_event_index', stream_event_index, \
+        'insert_datetime', insert_datetime, 'client_datetime', client_datetime, \
+        'payload', payload) \
+        FROM events \
+        WHERE ($1::integer IS NULL OR stream_id = $1) \
+        AND ($2::timestamp IS NULL OR insert_datetime >= $2) \
+        AND ($3::timestamp IS NULL OR insert_datetime < $3)"
+        .to_owned();
+    for (field, value) in &query.filters {
+        sql += &format!(
+            " AND payload->>${}::text = ${}::text",
+            params.len() + 1,
+            params.len() + 2
+        );
+        params.push(Box::new(field.clone()));
+        params.push(Box::new(value.clone()));
+    }
+    if let Some(text) = query.search_text() {
+        sql += &format!(
+            " AND payload_tsv @@ plainto_tsquery('simple', ${}::text)",
+            params.len() + 1
+        );
+        params.push(Box::new(text.to_owned()));
+    }
+    // Only added when given, so the index on the ordering can be used.
+    if let Some(after) = &query.after {
+        sql += &format!(
+            " AND (insert_datetime, stream_id, stream_event_index) > (${}, ${}, ${})",
+            params.len() + 1,
+            params.len() + 2,
+            params.len() + 3
+        );
+        params.push(Box::new(after.insert_datetime.naive_utc()));
+        params.push(Box::new(after.stream_id.0 as i32));
+        params.push(Box::new(after.stream_event_index as i32));
+    }
+    sql += " ORDER BY insert_datetime, stream_id, stream_event_index LIMIT $4";
+    (sql, params)
+}
+
+/// The streams of events returned by [select_postgres_events], as in a [Snapshot].
+async fn select_postgres_streams(
+    client: &impl tokio_postgres::GenericClient,
+    events: &[serde_json::Value],
+) -> Result<Vec<serde_json::Value>> {
+    let stream_ids: Vec<i32> = event_stream_ids(events)?
+        .into_iter()
+        .map(|stream_id| stream_id as i32)
+        .collect();
+    let rows = client
+        .query(
+            &format!(
+                "SELECT {} FROM streams WHERE stream_id = ANY($1) ORDER BY stream_id",
+                POSTGRES_STREAM_OBJECT
+            ),
+            &[&stream_ids],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// A stream row as a JSON object, as in a [Snapshot].
+const POSTGRES_STREAM_OBJECT: &str = "json_build_object(\
+    'stream_id', stream_id, 'headers', headers, 'start_datetime', start_datetime, \
+    'end_datetime', end_datetime, 'event_count', event_count, \
+    'stale_datetime', stale_datetime, 'clock_skew_ms', clock_skew_ms, 'sampling', sampling, \
+    'labels', COALESCE((SELECT jsonb_object_agg(key, value) FROM stream_labels \
+        WHERE stream_labels.stream_id = streams.stream_id), '{}'), \
+    'stored_event_count', stored_event_count, 'stored_payload_bytes', stored_payload_bytes, \
+    'last_insert_datetime', last_insert_datetime, 'stream_uuid', stream_uuid)";
+
+/// The window for long-distance matching, as for `zstd --long`. It's the largest window decoders
+/// accept without being told to.
+const LONG_WINDOW_LOG: u32 = 27;
+
+/// How a [JsonFileWriter] names, compresses and rotates its files.
+#[derive(Debug)]
+struct JsonFileOptions {
+    dir: PathBuf,
+    /// See [JsonFilesOpen] for the placeholders.
+    name_template: String,
+    hostname: String,
+    compression_level: i32,
+    long_distance_matching: bool,
+    /// Encoders copy it in each time a file or stream starts.
+    dictionary: Option<Vec<u8>>,
+    /// zstd workers per file. Each flush waits for them to finish what they have.
+    compression_threads: u32,
+    /// Wall-clock period, aligned to the Unix epoch so hourly files change on the hour and daily
+    /// ones at midnight UTC.
+    rotate_interval: Option<std::time::Duration>,
+    /// Compressed bytes.
+    rotate_size: Option<u64>,
+    /// Finished files are encrypted with this, if set.
+    encryption_key: Option<EncryptionKey>,
+    /// Copied to each writer, which fsyncs on flush when it's due.
+    fsync: FsyncSchedule,
+    file_closed: FileClosedHook,
+}
+
+impl JsonFileOptions {
+    /// The start of a file's name. A random part and ".json.zst" follow, so names are unique.
+    fn file_name_prefix(&self, table: &str, opened: DateTime<Utc>) -> Result<String> {
+        let name = self
+            .name_template
+            .replace("{table}", table)
+            .replace("{date}", &opened.format("%Y%m%dT%H%M%S%.6fZ").to_string())
+            .replace("{hostname}", &self.hostname);
+        if let Some(start) = name.find('{') {
+            bail!(
+                "unknown placeholder in file name template at {:?}",
+                &name[start..]
+            );
+        }
+        if name.contains(std::path::is_separator) {
+            bail!("file name {:?} has a path separator", name);
+        }
+        Ok(format!("{}.", name))
+    }
+}
+
+struct JsonFileWriter {
+    w: Option<zstd::Encoder<'static, NamedTempFile>>,
+    table: String,
+    options: Arc<JsonFileOptions>,
+    /// When the current file's rotation interval is over.
+    period_end: Option<DateTime<Utc>>,
+    fsync: FsyncSchedule,
+}
+
+impl JsonFileWriter {
+    fn new(table: String, options: Arc<JsonFileOptions>) -> Result<Self> {
+        Ok(Self {
+            w: None,
+            table,
+            fsync: options.fsync.clone(),
+            options,
+            period_end: None,
+        })
+    }
+    /// Flushes the compressed stream but keeps the file open for the next stream.
+    fn flush(&mut self) -> Result<()> {
+        if let Some(file) = self.finish_stream()? {
+            if self.fsync.due() {
+                file.as_file().sync_data().context("fsyncing json file")?;
+            }
+            self.w = Some(self.new_encoder(file)?)
+        }
+        Ok(())
+    }
+    /// Finished files are fsynced unless that's left to the OS, before anything's told about
+    /// them.
+    fn finish_file(&mut self) -> Result<()> {
+        if let Some(file) = self.finish_stream()? {
+            let path = match &self.options.encryption_key {
+                Some(key) => encryption::encrypt_file(key, file.path())?,
+                None => file.path().to_owned(),
+            };
+            if self.fsync.enabled() {
+                std::fs::File::open(&path)?
+                    .sync_all()
+                    .with_context(|| format!("fsyncing {}", path.display()))?;
+            }
+            self.options.file_closed.run(&self.table, &path);
+        }
+        Ok(())
+    }
+    fn finish_stream(&mut self) -> Result<Option<NamedTempFile>> {
+        let Some(w) = self.w.take() else {
+            return Ok(None);
+        };
+        Ok(Some(w.finish()?))
+    }
+    fn new_encoder(&self, file: NamedTempFile) -> Result<zstd::Encoder<'static, NamedTempFile>> {
+        let options = &self.options;
+        let mut encoder = match &options.dictionary {
+            Some(dictionary) => {
+                zstd::Encoder::with_dictionary(file, options.compression_level, dictionary)?
+            }
+            None => zstd::Encoder::new(file, options.compression_level)?,
+        };
+        if options.compression_threads > 0 {
+            encoder.multithread(options.compression_threads)?;
+        }
+        if options.long_distance_matching {
+            encoder.long_distance_matching(true)?;
+            encoder.window_log(LONG_WINDOW_LOG)?;
+        }
+        Ok(encoder)
+    }
+    /// With the default name template, files are named for when they were opened, so sorting a
+    /// table's files by name orders them, and all but the last are closed.
+    fn open(&mut self) -> Result<()> {
+        self.finish_file()?;
+        let dir_path = &self.options.dir;
+        std::fs::create_dir_all(dir_path)?;
+        let now = Utc::now();
+        let temp_file = tempfile::Builder::new()
+            .prefix(&self.options.file_name_prefix(&self.table, now)?)
+            .append(true)
+            .suffix(".json.zst")
+            .keep(true)
+            .tempfile_in(dir_path)
+            .context("opening temp file")?;
+        self.period_end = self
+            .options
+            .rotate_interval
+            .map(|interval| period_end(now, interval));
+        self.w = Some(self.new_encoder(temp_file)?);
+        Ok(())
+    }
+    fn needs_rotation(&self) -> Result<bool> {
+        let Some(w) = &self.w else {
+            return Ok(false);
+        };
+        if self.period_end.is_some_and(|end| Utc::now() >= end) {
+            return Ok(true);
+        }
+        let Some(max_bytes) = self.options.rotate_size else {
+            return Ok(false);
+        };
+        // Only counts what the encoder has flushed so far.
+        Ok(w.get_ref().as_file().metadata()?.len() > max_bytes)
+    }
+    /// The file currently being written, if any.
+    fn path(&self) -> Option<&std::path::Path> {
+        self.w.as_ref().map(|w| w.get_ref().path())
+    }
+    /// Rotates before handing out the writer, so lines aren't split across files.
+    fn write(&mut self) -> Result<impl Write + '_> {
+        if self.w.is_none() || self.needs_rotation()? {
+            self.open()?;
+        }
+        Ok(self.w.as_mut().unwrap())
+    }
+}
+
+/// The end of the rotation period containing `now`.
+fn period_end(now: DateTime<Utc>, interval: std::time::Duration) -> DateTime<Utc> {
+    let secs = interval.as_secs().max(1) as i64;
+    let end = (now.timestamp().div_euclid(secs) + 1) * secs;
+    DateTime::from_timestamp(end, 0).unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// A fallback for when [Connection::shutdown] wasn't called. This blocks to finish the file, so
+/// nothing's lost even if the runtime is shutting down.
+impl Drop for JsonFileWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish_file() {
+            error!(table = self.table, %err, "finishing json file");
+        }
+    }
+}
+
+fn select_sqlite_events(
+    conn: &rusqlite::Connection,
+    query: &EventQuery,
+) -> Result<Vec<serde_json::Value>> {
+    let mut events = vec![];
+    for_each_sqlite_event(conn, query, |event| {
+        events.push(event);
+        Ok(())
+    })?;
+    Ok(events)
+}
+
+/// Calls `f` with each event [select_sqlite_events] would return, as they're read.
+fn for_each_sqlite_event(
+    conn: &rusqlite::Connection,
+    query: &EventQuery,
+    mut f: impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
+    let (since, until) = query.selection.text_bounds();
+    let after = query.after.as_ref().map(|after| {
+        (
+            text_datetime(after.insert_datetime),
+            after.stream_id,
+            after.stream_event_index,
+        )
+    });
+    let text = query.search_text().map(full_text::sqlite_match);
+    let mut params: Vec<&dyn rusqlite::ToSql> =
+        vec![&query.selection.stream_id, &since, &until, &query.limit];
+    let mut sql = "\
+        select json_object(\
+            'stream_id', stream_id, 'stream_event_index', stream_event_index, \
+            'insert_datetime', insert_datetime, 'client_datetime', client_datetime, \
+            'payload', json(payload)) \
+        from events \
+        where (?1 is null or stream_id = ?1) \
+            and (?2 is null or insert_datetime >= ?2) \
+            and (?3 is null or insert_datetime < ?3)"
+        .to_owned();
+    for (field, value) in &query.filters {
+        sql += &format!(
+            " and cast(payload ->> ?{} as text) = ?{}",
+            params.len() + 1,
+            params.len() + 2
+        );
+        params.push(field);
+        params.push(value);
+    }
+    if let Some(text) = &text {
+        sql += &format!(
+            " and rowid in (select rowid from events_fts where events_fts match ?{})",
+            params.len() + 1
+        );
+        params.push(text);
+    }
+    // Only added when given, so the index on the ordering can be used.
+    if let Some((insert_datetime, stream_id, stream_event_index)) = &after {
+        sql += &format!(
+            " and (insert_datetime, stream_id, stream_event_index) > (?{}, ?{}, ?{})",
+            params.len() + 1,
+            params.len() + 2,
+            params.len() + 3
+        );
+        params.extend([
+            insert_datetime as &dyn rusqlite::ToSql,
+            stream_id,
+            stream_event_index,
+        ]);
+    }
+    sql += " order by insert_datetime, stream_id, stream_event_index limit ?4";
+    let mut stmt = conn.prepare(&sql).with_context(|| match text {
+        Some(_) => "full-text search needs the database opened with --full-text-search",
+        None => "selecting events",
+    })?;
+    let mut rows = stmt.query(params.as_slice())?;
+    while let Some(row) = rows.next()? {
+        f(row.get(0)?)?;
+    }
+    Ok(())
+}
+
+/// The streams of events returned by [select_sqlite_events], as in a [Snapshot].
+fn select_sqlite_streams(
+    conn: &rusqlite::Connection,
+    events: &[serde_json::Value],
+) -> Result<Vec<serde_json::Value>> {
+    let stream_ids = serde_json::to_string(&event_stream_ids(events)?)?;
+    let streams = conn
+        .prepare_cached(&format!(
+            "select {} from streams \
+            where stream_id in (select value from json_each(?)) \
+            order by stream_id",
+            SQLITE_STREAM_OBJECT
+        ))?
+        .query_map([stream_ids], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(streams)
+}
+
+/// A stream row as a JSON object, as in a [Snapshot].
+const SQLITE_STREAM_OBJECT: &str = "json_object(\
+    'stream_id', stream_id, 'headers', json(headers), \
+    'start_datetime', start_datetime, 'end_datetime', end_datetime, \
+    'event_count', event_count, 'stale_datetime', stale_datetime, \
+    'clock_skew_ms', clock_skew_ms, 'sampling', json(sampling), \
+    'labels', json((select json_group_object(key, value) from stream_labels \
+        where stream_labels.stream_id = streams.stream_id)), \
+    'stored_event_count', stored_event_count, 'stored_payload_bytes', stored_payload_bytes, \
+    'last_insert_datetime', last_insert_datetime, 'stream_uuid', stream_uuid)";
+
+#[async_trait]
+impl Connection for rusqlite::Connection {
+    async fn new_stream(&mut self, headers_value: SerializedHeaders) -> Result<StreamId> {
+        Ok(self.query_row(
+            "\
+            insert into streams\
+                (headers, start_datetime, stream_uuid)\
+                values (jsonb(?), datetime('now'), ?)\
+                returning stream_id",
+            rusqlite::params![headers_value, StreamUuid::now_v7()],
+            |row| row.get(0),
+        )?)
+    }
+    async fn insert_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        payload: &RawValue,
+        event_id: Option<&str>,
+        collector: Option<&serde_json::Value>,
+        client_datetime: Option<DateTime<Utc>>,
+    ) -> Result<Inserted> {
+        let inserted = self.execute(
+            "\
+            insert into events \
+                (insert_datetime, stream_event_index, payload, stream_id, event_id, collector, \
+                client_datetime) \
+            values (datetime('now'), ?, jsonb(?), ?, ?, jsonb(?), ?) \
+            on conflict do nothing",
+            rusqlite::params![
+                stream_event_index,
+                payload.get(),
+                stream_id,
+                event_id,
+                collector.map(|value| value.to_string()),
+                client_datetime.map(text_datetime),
+            ],
+        )?;
+        if inserted == 0 {
+            debug!(%stream_id, event_id, "dropped duplicate event");
+            return Ok(Inserted::Duplicate);
+        }
+        Ok(Inserted::Stored)
+    }
+    async fn insert_events(
+        &mut self,
+        stream_id: StreamId,
+        events: &[NewEvent<'_>],
+    ) -> Result<Vec<Inserted>> {
+        // A savepoint rather than a transaction, as there may be a batch open already.
+        let savepoint = self.savepoint()?;
+        let mut inserted = Vec::with_capacity(events.len());
+        {
+            let mut stmt = savepoint.prepare_cached(
+                "\
+                insert into events \
+                    (insert_datetime, stream_event_index, payload, stream_id, event_id, \
+                    collector, client_datetime) \
+                values (datetime('now'), ?, jsonb(?), ?, ?, jsonb(?), ?) \
+                on conflict do nothing",
+            )?;
+            for event in events {
+                let rows = stmt.execute(rusqlite::params![
+                    event.stream_event_index,
+                    event.payload.get(),
+                    stream_id,
+                    event.event_id,
+                    event.collector.map(|value| value.to_string()),
+                    event.client_datetime.map(text_datetime),
+                ])?;
+                inserted.push(match rows {
+                    0 => {
+                        debug!(%stream_id, event_id = event.event_id, "dropped duplicate event");
+                        Inserted::Duplicate
+                    }
+                    _ => Inserted::Stored,
+                });
+            }
+        }
+        savepoint.commit()?;
+        Ok(inserted)
+    }
+    async fn resume_stream(&mut self, stream_id: StreamId) -> Result<StreamEventIndex> {
+        use rusqlite::OptionalExtension;
+        self.query_row(
+            "\
+            select coalesce(max(stream_event_index), 0) \
+            from streams left join events using (stream_id) \
+            where streams.stream_id = ? group by streams.stream_id",
+            rusqlite::params![stream_id],
+            |row| row.get(0),
+        )
+        .optional()?
+        .ok_or_else(|| StreamNotFound(stream_id).into())
+    }
+    async fn find_stream(&mut self, stream_uuid: StreamUuid) -> Result<StreamId> {
+        use rusqlite::OptionalExtension;
+        self.query_row(
+            "select stream_id from streams where stream_uuid = ?",
+            rusqlite::params![stream_uuid],
+            |row| row.get(0),
+        )
+        .optional()?
+        .ok_or_else(|| anyhow!("stream {} not found", stream_uuid))
+    }
+    async fn close_stream(&mut self, stream_id: StreamId) -> Result<()> {
+        let updated = self.execute(
+            "\
+            update streams set end_datetime = datetime('now'), \
+                event_count = stored_event_count \
+            where stream_id = ?1",
+            rusqlite::params![stream_id],
+        )?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+    async fn record_clock_skew(
+        &mut self,
+        stream_id: StreamId,
+        clock_skew: chrono::TimeDelta,
+    ) -> Result<()> {
+        let updated = self.execute(
+            "update streams set clock_skew_ms = ? where stream_id = ?",
+            rusqlite::params![clock_skew.num_milliseconds(), stream_id],
+        )?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+    async fn record_sampling(
+        &mut self,
+        stream_id: StreamId,
+        sampling: &serde_json::Value,
+    ) -> Result<()> {
+        let updated = self.execute(
+            "update streams set sampling = jsonb(?) where stream_id = ?",
+            rusqlite::params![sampling.to_string(), stream_id],
+        )?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+    async fn update_stream_headers(
+        &mut self,
+        stream_id: StreamId,
+        patch: &serde_json::Value,
+    ) -> Result<()> {
+        // SQLite's own patch is a JSON merge patch.
+        let updated = self.execute(
+            "update streams set headers = jsonb_patch(headers, ?) where stream_id = ?",
+            rusqlite::params![patch.to_string(), stream_id],
+        )?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+    async fn set_stream_labels(
+        &mut self,
+        stream_id: StreamId,
+        labels: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        use rusqlite::OptionalExtension;
+        let savepoint = self.savepoint()?;
+        savepoint
+            .query_row(
+                "select 1 from streams where stream_id = ?",
+                [stream_id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("stream {} not found", stream_id))?;
+        for (key, value) in labels {
+            match value {
+                Some(value) => savepoint.execute(
+                    "\
+                    insert into stream_labels (stream_id, key, value) values (?, ?, ?) \
+                    on conflict (stream_id, key) do update set value = excluded.value",
+                    rusqlite::params![stream_id, key, value],
+                )?,
+                None => savepoint.execute(
+                    "delete from stream_labels where stream_id = ? and key = ?",
+                    rusqlite::params![stream_id, key],
+                )?,
+            };
+        }
+        savepoint.commit()?;
+        Ok(())
+    }
+    async fn revise_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        revision: EventRevision,
+        payload: &str,
+    ) -> Result<Revised> {
+        use rusqlite::OptionalExtension;
+        let tx = self.transaction()?;
+        let latest: Option<EventRevision> = tx
+            .query_row(
+                "select revision from events where stream_id = ? and stream_event_index = ?",
+                rusqlite::params![stream_id, stream_event_index],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(latest) = latest else {
+            return Ok(Revised::NotFound);
+        };
+        if revision <= latest {
+            return Ok(Revised::Stale { latest });
+        }
+        tx.execute(
+            "\
+            insert into event_revisions \
+                (stream_id, stream_event_index, revision, insert_datetime, payload) \
+            select stream_id, stream_event_index, revision, insert_datetime, payload from events \
+            where stream_id = ? and stream_event_index = ?",
+            rusqlite::params![stream_id, stream_event_index],
+        )?;
+        tx.execute(
+            "\
+            update events set revision = ?3, payload = jsonb(?4), insert_datetime = datetime('now') \
+            where stream_id = ?1 and stream_event_index = ?2",
+            rusqlite::params![stream_id, stream_event_index, revision, payload],
+        )?;
+        tx.commit()?;
+        Ok(Revised::Superseded)
+    }
+    async fn query_events(&mut self, query: &EventQuery) -> Result<Vec<serde_json::Value>> {
+        select_sqlite_events(self, query)
+    }
+    async fn snapshot(&mut self, query: &EventQuery) -> Result<Snapshot> {
+        // Reads in a transaction all see the same database.
+        let tx = self.transaction()?;
+        let events = select_sqlite_events(&tx, query)?;
+        let streams = select_sqlite_streams(&tx, &events)?;
+        tx.commit()?;
+        Ok(Snapshot { events, streams })
+    }
+    async fn recent_streams(
+        &mut self,
+        limit: usize,
+        labels: &[(String, String)],
+    ) -> Result<Vec<serde_json::Value>> {
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+        let mut conditions = vec!["true"];
+        for (key, value) in labels {
+            conditions.push(
+                "exists (select 1 from stream_labels \
+                where stream_labels.stream_id = streams.stream_id and key = ? and value = ?)",
+            );
+            params.extend([key as &dyn rusqlite::ToSql, value]);
+        }
+        params.push(&limit);
+        let streams = self
+            .prepare_cached(&format!(
+                "select {} from streams where {} order by stream_id desc limit ?",
+                SQLITE_STREAM_OBJECT,
+                conditions.join(" and ")
+            ))?
+            .query_map(&params[..], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(streams)
+    }
+    async fn snapshot_batches(
+        &mut self,
+        query: &EventQuery,
+        batch_size: usize,
+        each: &mut (dyn FnMut(Snapshot) -> Result<()> + Send),
+    ) -> Result<()> {
+        let tx = self.transaction()?;
+        let mut events = vec![];
+        for_each_sqlite_event(&tx, query, |event| {
+            events.push(event);
+            if events.len() >= batch_size {
+                let events = std::mem::take(&mut events);
+                let streams = select_sqlite_streams(&tx, &events)?;
+                each(Snapshot { events, streams })?;
+            }
+            Ok(())
+        })?;
+        if !events.is_empty() {
+            let streams = select_sqlite_streams(&tx, &events)?;
+            each(Snapshot { events, streams })?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+    async fn stats(&mut self) -> Result<StorageStats> {
+        Ok(self.query_row(
+            "\
+            select (select count(*) from streams), \
+                (select count(*) from streams where end_datetime is not null), \
+                count(*), coalesce(sum(length(payload)), 0), \
+                min(insert_datetime), max(insert_datetime) \
+            from events",
+            [],
+            |row| {
+                Ok(StorageStats {
+                    streams: row.get(0)?,
+                    ended_streams: row.get(1)?,
+                    events: row.get(2)?,
+                    payload_bytes: row.get(3)?,
+                    first_insert_datetime: row.get(4)?,
+                    last_insert_datetime: row.get(5)?,
+                })
+            },
+        )?)
+    }
+    async fn record_anomaly(&mut self, anomaly: &StreamAnomaly) -> Result<()> {
+        self.execute(
+            "\
+            insert into stream_anomalies \
+                (stream_id, stream_event_index, kind, detected_datetime, details) \
+            values (?, ?, ?, datetime('now'), ?)",
+            rusqlite::params![
+                anomaly.stream_id,
+                anomaly.stream_event_index,
+                anomaly.kind.as_str(),
+                anomaly.details
+            ],
+        )?;
+        Ok(())
+    }
+    async fn begin_batch(&mut self) -> Result<bool> {
+        // Taking the write lock up front waits for other connections, where upgrading to it later
+        // could fail straight away.
+        self.execute_batch("begin immediate")?;
+        Ok(true)
+    }
+    fn batches(&self) -> bool {
+        true
+    }
+    async fn commit_batch(&mut self) -> Result<()> {
+        Ok(self.execute_batch("commit")?)
+    }
+    async fn rollback_batch(&mut self) -> Result<()> {
+        Ok(self.execute_batch("rollback")?)
+    }
+    async fn save_link(&mut self, link_id: &str, query: &str) -> Result<()> {
+        self.execute(
+            "\
+            insert into links (link_id, query, created_datetime) values (?, ?, datetime('now')) \
+            on conflict do nothing",
+            rusqlite::params![link_id, query],
+        )?;
+        Ok(())
+    }
+    async fn load_link(&mut self, link_id: &str) -> Result<Option<String>> {
+        use rusqlite::OptionalExtension;
+        Ok(self
+            .query_row(
+                "select query from links where link_id = ?",
+                rusqlite::params![link_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+    async fn find_request_hash(
+        &mut self,
+        hash: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        use rusqlite::OptionalExtension;
+        self.execute(
+            "delete from request_hashes where received_datetime < ?",
+            rusqlite::params![text_datetime(since)],
+        )?;
+        let received: Option<String> = self
+            .query_row(
+                "select received_datetime from request_hashes where hash = ?",
+                rusqlite::params![hash],
+                |row| row.get(0),
+            )
+            .optional()?;
+        received.as_deref().map(parse_datetime).transpose()
+    }
+    async fn save_request_hash(&mut self, hash: &str) -> Result<()> {
+        self.execute(
+            "\
+            insert into request_hashes (hash, received_datetime) values (?, datetime('now')) \
+            on conflict do nothing",
+            rusqlite::params![hash],
+        )?;
+        Ok(())
+    }
+    async fn merge_streams(&mut self, from: StreamId, into: StreamId) -> Result<u64> {
+        use rusqlite::OptionalExtension;
+        if from == into {
+            bail!("can't merge a stream into itself");
+        }
+        let tx = self.transaction()?;
+        let index_offset: StreamEventIndex = tx
+            .query_row(
+                "\
+                select coalesce(max(stream_event_index), 0) \
+                from streams left join events using (stream_id) \
+                where streams.stream_id = ? group by streams.stream_id",
+                rusqlite::params![into],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("stream {} not found", into))?;
+        tx.execute(
+            "\
+            update event_revisions set stream_id = ?2, stream_event_index = stream_event_index + ?3 \
+            where stream_id = ?1",
+            rusqlite::params![from, into, index_offset],
+        )?;
+        let moved = tx
+            .execute(
+                "\
+                update events set stream_id = ?2, stream_event_index = stream_event_index + ?3 \
+                where stream_id = ?1",
+                rusqlite::params![from, into, index_offset],
+            )
+            .context("moving events")? as u64;
+        // The merged stream's headers would otherwise be lost.
+        let audited = tx.execute(
+            "\
+            insert into audit_log (datetime, operation, details) \
+            select datetime('now'), 'merge_streams', jsonb_object(\
+                'from', ?1, 'into', ?2, 'events', ?3, 'index_offset', ?4, \
+                'from_headers', json(headers)) \
+            from streams where stream_id = ?1",
+            rusqlite::params![from, into, moved, index_offset],
+        )?;
+        if audited == 0 {
+            bail!("stream {} not found", from);
+        }
+        tx.execute(
+            "delete from streams where stream_id = ?",
+            rusqlite::params![from],
+        )?;
+        tx.execute(
+            "\
+            update streams set event_count = stored_event_count \
+            where stream_id = ?1 and event_count is not null",
+            rusqlite::params![into],
+        )?;
+        tx.commit()?;
+        Ok(moved)
+    }
+    async fn split_stream(
+        &mut self,
+        stream_id: StreamId,
+        at: DateTime<Utc>,
+    ) -> Result<(StreamId, u64)> {
+        use rusqlite::OptionalExtension;
+        let at = text_datetime(at);
+        let tx = self.transaction()?;
+        let new_stream_id: StreamId = tx
+            .query_row(
+                "\
+                insert into streams (headers, start_datetime, end_datetime, event_count, stream_uuid) \
+                select headers, ?2, end_datetime, event_count, ?3 from streams where stream_id = ?1 \
+                returning stream_id",
+                rusqlite::params![stream_id, at, StreamUuid::now_v7()],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("stream {} not found", stream_id))?;
+        // The new stream's indexes start from 1.
+        let index_offset: StreamEventIndex = tx.query_row(
+            "\
+            select coalesce(min(stream_event_index) - 1, 0) from events \
+            where stream_id = ? and insert_datetime >= ?",
+            rusqlite::params![stream_id, at],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "\
+            update event_revisions set stream_id = ?3, stream_event_index = stream_event_index - ?4 \
+            where stream_id = ?1 and stream_event_index in (select stream_event_index from events \
+                where stream_id = ?1 and insert_datetime >= ?2)",
+            rusqlite::params![stream_id, at, new_stream_id, index_offset],
+        )?;
+        let moved = tx.execute(
+            "\
+            update events set stream_id = ?3, stream_event_index = stream_event_index - ?4 \
+            where stream_id = ?1 and insert_datetime >= ?2",
+            rusqlite::params![stream_id, at, new_stream_id, index_offset],
+        )? as u64;
+        tx.execute(
+            "\
+            update streams set event_count = stored_event_count \
+            where stream_id in (?, ?) and event_count is not null",
+            rusqlite::params![stream_id, new_stream_id],
+        )?;
+        tx.execute(
+            "\
+            insert into audit_log (datetime, operation, details) \
+            values (datetime('now'), 'split_stream', jsonb_object(\
+                'stream_id', ?1, 'at', ?2, 'new_stream_id', ?3, 'events', ?4))",
+            rusqlite::params![stream_id, at, new_stream_id, moved],
+        )?;
+        tx.commit()?;
+        Ok((new_stream_id, moved))
+    }
+    async fn prune(&mut self, policy: &RetentionPolicy) -> Result<Pruned> {
+        let before = policy.before.map(text_datetime);
+        let tx = self.transaction()?;
+        let mut pruned = Pruned::default();
+        if let Some(before) = &before {
+            pruned.events += tx.execute(
+                "delete from events where insert_datetime < ?",
+                rusqlite::params![before],
+            )? as u64;
+        }
+        if let Some(max_events) = policy.max_events {
+            pruned.events += tx.execute(
+                "\
+                delete from events where rowid in \
+                    (select rowid from events order by rowid desc limit -1 offset ?)",
+                rusqlite::params![max_events],
+            )? as u64;
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            pruned.events += tx.execute(
+                "\
+                delete from events where rowid in (select rowid from \
+                    (select rowid, sum(length(payload)) over (order by rowid desc) as total \
+                    from events) \
+                where total > ?)",
+                rusqlite::params![max_bytes],
+            )? as u64;
+        }
+        tx.execute(
+            "\
+            delete from event_revisions where not exists (select 1 from events \
+                where events.stream_id = event_revisions.stream_id \
+                and events.stream_event_index = event_revisions.stream_event_index)",
+            [],
+        )?;
+        // Streams that ended with events are only emptied by pruning. Open streams stay, since
+        // their next events still need them.
+        pruned.streams = tx.execute(
+            "\
+            delete from streams \
+            where not exists (select 1 from events where events.stream_id = streams.stream_id) \
+                and (end_datetime is not null or stale_datetime is not null) \
+                and (start_datetime < ? or event_count > 0)",
+            rusqlite::params![before],
+        )? as u64;
+        tx.commit()?;
+        Ok(pruned)
+    }
+    async fn mark_stale_streams(&mut self, quiet_since: DateTime<Utc>) -> Result<StaleStreams> {
+        let quiet_since = text_datetime(quiet_since);
+        let tx = self.transaction()?;
+        let revived = tx.execute(
+            "\
+            update streams set stale_datetime = null \
+            where stale_datetime is not null and end_datetime is null and stream_id in \
+                (select stream_id from events where insert_datetime >= ?)",
+            rusqlite::params![quiet_since],
+        )? as u64;
+        let marked = tx.execute(
+            "\
+            update streams set stale_datetime = datetime('now') \
+            where stale_datetime is null and end_datetime is null and start_datetime < ?1 \
+                and stream_id not in (select stream_id from events where insert_datetime >= ?1)",
+            rusqlite::params![quiet_since],
+        )? as u64;
+        tx.commit()?;
+        Ok(StaleStreams { marked, revived })
+    }
+    async fn roll_up(&mut self, rollup: &Rollup) -> Result<u64> {
+        rollups::roll_up_sqlite(self, rollup)
+    }
+    async fn read_rollup(
+        &mut self,
+        name: &str,
+        selection: &EventSelection,
+    ) -> Result<Vec<RollupRow>> {
+        rollups::read_rollup_sqlite(self, name, selection)
+    }
+    async fn read_series(&mut self, query: &SeriesQuery) -> Result<Vec<SeriesPoint>> {
+        series::read_series_sqlite(self, query)
+    }
+    async fn insert_metric_points(&mut self, points: &[MetricPoint]) -> Result<u64> {
+        metric_points::insert_metric_points_sqlite(self, points)
+    }
+    async fn rewrite_events(
+        &mut self,
+        selection: &EventSelection,
+        rewrite: PayloadRewriter<'_>,
+    ) -> Result<u64> {
+        let (since, until) = selection.text_bounds();
+        let tx = self.transaction()?;
+        let mut updated = 0;
+        let mut last_rowid = 0;
+        loop {
+            // Read in batches so updates aren't made under a running select.
+            let batch = tx
+                .prepare_cached(
+                    "\
+                    select rowid, json(payload) from events \
+                    where rowid > ?1 \
+                        and (?2 is null or stream_id = ?2) \
+                        and (?3 is null or insert_datetime >= ?3) \
+                        and (?4 is null or insert_datetime < ?4) \
+                    order by rowid limit ?5",
+                )?
+                .query_map(
+                    rusqlite::params![
+                        last_rowid,
+                        selection.stream_id,
+                        since,
+                        until,
+                        READ_BATCH_SIZE
+                    ],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+                )?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let Some(&(batch_last_rowid, _)) = batch.last() else {
+                break;
+            };
+            last_rowid = batch_last_rowid;
+            for (rowid, payload) in batch {
+                if let Some(new_payload) = rewrite(&payload)? {
+                    tx.prepare_cached("update events set payload = jsonb(?) where rowid = ?")?
+                        .execute(rusqlite::params![new_payload, rowid])?;
+                    updated += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+    async fn import_streams(&mut self, streams: &[ImportedStream]) -> Result<()> {
+        let tx = self.transaction()?;
+        for stream in streams {
+            tx.prepare_cached(
+                "\
+                insert into streams \
+                    (stream_id, headers, start_datetime, end_datetime, event_count, clock_skew_ms, \
+                    stream_uuid) \
+                values (?, jsonb(?), ?, ?, ?, ?, ?) \
+                on conflict (stream_id) do update set \
+                    end_datetime = coalesce(end_datetime, excluded.end_datetime), \
+                    event_count = coalesce(event_count, excluded.event_count), \
+                    clock_skew_ms = coalesce(clock_skew_ms, excluded.clock_skew_ms), \
+                    stream_uuid = coalesce(stream_uuid, excluded.stream_uuid)",
+            )?
+            .execute(rusqlite::params![
+                stream.stream_id,
+                stream.headers.to_string(),
+                text_datetime(stream.start_datetime),
+                stream.end_datetime.map(text_datetime),
+                stream.event_count,
+                stream.clock_skew_ms,
+                stream.stream_uuid,
+            ])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+    async fn import_events(&mut self, events: &[ImportedEvent]) -> Result<u64> {
+        use rusqlite::OptionalExtension;
+        let tx = self.transaction()?;
+        let mut imported = 0;
+        for event in events {
+            let latest: Option<EventRevision> = tx
+                .prepare_cached(
+                    "select revision from events where stream_id = ? and stream_event_index = ?",
+                )?
+                .query_row(
+                    rusqlite::params![event.stream_id, event.stream_event_index],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let insert_datetime = text_datetime(event.insert_datetime);
+            let payload = event.payload.to_string();
+            imported += match latest {
+                None => tx
+                    .prepare_cached(
+                        "\
+                        insert into events \
+                            (stream_id, stream_event_index, insert_datetime, revision, payload, \
+                            event_id, collector, client_datetime) \
+                        values (?, ?, ?, ?, jsonb(?), ?, jsonb(?), ?) \
+                        on conflict do nothing",
+                    )?
+                    .execute(rusqlite::params![
+                        event.stream_id,
+                        event.stream_event_index,
+                        insert_datetime,
+                        event.revision,
+                        payload,
+                        event.event_id,
+                        event.collector.as_ref().map(|value| value.to_string()),
+                        event.client_datetime.map(text_datetime),
+                    ])?,
+                Some(latest) if event.revision > latest => {
+                    tx.prepare_cached(
+                        "\
+                        insert into event_revisions \
+                            (stream_id, stream_event_index, revision, insert_datetime, payload) \
+                        select stream_id, stream_event_index, revision, insert_datetime, payload \
+                        from events where stream_id = ? and stream_event_index = ?",
+                    )?
+                    .execute(rusqlite::params![event.stream_id, event.stream_event_index])?;
+                    tx.prepare_cached(
+                        "\
+                        update events \
+                            set insert_datetime = ?3, revision = ?4, payload = jsonb(?5) \
+                        where stream_id = ?1 and stream_event_index = ?2",
+                    )?
+                    .execute(rusqlite::params![
+                        event.stream_id,
+                        event.stream_event_index,
+                        insert_datetime,
+                        event.revision,
+                        payload,
+                    ])?
+                }
+                Some(_) => 0,
+            } as u64;
+        }
+        tx.commit()?;
+        Ok(imported)
+    }
+    async fn events_after(
+        &mut self,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<Vec<ImportedEvent>> {
+        let columns = "\
+            select stream_id, stream_event_index, insert_datetime, revision, json(payload), \
+                event_id, json(collector), client_datetime \
+            from events";
+        let order = "order by insert_datetime, stream_id, stream_event_index";
+        let cursor = cursor.map(|cursor| {
+            (
+                text_datetime(cursor.insert_datetime),
+                cursor.stream_id,
+                cursor.stream_event_index,
+            )
+        });
+        // Separate statements, so the one with the cursor can use the index on the ordering.
+        let mut stmt = match &cursor {
+            None => self.prepare_cached(&format!("{} {} limit ?", columns, order))?,
+            Some(_) => self.prepare_cached(&format!(
+                "{} where (insert_datetime, stream_id, stream_event_index) > (?, ?, ?) {} limit ?",
+                columns, order
+            ))?,
+        };
+        let read = |row: &rusqlite::Row| {
+            Ok((
+                row.get::<_, StreamId>(0)?,
+                row.get::<_, StreamEventIndex>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, EventRevision>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, Option<String>>(6)?,
+                row.get::<_, Option<String>>(7)?,
+            ))
+        };
+        let rows = match &cursor {
+            None => stmt.query_map(rusqlite::params![limit], read)?,
+            Some((insert_datetime, stream_id, stream_event_index)) => stmt.query_map(
+                rusqlite::params![insert_datetime, stream_id, stream_event_index, limit],
+                read,
+            )?,
+        };
+        let mut events = vec![];
+        for row in rows {
+            let (
+                stream_id,
+                stream_event_index,
+                inserted,
+                revision,
+                payload,
+                event_id,
+                collector,
+                client_datetime,
+            ) = row?;
+            events.push(ImportedEvent {
+                stream_id,
+                stream_event_index,
+                insert_datetime: parse_datetime(&inserted)?,
+                revision,
+                payload: serde_json::from_str(&payload)?,
+                event_id,
+                collector: collector.as_deref().map(serde_json::from_str).transpose()?,
+                client_datetime: client_datetime.as_deref().map(parse_datetime).transpose()?,
+            });
+        }
+        Ok(events)
+    }
+    async fn streams_by_id(&mut self, stream_ids: &[StreamId]) -> Result<Vec<ImportedStream>> {
+        let stream_ids =
+            serde_json::to_string(&stream_ids.iter().map(|id| id.0).collect::<Vec<_>>())?;
+        let mut stmt = self.prepare_cached(
+            "\
+            select stream_id, json(headers), start_datetime, end_datetime, event_count, \
+                clock_skew_ms, stream_uuid \
+            from streams \
+            where stream_id in (select value from json_each(?)) \
+            order by stream_id",
+        )?;
+        let rows = stmt.query_map([stream_ids], |row| {
+            Ok((
+                row.get::<_, StreamId>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<u64>>(4)?,
+                row.get::<_, Option<i64>>(5)?,
+                row.get::<_, Option<StreamUuid>>(6)?,
+            ))
+        })?;
+        let mut streams = vec![];
+        for row in rows {
+            let (
+                stream_id,
+                headers,
+                start_datetime,
+                end_datetime,
+                event_count,
+                clock_skew_ms,
+                stream_uuid,
+            ) = row?;
+            streams.push(ImportedStream {
+                stream_id,
+                headers: headers
+                    .as_deref()
+                    .map(serde_json::from_str)
+                    .transpose()?
+                    .unwrap_or_default(),
+                start_datetime: parse_datetime(&start_datetime)?,
+                end_datetime: end_datetime.as_deref().map(parse_datetime).transpose()?,
+                event_count,
+                clock_skew_ms,
+                stream_uuid,
+            });
+        }
+        Ok(streams)
+    }
+}
+
+#[async_trait]
+impl Connection for duckdb::Connection {
+    async fn new_stream(&mut self, headers_value: SerializedHeaders) -> Result<StreamId> {
+        Ok(self.query_row(
+            "insert into streams (headers, stream_uuid) values (?, cast(? as uuid)) \
+            returning stream_id",
+            duckdb::params![headers_value, StreamUuid::now_v7()],
+            |row| row.get(0),
+        )?)
+    }
+    async fn insert_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        payload: &RawValue,
+        event_id: Option<&str>,
+        collector: Option<&serde_json::Value>,
+        client_datetime: Option<DateTime<Utc>>,
+    ) -> Result<Inserted> {
+        let inserted = self.execute(
+            "\
+            insert into events \
+                (stream_event_index, payload, stream_id, event_id, collector, client_datetime) \
+            values (?, ?, ?, ?, ?, cast(? as timestamp)) \
+            on conflict do nothing",
+            duckdb::params![
+                stream_event_index,
+                payload.get(),
+                stream_id,
+                event_id,
+                collector.map(|value| value.to_string()),
+                client_datetime.map(text_datetime),
+            ],
+        )?;
+        if inserted == 0 {
+            debug!(%stream_id, event_id, "dropped duplicate event");
+            return Ok(Inserted::Duplicate);
+        }
+        Ok(Inserted::Stored)
+    }
+    async fn resume_stream(&mut self, stream_id: StreamId) -> Result<StreamEventIndex> {
+        match self.query_row(
+            "\
+            select coalesce(max(stream_event_index), 0) \
+            from streams left join events using (stream_id) \
+            where streams.stream_id = ? group by streams.stream_id",
+            duckdb::params![stream_id],
+            |row| row.get(0),
+        ) {
+            Err(duckdb::Error::QueryReturnedNoRows) => Err(StreamNotFound(stream_id).into()),
+            result => Ok(result?),
+        }
+    }
+    async fn find_stream(&mut self, stream_uuid: StreamUuid) -> Result<StreamId> {
+        match self.query_row(
+            "select stream_id from streams where stream_uuid = cast(? as uuid)",
+            duckdb::params![stream_uuid],
+            |row| row.get(0),
+        ) {
+            Err(duckdb::Error::QueryReturnedNoRows) => {
+                Err(anyhow!("stream {} not found", stream_uuid))
+            }
+            result => Ok(result?),
+        }
+    }
+    async fn close_stream(&mut self, stream_id: StreamId) -> Result<()> {
+        let updated = self.execute(
+            "\
+            update streams set end_datetime = current_timestamp, \
+                event_count = (select count(*) from events where stream_id = $1) \
+            where stream_id = $1",
+            duckdb::params![stream_id],
+        )?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+    async fn record_clock_skew(
+        &mut self,
+        stream_id: StreamId,
+        clock_skew: chrono::TimeDelta,
+    ) -> Result<()> {
+        let updated = self.execute(
+            "update streams set clock_skew_ms = ? where stream_id = ?",
+            duckdb::params![clock_skew.num_milliseconds(), stream_id],
+        )?;
+        if updated == 0 {
+            bail!("stream {} not found", stream_id);
+        }
+        Ok(())
+    }
+    async fn update_stream_headers(
+        &mut self,
+        stream_id: StreamId,
+        patch: &serde_json::Value,
+    ) -> Result<()> {
+        let tx = self.transaction()?;
+        let headers = match tx.query_row(
+            "select headers from streams where stream_id = ?",
+            duckdb::params![stream_id],
+            |row| row.get::<_, String>(0),
+        ) {
+            Err(duckdb::Error::QueryReturnedNoRows) => bail!("stream {} not found", stream_id),
+            result => result?,
+        };
+        let mut headers: serde_json::Value = serde_json::from_str(&headers)?;
+        merge_patch(&mut headers, patch);
+        tx.execute(
+            "update streams set headers = ? where stream_id = ?",
+            duckdb::params![headers.to_string(), stream_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+    async fn revise_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        revision: EventRevision,
+        payload: &str,
+    ) -> Result<Revised> {
+        let tx = self.transaction()?;
+        let latest = match tx.query_row(
+            "select revision from events where stream_id = $1 and stream_event_index = $2",
+            duckdb::params![stream_id, stream_event_index],
+            |row| row.get(0),
+        ) {
+            Err(duckdb::Error::QueryReturnedNoRows) => return Ok(Revised::NotFound),
+            result => result?,
+        };
+        if revision <= latest {
+            return Ok(Revised::Stale { latest });
+        }
+        tx.execute(
+            "\
+            insert into event_revisions \
+                (stream_id, stream_event_index, revision, insert_timestamp, payload) \
+            select stream_id, stream_event_index, revision, insert_timestamp, payload from events \
+            where stream_id = $1 and stream_event_index = $2",
+            duckdb::params![stream_id, stream_event_index],
+        )?;
+        tx.execute(
+            "\
+            update events set revision = $3, payload = $4, insert_timestamp = current_timestamp \
+            where stream_id = $1 and stream_event_index = $2",
+            duckdb::params![stream_id, stream_event_index, revision, payload],
+        )?;
+        tx.commit()?;
+        Ok(Revised::Superseded)
+    }
+    async fn query_events(&mut self, query: &EventQuery) -> Result<Vec<serde_json::Value>> {
+        select_duckdb_events(self, query)
+    }
+    async fn snapshot(&mut self, query: &EventQuery) -> Result<Snapshot> {
+        let tx = self.transaction()?;
+        let events = select_duckdb_events(&tx, query)?;
+        let streams = select_duckdb_streams(&tx, &events)?;
+        tx.commit()?;
+        Ok(Snapshot { events, streams })
+    }
+    async fn snapshot_batches(
+        &mut self,
+        query: &EventQuery,
+        batch_size: usize,
+        each: &mut (dyn FnMut(Snapshot) -> Result<()> + Send),
+    ) -> Result<()> {
+        let tx = self.transaction()?;
+        for_each_duckdb_page(&tx, query, batch_size, |events| {
+            let streams = select_duckdb_streams(&tx, &events)?;
+            each(Snapshot { events, streams })
+        })?;
+        tx.commit()?;
+        Ok(())
+    }
+    async fn stats(&mut self) -> Result<StorageStats> {
+        Ok(self.query_row(
+            "\
+            select (select count(*) from streams), \
+                (select count(*) from streams where end_datetime is not null), \
+                count(*), cast(coalesce(sum(octet_length(payload)), 0) as bigint), \
+                cast(min(insert_timestamp) as varchar), cast(max(insert_timestamp) as varchar) \
+            from events",
+            [],
+            |row| {
+                Ok(StorageStats {
+                    streams: row.get(0)?,
+                    ended_streams: row.get(1)?,
+                    events: row.get(2)?,
+                    payload_bytes: row.get(3)?,
+                    first_insert_datetime: row.get(4)?,
+                    last_insert_datetime: row.get(5)?,
+                })
+            },
+        )?)
+    }
+    async fn rewrite_events(
+        &mut self,
+        selection: &EventSelection,
+        rewrite: PayloadRewriter<'_>,
+    ) -> Result<u64> {
+        let (since, until) = selection.text_bounds();
+        let tx = self.transaction()?;
+        let mut updated = 0;
+        let mut last_rowid = -1;
+        loop {
+            let batch = tx
+                .prepare_cached(
+                    "\
+                    select rowid, decode(payload) from events \
+                    where rowid > $1 \
+                        and ($2 is null or stream_id = $2) \
+                        and ($3 is null or insert_timestamp >= cast($3 as timestamp)) \
+                        and ($4 is null or insert_timestamp < cast($4 as timestamp)) \
+                    order by rowid limit $5",
+                )?
+                .query_map(
+                    duckdb::params![
+                        last_rowid,
+                        selection.stream_id,
+                        since,
+                        until,
+                        READ_BATCH_SIZE
+                    ],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+                )?
+                .collect::<duckdb::Result<Vec<_>>>()?;
+            let Some(&(batch_last_rowid, _)) = batch.last() else {
+                break;
+            };
+            last_rowid = batch_last_rowid;
+            for (rowid, payload) in batch {
+                if let Some(new_payload) = rewrite(&payload)? {
+                    tx.prepare_cached("update events set payload = $1 where rowid = $2")?
+                        .execute(duckdb::params![new_payload, rowid])?;
+                    updated += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+}
+
+fn select_duckdb_events(
+    conn: &duckdb::Connection,
+    query: &EventQuery,
+) -> Result<Vec<serde_json::Value>> {
+    let mut events = vec![];
+    query.refuse_unsupported()?;
+    for_each_duckdb_page(conn, query, READ_BATCH_SIZE, |page| {
+        events.extend(page);
+        Ok(())
+    })?;
+    Ok(events)
+}
+
+/// Calls `f` with pages of the selected events. Payloads are stored as blobs, so filters are
+/// applied once they're read and parsed, and pages are read by rowid so that the rows filtered
+/// out needn't all be in memory. Pages can be smaller than `page_size`, but aren't empty.
+fn for_each_duckdb_page(
+    conn: &duckdb::Connection,
+    query: &EventQuery,
+    page_size: usize,
+    mut f: impl FnMut(Vec<serde_json::Value>) -> Result<()>,
+) -> Result<()> {
+    query.refuse_unsupported()?;
+    let (since, until) = query.selection.text_bounds();
+    let mut stmt = conn.prepare(
+        "\
+        select rowid, stream_id, stream_event_index, cast(insert_timestamp as varchar), \
+            decode(payload), cast(client_datetime as varchar) \
+        from events \
+        where rowid > $1 \
+            and ($2 is null or stream_id = $2) \
+            and ($3 is null or insert_timestamp >= cast($3 as timestamp)) \
+            and ($4 is null or insert_timestamp < cast($4 as timestamp)) \
+        order by rowid limit $5",
+    )?;
+    let mut remaining = query.limit;
+    let mut last_rowid = -1;
+    while remaining > 0 {
+        let rows = stmt
+            .query_map(
+                duckdb::params![
+                    last_rowid,
+                    query.selection.stream_id,
+                    since,
+                    until,
+                    page_size
+                ],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, String>(4)?,
+                        row.get::<_, Option<String>>(5)?,
+                    ))
+                },
+            )?
+            .collect::<duckdb::Result<Vec<_>>>()?;
+        let Some(&(page_last_rowid, ..)) = rows.last() else {
+            break;
+        };
+        last_rowid = page_last_rowid;
+        let mut events = vec![];
+        for (_, stream_id, stream_event_index, insert_datetime, payload, client_datetime) in rows {
+            let payload: serde_json::Value = serde_json::from_str(&payload)?;
+            if events.len() < remaining && payload_matches(&payload, &query.filters) {
+                events.push(json!({
+                    "stream_id": stream_id,
+                    "stream_event_index": stream_event_index,
+                    "insert_datetime": insert_datetime,
+                    "client_datetime": client_datetime,
+                    "payload": payload,
+                }));
+            }
+        }
+        remaining -= events.len();
+        if !events.is_empty() {
+            f(events)?;
+        }
+    }
+    Ok(())
+}
+
+/// The streams of events returned by [select_duckdb_events], as in a [Snapshot].
+fn select_duckdb_streams(
+    conn: &duckdb::Connection,
+    events: &[serde_json::Value],
+) -> Result<Vec<serde_json::Value>> {
+    let mut stmt = conn.prepare_cached(
+        "\
+        select stream_id, headers, cast(start_timestamp as varchar), \
+            cast(end_datetime as varchar), event_count, clock_skew_ms, cast(stream_uuid as varchar) \
+        from streams where stream_id = ?",
+    )?;
+    event_stream_ids(events)?
+        .into_iter()
+        .map(|stream_id| {
+            let (
+                stream_id,
+                headers,
+                start_datetime,
+                end_datetime,
+                event_count,
+                clock_skew_ms,
+                stream_uuid,
+            ) = stmt.query_row(duckdb::params![stream_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<u64>>(4)?,
+                    row.get::<_, Option<i64>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            })?;
+            // Headers are stored as text.
+            let headers: serde_json::Value = serde_json::from_str(&headers)?;
+            Ok(json!({
+                "stream_id": stream_id,
+                "headers": headers,
+                "start_datetime": start_datetime,
+                "end_datetime": end_datetime,
+                "event_count": event_count,
+                "clock_skew_ms": clock_skew_ms,
+                "stream_uuid": stream_uuid,
+            }))
+        })
+        .collect()
+}
+
+/// Remembers the most recent event IDs seen per stream. The file backend can't look up what it has
+/// already written, so duplicates are only caught within this window.
+#[derive(Default)]
+struct DedupWindow {
+    capacity: usize,
+    order: VecDeque<(StreamId, String)>,
+    seen: HashSet<(StreamId, String)>,
+}
+
+impl DedupWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Returns false if the event ID was already seen for the stream.
+    fn insert(&mut self, stream_id: StreamId, event_id: &str) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        let key = (stream_id, event_id.to_owned());
+        if self.seen.contains(&key) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+        self.order.push_back(key.clone());
+        self.seen.insert(key);
+        true
+    }
+}
+
+/// Open streams [JsonFiles] remembers. Streams that are never closed would otherwise be remembered
+/// forever; past this, the least recently active are forgotten, and take no more events.
+const MAX_JSON_FILES_STREAMS: usize = 100_000;
+
+pub struct JsonFiles {
+    streams: JsonFileWriter,
+    events: JsonFileWriter,
+    // Streams are written when they start, so their ends go in a separate table.
+    stream_ends: JsonFileWriter,
+    dedup: DedupWindow,
+    // Written files aren't read back, so streams can only be resumed while this remembers them.
+    last_stream_event_indexes: HashMap<StreamId, StreamEventIndex>,
+    /// Written on every line for the stream, since its random ID can collide with another
+    /// process's.
+    stream_uuids: HashMap<StreamId, StreamUuid>,
+    stream_event_counts: HashMap<StreamId, u64>,
+    /// Open streams by when they last started or had events, so the least recently active can be
+    /// forgotten past [MAX_JSON_FILES_STREAMS].
+    streams_by_activity: BTreeMap<u64, StreamId>,
+    stream_activity: HashMap<StreamId, u64>,
+    /// Counts up with each start or event, to order [Self::streams_by_activity].
+    activity: u64,
+    compression_stats: CompressionStats,
+}
+
+impl JsonFiles {
+    fn forget_stream(&mut self, stream_id: StreamId) {
+        self.last_stream_event_indexes.remove(&stream_id);
+        self.stream_event_counts.remove(&stream_id);
+        self.compression_stats.forget(stream_id);
+        self.stream_uuids.remove(&stream_id);
+        if let Some(activity) = self.stream_activity.remove(&stream_id) {
+            self.streams_by_activity.remove(&activity);
+        }
+    }
+
+    /// Makes the stream the most recently active, forgetting the least recently active if there
+    /// are too many.
+    fn touch_stream(&mut self, stream_id: StreamId) {
+        self.activity += 1;
+        if let Some(previous) = self.stream_activity.insert(stream_id, self.activity) {
+            self.streams_by_activity.remove(&previous);
+        }
+        self.streams_by_activity.insert(self.activity, stream_id);
+        if self.streams_by_activity.len() > MAX_JSON_FILES_STREAMS {
+            if let Some((_, idlest)) = self.streams_by_activity.pop_first() {
+                debug!(stream_id = %idlest, "forgetting least recently active stream");
+                self.forget_stream(idlest);
+            }
+        }
+    }
+
+    /// Everything in the output directory, decrypting finished files if they're encrypted.
+    fn contents(&self) -> Result<JsonFilesContents> {
+        let options = &self.events.options;
+        JsonFilesContents::read(
+            &options.dir,
+            options.encryption_key.as_ref(),
+            options.dictionary.as_deref(),
+        )
+    }
+
+    /// Appends the event's line for the events files, unless it's a duplicate.
+    fn event_line(
+        &mut self,
+        stream_id: StreamId,
+        event: &NewEvent<'_>,
+        lines: &mut Vec<u8>,
+    ) -> Result<Inserted> {
+        // Its lines would have no UUID to be told apart from other processes' streams by.
+        let Some(stream_uuid) = self.stream_uuids.get(&stream_id).copied() else {
+            bail!("stream {} not known to this process", stream_id);
+        };
+        if let Some(event_id) = event.event_id {
+            if !self.dedup.insert(stream_id, event_id) {
+                debug!(%stream_id, event_id, "dropped duplicate event");
+                return Ok(Inserted::Duplicate);
+            }
+        }
+        self.compression_stats
+            .record(stream_id, event.payload.get().as_bytes());
+        // Each event has to stay on one line.
+        let compacted;
+        let payload = match event.payload.get().contains('\n') {
+            true => {
+                let value: serde_json::Value = serde_json::from_str(event.payload.get())?;
+                compacted = serde_json::value::to_raw_value(&value)?;
+                &*compacted
+            }
+            false => event.payload,
+        };
+        let line_json = EventLine {
+            client_datetime: event.client_datetime.map(|client| client.to_rfc3339()),
+            collector: event.collector,
+            event_id: event.event_id,
+            insert_datetime: json_datetime_now(),
+            payload,
+            stream_event_index: event.stream_event_index,
+            stream_id: stream_id.0,
+            stream_uuid: Some(stream_uuid),
+        };
+        serde_json::to_writer(&mut *lines, &line_json)?;
+        lines.push(b'\n');
+        let last = self.last_stream_event_indexes.entry(stream_id).or_default();
+        *last = event.stream_event_index.max(*last);
+        *self.stream_event_counts.entry(stream_id).or_default() += 1;
+        self.touch_stream(stream_id);
+        Ok(Inserted::Stored)
+    }
+}
+
+/// An event as written to the events files. The payload is copied in as it came, rather than
+/// parsed and written out again. Fields are in the order `json!` would put them.
+#[derive(serde::Serialize)]
+struct EventLine<'a> {
+    client_datetime: Option<String>,
+    collector: Option<&'a serde_json::Value>,
+    event_id: Option<&'a str>,
+    insert_datetime: serde_json::Value,
+    payload: &'a RawValue,
+    stream_event_index: StreamEventIndex,
+    stream_id: u32,
+    stream_uuid: Option<StreamUuid>,
+}
+
+fn json_datetime_now() -> serde_json::Value {
+    json!(Utc::now().to_rfc3339())
+}
+
+/// Whether a file in the JSON files directory is one the writers finished, encrypted or not.
+fn is_json_file_name(name: &str) -> bool {
+    name.ends_with(".json.zst") || name.ends_with(&format!(".json.zst{}", ENCRYPTED_SUFFIX))
+}
+
+/// The JSON value on each line of a file, which is zstd compressed if its name ends in ".zst", and
+/// was encrypted with `key` if it then has [ENCRYPTED_SUFFIX]. Compressed files written with a
+/// dictionary need it given. A file still being written can end in a partial line, which is
+/// skipped.
+pub fn read_json_lines(
+    path: &std::path::Path,
+    key: Option<&EncryptionKey>,
+    dictionary: Option<&[u8]>,
+) -> Result<impl Iterator<Item = Result<serde_json::Value>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let name = path.to_string_lossy();
+    let (name, file): (_, Box<dyn std::io::Read + Send>) = match name.strip_suffix(ENCRYPTED_SUFFIX)
+    {
+        Some(name) => {
+            let key = key.with_context(|| format!("{} is encrypted", path.display()))?;
+            let reader = DecryptingReader::new(key, file)
+                .with_context(|| format!("decrypting {}", path.display()))?;
+            (name, Box::new(reader))
+        }
+        None => (&*name, Box::new(file)),
+    };
+    let mut reader: Box<dyn BufRead + Send> = match (name.ends_with(".zst"), dictionary) {
+        (true, Some(dictionary)) => Box::new(std::io::BufReader::new(
+            zstd::Decoder::with_dictionary(std::io::BufReader::new(file), dictionary)?,
+        )),
+        (true, None) => Box::new(std::io::BufReader::new(zstd::Decoder::new(file)?)),
+        (false, _) => Box::new(std::io::BufReader::new(file)),
+    };
+    let path = path.to_owned();
+    let mut line = vec![];
+    Ok(std::iter::from_fn(move || {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return None,
+            Ok(_) if line.ends_with(b"\n") => {}
+            Ok(_) => return None,
+            Err(err) => {
+                debug!(%err, path = %path.display(), "stopped reading unfinished file");
+                return None;
+            }
+        }
+        Some(serde_json::from_slice(&line).with_context(|| format!("reading {}", path.display())))
+    }))
+}
+
+/// The tables of a JSON files directory, read back from every file in it.
+#[derive(Default)]
+struct JsonFilesContents {
+    /// Stream lines by key, with the end and event count from stream ends merged in.
+    streams: HashMap<JsonFilesStreamKey, serde_json::Map<String, serde_json::Value>>,
+    /// The latest revision of each event, by stream key and stream event index.
+    events: HashMap<(JsonFilesStreamKey, i64), serde_json::Value>,
+}
+
+/// A stream's ID, and its UUID for lines written since streams had them. Random IDs from different
+/// processes can collide, and the UUID tells their streams apart.
+type JsonFilesStreamKey = (i64, Option<String>);
+
+fn json_files_stream_key(line: &serde_json::Value) -> Option<JsonFilesStreamKey> {
+    Some((
+        line.get("stream_id")?.as_i64()?,
+        line.get("stream_uuid")
+            .and_then(|uuid| uuid.as_str())
+            .map(str::to_owned),
+    ))
+}
+
+impl JsonFilesContents {
+    /// Tables are told apart by their lines rather than file names, since the name template can
+    /// leave the table out.
+    fn read(
+        dir: &std::path::Path,
+        key: Option<&EncryptionKey>,
+        dictionary: Option<&[u8]>,
+    ) -> Result<Self> {
+        let mut contents = Self::default();
+        if !dir.exists() {
+            return Ok(contents);
+        }
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !is_json_file_name(&path.to_string_lossy()) {
+                continue;
+            }
+            for value in read_json_lines(&path, key, dictionary)? {
+                contents.insert(value?);
+            }
+        }
+        Ok(contents)
+    }
+
+    fn insert(&mut self, value: serde_json::Value) {
+        let Some(key) = json_files_stream_key(&value) else {
+            return;
+        };
+        let serde_json::Value::Object(mut line) = value else {
+            return;
+        };
+        if line.contains_key("payload") {
+            let Some(index) = line.get("stream_event_index").and_then(|i| i.as_i64()) else {
+                return;
+            };
+            let revision = |event: &serde_json::Value| event["revision"].as_u64().unwrap_or(0);
+            let event = serde_json::Value::Object(line);
+            match self.events.entry((key, index)) {
+                std::collections::hash_map::Entry::Occupied(mut stored) => {
+                    if revision(&event) > revision(stored.get()) {
+                        stored.insert(event);
+                    }
+                }
+                std::collections::hash_map::Entry::Vacant(vacant) => {
+                    vacant.insert(event);
+                }
+            }
+        } else {
+            // Stream starts and ends.
+            line.remove("stream_id");
+            line.remove("stream_uuid");
+            let stream = self.streams.entry(key).or_default();
+            // Labels are set a few at a time, so each line's are merged into those before.
+            if let Some(labels) = line.remove("labels") {
+                merge_patch(stream.entry("labels").or_insert(json!({})), &labels);
+            }
+            stream.extend(line);
+        }
+    }
+
+    /// Selected events in insert order, as returned by [Connection::query_events].
+    fn query(&self, query: &EventQuery) -> Vec<serde_json::Value> {
+        self.query_keyed(query)
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    /// Selected events in insert order, with the keys of their streams.
+    fn query_keyed(&self, query: &EventQuery) -> Vec<(&JsonFilesStreamKey, serde_json::Value)> {
+        let selection = &query.selection;
+        let mut events: Vec<(DateTime<Utc>, &JsonFilesStreamKey, &serde_json::Value)> = self
+            .events
+            .iter()
+            .filter_map(|((key, _), event)| {
+                let inserted = event["insert_datetime"].as_str()?;
+                Some((
+                    DateTime::parse_from_rfc3339(inserted).ok()?.to_utc(),
+                    key,
+                    event,
+                ))
+            })
+            .filter(|(inserted, _, event)| {
+                selection
+                    .stream_id
+                    .is_none_or(|stream_id| event["stream_id"] == stream_id.0)
+                    && selection.since.is_none_or(|since| *inserted >= since)
+                    && selection.until.is_none_or(|until| *inserted < until)
+                    && payload_matches(&event["payload"], &query.filters)
+            })
+            .collect();
+        events.sort_by_key(|(inserted, _, event)| {
+            (
+                *inserted,
+                event["stream_id"].as_i64(),
+                event["stream_event_index"].as_i64(),
+            )
+        });
+        events
+            .into_iter()
+            .take(query.limit)
+            .map(|(_, key, event)| {
+                let event = json!({
+                    "stream_id": event["stream_id"],
+                    "stream_event_index": event["stream_event_index"],
+                    "insert_datetime": event["insert_datetime"],
+                    "client_datetime": event["client_datetime"],
+                    "payload": event["payload"],
+                });
+                (key, event)
+            })
+            .collect()
+    }
+
+    /// A stream as in a [Snapshot]. Streams whose files were pruned have only their ID.
+    fn stream(&self, key: &JsonFilesStreamKey) -> serde_json::Value {
+        let stream = self.streams.get(key);
+        let field = |name: &str| {
+            stream
+                .and_then(|stream| stream.get(name))
+                .cloned()
+                .unwrap_or_default()
+        };
+        json!({
+            "stream_id": key.0,
+            "stream_uuid": key.1,
+            "headers": field("headers"),
+            "start_datetime": field("start_datetime"),
+            "end_datetime": field("end_datetime"),
+            "event_count": field("event_count"),
+            "clock_skew_ms": field("clock_skew_ms"),
+        })
+    }
+
+    fn stats(&self) -> StorageStats {
+        let insert_datetimes = self.events.values().filter_map(|event| {
+            let inserted = event["insert_datetime"].as_str()?;
+            Some((DateTime::parse_from_rfc3339(inserted).ok()?, inserted))
+        });
+        let first = insert_datetimes
+            .clone()
+            .min()
+            .map(|(_, text)| text.to_owned());
+        let last = insert_datetimes.max().map(|(_, text)| text.to_owned());
+        StorageStats {
+            streams: self
+                .streams
+                .values()
+                .filter(|stream| stream.contains_key("start_datetime"))
+                .count() as u64,
+            ended_streams: self
+                .streams
+                .values()
+                .filter(|stream| stream.contains_key("end_datetime"))
+                .count() as u64,
+            events: self.events.len() as u64,
+            payload_bytes: self
+                .events
+                .values()
+                .map(|event| event["payload"].to_string().len() as u64)
+                .sum(),
+            first_insert_datetime: first,
+            last_insert_datetime: last,
+        }
+    }
+}
+
+#[async_trait]
+impl Connection for JsonFiles {
+    async fn new_stream(&mut self, headers: SerializedHeaders) -> Result<StreamId> {
+        // IDs can still collide with other processes', which the stream's UUID tells apart.
+        let stream_id = loop {
+            let stream_id = StreamId(random());
+            if !self.last_stream_event_indexes.contains_key(&stream_id) {
+                break stream_id;
+            }
+        };
+        let stream_uuid = StreamUuid::now_v7();
+        let start_datetime = Utc::now().to_rfc3339();
+        let json_value = json!({
+            "stream_id": stream_id.0,
+            "stream_uuid": stream_uuid,
+            "start_datetime": start_datetime,
+            "headers": headers,
+        });
+        {
+            let mut writer = self.streams.write()?;
+            serde_json::to_writer(&mut writer, &json_value)?;
+            writer.write_all(b"\n")?;
+        }
+        self.last_stream_event_indexes.insert(stream_id, 0);
+        self.stream_uuids.insert(stream_id, stream_uuid);
+        self.touch_stream(stream_id);
+        Ok(stream_id)
+    }
+
+    async fn insert_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        payload: &RawValue,
+        event_id: Option<&str>,
+        collector: Option<&serde_json::Value>,
+        client_datetime: Option<DateTime<Utc>>,
+    ) -> Result<Inserted> {
+        let event = NewEvent {
+            stream_event_index,
+            payload,
+            event_id,
+            collector,
+            client_datetime,
+        };
+        let mut lines = Vec::new();
+        let inserted = self.event_line(stream_id, &event, &mut lines)?;
+        self.events.write()?.write_all(&lines)?;
+        Ok(inserted)
+    }
+
+    async fn insert_events(
+        &mut self,
+        stream_id: StreamId,
+        events: &[NewEvent<'_>],
+    ) -> Result<Vec<Inserted>> {
+        let mut lines = Vec::new();
+        let inserted = events
+            .iter()
+            .map(|event| self.event_line(stream_id, event, &mut lines))
+            .collect::<Result<_>>()?;
+        // One write for the lot, so the batch isn't split across files by rotation.
+        self.events.write()?.write_all(&lines)?;
+        Ok(inserted)
+    }
+
+    async fn resume_stream(&mut self, stream_id: StreamId) -> Result<StreamEventIndex> {
+        self.last_stream_event_indexes
+            .get(&stream_id)
+            .copied()
+            .ok_or(StreamNotFound(stream_id))
+            .context("only streams this process started are known")
+    }
+
+    async fn close_stream(&mut self, stream_id: StreamId) -> Result<()> {
+        // The count only covers events written by this process.
+        let event_count = self
+            .stream_event_counts
+            .get(&stream_id)
+            .copied()
+            .unwrap_or_default();
+        let stream_uuid = self.stream_uuids.get(&stream_id).copied();
+        self.forget_stream(stream_id);
+        let line_json = json!({
+            "stream_id": stream_id.0,
+            "stream_uuid": stream_uuid,
+            "end_datetime": json_datetime_now(),
+            "event_count": event_count,
+        });
+        let mut writer = self.stream_ends.write()?;
+        serde_json::to_writer(&mut writer, &line_json)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Written with the stream ends, which are merged into the stream when the files are read.
+    async fn record_clock_skew(
+        &mut self,
+        stream_id: StreamId,
+        clock_skew: chrono::TimeDelta,
+    ) -> Result<()> {
+        let line_json = json!({
+            "stream_id": stream_id.0,
+            "stream_uuid": self.stream_uuids.get(&stream_id),
+            "clock_skew_ms": clock_skew.num_milliseconds(),
+        });
+        let mut writer = self.stream_ends.write()?;
+        serde_json::to_writer(&mut writer, &line_json)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Written with the stream ends, as labels to merge into the stream's when the files are read.
+    async fn set_stream_labels(
+        &mut self,
+        stream_id: StreamId,
+        labels: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        let line_json = json!({
+            "stream_id": stream_id.0,
+            "stream_uuid": self.stream_uuids.get(&stream_id),
+            "labels": labels,
+        });
+        let mut writer = self.stream_ends.write()?;
+        serde_json::to_writer(&mut writer, &line_json)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Revisions are appended like any other event. The files can't be checked for what's already
+    /// there, so the latest revision is picked when they're read.
+    async fn revise_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        revision: EventRevision,
+        payload: &str,
+    ) -> Result<Revised> {
+        let payload_value: serde_json::Value = serde_json::from_str(payload)?;
+        let line_json = json!({
+            "insert_datetime": json_datetime_now(),
+            "stream_id": stream_id.0,
+            "stream_uuid": self.stream_uuids.get(&stream_id),
+            "stream_event_index": stream_event_index,
+            "revision": revision,
+            "payload": payload_value,
+        });
+        let mut writer = self.events.write()?;
+        serde_json::to_writer(&mut writer, &line_json)?;
+        writer.write_all(b"\n")?;
+        Ok(Revised::Superseded)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.streams.flush()?;
+        self.events.flush()?;
+        self.stream_ends.flush()?;
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.streams.finish_file()?;
+        self.events.finish_file()?;
+        self.stream_ends.finish_file()?;
+        Ok(())
+    }
+
+    /// Files are started when they're first written to, so finishing them is enough.
+    async fn rotate(&mut self) -> Result<()> {
+        self.commit().await
+    }
+
+    fn commit_on_sigint(&self) -> bool {
+        true
+    }
+
+    /// Reads every file, after flushing what this connection has written.
+    async fn query_events(&mut self, query: &EventQuery) -> Result<Vec<serde_json::Value>> {
+        query.refuse_unsupported()?;
+        self.flush().await?;
+        Ok(self.contents()?.query(query))
+    }
+
+    async fn snapshot(&mut self, query: &EventQuery) -> Result<Snapshot> {
+        query.refuse_unsupported()?;
+        self.flush().await?;
+        let contents = self.contents()?;
+        let keyed = contents.query_keyed(query);
+        let streams = keyed
+            .iter()
+            .map(|(key, _)| *key)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|key| contents.stream(key))
+            .collect();
+        let events = keyed.into_iter().map(|(_, event)| event).collect();
+        Ok(Snapshot { events, streams })
+    }
+
+    async fn stats(&mut self) -> Result<StorageStats> {
+        self.flush().await?;
+        Ok(self.contents()?.stats())
+    }
+
+    /// Whole files are deleted, oldest first, skipping the ones still being written and anything
+    /// else in the directory. Event count budgets aren't applied, since files aren't read back to
+    /// count them.
+    async fn prune(&mut self, policy: &RetentionPolicy) -> Result<Pruned> {
+        if policy.max_events.is_some() {
+            warn!("json files don't support pruning by event count");
+        }
+        let open_file_names: Vec<_> = [&self.streams, &self.events, &self.stream_ends]
+            .into_iter()
+            .filter_map(|writer| writer.path()?.file_name())
+            .collect();
+        let mut files = vec![];
+        let dir = &self.events.options.dir;
+        if !dir.exists() {
+            return Ok(Pruned::default());
+        }
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if open_file_names.contains(&entry.file_name().as_os_str())
+                || !is_json_file_name(&entry.file_name().to_string_lossy())
+            {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            files.push((
+                DateTime::<Utc>::from(metadata.modified()?),
+                metadata.len(),
+                path,
+            ));
+        }
+        // Newest first, so the byte budget goes to the most recent files.
+        files.sort_by_key(|(modified, _, _)| std::cmp::Reverse(*modified));
+        let mut pruned = Pruned::default();
+        let mut total_bytes = 0;
+        for (modified, len, path) in files {
+            total_bytes += len;
+            let too_old = policy.before.is_some_and(|before| modified < before);
+            let over_budget = policy.max_bytes.is_some_and(|max| total_bytes > max);
+            if too_old || over_budget {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("removing {}", path.display()))?;
+                pruned.files += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    fn compression_report(&self, limit: usize) -> Option<serde_json::Value> {
+        Some(self.compression_stats.report(limit))
+    }
+}
@@ -0,0 +1,542 @@
+use super::*;
+use std::collections::BTreeMap;
+
+/// Streams and events kept in memory, for tests and demos that shouldn't need a database or
+/// files. Everything is lost when the process exits. Lookups are by scanning, so it's only meant
+/// for small amounts of data.
+#[derive(Default)]
+pub struct Memory {
+    streams: BTreeMap<u32, ImportedStream>,
+    /// Ordered by [EventCursor], which is insert order unless the clock goes backwards.
+    events: Vec<ImportedEvent>,
+    /// Earlier revisions of events, replaced in events by corrections.
+    history: Vec<ImportedEvent>,
+    links: HashMap<String, String>,
+    /// When request body hashes were first received.
+    request_hashes: HashMap<String, DateTime<Utc>>,
+    /// When open streams were marked stale, by stream ID.
+    stale: BTreeMap<u32, DateTime<Utc>>,
+    /// How streams' events were sampled, by stream ID.
+    sampling: BTreeMap<u32, serde_json::Value>,
+    labels: BTreeMap<u32, BTreeMap<String, String>>,
+    anomalies: Vec<StreamAnomaly>,
+}
+
+fn cursor_key(event: &ImportedEvent) -> (DateTime<Utc>, u32, StreamEventIndex) {
+    (
+        event.insert_datetime,
+        event.stream_id.0,
+        event.stream_event_index,
+    )
+}
+
+fn after_key(cursor: &EventCursor) -> (DateTime<Utc>, u32, StreamEventIndex) {
+    (
+        cursor.insert_datetime,
+        cursor.stream_id.0,
+        cursor.stream_event_index,
+    )
+}
+
+impl Memory {
+    /// Keeps events in cursor order, which imports with earlier insert times can be out of.
+    fn insert(&mut self, event: ImportedEvent) {
+        let key = cursor_key(&event);
+        let at = self
+            .events
+            .partition_point(|stored| cursor_key(stored) <= key);
+        self.events.insert(at, event);
+    }
+
+    fn position(&self, stream_id: StreamId, stream_event_index: StreamEventIndex) -> Option<usize> {
+        self.events.iter().position(|event| {
+            event.stream_id == stream_id && event.stream_event_index == stream_event_index
+        })
+    }
+
+    fn stream_json(&self, stream_id: StreamId) -> serde_json::Value {
+        let stream = self.streams.get(&stream_id.0);
+        let events = self
+            .events
+            .iter()
+            .filter(|event| event.stream_id == stream_id);
+        let (mut stored_event_count, mut stored_payload_bytes) = (0, 0);
+        let mut last_insert_datetime = None;
+        for event in events {
+            stored_event_count += 1;
+            stored_payload_bytes += event.payload.to_string().len();
+            last_insert_datetime = last_insert_datetime.max(Some(event.insert_datetime));
+        }
+        json!({
+            "stream_id": stream_id.0,
+            "headers": stream.map(|stream| &stream.headers),
+            "start_datetime": stream.map(|stream| stream.start_datetime.to_rfc3339()),
+            "end_datetime": stream.and_then(|stream| stream.end_datetime).map(|end| end.to_rfc3339()),
+            "event_count": stream.and_then(|stream| stream.event_count),
+            "clock_skew_ms": stream.and_then(|stream| stream.clock_skew_ms),
+            "stale_datetime": self.stale.get(&stream_id.0).map(|stale| stale.to_rfc3339()),
+            "sampling": self.sampling.get(&stream_id.0),
+            "labels": self.labels.get(&stream_id.0).cloned().unwrap_or_default(),
+            "stored_event_count": stored_event_count,
+            "stored_payload_bytes": stored_payload_bytes,
+            "last_insert_datetime": last_insert_datetime.map(|last| last.to_rfc3339()),
+            "stream_uuid": stream.and_then(|stream| stream.stream_uuid),
+        })
+    }
+}
+
+#[async_trait]
+impl Connection for Memory {
+    async fn new_stream(&mut self, headers: SerializedHeaders) -> Result<StreamId> {
+        let stream_id = StreamId(self.streams.keys().next_back().map_or(1, |last| last + 1));
+        self.streams.insert(
+            stream_id.0,
+            ImportedStream {
+                stream_id,
+                headers,
+                start_datetime: Utc::now(),
+                end_datetime: None,
+                event_count: None,
+                clock_skew_ms: None,
+                stream_uuid: Some(StreamUuid::now_v7()),
+            },
+        );
+        Ok(stream_id)
+    }
+
+    async fn insert_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        payload: &RawValue,
+        event_id: Option<&str>,
+        collector: Option<&serde_json::Value>,
+        client_datetime: Option<DateTime<Utc>>,
+    ) -> Result<Inserted> {
+        if !self.streams.contains_key(&stream_id.0) {
+            bail!("stream {} not found", stream_id);
+        }
+        if let Some(event_id) = event_id {
+            let duplicate = self.events.iter().any(|event| {
+                event.stream_id == stream_id && event.event_id.as_deref() == Some(event_id)
+            });
+            if duplicate {
+                debug!(%stream_id, event_id, "dropped duplicate event");
+                return Ok(Inserted::Duplicate);
+            }
+        }
+        self.insert(ImportedEvent {
+            stream_id,
+            stream_event_index,
+            insert_datetime: Utc::now(),
+            revision: 0,
+            payload: serde_json::from_str(payload.get())?,
+            event_id: event_id.map(str::to_owned),
+            collector: collector.cloned(),
+            client_datetime,
+        });
+        Ok(Inserted::Stored)
+    }
+
+    async fn resume_stream(&mut self, stream_id: StreamId) -> Result<StreamEventIndex> {
+        if !self.streams.contains_key(&stream_id.0) {
+            return Err(StreamNotFound(stream_id).into());
+        }
+        Ok(self
+            .events
+            .iter()
+            .filter(|event| event.stream_id == stream_id)
+            .map(|event| event.stream_event_index)
+            .max()
+            .unwrap_or(0))
+    }
+
+    async fn find_stream(&mut self, stream_uuid: StreamUuid) -> Result<StreamId> {
+        self.streams
+            .values()
+            .find(|stream| stream.stream_uuid == Some(stream_uuid))
+            .map(|stream| stream.stream_id)
+            .ok_or_else(|| anyhow!("stream {} not found", stream_uuid))
+    }
+
+    async fn close_stream(&mut self, stream_id: StreamId) -> Result<()> {
+        let event_count = self
+            .events
+            .iter()
+            .filter(|event| event.stream_id == stream_id)
+            .count() as u64;
+        let stream = self
+            .streams
+            .get_mut(&stream_id.0)
+            .ok_or_else(|| anyhow!("stream {} not found", stream_id))?;
+        stream.end_datetime = Some(Utc::now());
+        stream.event_count = Some(event_count);
+        Ok(())
+    }
+
+    async fn record_clock_skew(
+        &mut self,
+        stream_id: StreamId,
+        clock_skew: chrono::TimeDelta,
+    ) -> Result<()> {
+        let stream = self
+            .streams
+            .get_mut(&stream_id.0)
+            .ok_or_else(|| anyhow!("stream {} not found", stream_id))?;
+        stream.clock_skew_ms = Some(clock_skew.num_milliseconds());
+        Ok(())
+    }
+
+    async fn record_sampling(
+        &mut self,
+        stream_id: StreamId,
+        sampling: &serde_json::Value,
+    ) -> Result<()> {
+        if !self.streams.contains_key(&stream_id.0) {
+            bail!("stream {} not found", stream_id);
+        }
+        self.sampling.insert(stream_id.0, sampling.clone());
+        Ok(())
+    }
+
+    async fn update_stream_headers(
+        &mut self,
+        stream_id: StreamId,
+        patch: &serde_json::Value,
+    ) -> Result<()> {
+        let stream = self
+            .streams
+            .get_mut(&stream_id.0)
+            .ok_or_else(|| anyhow!("stream {} not found", stream_id))?;
+        merge_patch(&mut stream.headers, patch);
+        Ok(())
+    }
+
+    async fn set_stream_labels(
+        &mut self,
+        stream_id: StreamId,
+        labels: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        if !self.streams.contains_key(&stream_id.0) {
+            bail!("stream {} not found", stream_id);
+        }
+        let stream_labels = self.labels.entry(stream_id.0).or_default();
+        for (key, value) in labels {
+            match value {
+                Some(value) => stream_labels.insert(key.clone(), value.clone()),
+                None => stream_labels.remove(key),
+            };
+        }
+        Ok(())
+    }
+
+    async fn record_anomaly(&mut self, anomaly: &StreamAnomaly) -> Result<()> {
+        self.anomalies.push(anomaly.clone());
+        Ok(())
+    }
+
+    async fn revise_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        revision: EventRevision,
+        payload: &str,
+    ) -> Result<Revised> {
+        let Some(position) = self.position(stream_id, stream_event_index) else {
+            return Ok(Revised::NotFound);
+        };
+        let latest = self.events[position].revision;
+        if revision <= latest {
+            return Ok(Revised::Stale { latest });
+        }
+        let payload = serde_json::from_str(payload)?;
+        let previous = self.events.remove(position);
+        self.insert(ImportedEvent {
+            insert_datetime: Utc::now(),
+            revision,
+            payload,
+            ..previous.clone()
+        });
+        self.history.push(previous);
+        Ok(Revised::Superseded)
+    }
+
+    async fn query_events(&mut self, query: &EventQuery) -> Result<Vec<serde_json::Value>> {
+        let selection = &query.selection;
+        Ok(self
+            .events
+            .iter()
+            .filter(|event| {
+                selection
+                    .stream_id
+                    .is_none_or(|stream_id| event.stream_id == stream_id)
+                    && selection
+                        .since
+                        .is_none_or(|since| event.insert_datetime >= since)
+                    && selection
+                        .until
+                        .is_none_or(|until| event.insert_datetime < until)
+                    && payload_matches(&event.payload, &query.filters)
+                    && query
+                        .after
+                        .as_ref()
+                        .is_none_or(|after| cursor_key(event) > after_key(after))
+                    && query.search_text().is_none_or(|text| {
+                        full_text::payload_contains(&event.payload.to_string(), text)
+                    })
+            })
+            .take(query.limit)
+            .map(|event| {
+                json!({
+                    "stream_id": event.stream_id.0,
+                    "stream_event_index": event.stream_event_index,
+                    "insert_datetime": event.insert_datetime.to_rfc3339(),
+                    "client_datetime": event.client_datetime.map(|client| client.to_rfc3339()),
+                    "payload": event.payload,
+                })
+            })
+            .collect())
+    }
+
+    /// Nothing else can change the data while this has it borrowed, so a query is a snapshot.
+    async fn snapshot(&mut self, query: &EventQuery) -> Result<Snapshot> {
+        let events = self.query_events(query).await?;
+        let streams = event_stream_ids(&events)?
+            .into_iter()
+            .map(|stream_id| self.stream_json(StreamId(stream_id as u32)))
+            .collect();
+        Ok(Snapshot { events, streams })
+    }
+
+    async fn read_series(&mut self, query: &SeriesQuery) -> Result<Vec<SeriesPoint>> {
+        series::series_of(self.events.iter(), query)
+    }
+
+    async fn recent_streams(
+        &mut self,
+        limit: usize,
+        labels: &[(String, String)],
+    ) -> Result<Vec<serde_json::Value>> {
+        let labelled = |stream_id: u32| {
+            let stream_labels = self.labels.get(&stream_id);
+            labels.iter().all(|(key, value)| {
+                stream_labels.and_then(|stream_labels| stream_labels.get(key)) == Some(value)
+            })
+        };
+        Ok(self
+            .streams
+            .keys()
+            .rev()
+            .filter(|&&stream_id| labelled(stream_id))
+            .take(limit)
+            .map(|&stream_id| self.stream_json(StreamId(stream_id)))
+            .collect())
+    }
+
+    async fn stats(&mut self) -> Result<StorageStats> {
+        Ok(StorageStats {
+            streams: self.streams.len() as u64,
+            ended_streams: self
+                .streams
+                .values()
+                .filter(|stream| stream.end_datetime.is_some())
+                .count() as u64,
+            events: self.events.len() as u64,
+            payload_bytes: self
+                .events
+                .iter()
+                .map(|event| event.payload.to_string().len() as u64)
+                .sum(),
+            first_insert_datetime: self
+                .events
+                .first()
+                .map(|event| event.insert_datetime.to_rfc3339()),
+            last_insert_datetime: self
+                .events
+                .last()
+                .map(|event| event.insert_datetime.to_rfc3339()),
+        })
+    }
+
+    async fn save_link(&mut self, link_id: &str, query: &str) -> Result<()> {
+        self.links
+            .entry(link_id.to_owned())
+            .or_insert_with(|| query.to_owned());
+        Ok(())
+    }
+
+    async fn load_link(&mut self, link_id: &str) -> Result<Option<String>> {
+        Ok(self.links.get(link_id).cloned())
+    }
+
+    async fn find_request_hash(
+        &mut self,
+        hash: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        self.request_hashes.retain(|_, received| *received >= since);
+        Ok(self.request_hashes.get(hash).copied())
+    }
+
+    async fn save_request_hash(&mut self, hash: &str) -> Result<()> {
+        self.request_hashes
+            .entry(hash.to_owned())
+            .or_insert_with(Utc::now);
+        Ok(())
+    }
+
+    async fn prune(&mut self, policy: &RetentionPolicy) -> Result<Pruned> {
+        let before_count = self.events.len();
+        if let Some(before) = policy.before {
+            self.events.retain(|event| event.insert_datetime >= before);
+        }
+        if let Some(max_events) = policy.max_events {
+            let excess = self.events.len().saturating_sub(max_events as usize);
+            self.events.drain(..excess);
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            let mut total = 0;
+            let kept = self
+                .events
+                .iter()
+                .rev()
+                .take_while(|event| {
+                    total += event.payload.to_string().len() as u64;
+                    total <= max_bytes
+                })
+                .count();
+            let excess = self.events.len() - kept;
+            self.events.drain(..excess);
+        }
+        let mut pruned = Pruned {
+            events: (before_count - self.events.len()) as u64,
+            ..Default::default()
+        };
+        let stored: HashSet<(StreamId, StreamEventIndex)> = self
+            .events
+            .iter()
+            .map(|event| (event.stream_id, event.stream_event_index))
+            .collect();
+        self.history
+            .retain(|event| stored.contains(&(event.stream_id, event.stream_event_index)));
+        // Streams that ended with events are only emptied by pruning. Open streams stay, since
+        // their next events still need them.
+        let with_events: HashSet<StreamId> =
+            stored.iter().map(|(stream_id, _)| *stream_id).collect();
+        let streams_before = self.streams.len();
+        let stale = &self.stale;
+        self.streams.retain(|_, stream| {
+            with_events.contains(&stream.stream_id)
+                || (stream.end_datetime.is_none() && !stale.contains_key(&stream.stream_id.0))
+                || !(policy
+                    .before
+                    .is_some_and(|before| stream.start_datetime < before)
+                    || stream.event_count.is_some_and(|count| count > 0))
+        });
+        pruned.streams = (streams_before - self.streams.len()) as u64;
+        let streams = &self.streams;
+        self.stale
+            .retain(|stream_id, _| streams.contains_key(stream_id));
+        Ok(pruned)
+    }
+
+    async fn mark_stale_streams(&mut self, quiet_since: DateTime<Utc>) -> Result<StaleStreams> {
+        let recent: HashSet<StreamId> = self
+            .events
+            .iter()
+            .filter(|event| event.insert_datetime >= quiet_since)
+            .map(|event| event.stream_id)
+            .collect();
+        let mut sweep = StaleStreams::default();
+        for stream in self.streams.values() {
+            if stream.end_datetime.is_some() {
+                continue;
+            }
+            let stream_id = stream.stream_id;
+            if recent.contains(&stream_id) {
+                if self.stale.remove(&stream_id.0).is_some() {
+                    sweep.revived += 1;
+                }
+            } else if stream.start_datetime < quiet_since && !self.stale.contains_key(&stream_id.0)
+            {
+                self.stale.insert(stream_id.0, Utc::now());
+                sweep.marked += 1;
+            }
+        }
+        Ok(sweep)
+    }
+
+    async fn events_after(
+        &mut self,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<Vec<ImportedEvent>> {
+        let start = match cursor {
+            Some(cursor) => {
+                let key = after_key(cursor);
+                self.events
+                    .partition_point(|event| cursor_key(event) <= key)
+            }
+            None => 0,
+        };
+        Ok(self.events[start..].iter().take(limit).cloned().collect())
+    }
+
+    async fn streams_by_id(&mut self, stream_ids: &[StreamId]) -> Result<Vec<ImportedStream>> {
+        Ok(stream_ids
+            .iter()
+            .filter_map(|stream_id| self.streams.get(&stream_id.0).cloned())
+            .collect())
+    }
+
+    async fn import_streams(&mut self, streams: &[ImportedStream]) -> Result<()> {
+        for stream in streams {
+            let stored = self
+                .streams
+                .entry(stream.stream_id.0)
+                .or_insert_with(|| stream.clone());
+            stored.end_datetime = stored.end_datetime.or(stream.end_datetime);
+            stored.event_count = stored.event_count.or(stream.event_count);
+            stored.stream_uuid = stored.stream_uuid.or(stream.stream_uuid);
+        }
+        Ok(())
+    }
+
+    async fn import_events(&mut self, events: &[ImportedEvent]) -> Result<u64> {
+        // Checked first, so a missing stream stores none of the events.
+        if let Some(event) = events
+            .iter()
+            .find(|event| !self.streams.contains_key(&event.stream_id.0))
+        {
+            bail!("stream {} not found", event.stream_id);
+        }
+        let mut imported = 0;
+        for event in events {
+            match self.position(event.stream_id, event.stream_event_index) {
+                None => {
+                    let duplicate = event.event_id.is_some()
+                        && self.events.iter().any(|stored| {
+                            stored.stream_id == event.stream_id && stored.event_id == event.event_id
+                        });
+                    if duplicate {
+                        continue;
+                    }
+                    self.insert(event.clone());
+                }
+                Some(position) if event.revision > self.events[position].revision => {
+                    let previous = self.events.remove(position);
+                    self.insert(ImportedEvent {
+                        insert_datetime: event.insert_datetime,
+                        revision: event.revision,
+                        payload: event.payload.clone(),
+                        ..previous.clone()
+                    });
+                    self.history.push(previous);
+                }
+                Some(_) => continue,
+            }
+            imported += 1;
+        }
+        Ok(imported)
+    }
+}
@@ -0,0 +1,71 @@
+use super::*;
+
+/// A metric's value at a time, stored in the metric_points table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricPoint {
+    pub name: String,
+    /// What reported the metric, like a service and host.
+    pub resource: serde_json::Value,
+    /// What the point is of, within the metric.
+    pub attributes: serde_json::Value,
+    pub datetime: DateTime<Utc>,
+    pub value: f64,
+}
+
+/// SQLite's text datetime format, with the fraction of a second that metrics are often reported
+/// at.
+fn sqlite_datetime(datetime: DateTime<Utc>) -> String {
+    datetime.format("%Y-%m-%d %H:%M:%S%.f").to_string()
+}
+
+pub(crate) fn insert_metric_points_sqlite(
+    conn: &mut rusqlite::Connection,
+    points: &[MetricPoint],
+) -> Result<u64> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "insert into metric_points(name, resource, attributes, datetime, value) \
+            values (?, jsonb(?), jsonb(?), ?, ?)",
+        )?;
+        for point in points {
+            stmt.execute(rusqlite::params![
+                point.name,
+                point.resource,
+                point.attributes,
+                sqlite_datetime(point.datetime),
+                point.value,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(points.len() as u64)
+}
+
+pub(crate) async fn insert_metric_points_postgres(
+    client: &mut Client,
+    points: &[MetricPoint],
+) -> Result<u64> {
+    let tx = client.transaction().await?;
+    let stmt = tx
+        .prepare(
+            "INSERT INTO metric_points(name, resource, attributes, datetime, value) \
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .await?;
+    for point in points {
+        tx.execute(
+            &stmt,
+            &[
+                &point.name,
+                &point.resource,
+                &point.attributes,
+                &point.datetime.naive_utc(),
+                &point.value,
+            ],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(points.len() as u64)
+}
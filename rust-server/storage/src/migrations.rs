@@ -0,0 +1,235 @@
+use super::*;
+use tokio_postgres::{Client, GenericClient};
+
+/// A schema change. Each is applied once, in order.
+pub(super) struct Migration {
+    /// Identifies the migration once it's applied, so it must never change.
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Upgrades from each past SQLite schema version to the next, where user_version 1 is the first
+/// schema. The embedded schema is the latest version, so new databases don't need any of these.
+pub(super) const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "sqlite-event-id",
+        sql: include_str!("../../sql/sqlite-event-id.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-event-index",
+        sql: include_str!("../../sql/sqlite-stream-event-index.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-end",
+        sql: include_str!("../../sql/sqlite-stream-end.sql"),
+    },
+    Migration {
+        name: "sqlite-collector",
+        sql: include_str!("../../sql/sqlite-collector.sql"),
+    },
+    Migration {
+        name: "sqlite-event-revisions",
+        sql: include_str!("../../sql/sqlite-event-revisions.sql"),
+    },
+    Migration {
+        name: "sqlite-audit-log",
+        sql: include_str!("../../sql/sqlite-audit-log.sql"),
+    },
+    Migration {
+        name: "sqlite-links",
+        sql: include_str!("../../sql/sqlite-links.sql"),
+    },
+    Migration {
+        name: "sqlite-events-insert-order",
+        sql: include_str!("../../sql/sqlite-events-insert-order.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-stale",
+        sql: include_str!("../../sql/sqlite-stream-stale.sql"),
+    },
+    Migration {
+        name: "sqlite-client-datetime",
+        sql: include_str!("../../sql/sqlite-client-datetime.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-anomalies",
+        sql: include_str!("../../sql/sqlite-stream-anomalies.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-sampling",
+        sql: include_str!("../../sql/sqlite-stream-sampling.sql"),
+    },
+    Migration {
+        name: "sqlite-rollups",
+        sql: include_str!("../../sql/sqlite-rollups.sql"),
+    },
+    Migration {
+        name: "sqlite-metric-points",
+        sql: include_str!("../../sql/sqlite-metric-points.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-labels",
+        sql: include_str!("../../sql/sqlite-stream-labels.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-stats",
+        sql: include_str!("../../sql/sqlite-stream-stats.sql"),
+    },
+    Migration {
+        name: "sqlite-stream-uuid",
+        sql: include_str!("../../sql/sqlite-stream-uuid.sql"),
+    },
+    Migration {
+        name: "sqlite-request-hashes",
+        sql: include_str!("../../sql/sqlite-request-hashes.sql"),
+    },
+];
+
+/// The user_version of a SQLite database with every migration applied.
+pub(super) const SQLITE_VERSION: usize = SQLITE_MIGRATIONS.len() + 1;
+
+/// Postgres migrations, recorded by name in the schema_migrations table as they're applied.
+pub(super) const POSTGRES_MIGRATIONS: &[Migration] = &[
+    // The schema that was replayed on every start before there were migrations. It only creates
+    // what's missing, so it's safe over databases that already have it.
+    Migration {
+        name: "0001-baseline",
+        sql: include_str!("../../sql/postgres.sql"),
+    },
+    Migration {
+        name: "0002-events-insert-order",
+        sql: include_str!("../../sql/postgres-events-insert-order.sql"),
+    },
+    Migration {
+        name: "0003-stream-stale",
+        sql: include_str!("../../sql/postgres-stream-stale.sql"),
+    },
+    Migration {
+        name: "0004-client-datetime",
+        sql: include_str!("../../sql/postgres-client-datetime.sql"),
+    },
+    Migration {
+        name: "0005-stream-anomalies",
+        sql: include_str!("../../sql/postgres-stream-anomalies.sql"),
+    },
+    Migration {
+        name: "0006-stream-sampling",
+        sql: include_str!("../../sql/postgres-stream-sampling.sql"),
+    },
+    Migration {
+        name: "0007-rollups",
+        sql: include_str!("../../sql/postgres-rollups.sql"),
+    },
+    Migration {
+        name: "0008-metric-points",
+        sql: include_str!("../../sql/postgres-metric-points.sql"),
+    },
+    Migration {
+        name: "0009-stream-labels",
+        sql: include_str!("../../sql/postgres-stream-labels.sql"),
+    },
+    Migration {
+        name: "0010-stream-stats",
+        sql: include_str!("../../sql/postgres-stream-stats.sql"),
+    },
+    Migration {
+        name: "0011-stream-uuid",
+        sql: include_str!("../../sql/postgres-stream-uuid.sql"),
+    },
+    Migration {
+        name: "0012-request-hashes",
+        sql: include_str!("../../sql/postgres-request-hashes.sql"),
+    },
+];
+
+/// Serializes Postgres migrations between servers starting at the same time.
+const POSTGRES_MIGRATION_LOCK: i64 = 0x7465_6c65_6d65_7472;
+
+/// Brings a SQLite database from `user_version` up to [SQLITE_VERSION] one version at a time.
+pub(super) fn migrate_sqlite(tx: &rusqlite::Transaction, user_version: usize) -> Result<()> {
+    if user_version > SQLITE_VERSION {
+        bail!(
+            "database schema version {} is newer than this server's ({})",
+            user_version,
+            SQLITE_VERSION
+        );
+    }
+    for (index, migration) in SQLITE_MIGRATIONS.iter().enumerate().skip(user_version - 1) {
+        info!(migration.name, "applying sqlite migration");
+        tx.execute_batch(migration.sql)
+            .with_context(|| format!("applying migration {}", migration.name))?;
+        tx.pragma_update(None, "user_version", index + 2)?;
+    }
+    Ok(())
+}
+
+/// Applies the migrations a Postgres database doesn't have yet, in one transaction.
+pub(super) async fn migrate_postgres(client: &mut Client) -> Result<()> {
+    let tx = client.transaction().await?;
+    tx.execute(
+        "SELECT pg_advisory_xact_lock($1)",
+        &[&POSTGRES_MIGRATION_LOCK],
+    )
+    .await?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations(
+          name TEXT PRIMARY KEY,
+          applied_datetime TIMESTAMP NOT NULL)",
+    )
+    .await?;
+    let applied = applied_postgres_migrations(&tx).await?;
+    for migration in POSTGRES_MIGRATIONS {
+        if applied.contains(migration.name) {
+            continue;
+        }
+        info!(migration.name, "applying postgres migration");
+        tx.batch_execute(migration.sql)
+            .await
+            .with_context(|| format!("applying migration {}", migration.name))?;
+        tx.execute(
+            "INSERT INTO schema_migrations (name, applied_datetime) VALUES ($1, NOW())",
+            &[&migration.name],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The names of built-in migrations the database hasn't had.
+pub(super) async fn pending_postgres_migrations(client: &Client) -> Result<Vec<&'static str>> {
+    let has_table: bool = client
+        .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+        .await?
+        .get(0);
+    let applied = match has_table {
+        true => applied_postgres_migrations(client).await?,
+        false => Default::default(),
+    };
+    Ok(POSTGRES_MIGRATIONS
+        .iter()
+        .map(|migration| migration.name)
+        .filter(|name| !applied.contains(*name))
+        .collect())
+}
+
+/// The migrations the database has had. Refuses databases with migrations from a newer server.
+async fn applied_postgres_migrations(client: &impl GenericClient) -> Result<HashSet<String>> {
+    let applied: HashSet<String> = client
+        .query("SELECT name FROM schema_migrations", &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let unknown: Vec<&String> = applied
+        .iter()
+        .filter(|name| !POSTGRES_MIGRATIONS.iter().any(|m| m.name == *name))
+        .collect();
+    if !unknown.is_empty() {
+        bail!(
+            "database has migrations this server doesn't know, it may be newer: {:?}",
+            unknown
+        );
+    }
+    Ok(applied)
+}
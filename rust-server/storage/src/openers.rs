@@ -0,0 +1,872 @@
+use super::migrations::*;
+use super::*;
+use crate::postgres_tls::{take_tls_params, SslMode, TlsParams};
+use crate::secrets::read_secret;
+use crate::{JsonFiles, Postgres};
+use rusqlite::OptionalExtension;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_postgres::NoTls;
+use tracing::{debug, error, warn};
+
+#[derive(Clone, clap::Args)]
+struct LocalStorageArgs {
+    #[arg(long)]
+    schema_path: Option<String>,
+    // I'd rather have a default for duck DB and sqlite that are exposed here, but LocalStorageArgs
+    // is shared by them.
+    #[arg(long)]
+    db_path: Option<PathBuf>,
+    /// Open databases whose recorded schema doesn't match --schema-path, and apply built-in
+    /// upgrades to databases created from a custom schema.
+    #[arg(long)]
+    allow_schema_mismatch: bool,
+}
+
+impl LocalStorageArgs {
+    fn open_schema_path_or_embedded(&self, embedded: &str) -> Result<String> {
+        if let Some(schema_path) = &self.schema_path {
+            Ok(fs::read_to_string(schema_path)?)
+        } else {
+            Ok(embedded.to_owned())
+        }
+    }
+}
+
+/// The schema SQLite databases are created with, and what to do about existing databases created
+/// with another.
+#[derive(Clone)]
+pub(super) struct SqliteSchema {
+    contents: String,
+    /// SHA-256 of a --schema-path schema, recorded in databases created from it. None for the
+    /// embedded schema.
+    custom_fingerprint: Option<String>,
+    allow_mismatch: bool,
+    extract: ExtractArgs,
+    indexes: SqliteIndexArgs,
+    full_text: FullTextArgs,
+}
+
+impl SqliteSchema {
+    fn new(
+        args: &LocalStorageArgs,
+        extract: &ExtractArgs,
+        indexes: &SqliteIndexArgs,
+        full_text: &FullTextArgs,
+    ) -> Result<Self> {
+        let contents = args.open_schema_path_or_embedded(include_str!("../../sql/sqlite.sql"))?;
+        let custom_fingerprint = args.schema_path.as_ref().map(|_| {
+            Sha256::digest(contents.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        });
+        Ok(Self {
+            contents,
+            custom_fingerprint,
+            allow_mismatch: args.allow_schema_mismatch,
+            extract: extract.clone(),
+            indexes: indexes.clone(),
+            full_text: full_text.clone(),
+        })
+    }
+
+    /// Refuses to upgrade a database whose schema the built-in upgrades weren't written for,
+    /// unless mismatches are allowed.
+    fn check_upgrade(&self, tx: &rusqlite::Transaction, user_version: usize) -> Result<()> {
+        let has_custom_schema: bool = tx.query_row(
+            "select exists(select 1 from sqlite_master where type = 'table' and name = 'custom_schema')",
+            [],
+            |row| row.get(0),
+        )?;
+        let recorded: Option<String> = match has_custom_schema {
+            true => tx
+                .query_row("select fingerprint from custom_schema", [], |row| {
+                    row.get(0)
+                })
+                .optional()?,
+            false => None,
+        };
+        let upgrades_pending = user_version < SQLITE_VERSION;
+        let problem = match (&recorded, &self.custom_fingerprint) {
+            (Some(recorded), Some(custom)) if recorded != custom => {
+                "was created from a different --schema-path"
+            }
+            (Some(_), None) => "was created from a custom --schema-path, not the built-in schema",
+            (None, Some(_)) => "was created from the built-in schema, not --schema-path",
+            (Some(_), Some(_)) if upgrades_pending => {
+                "has a custom schema that the built-in upgrades weren't written for"
+            }
+            _ => return Ok(()),
+        };
+        if !self.allow_mismatch {
+            bail!(
+                "database {} (schema version {}, this server's is {}); \
+                pass --allow-schema-mismatch to open it anyway",
+                problem,
+                user_version,
+                SQLITE_VERSION
+            );
+        }
+        warn!(problem, "opening database with mismatched schema");
+        Ok(())
+    }
+
+    /// Records which custom schema the database now matches.
+    fn record(&self, tx: &rusqlite::Transaction) -> Result<()> {
+        let Some(fingerprint) = &self.custom_fingerprint else {
+            return Ok(());
+        };
+        tx.execute_batch(
+            "create table if not exists custom_schema(fingerprint text not null) strict;
+            delete from custom_schema;",
+        )?;
+        tx.execute("insert into custom_schema values (?)", [fingerprint])?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, clap::Args)]
+pub struct SqliteOpen {
+    #[command(flatten)]
+    args: LocalStorageArgs,
+    /// Once the database file is bigger than this many bytes, it's renamed with a timestamp suffix
+    /// and a fresh one started.
+    #[arg(long)]
+    rotate_size: Option<u64>,
+    /// Encrypts the database with SQLCipher, which needs the server built with the sqlcipher
+    /// feature.
+    #[command(flatten)]
+    encryption: EncryptionArgs,
+    #[command(flatten)]
+    extract: ExtractArgs,
+    #[command(flatten)]
+    indexes: SqliteIndexArgs,
+    #[command(flatten)]
+    full_text: FullTextArgs,
+    #[command(flatten)]
+    durability: DurabilityArgs,
+}
+
+#[async_trait]
+impl StorageOpen for SqliteOpen {
+    type Conn = RotatingSqlite;
+
+    async fn open(&self) -> Result<Self::Conn> {
+        let db_path = self.db_path();
+        let schema = SqliteSchema::new(&self.args, &self.extract, &self.indexes, &self.full_text)?;
+        let key = self.encryption.key()?;
+        let conn = RotatingSqlite::open_file(&db_path, &schema, key.as_ref(), &self.durability)?;
+        let fsync = match self.durability.durability {
+            Some(Durability::FsyncInterval) => {
+                self.durability.fsync_schedule(Durability::FsyncInterval)
+            }
+            _ => FsyncSchedule::never(),
+        };
+        Ok(RotatingSqlite {
+            conn,
+            path: db_path,
+            schema,
+            key,
+            max_bytes: self.rotate_size,
+            durability: self.durability.clone(),
+            fsync,
+            blocked: Default::default(),
+            in_batch: false,
+            request_hashes_since: None,
+        })
+    }
+
+    async fn open_read_only(&self) -> Result<Self::Conn> {
+        let db_path = self.db_path();
+        let schema = SqliteSchema::new(&self.args, &self.extract, &self.indexes, &self.full_text)?;
+        let key = self.encryption.key()?;
+        let conn = open_sqlite_read_only(&db_path, key.as_ref())?;
+        Ok(RotatingSqlite {
+            conn,
+            path: db_path,
+            schema,
+            key,
+            max_bytes: None,
+            durability: self.durability.clone(),
+            fsync: FsyncSchedule::never(),
+            blocked: Default::default(),
+            in_batch: false,
+            request_hashes_since: None,
+        })
+    }
+
+    async fn check(&self) -> Result<()> {
+        let db_path = self.db_path();
+        let key = self.encryption.key()?;
+        if !db_path.exists() {
+            return check_writable_dir(&db_path);
+        }
+        let schema = SqliteSchema::new(&self.args, &self.extract, &self.indexes, &self.full_text)?;
+        let mut conn = rusqlite::Connection::open(&db_path)?;
+        if let Some(key) = &key {
+            encryption::key_sqlite(&conn, key)?;
+        }
+        let tx = conn.transaction()?;
+        let user_version: usize = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if user_version != SQLITE_VERSION {
+            bail!(
+                "database schema version {} isn't this server's ({})",
+                user_version,
+                SQLITE_VERSION
+            );
+        }
+        schema.check_upgrade(&tx, user_version)?;
+        // Rolled back when the transaction drops.
+        tx.execute_batch("create table telemetry_check(x); drop table telemetry_check;")
+            .context("database isn't writable")?;
+        Ok(())
+    }
+
+    fn info(&self) -> serde_json::Value {
+        json!({
+            "backend": "sqlite",
+            "db_path": self.db_path(),
+            "custom_schema": self.args.schema_path.is_some(),
+            "rotate_size": self.rotate_size,
+            "encryption_key": self.encryption.info(),
+            "extracted_fields": self.extract.to_json(),
+            "json_indexes": self.indexes.to_json(),
+            "full_text_search": self.full_text.to_json(),
+            "durability": self.durability.info("each event committed"),
+        })
+    }
+
+    /// Rotating renames the file out from under other connections.
+    fn max_connections(&self) -> usize {
+        match self.rotate_size {
+            Some(_) => 1,
+            None => usize::MAX,
+        }
+    }
+}
+
+impl SqliteOpen {
+    pub fn extract_mut(&mut self) -> &mut ExtractArgs {
+        &mut self.extract
+    }
+
+    fn db_path(&self) -> PathBuf {
+        self.args
+            .db_path
+            .clone()
+            .unwrap_or_else(|| "telemetry.sqlite.db".to_owned().into())
+    }
+}
+
+/// Checks that a file could be created at the path, by creating a temporary file beside it.
+fn check_writable_dir(path: &std::path::Path) -> Result<()> {
+    let mut dir = path.parent().unwrap_or(path);
+    // Directories that don't exist yet are created in the nearest one that does.
+    while !dir.as_os_str().is_empty() && !dir.exists() {
+        dir = dir.parent().unwrap_or(std::path::Path::new(""));
+    }
+    if dir.as_os_str().is_empty() {
+        dir = std::path::Path::new(".");
+    }
+    tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("{} isn't writable", dir.display()))?;
+    Ok(())
+}
+
+/// How long SQLite waits for another connection to let go of the database before giving up.
+const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens the database file, creating or upgrading its schema as needed.
+pub(super) fn open_sqlite(
+    db_path: &std::path::Path,
+    schema: &SqliteSchema,
+    key: Option<&EncryptionKey>,
+    durability: &DurabilityArgs,
+) -> Result<rusqlite::Connection> {
+    let mut conn = rusqlite::Connection::open(db_path)?;
+    if let Some(key) = key {
+        encryption::key_sqlite(&conn, key)?;
+    }
+    // Other connections to the database can hold its lock for a while.
+    conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
+    if let Some(synchronous) = durability.sqlite_synchronous() {
+        conn.pragma_update(None, "synchronous", synchronous)?;
+    }
+    conn.pragma_update(None, "foreign_keys", "on")?;
+    if !conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))? {
+        warn!("foreign keys not enabled");
+    }
+    let tx = conn.transaction()?;
+    let user_version: usize = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if user_version == 0 {
+        tx.execute_batch(&schema.contents)?;
+    } else {
+        schema.check_upgrade(&tx, user_version)?;
+        migrate_sqlite(&tx, user_version)?;
+    }
+    tx.pragma_update(None, "user_version", SQLITE_VERSION)?;
+    schema.record(&tx)?;
+    schema.extract.apply_sqlite(&tx)?;
+    schema.indexes.apply(&tx)?;
+    schema.full_text.apply_sqlite(&tx)?;
+    tx.commit()?;
+    Ok(conn)
+}
+
+/// Opens an existing database file without creating, upgrading or writing to it.
+fn open_sqlite_read_only(
+    db_path: &std::path::Path,
+    key: Option<&EncryptionKey>,
+) -> Result<rusqlite::Connection> {
+    let flags =
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let conn = rusqlite::Connection::open_with_flags(db_path, flags)
+        .with_context(|| format!("opening {}", db_path.display()))?;
+    if let Some(key) = key {
+        encryption::key_sqlite(&conn, key)?;
+    }
+    conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
+    let user_version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if user_version != SQLITE_VERSION {
+        bail!(
+            "database schema version {} isn't this server's ({}), and read-only storage isn't \
+            upgraded",
+            user_version,
+            SQLITE_VERSION
+        );
+    }
+    Ok(conn)
+}
+
+#[derive(Clone, clap::Args)]
+pub struct DuckDbOpen {
+    #[command(flatten)]
+    args: LocalStorageArgs,
+}
+
+#[async_trait]
+impl StorageOpen for DuckDbOpen {
+    type Conn = duckdb::Connection;
+
+    async fn open(&self) -> Result<Self::Conn> {
+        let db_path = self.db_path();
+        let schema_contents = self
+            .args
+            .open_schema_path_or_embedded(include_str!("../../sql/duckdb.sql"))?;
+        let mut conn = duckdb::Connection::open(db_path)?;
+        let tx = conn.transaction()?;
+        if let Err(err) = tx.execute_batch(&schema_contents) {
+            warn!(%err, "initing duckdb schema (haven't figured out user_version yet)");
+        }
+        tx.commit()?;
+        // Added since, so databases created before have it too.
+        conn.execute_batch("alter table streams add column if not exists stream_uuid uuid")?;
+        Ok(conn)
+    }
+
+    async fn open_read_only(&self) -> Result<Self::Conn> {
+        let db_path = self.db_path();
+        let config = duckdb::Config::default().access_mode(duckdb::AccessMode::ReadOnly)?;
+        duckdb::Connection::open_with_flags(&db_path, config)
+            .with_context(|| format!("opening {}", db_path.display()))
+    }
+
+    async fn check(&self) -> Result<()> {
+        let db_path = self.db_path();
+        if !db_path.exists() {
+            return check_writable_dir(&db_path);
+        }
+        // There's no schema version to compare.
+        let mut conn = duckdb::Connection::open(db_path)?;
+        let tx = conn.transaction()?;
+        tx.execute_batch("create table telemetry_check(x integer); drop table telemetry_check;")
+            .context("database isn't writable")?;
+        Ok(())
+    }
+
+    fn info(&self) -> serde_json::Value {
+        json!({
+            "backend": "duckdb",
+            "db_path": self.db_path(),
+            "custom_schema": self.args.schema_path.is_some(),
+            "durability": "each event committed",
+        })
+    }
+}
+
+impl DuckDbOpen {
+    fn db_path(&self) -> PathBuf {
+        self.args
+            .db_path
+            .clone()
+            .unwrap_or_else(|| "duck.db".into())
+    }
+}
+
+/// Keeps everything in memory until the process exits. There's nothing to configure.
+#[derive(Clone, clap::Args)]
+pub struct MemoryOpen {}
+
+#[async_trait]
+impl StorageOpen for MemoryOpen {
+    type Conn = Memory;
+
+    async fn open(&self) -> Result<Self::Conn> {
+        Ok(Memory::default())
+    }
+
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn info(&self) -> serde_json::Value {
+        json!({
+            "backend": "memory",
+            "durability": "none, everything is lost on exit",
+        })
+    }
+}
+
+/// Storage options, usually parsed from the command line, that can open a [Connection] to it.
+#[async_trait]
+pub trait StorageOpen: Send + Sync {
+    type Conn: Connection + 'static;
+    async fn open(&self) -> Result<Self::Conn>;
+    /// Checks that the storage can be opened and written to, and has the schema this server
+    /// expects, without changing it.
+    async fn check(&self) -> Result<()>;
+    /// What the storage is and how durable its writes are, for logging. Leaves out secrets.
+    fn info(&self) -> serde_json::Value;
+    /// How many connections can be open to the storage and used at once. Storage keeping state in
+    /// the process, like the files being written, can only have one.
+    fn max_connections(&self) -> usize {
+        1
+    }
+    /// Opens the storage as a trait object, so callers needn't know which backend it is.
+    async fn open_boxed(&self) -> Result<Box<dyn Connection + Send>> {
+        Ok(Box::new(self.open().await?))
+    }
+    /// Opens existing storage for querying without writing to it, like a snapshot of a
+    /// production database. Its schema is left as it is, so it has to be this server's already.
+    async fn open_read_only(&self) -> Result<Self::Conn> {
+        Err(anyhow!("read-only mode is not supported by this storage"))
+    }
+    async fn open_read_only_boxed(&self) -> Result<Box<dyn Connection + Send>> {
+        Ok(Box::new(self.open_read_only().await?))
+    }
+}
+
+#[derive(Clone, clap::Args)]
+pub struct JsonFilesOpen {
+    /// How many recent event IDs to remember for dropping duplicate events. 0 disables it.
+    #[arg(long, default_value_t = 10000)]
+    dedup_window: usize,
+    /// Start new files on wall-clock boundaries of this period, like "1h" or "1day".
+    #[arg(long, value_parser = humantime::parse_duration)]
+    rotate_interval: Option<Duration>,
+    /// Start a new file once the current one has this many compressed bytes.
+    #[arg(long)]
+    rotate_size: Option<u64>,
+    /// Where files are written. Created if it doesn't exist.
+    #[arg(long, default_value = "json_files")]
+    output_dir: PathBuf,
+    /// File names, with {table}, {date} (when the file was opened, in UTC) and {hostname}
+    /// substituted. A random part and ".json.zst" are appended.
+    #[arg(long, default_value = "{table}.file.{date}")]
+    file_name_template: String,
+    /// Zstd compression level. 0 is zstd's default, 3. Telemetry is repetitive enough that higher
+    /// levels usually pay for themselves.
+    #[arg(long, default_value_t = 0)]
+    compression_level: i32,
+    /// Has zstd look much further back for repeats, with a 128MiB window, for events that repeat
+    /// each other from far apart. Costs the writer memory, but readers need nothing extra.
+    #[arg(long)]
+    compression_long: bool,
+    /// Zstd dictionary to compress with, as written by the zstd-train subcommand. Files written
+    /// with a dictionary need the same one to be read.
+    #[arg(long)]
+    compression_dictionary: Option<PathBuf>,
+    /// Worker threads to compress with, besides the thread writing events. 0 compresses on the
+    /// writing thread, which caps throughput at what one core can compress.
+    #[arg(long, default_value_t = 0)]
+    compression_threads: u32,
+    /// Shell command to run when a file is finished, with the file's path as $1 and its table as
+    /// $2.
+    #[arg(long)]
+    file_closed_command: Option<String>,
+    /// URL to POST the finished file's path and table to, as JSON.
+    #[arg(long)]
+    file_closed_webhook: Option<reqwest::Url>,
+    /// Encrypts finished files with AES-256-GCM, adding ".enc" to their names. The file being
+    /// written isn't encrypted until it's finished.
+    #[command(flatten)]
+    encryption: EncryptionArgs,
+    #[command(flatten)]
+    durability: DurabilityArgs,
+}
+
+#[async_trait]
+impl StorageOpen for JsonFilesOpen {
+    type Conn = JsonFiles;
+
+    async fn open(&self) -> Result<Self::Conn> {
+        let options = self.file_options()?;
+        let recovered =
+            json_recovery::recover_json_files(&options.dir, options.encryption_key.as_ref())
+                .context("recovering unfinished files")?;
+        if recovered.files != 0 {
+            warn!(
+                files = recovered.files,
+                frames = recovered.frames,
+                dropped_bytes = recovered.dropped_bytes,
+                "recovered files left unfinished by an earlier run"
+            );
+        }
+        let streams = JsonFileWriter::new("streams".to_owned(), Arc::clone(&options))
+            .context("opening streams")?;
+        let events = JsonFileWriter::new("events".to_owned(), Arc::clone(&options))
+            .context("opening events")?;
+        let stream_ends = JsonFileWriter::new("stream_ends".to_owned(), options)
+            .context("opening stream ends")?;
+        Ok(JsonFiles {
+            streams,
+            events,
+            stream_ends,
+            dedup: DedupWindow::new(self.dedup_window),
+            last_stream_event_indexes: Default::default(),
+            stream_uuids: Default::default(),
+            stream_event_counts: Default::default(),
+            streams_by_activity: Default::default(),
+            stream_activity: Default::default(),
+            activity: 0,
+            compression_stats: Default::default(),
+        })
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.file_options()?;
+        check_writable_dir(&self.output_dir.join("check"))
+    }
+
+    fn info(&self) -> serde_json::Value {
+        json!({
+            "backend": "jsonfiles",
+            "output_dir": self.output_dir,
+            "rotate_interval": self.rotate_interval.map(|d| humantime::format_duration(d).to_string()),
+            "rotate_size": self.rotate_size,
+            "compression_level": self.compression_level,
+            "compression_long": self.compression_long,
+            "compression_dictionary": self.compression_dictionary,
+            "compression_threads": self.compression_threads,
+            "dedup_window": self.dedup_window,
+            "encryption_key": self.encryption.info(),
+            "durability": self.durability.info(
+                "buffered until a file is finished by rotation, SIGINT or shutdown"
+            ),
+        })
+    }
+}
+
+impl JsonFilesOpen {
+    fn file_options(&self) -> Result<Arc<JsonFileOptions>> {
+        if !zstd::compression_level_range().contains(&self.compression_level) {
+            bail!(
+                "compression level must be in {:?}",
+                zstd::compression_level_range()
+            );
+        }
+        let dictionary = self
+            .compression_dictionary
+            .as_ref()
+            .map(|path| std::fs::read(path).with_context(|| format!("reading {}", path.display())))
+            .transpose()?;
+        let options = Arc::new(JsonFileOptions {
+            dir: self.output_dir.clone(),
+            name_template: self.file_name_template.clone(),
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            compression_level: self.compression_level,
+            long_distance_matching: self.compression_long,
+            dictionary,
+            compression_threads: self.compression_threads,
+            rotate_interval: self.rotate_interval,
+            rotate_size: self.rotate_size,
+            encryption_key: self.encryption.key()?,
+            fsync: self.durability.fsync_schedule(Durability::OsBuffered),
+            file_closed: FileClosedHook {
+                command: self.file_closed_command.clone(),
+                webhook: self.file_closed_webhook.clone(),
+                client: Default::default(),
+            },
+        });
+        // Catch bad templates before the first event.
+        options.file_name_prefix("events", Utc::now())?;
+        Ok(options)
+    }
+}
+
+#[derive(Clone, clap::Args)]
+pub struct PostgresOpener {
+    /// Custom schema to run on every start, instead of applying the built-in migrations.
+    #[arg(long)]
+    pub schema_path: Option<String>,
+    #[arg(long, required_unless_present = "conn_str_file")]
+    pub conn_str: Option<String>,
+    /// Reads the connection string from a file instead, so it isn't in the process's arguments. A
+    /// bare file name is looked for in $CREDENTIALS_DIRECTORY, where systemd's LoadCredential=
+    /// puts it, and /run/secrets, where Docker secrets are, before the current directory.
+    #[arg(long, conflicts_with = "conn_str")]
+    pub conn_str_file: Option<String>,
+    /// Reads the password from a file, found as --conn-str-file is, for connection strings without
+    /// one. Without it, $PGPASSWORD is used as libpq does.
+    #[arg(long)]
+    pub password_file: Option<String>,
+    /// Postgres schema for the tables, created if it's missing, so several servers can share a
+    /// database. Without it tables go in the first schema on the user's search_path, usually
+    /// public.
+    #[arg(long, value_parser = parse_db_schema)]
+    pub db_schema: Option<String>,
+    /// A CA certificate to trust as well as the system's, as PEM, for the sslmodes that check the
+    /// server's certificate. Takes the place of the connection string's sslrootcert.
+    #[arg(long)]
+    pub tls_root_cert_path: Option<String>,
+    /// Uses TLS, checking the server's certificate and host name as sslmode=verify-full does, when
+    /// the connection string has no sslmode of its own.
+    #[arg(long)]
+    pub use_tls: bool,
+    #[command(flatten)]
+    pub extract: ExtractArgs,
+    #[command(flatten)]
+    pub indexes: PostgresIndexArgs,
+    #[command(flatten)]
+    pub full_text: FullTextArgs,
+    #[command(flatten)]
+    pub durability: DurabilityArgs,
+    #[command(flatten)]
+    pub partitions: PartitionArgs,
+    #[command(flatten)]
+    pub timescale: TimescaleArgs,
+}
+
+#[async_trait]
+impl StorageOpen for PostgresOpener {
+    type Conn = Postgres;
+
+    async fn open(&self) -> Result<Self::Conn> {
+        let mut client = self.connect().await?;
+        if let Some(db_schema) = &self.db_schema {
+            client
+                .batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS \"{}\"", db_schema))
+                .await
+                .with_context(|| format!("creating schema {}", db_schema))?;
+        }
+        match &self.schema_path {
+            Some(schema_path) => {
+                client
+                    .batch_execute(fs::read_to_string(schema_path)?.as_str())
+                    .await?
+            }
+            None => migrate_postgres(&mut client).await?,
+        }
+        // Before the indexes, so that they're made on the partitioned table.
+        let layout = if self.timescale.apply(&mut client).await? {
+            EventsLayout::Hypertable
+        } else if self.partitions.apply(&mut client).await? {
+            EventsLayout::Partitioned
+        } else {
+            EventsLayout::Table
+        };
+        self.extract.apply_postgres(&mut client).await?;
+        self.indexes.apply(&client).await?;
+        self.full_text.apply_postgres(&client).await?;
+        if let Some(synchronous_commit) = self.durability.postgres_synchronous_commit() {
+            client
+                .batch_execute(&format!("SET synchronous_commit = {}", synchronous_commit))
+                .await?;
+        }
+        Ok(Postgres {
+            client,
+            partitions: self.partitions.clone(),
+            layout,
+        })
+    }
+
+    async fn open_read_only(&self) -> Result<Self::Conn> {
+        let mut client = self.connect().await?;
+        client
+            .batch_execute("SET default_transaction_read_only = on")
+            .await?;
+        if self.schema_path.is_none() {
+            let pending = pending_postgres_migrations(&client).await?;
+            if !pending.is_empty() {
+                bail!(
+                    "database is missing migrations {:?}, and read-only storage isn't migrated",
+                    pending
+                );
+            }
+        }
+        let layout = EventsLayout::detect(&mut client).await?;
+        Ok(Postgres {
+            client,
+            partitions: Default::default(),
+            layout,
+        })
+    }
+
+    async fn check(&self) -> Result<()> {
+        let client = self.connect().await.context("connecting")?;
+        if self.schema_path.is_none() {
+            let pending = pending_postgres_migrations(&client).await?;
+            if !pending.is_empty() {
+                bail!("database is missing migrations {:?}", pending);
+            }
+        }
+        let writable: bool = client
+            .query_one(
+                "SELECT has_table_privilege('streams', 'INSERT') \
+                AND has_table_privilege('events', 'INSERT')",
+                &[],
+            )
+            .await?
+            .get(0);
+        if !writable {
+            bail!("can't insert into streams and events");
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> serde_json::Value {
+        // The connection string can have a password, so only pick out where it goes.
+        let tls = self.config().ok();
+        let config = tls.as_ref().map(|(config, ..)| config);
+        let hosts: Option<Vec<String>> = config.map(|config| {
+            config
+                .get_hosts()
+                .iter()
+                .map(|host| match host {
+                    tokio_postgres::config::Host::Tcp(host) => host.clone(),
+                    tokio_postgres::config::Host::Unix(path) => path.display().to_string(),
+                })
+                .collect()
+        });
+        json!({
+            "backend": "postgres",
+            "hosts": hosts,
+            "dbname": config.and_then(|config| config.get_dbname()),
+            "user": config.and_then(|config| config.get_user()),
+            "conn_str_file": self.conn_str_file,
+            "password_file": self.password_file,
+            "use_tls": self.use_tls,
+            "ssl_mode": tls.as_ref().map(|(_, ssl_mode, _)| ssl_mode.name()),
+            "tls_root_cert_path": tls.as_ref().and_then(|(.., tls)| tls.root_cert.as_ref()),
+            "tls_client_cert_path": tls.as_ref().and_then(|(.., tls)| tls.cert.as_ref()),
+            "custom_schema": self.schema_path.is_some(),
+            "db_schema": self.db_schema,
+            "extracted_fields": self.extract.to_json(),
+            "query_indexes": self.indexes.to_json(),
+            "full_text_search": self.full_text.to_json(),
+            "durability": self.durability.info("each event committed"),
+            "partitions": self.partitions.to_json(),
+            "timescale": self.timescale.to_json(),
+        })
+    }
+
+    fn max_connections(&self) -> usize {
+        usize::MAX
+    }
+}
+
+impl PostgresOpener {
+    /// The connection string, from --conn-str or --conn-str-file.
+    fn conn_str(&self) -> Result<String> {
+        match (&self.conn_str, &self.conn_str_file) {
+            (Some(conn_str), _) => Ok(conn_str.clone()),
+            (None, Some(conn_str_file)) => read_secret(conn_str_file),
+            (None, None) => bail!("postgres needs --conn-str or --conn-str-file"),
+        }
+    }
+
+    /// The password for connection strings without one, from --password-file, or else $PGPASSWORD
+    /// as libpq does.
+    fn password(&self) -> Result<Option<String>> {
+        match &self.password_file {
+            Some(password_file) => read_secret(password_file).map(Some),
+            None => Ok(std::env::var("PGPASSWORD").ok()),
+        }
+    }
+
+    /// The connection settings tokio-postgres is given, and how to use TLS: as the connection
+    /// string's sslmode says if it has one, or else verifying the server fully with --use-tls, and
+    /// not using TLS without it.
+    fn config(&self) -> Result<(tokio_postgres::Config, SslMode, TlsParams)> {
+        let (conn_str, mut tls) = take_tls_params(&self.conn_str()?)?;
+        let mut config: tokio_postgres::Config =
+            conn_str.parse().context("parsing connection string")?;
+        if config.get_password().is_none() {
+            if let Some(password) = self.password()? {
+                config.password(password);
+            }
+        }
+        if let Some(tls_root_cert_path) = &self.tls_root_cert_path {
+            tls.root_cert = Some(tls_root_cert_path.clone());
+        }
+        let ssl_mode = match (tls.ssl_mode, self.use_tls) {
+            (Some(SslMode::Disable), true) => {
+                bail!("--use-tls was given, but the connection string has sslmode=disable")
+            }
+            (Some(ssl_mode), _) => ssl_mode,
+            (None, true) => SslMode::VerifyFull,
+            (None, false) => SslMode::Disable,
+        };
+        Ok((config, ssl_mode, tls))
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let (config, ssl_mode, tls) = self.config()?;
+        let client = match ssl_mode {
+            SslMode::Disable => {
+                debug!("Initializing postgres storage without TLS");
+                let (client, conn) = config.connect(NoTls).await?;
+                tokio::spawn(async move {
+                    if let Err(err) = conn.await {
+                        error!(%err, "postgres connection failed");
+                    }
+                });
+                client
+            }
+            _ => {
+                let connector = tls.connector(ssl_mode)?;
+                let (client, conn) = config.connect(connector).await?;
+                tokio::spawn(async move {
+                    if let Err(err) = conn.await {
+                        error!(%err, "postgres connection failed");
+                    }
+                });
+                client
+            }
+        };
+        // Every statement after this, including the migrations, uses the schema's tables without
+        // naming it.
+        if let Some(db_schema) = &self.db_schema {
+            client
+                .batch_execute(&format!("SET search_path TO \"{}\"", db_schema))
+                .await?;
+        }
+        Ok(client)
+    }
+}
+
+/// Schema names are put in statements as quoted identifiers, so they're limited to what doesn't
+/// need escaping there.
+fn parse_db_schema(name: &str) -> Result<String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid || name.len() > 63 {
+        bail!(
+            "schema names are up to 63 letters, digits and underscores, not starting with a digit"
+        );
+    }
+    Ok(name.to_owned())
+}
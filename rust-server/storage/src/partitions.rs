@@ -0,0 +1,238 @@
+use super::*;
+use chrono::Datelike;
+
+/// Makes inserts into events skip those repeating an event ID, for tables that can't have a unique
+/// index on event IDs alone.
+pub(crate) const UNIQUE_EVENT_IDS: &str = include_str!("../../sql/postgres-unique-event-ids.sql");
+
+/// How Postgres's events table is split up by insert time, which decides how event IDs are kept
+/// unique and how old events are dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EventsLayout {
+    Table,
+    Partitioned,
+    /// A TimescaleDB hypertable, split into chunks by TimescaleDB itself.
+    Hypertable,
+}
+
+impl EventsLayout {
+    /// How an existing events table is laid out, without changing it, as neither partitioning nor
+    /// TimescaleDB changes anything when not asked to.
+    pub(crate) async fn detect(client: &mut Client) -> Result<Self> {
+        Ok(if TimescaleArgs::default().apply(client).await? {
+            EventsLayout::Hypertable
+        } else if PartitionArgs::default().apply(client).await? {
+            EventsLayout::Partitioned
+        } else {
+            EventsLayout::Table
+        })
+    }
+}
+
+/// How much insert time each partition of Postgres's events table covers.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum PartitionPeriod {
+    Day,
+    Month,
+}
+
+impl PartitionPeriod {
+    fn name(self) -> &'static str {
+        match self {
+            PartitionPeriod::Day => "day",
+            PartitionPeriod::Month => "month",
+        }
+    }
+
+    /// The start of the period `datetime` is in.
+    fn start(self, datetime: NaiveDateTime) -> NaiveDateTime {
+        let date = match self {
+            PartitionPeriod::Day => datetime.date(),
+            PartitionPeriod::Month => datetime.date().with_day(1).unwrap(),
+        };
+        date.and_time(chrono::NaiveTime::MIN)
+    }
+
+    /// The start of the period after the one `datetime` is in.
+    fn next(self, datetime: NaiveDateTime) -> NaiveDateTime {
+        let start = self.start(datetime);
+        match self {
+            PartitionPeriod::Day => start.checked_add_days(chrono::Days::new(1)),
+            PartitionPeriod::Month => start.checked_add_months(chrono::Months::new(1)),
+        }
+        .expect("partition dates are in range")
+    }
+}
+
+/// Partitioning of Postgres's events table by insert time, so old events can be dropped a
+/// partition at a time rather than deleted.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct PartitionArgs {
+    /// Partitions the events table by insert time, a partition a day or a month, and drops whole
+    /// partitions that are past --retain-for. An existing table becomes the partition of
+    /// everything before the next period, which takes a scan of it, with writes locked out, the
+    /// first time.
+    #[arg(long, value_enum)]
+    pub partition_events: Option<PartitionPeriod>,
+    /// How many partitions after the current one to keep created ahead of their events.
+    #[arg(long, default_value = "3")]
+    pub partitions_ahead: u32,
+}
+
+/// A partition of the events table, and the end of the insert times it holds, if it has one.
+struct Partition {
+    name: String,
+    until: Option<NaiveDateTime>,
+}
+
+impl PartitionArgs {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!(self.partition_events.map(|period| json!({
+            "period": period.name(),
+            "ahead": self.partitions_ahead,
+        })))
+    }
+
+    /// Partitions the events table if asked to and it isn't already, and creates the partitions
+    /// it needs now. Returns whether the table is partitioned, as it stays once it has been.
+    pub(crate) async fn apply(&self, client: &mut Client) -> Result<bool> {
+        let partitioned: bool = client
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM pg_partitioned_table \
+                WHERE partrelid = 'events'::regclass)",
+                &[],
+            )
+            .await?
+            .get(0);
+        let Some(period) = self.partition_events else {
+            return Ok(partitioned);
+        };
+        if !partitioned {
+            let until = period.next(Utc::now().naive_utc());
+            let tx = client.transaction().await?;
+            tx.batch_execute(include_str!("../../sql/postgres-partitioned-events.sql"))
+                .await
+                .context("partitioning events")?;
+            tx.batch_execute(UNIQUE_EVENT_IDS).await?;
+            tx.batch_execute(&format!(
+                "ALTER TABLE events ATTACH PARTITION events_unpartitioned \
+                FOR VALUES FROM (MINVALUE) TO ('{}')",
+                until
+            ))
+            .await
+            .context("attaching existing events")?;
+            tx.commit().await?;
+            info!(period = period.name(), "partitioned events");
+        }
+        self.create(client).await?;
+        Ok(true)
+    }
+
+    /// Creates the partitions from the end of the latest one, or the start of the current period,
+    /// to --partitions-ahead periods after the current one. Returns how many were created.
+    pub(crate) async fn create(&self, client: &Client) -> Result<u64> {
+        let Some(period) = self.partition_events else {
+            return Ok(0);
+        };
+        let now = Utc::now().naive_utc();
+        let current = period.start(now);
+        let mut start = partitions(client)
+            .await?
+            .iter()
+            .filter_map(|partition| partition.until)
+            .fold(current, NaiveDateTime::max);
+        let end = (0..=self.partitions_ahead).fold(current, |end, _| period.next(end));
+        let mut created = 0;
+        while start < end {
+            let until = period.next(start);
+            let name = format!("events_p{}", start.format("%Y%m%d"));
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE \"{name}\" PARTITION OF events \
+                    FOR VALUES FROM ('{start}') TO ('{until}');
+                    CREATE UNIQUE INDEX \"{name}_stream_event_id\" \
+                    ON \"{name}\"(stream_id, event_id);",
+                ))
+                .await
+                .with_context(|| format!("creating partition {}", name))?;
+            debug!(%name, %start, %until, "created partition");
+            created += 1;
+            start = until;
+        }
+        Ok(created)
+    }
+}
+
+/// Drops the partitions holding only events inserted before `before`. Returns how many partitions
+/// and events were dropped.
+pub(crate) async fn drop_partitions(
+    tx: &tokio_postgres::Transaction<'_>,
+    before: NaiveDateTime,
+) -> Result<(u64, u64)> {
+    let mut dropped = (0, 0);
+    for partition in partitions(tx).await? {
+        if partition.until.is_none_or(|until| until > before) {
+            continue;
+        }
+        let events = uncount_events(tx, &format!("\"{}\"", partition.name)).await?;
+        tx.batch_execute(&format!("DROP TABLE \"{}\"", partition.name))
+            .await
+            .with_context(|| format!("dropping partition {}", partition.name))?;
+        info!(partition = %partition.name, events, "dropped partition");
+        dropped.0 += 1;
+        dropped.1 += events;
+    }
+    Ok(dropped)
+}
+
+/// Takes the events in a table about to be dropped off their streams' counts, as the triggers that
+/// would on delete don't run. Returns how many events there were.
+pub(crate) async fn uncount_events(
+    tx: &tokio_postgres::Transaction<'_>,
+    table: &str,
+) -> Result<u64> {
+    let events: i64 = tx
+        .query_one(
+            &format!(
+                "WITH dropped AS (SELECT stream_id, COUNT(*) AS events, \
+                    SUM(pg_column_size(payload)) AS bytes FROM {} GROUP BY stream_id), \
+                updated AS (UPDATE streams SET \
+                    stored_event_count = stored_event_count - dropped.events, \
+                    stored_payload_bytes = stored_payload_bytes - dropped.bytes \
+                    FROM dropped WHERE streams.stream_id = dropped.stream_id) \
+                SELECT COALESCE(SUM(events), 0)::bigint FROM dropped",
+                table
+            ),
+            &[],
+        )
+        .await?
+        .get(0);
+    Ok(events as u64)
+}
+
+/// The events table's partitions, read from the bounds Postgres describes them with, like
+/// `FOR VALUES FROM ('2024-01-01 00:00:00') TO ('2024-02-01 00:00:00')` or `DEFAULT`.
+async fn partitions(client: &impl tokio_postgres::GenericClient) -> Result<Vec<Partition>> {
+    let rows = client
+        .query(
+            "SELECT c.relname::text, pg_get_expr(c.relpartbound, c.oid) \
+            FROM pg_inherits JOIN pg_class c ON c.oid = pg_inherits.inhrelid \
+            WHERE pg_inherits.inhparent = 'events'::regclass",
+            &[],
+        )
+        .await?;
+    rows.iter()
+        .map(|row| {
+            let name: String = row.get(0);
+            let bound: &str = row.get(1);
+            let until = match bound.split_once(" TO ('") {
+                Some((_, until)) => {
+                    let until = until.trim_end_matches("')");
+                    Some(parse_datetime(until)?.naive_utc())
+                }
+                None => None,
+            };
+            Ok(Partition { name, until })
+        })
+        .collect()
+}
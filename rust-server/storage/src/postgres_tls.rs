@@ -0,0 +1,216 @@
+use super::*;
+use native_tls::{Certificate, Identity, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use std::fs;
+
+/// Whether a Postgres connection uses TLS, and how much of the server's certificate it checks, as
+/// libpq's sslmode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SslMode {
+    /// No TLS.
+    Disable,
+    /// TLS if the server supports it, without checking its certificate.
+    Prefer,
+    /// TLS without checking the certificate, unless a root certificate is given, which it's then
+    /// checked against as with VerifyCa.
+    Require,
+    /// TLS with a certificate signed by a trusted CA, for any host name.
+    VerifyCa,
+    /// TLS with a certificate signed by a trusted CA, for the host connected to.
+    VerifyFull,
+}
+
+impl SslMode {
+    fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "disable" => SslMode::Disable,
+            "prefer" => SslMode::Prefer,
+            "require" => SslMode::Require,
+            "verify-ca" => SslMode::VerifyCa,
+            "verify-full" => SslMode::VerifyFull,
+            _ => bail!(
+                "sslmode {:?} isn't disable, prefer, require, verify-ca or verify-full",
+                value
+            ),
+        })
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyCa => "verify-ca",
+            SslMode::VerifyFull => "verify-full",
+        }
+    }
+
+    /// The sslmode tokio-postgres is given, which only decides whether TLS is required, as it
+    /// leaves checking certificates to the connector.
+    fn tokio_postgres_value(self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => "require",
+        }
+    }
+}
+
+/// The TLS settings of a connection string, taken out of it as tokio-postgres leaves TLS to the
+/// connector, and doesn't accept the verify sslmodes.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TlsParams {
+    pub ssl_mode: Option<SslMode>,
+    /// sslrootcert, a CA certificate to trust.
+    pub root_cert: Option<String>,
+    /// sslcert and sslkey, a client certificate and its key to present to the server.
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl TlsParams {
+    /// A connector checking certificates as the mode does, trusting the root certificate as well
+    /// as the system's, and presenting the client certificate if there is one.
+    pub(crate) fn connector(&self, ssl_mode: SslMode) -> Result<MakeTlsConnector> {
+        let mut builder = TlsConnector::builder();
+        if let Some(root_cert) = &self.root_cert {
+            debug!("Adding TLS root cert from {}", root_cert);
+            let cert = fs::read(root_cert).with_context(|| format!("reading {}", root_cert))?;
+            builder.add_root_certificate(Certificate::from_pem(&cert)?);
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                debug!("Using TLS client cert from {}", cert);
+                let cert = fs::read(cert).with_context(|| format!("reading {}", cert))?;
+                let key = fs::read(key).with_context(|| format!("reading {}", key))?;
+                let identity = Identity::from_pkcs8(&cert, &key)
+                    .context("reading client certificate and PKCS#8 key")?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("sslcert and sslkey have to be given together"),
+        }
+        match ssl_mode {
+            SslMode::Disable | SslMode::VerifyFull => {}
+            SslMode::Require if self.root_cert.is_some() => {
+                builder.danger_accept_invalid_hostnames(true);
+            }
+            SslMode::Prefer | SslMode::Require => {
+                builder.danger_accept_invalid_certs(true);
+            }
+            SslMode::VerifyCa => {
+                builder.danger_accept_invalid_hostnames(true);
+            }
+        }
+        Ok(MakeTlsConnector::new(builder.build()?))
+    }
+}
+
+/// Takes the TLS settings out of a connection string, either a URL or `key=value` pairs. Returns
+/// the string tokio-postgres should be given, with sslmode replaced by the closest it knows.
+pub(crate) fn take_tls_params(conn_str: &str) -> Result<(String, TlsParams)> {
+    let mut tls = TlsParams::default();
+    let mut take = |key: &str, value: String| -> Result<Option<String>> {
+        match key {
+            "sslmode" => {
+                let ssl_mode = SslMode::parse(&value)?;
+                tls.ssl_mode = Some(ssl_mode);
+                return Ok(Some(format!("sslmode={}", ssl_mode.tokio_postgres_value())));
+            }
+            "sslrootcert" => tls.root_cert = Some(value),
+            "sslcert" => tls.cert = Some(value),
+            _ => tls.key = Some(value),
+        }
+        Ok(None)
+    };
+    let is_tls = |key: &str| matches!(key, "sslmode" | "sslrootcert" | "sslcert" | "sslkey");
+    if conn_str.starts_with("postgres://") || conn_str.starts_with("postgresql://") {
+        let Some((base, query)) = conn_str.split_once('?') else {
+            return Ok((conn_str.to_owned(), tls));
+        };
+        let mut kept = vec![];
+        for pair in query.split('&') {
+            match pair.split_once('=') {
+                Some((key, value)) if is_tls(key) => {
+                    kept.extend(take(key, percent_decode(value)?)?)
+                }
+                _ => kept.push(pair.to_owned()),
+            }
+        }
+        let conn_str = match kept.is_empty() {
+            true => base.to_owned(),
+            false => format!("{}?{}", base, kept.join("&")),
+        };
+        return Ok((conn_str, tls));
+    }
+    let mut kept = vec![];
+    for (key, value, text) in key_value_pairs(conn_str)? {
+        match is_tls(key) {
+            true => kept.extend(take(key, value)?),
+            false => kept.push(text.to_owned()),
+        }
+    }
+    Ok((kept.join(" "), tls))
+}
+
+/// The pairs of a `key=value` connection string, with each value unquoted and the text it was
+/// written as. Values can be single quoted, and backslashes escape the character after them.
+fn key_value_pairs(conn_str: &str) -> Result<Vec<(&str, String, &str)>> {
+    let mut pairs = vec![];
+    let mut rest = conn_str.trim_start();
+    while !rest.is_empty() {
+        let start = rest;
+        let key_len = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = &rest[..key_len];
+        rest = rest[key_len..].trim_start();
+        rest = rest
+            .strip_prefix('=')
+            .with_context(|| format!("connection string parameter {} has no value", key))?
+            .trim_start();
+        let mut value = String::new();
+        let quoted = rest.starts_with('\'');
+        let mut chars = rest.char_indices().skip(quoted as usize);
+        let mut end = rest.len();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                '\'' if quoted => {
+                    end = i + 1;
+                    break;
+                }
+                c if c.is_whitespace() && !quoted => {
+                    end = i;
+                    break;
+                }
+                c => value.push(c),
+            }
+        }
+        rest = &rest[end..];
+        pairs.push((key, value, &start[..start.len() - rest.len()]));
+        rest = rest.trim_start();
+    }
+    Ok(pairs)
+}
+
+/// Decodes the %XX escapes of a URL query value.
+fn percent_decode(value: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .with_context(|| format!("bad escape in {:?}", value))?;
+        bytes.push(hex);
+        rest = &rest[2..];
+    }
+    Ok(String::from_utf8(bytes)?)
+}
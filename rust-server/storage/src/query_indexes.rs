@@ -0,0 +1,104 @@
+use super::*;
+use rusqlite::OptionalExtension;
+
+/// Indexes on payload paths of SQLite's events table, for queries that filter on them with
+/// `json_extract`.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct SqliteIndexArgs {
+    /// Indexes `json_extract(payload, PATH)`, like '$.level' or '$.device.id', when the database
+    /// is opened. Queries must use the same expression to use the index. Can be repeated.
+    #[arg(long, value_parser = parse_json_path)]
+    json_index: Vec<String>,
+}
+
+fn parse_json_path(path: &str) -> Result<String> {
+    if !path.starts_with('$') {
+        bail!("{:?} isn't a JSON path, like $.level", path);
+    }
+    if path.contains('\'') {
+        bail!("JSON path {:?} can't have a single quote", path);
+    }
+    Ok(path.to_owned())
+}
+
+/// The index's name, from the path's letters and digits.
+fn json_index_name(path: &str) -> String {
+    let mut name = "events_json".to_owned();
+    for part in path[1..]
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+    {
+        name += "_";
+        name += &part.to_ascii_lowercase();
+    }
+    name
+}
+
+impl SqliteIndexArgs {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!(self.json_index)
+    }
+
+    /// Creates the indexes that are missing. An index of the same name on another path, as paths
+    /// differing only in punctuation share a name, is replaced. Indexes on paths no longer given
+    /// are left as they are.
+    pub(crate) fn apply(&self, tx: &rusqlite::Transaction) -> Result<()> {
+        let mut names = HashSet::new();
+        for path in &self.json_index {
+            let name = json_index_name(path);
+            if !names.insert(name.clone()) {
+                bail!("JSON index {} would have the same name as another", path);
+            }
+            let sql = format!(
+                "CREATE INDEX \"{}\" ON events(json_extract(payload, '{}'))",
+                name, path
+            );
+            let existing: Option<String> = tx
+                .query_row(
+                    "select sql from sqlite_schema where type = 'index' and name = ?",
+                    [&name],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match existing {
+                Some(existing) if existing == sql => continue,
+                Some(_) => tx.execute_batch(&format!("drop index \"{}\"", name))?,
+                None => {}
+            }
+            tx.execute_batch(&sql)?;
+            debug!(%path, %name, "created JSON index");
+        }
+        Ok(())
+    }
+}
+
+/// Indexes for querying Postgres's events table by payload content and insert time.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct PostgresIndexArgs {
+    /// Creates a GIN index on payload, for containment (@>) and key (?) queries, and a BRIN index
+    /// on insert_datetime, for time ranges over large tables, when the database is opened. The
+    /// first time can take a while on a full table, which is locked for writes meanwhile.
+    #[arg(long)]
+    pub query_indexes: bool,
+}
+
+impl PostgresIndexArgs {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!(self.query_indexes)
+    }
+
+    pub(crate) async fn apply(&self, client: &Client) -> Result<()> {
+        if !self.query_indexes {
+            return Ok(());
+        }
+        client
+            .batch_execute(
+                "CREATE INDEX IF NOT EXISTS events_payload_gin ON events USING GIN (payload);
+                CREATE INDEX IF NOT EXISTS events_insert_datetime_brin \
+                ON events USING BRIN (insert_datetime);",
+            )
+            .await
+            .context("creating query indexes")?;
+        Ok(())
+    }
+}
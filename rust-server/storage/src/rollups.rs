@@ -0,0 +1,249 @@
+use super::*;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A count of events kept in the rollups table, by a group in buckets of insert time, so
+/// dashboards can read it instead of scanning events.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rollup {
+    pub name: String,
+    pub group: RollupGroup,
+    /// Whole seconds.
+    pub bucket: Duration,
+}
+
+/// What a rollup counts events by within each bucket.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RollupGroup {
+    Stream,
+    /// A top-level payload field, compared as text.
+    Field(String),
+}
+
+/// A rollup's count of the events in one bucket and group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RollupRow {
+    pub bucket_start: DateTime<Utc>,
+    /// The stream ID or field value. None for events without the field.
+    pub group: Option<String>,
+    pub count: u64,
+}
+
+/// Parses NAME=GROUP/BUCKET, like "per_stream=stream/1m" or "levels=payload.level/5m". The bucket
+/// defaults to a minute.
+impl FromStr for Rollup {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (name, definition) = text
+            .split_once('=')
+            .context("rollup must be NAME=GROUP/BUCKET, like per_stream=stream/1m")?;
+        if name.is_empty() || name.contains('/') {
+            bail!("rollup name {:?} must be non-empty and have no /", name);
+        }
+        let (group, bucket) = definition.split_once('/').unwrap_or((definition, "1m"));
+        let group = match group.split_once('.') {
+            None if group == "stream" => RollupGroup::Stream,
+            Some(("payload", field)) if !field.is_empty() => RollupGroup::Field(field.to_owned()),
+            _ => bail!("rollup group {:?} must be stream or payload.<field>", group),
+        };
+        let bucket = humantime::parse_duration(bucket)
+            .with_context(|| format!("rollup bucket {:?}", bucket))?;
+        if bucket.as_secs() == 0 || bucket.subsec_nanos() != 0 {
+            bail!("rollup bucket must be a whole number of seconds");
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            group,
+            bucket,
+        })
+    }
+}
+
+impl Display for Rollup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.definition())
+    }
+}
+
+impl RollupRow {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "bucket_start": self.bucket_start.to_rfc3339(),
+            "group": self.group,
+            "count": self.count,
+        })
+    }
+}
+
+impl Rollup {
+    /// Stored with each row, so that rows counted another way are replaced when a rollup changes.
+    fn definition(&self) -> String {
+        let group = match &self.group {
+            RollupGroup::Stream => "stream".to_owned(),
+            RollupGroup::Field(field) => format!("payload.{}", field),
+        };
+        format!("{}/{}", group, humantime::format_duration(self.bucket))
+    }
+
+    fn bucket_secs(&self) -> i64 {
+        self.bucket.as_secs() as i64
+    }
+
+    fn field(&self) -> Option<&String> {
+        match &self.group {
+            RollupGroup::Stream => None,
+            RollupGroup::Field(field) => Some(field),
+        }
+    }
+}
+
+pub(crate) fn roll_up_sqlite(conn: &mut rusqlite::Connection, rollup: &Rollup) -> Result<u64> {
+    let tx = conn.transaction()?;
+    let definition = rollup.definition();
+    tx.execute(
+        "delete from rollups where name = ? and definition != ?",
+        [&rollup.name, &definition],
+    )?;
+    // The latest bucket is counted again, for the events inserted in it since.
+    let from: Option<String> = tx.query_row(
+        "select max(bucket_start) from rollups where name = ?",
+        [&rollup.name],
+        |row| row.get(0),
+    )?;
+    tx.execute(
+        "delete from rollups where name = ? and bucket_start >= ?",
+        rusqlite::params![rollup.name, from],
+    )?;
+    let bucket_secs = rollup.bucket_secs();
+    let mut params: Vec<&dyn rusqlite::ToSql> =
+        vec![&rollup.name, &definition, &bucket_secs, &from];
+    let group = match rollup.field() {
+        None => "cast(stream_id as text)",
+        Some(field) => {
+            params.push(field);
+            "cast(payload ->> ?5 as text)"
+        }
+    };
+    let written = tx.execute(
+        &format!(
+            "insert into rollups(name, definition, bucket_start, group_key, count) \
+            select ?1, ?2, datetime(unixepoch(insert_datetime) / ?3 * ?3, 'unixepoch') as bucket, \
+                {} as group_key, count(*) \
+            from events where ?4 is null or insert_datetime >= ?4 \
+            group by bucket, group_key",
+            group
+        ),
+        params.as_slice(),
+    )?;
+    tx.commit()?;
+    Ok(written as u64)
+}
+
+pub(crate) fn read_rollup_sqlite(
+    conn: &rusqlite::Connection,
+    name: &str,
+    selection: &EventSelection,
+) -> Result<Vec<RollupRow>> {
+    let (since, until) = selection.text_bounds();
+    let mut stmt = conn.prepare(
+        "select bucket_start, group_key, count from rollups \
+        where name = ?1 and (?2 is null or bucket_start >= ?2) \
+            and (?3 is null or bucket_start < ?3) \
+        order by bucket_start, group_key",
+    )?;
+    let rows = stmt
+        .query_map(rusqlite::params![name, since, until], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .map(|row| {
+            let (bucket_start, group, count) = row?;
+            Ok(RollupRow {
+                bucket_start: parse_datetime(&bucket_start)?,
+                group,
+                count,
+            })
+        })
+        .collect();
+    rows
+}
+
+pub(crate) async fn roll_up_postgres(client: &mut Client, rollup: &Rollup) -> Result<u64> {
+    let tx = client.transaction().await?;
+    let definition = rollup.definition();
+    tx.execute(
+        "DELETE FROM rollups WHERE name = $1 AND definition <> $2",
+        &[&rollup.name, &definition],
+    )
+    .await?;
+    // The latest bucket is counted again, for the events inserted in it since.
+    let from: Option<NaiveDateTime> = tx
+        .query_one(
+            "SELECT max(bucket_start) FROM rollups WHERE name = $1",
+            &[&rollup.name],
+        )
+        .await?
+        .get(0);
+    tx.execute(
+        "DELETE FROM rollups WHERE name = $1 AND bucket_start >= $2",
+        &[&rollup.name, &from],
+    )
+    .await?;
+    let bucket_secs = rollup.bucket_secs();
+    let mut params: Vec<PostgresParam> = vec![
+        Box::new(rollup.name.clone()),
+        Box::new(definition),
+        Box::new(bucket_secs),
+        Box::new(from),
+    ];
+    let group = match rollup.field() {
+        None => "stream_id::text",
+        Some(field) => {
+            params.push(Box::new(field.clone()));
+            "payload->>$5::text"
+        }
+    };
+    let params: Vec<_> = params.iter().map(|param| param.as_ref() as _).collect();
+    let written = tx
+        .execute(
+            &format!(
+                "INSERT INTO rollups(name, definition, bucket_start, group_key, count) \
+                SELECT $1, $2, to_timestamp(floor(extract(epoch FROM insert_datetime) \
+                    / $3::bigint) * $3::bigint) AT TIME ZONE 'UTC' AS bucket, \
+                    {} AS group_key, count(*) \
+                FROM events WHERE $4::timestamp IS NULL OR insert_datetime >= $4 \
+                GROUP BY bucket, group_key",
+                group
+            ),
+            &params,
+        )
+        .await?;
+    tx.commit().await?;
+    Ok(written)
+}
+
+pub(crate) async fn read_rollup_postgres(
+    client: &Client,
+    name: &str,
+    selection: &EventSelection,
+) -> Result<Vec<RollupRow>> {
+    let (since, until) = selection.naive_bounds();
+    let rows = client
+        .query(
+            "SELECT bucket_start, group_key, count FROM rollups \
+            WHERE name = $1 AND ($2::timestamp IS NULL OR bucket_start >= $2) \
+                AND ($3::timestamp IS NULL OR bucket_start < $3) \
+            ORDER BY bucket_start, group_key",
+            &[&name, &since, &until],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| RollupRow {
+            bucket_start: row.get::<_, NaiveDateTime>(0).and_utc(),
+            group: row.get(1),
+            count: row.get::<_, i64>(2) as u64,
+        })
+        .collect())
+}
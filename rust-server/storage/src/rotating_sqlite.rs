@@ -0,0 +1,472 @@
+use super::*;
+use futures::executor::block_on;
+use std::path::PathBuf;
+
+/// A SQLite database file that's replaced with a fresh one when it gets too big, for collectors
+/// with bounded storage.
+pub struct RotatingSqlite {
+    pub(super) conn: rusqlite::Connection,
+    pub(super) path: PathBuf,
+    pub(super) schema: SqliteSchema,
+    pub(super) key: Option<EncryptionKey>,
+    /// Never rotates if unset.
+    pub(super) max_bytes: Option<u64>,
+    pub(super) durability: DurabilityArgs,
+    /// Fsyncs for --durability fsync-interval. SQLite does its own for the other levels.
+    pub(super) fsync: FsyncSchedule,
+    pub(super) blocked: BlockingStats,
+    /// Rotating is held off while a batch's transaction is open, and done once it's committed.
+    pub(super) in_batch: bool,
+    /// The start of the dedup window when a request hash was last looked for. Hashes older than
+    /// it aren't carried over.
+    pub(super) request_hashes_since: Option<DateTime<Utc>>,
+}
+
+impl RotatingSqlite {
+    /// Runs calls on the database, which block the thread, off the async runtime's other tasks.
+    /// The connection's futures never wait, so they're polled to completion where they are.
+    fn blocking<T>(&mut self, call: impl FnOnce(&mut Self) -> T) -> T {
+        let mut blocked = std::mem::take(&mut self.blocked);
+        let result = blocked.run(|| call(self));
+        self.blocked = blocked;
+        result
+    }
+
+    fn size(&self) -> Result<u64> {
+        let page_count: u64 = self
+            .conn
+            .pragma_query_value(None, "page_count", |row| row.get(0))?;
+        let page_size: u64 = self
+            .conn
+            .pragma_query_value(None, "page_size", |row| row.get(0))?;
+        Ok(page_count * page_size)
+    }
+
+    fn rotate_if_too_big(&mut self) -> Result<()> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Ok(());
+        };
+        if self.in_batch {
+            return Ok(());
+        }
+        let size = self.size()?;
+        if size <= max_bytes {
+            return Ok(());
+        }
+        debug!(size, max_bytes, "sqlite database is over its rotate size");
+        self.rotate_now()
+    }
+
+    /// Rotates after storing if the file's too big. Failing to is only logged, as what was stored
+    /// is in the file, and reporting it as not stored would have it sent again.
+    fn rotate_after_write(&mut self) {
+        if let Err(err) = self.rotate_if_too_big() {
+            error!(?err, path = ?self.path, "rotating database");
+        }
+    }
+
+    /// Opens a database file for rotating into, with the table of what open streams had before.
+    pub(super) fn open_file(
+        path: &std::path::Path,
+        schema: &SqliteSchema,
+        key: Option<&EncryptionKey>,
+        durability: &DurabilityArgs,
+    ) -> Result<rusqlite::Connection> {
+        let conn = open_sqlite(path, schema, key, durability)?;
+        conn.execute_batch(CARRIED_EVENTS)?;
+        Ok(conn)
+    }
+
+    fn path_with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    /// Moves the database aside with the time appended to its name, and carries on in a new one.
+    /// The new one's set up beside it first, and only swapped in once it's open, so failing leaves
+    /// the database in use as it was.
+    fn rotate_now(&mut self) -> Result<()> {
+        let rotated_path =
+            self.path_with_suffix(&Utc::now().format(".%Y%m%dT%H%M%S%.6fZ").to_string());
+        if rotated_path.exists() {
+            bail!("{} already exists", rotated_path.display());
+        }
+        info!(?rotated_path, "rotating sqlite database");
+        let new_path = self.path_with_suffix(".rotating");
+        if let Err(err) = self.create_next(&new_path) {
+            let _ = std::fs::remove_file(&new_path);
+            return Err(err.context(format!("creating {}", new_path.display())));
+        }
+        std::fs::rename(&self.path, &rotated_path)
+            .with_context(|| format!("renaming database to {}", rotated_path.display()))?;
+        let opened = std::fs::rename(&new_path, &self.path)
+            .with_context(|| format!("renaming {} to database", new_path.display()))
+            .and_then(|()| {
+                Self::open_file(
+                    &self.path,
+                    &self.schema,
+                    self.key.as_ref(),
+                    &self.durability,
+                )
+            });
+        let conn = match opened {
+            Ok(conn) => conn,
+            Err(err) => {
+                // The full database is still the one in use, so it goes back where it was.
+                if let Err(err) = std::fs::rename(&rotated_path, &self.path) {
+                    error!(?err, ?rotated_path, "moving database back");
+                }
+                let _ = std::fs::remove_file(&new_path);
+                return Err(err);
+            }
+        };
+        let full = std::mem::replace(&mut self.conn, conn);
+        if let Err((_, err)) = full.close() {
+            error!(?err, ?rotated_path, "closing rotated database");
+        }
+        Ok(())
+    }
+
+    /// Creates the database to rotate into, with what's carried over from the current one.
+    fn create_next(&self, new_path: &std::path::Path) -> Result<()> {
+        if new_path.exists() {
+            // Left by a rotation that didn't finish.
+            std::fs::remove_file(new_path)?;
+        }
+        let conn = Self::open_file(new_path, &self.schema, self.key.as_ref(), &self.durability)?;
+        conn.execute("attach ? as rotated", rusqlite::params![self.path.to_str()])?;
+        // Open streams carry on into the new file, and keep their IDs. The last stream's carried
+        // over too, so new streams' IDs follow on from it rather than reusing closed streams'.
+        conn.execute(
+            "\
+            insert into streams select * from rotated.streams \
+            where end_datetime is null \
+            or stream_id = (select max(stream_id) from rotated.streams)",
+            [],
+        )?;
+        // Their events stay behind, so their counts start again, like everything read here.
+        conn.execute(
+            "update streams set stored_event_count = 0, stored_payload_bytes = 0",
+            [],
+        )?;
+        conn.execute(
+            "\
+            insert into stream_labels select * from rotated.stream_labels \
+            where stream_id in (select stream_id from streams)",
+            [],
+        )?;
+        // What's needed of their events to resume them after the last one, and drop repeats.
+        conn.execute(
+            "\
+            insert into carried_events (stream_id, stream_event_index) \
+            select stream_id, max(stream_event_index) from ( \
+                select stream_id, stream_event_index from rotated.events \
+                union all select stream_id, stream_event_index from rotated.carried_events) \
+            where stream_id in (select stream_id from streams where end_datetime is null) \
+            group by stream_id",
+            [],
+        )?;
+        conn.execute(
+            "\
+            insert into carried_events (stream_id, stream_event_index, event_id) \
+            select stream_id, stream_event_index, event_id from ( \
+                select stream_id, stream_event_index, event_id from rotated.events \
+                union all \
+                select stream_id, stream_event_index, event_id from rotated.carried_events) \
+            where event_id is not null \
+            and stream_id in (select stream_id from streams where end_datetime is null)",
+            [],
+        )?;
+        // So requests sent just before rotating are still caught repeating after.
+        if let Some(since) = self.request_hashes_since {
+            conn.execute(
+                "\
+                insert into request_hashes select * from rotated.request_hashes \
+                where received_datetime >= ?",
+                rusqlite::params![text_datetime(since)],
+            )?;
+        }
+        conn.execute("detach rotated", [])?;
+        conn.close().map_err(|(_, err)| err)?;
+        Ok(())
+    }
+}
+
+/// The last index and event IDs of streams that were open when their earlier events were rotated
+/// into another file. Inserting an event with a carried ID is ignored, like any other repeat.
+const CARRIED_EVENTS: &str = "\
+    create table if not exists carried_events(\
+        stream_id integer not null, \
+        stream_event_index integer not null, \
+        event_id text) strict; \
+    create unique index if not exists carried_events_stream_event_id \
+        on carried_events(stream_id, event_id); \
+    create trigger if not exists events_carried_event_id before insert on events \
+    when new.event_id is not null and exists ( \
+        select 1 from carried_events \
+        where stream_id = new.stream_id and event_id = new.event_id) \
+    begin select raise(ignore); end;";
+
+#[async_trait]
+impl Connection for RotatingSqlite {
+    async fn new_stream(&mut self, headers: SerializedHeaders) -> Result<StreamId> {
+        self.blocking(|this| block_on(this.conn.new_stream(headers)))
+    }
+    async fn insert_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        payload: &RawValue,
+        event_id: Option<&str>,
+        collector: Option<&serde_json::Value>,
+        client_datetime: Option<DateTime<Utc>>,
+    ) -> Result<Inserted> {
+        self.blocking(|this| {
+            let inserted = block_on(this.conn.insert_event(
+                stream_id,
+                stream_event_index,
+                payload,
+                event_id,
+                collector,
+                client_datetime,
+            ))?;
+            this.rotate_after_write();
+            Ok(inserted)
+        })
+    }
+    async fn insert_events(
+        &mut self,
+        stream_id: StreamId,
+        events: &[NewEvent<'_>],
+    ) -> Result<Vec<Inserted>> {
+        self.blocking(|this| {
+            let inserted = block_on(this.conn.insert_events(stream_id, events))?;
+            this.rotate_after_write();
+            Ok(inserted)
+        })
+    }
+    async fn resume_stream(&mut self, stream_id: StreamId) -> Result<StreamEventIndex> {
+        self.blocking(|this| {
+            let resumed = block_on(this.conn.resume_stream(stream_id))?;
+            // Its events from before rotating are in the rotated files.
+            let carried: Option<StreamEventIndex> = this.conn.query_row(
+                "select max(stream_event_index) from carried_events where stream_id = ?",
+                rusqlite::params![stream_id],
+                |row| row.get(0),
+            )?;
+            Ok(resumed.max(carried.unwrap_or(0)))
+        })
+    }
+    async fn find_stream(&mut self, stream_uuid: StreamUuid) -> Result<StreamId> {
+        self.blocking(|this| block_on(this.conn.find_stream(stream_uuid)))
+    }
+    async fn record_clock_skew(
+        &mut self,
+        stream_id: StreamId,
+        clock_skew: chrono::TimeDelta,
+    ) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.record_clock_skew(stream_id, clock_skew)))
+    }
+    async fn record_sampling(
+        &mut self,
+        stream_id: StreamId,
+        sampling: &serde_json::Value,
+    ) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.record_sampling(stream_id, sampling)))
+    }
+    async fn update_stream_headers(
+        &mut self,
+        stream_id: StreamId,
+        patch: &serde_json::Value,
+    ) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.update_stream_headers(stream_id, patch)))
+    }
+    async fn set_stream_labels(
+        &mut self,
+        stream_id: StreamId,
+        labels: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.set_stream_labels(stream_id, labels)))
+    }
+    async fn record_anomaly(&mut self, anomaly: &StreamAnomaly) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.record_anomaly(anomaly)))
+    }
+    async fn close_stream(&mut self, stream_id: StreamId) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.close_stream(stream_id)))
+    }
+    async fn revise_event(
+        &mut self,
+        stream_id: StreamId,
+        stream_event_index: StreamEventIndex,
+        revision: EventRevision,
+        payload: &str,
+    ) -> Result<Revised> {
+        self.blocking(|this| {
+            block_on(
+                this.conn
+                    .revise_event(stream_id, stream_event_index, revision, payload),
+            )
+        })
+    }
+    async fn query_events(&mut self, query: &EventQuery) -> Result<Vec<serde_json::Value>> {
+        self.blocking(|this| block_on(this.conn.query_events(query)))
+    }
+    async fn snapshot(&mut self, query: &EventQuery) -> Result<Snapshot> {
+        self.blocking(|this| block_on(this.conn.snapshot(query)))
+    }
+    async fn recent_streams(
+        &mut self,
+        limit: usize,
+        labels: &[(String, String)],
+    ) -> Result<Vec<serde_json::Value>> {
+        self.blocking(|this| block_on(this.conn.recent_streams(limit, labels)))
+    }
+    async fn snapshot_batches(
+        &mut self,
+        query: &EventQuery,
+        batch_size: usize,
+        each: &mut (dyn FnMut(Snapshot) -> Result<()> + Send),
+    ) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.snapshot_batches(query, batch_size, each)))
+    }
+    async fn stats(&mut self) -> Result<StorageStats> {
+        self.blocking(|this| block_on(this.conn.stats()))
+    }
+    async fn save_link(&mut self, link_id: &str, query: &str) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.save_link(link_id, query)))
+    }
+    async fn load_link(&mut self, link_id: &str) -> Result<Option<String>> {
+        self.blocking(|this| block_on(this.conn.load_link(link_id)))
+    }
+    async fn find_request_hash(
+        &mut self,
+        hash: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        self.blocking(|this| {
+            this.request_hashes_since = Some(since);
+            block_on(this.conn.find_request_hash(hash, since))
+        })
+    }
+    async fn save_request_hash(&mut self, hash: &str) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.save_request_hash(hash)))
+    }
+    async fn merge_streams(&mut self, from: StreamId, into: StreamId) -> Result<u64> {
+        self.blocking(|this| block_on(this.conn.merge_streams(from, into)))
+    }
+    async fn split_stream(
+        &mut self,
+        stream_id: StreamId,
+        at: DateTime<Utc>,
+    ) -> Result<(StreamId, u64)> {
+        self.blocking(|this| block_on(this.conn.split_stream(stream_id, at)))
+    }
+    async fn prune(&mut self, policy: &RetentionPolicy) -> Result<Pruned> {
+        self.blocking(|this| block_on(this.conn.prune(policy)))
+    }
+    async fn mark_stale_streams(&mut self, quiet_since: DateTime<Utc>) -> Result<StaleStreams> {
+        self.blocking(|this| block_on(this.conn.mark_stale_streams(quiet_since)))
+    }
+    async fn roll_up(&mut self, rollup: &Rollup) -> Result<u64> {
+        self.blocking(|this| block_on(this.conn.roll_up(rollup)))
+    }
+    async fn read_rollup(
+        &mut self,
+        name: &str,
+        selection: &EventSelection,
+    ) -> Result<Vec<RollupRow>> {
+        self.blocking(|this| block_on(this.conn.read_rollup(name, selection)))
+    }
+    async fn read_series(&mut self, query: &SeriesQuery) -> Result<Vec<SeriesPoint>> {
+        self.blocking(|this| block_on(this.conn.read_series(query)))
+    }
+    async fn insert_metric_points(&mut self, points: &[MetricPoint]) -> Result<u64> {
+        self.blocking(|this| block_on(this.conn.insert_metric_points(points)))
+    }
+    async fn rewrite_events(
+        &mut self,
+        selection: &EventSelection,
+        rewrite: PayloadRewriter<'_>,
+    ) -> Result<u64> {
+        self.blocking(|this| block_on(this.conn.rewrite_events(selection, rewrite)))
+    }
+    async fn events_after(
+        &mut self,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<Vec<ImportedEvent>> {
+        self.blocking(|this| block_on(this.conn.events_after(cursor, limit)))
+    }
+    async fn streams_by_id(&mut self, stream_ids: &[StreamId]) -> Result<Vec<ImportedStream>> {
+        self.blocking(|this| block_on(this.conn.streams_by_id(stream_ids)))
+    }
+    async fn import_streams(&mut self, streams: &[ImportedStream]) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.import_streams(streams)))
+    }
+    async fn import_events(&mut self, events: &[ImportedEvent]) -> Result<u64> {
+        self.blocking(|this| {
+            let imported = block_on(this.conn.import_events(events))?;
+            this.rotate_after_write();
+            Ok(imported)
+        })
+    }
+    async fn create_partitions(&mut self) -> Result<u64> {
+        self.blocking(|this| block_on(this.conn.create_partitions()))
+    }
+    async fn begin_batch(&mut self) -> Result<bool> {
+        self.blocking(|this| {
+            this.in_batch = block_on(this.conn.begin_batch())?;
+            Ok(this.in_batch)
+        })
+    }
+    fn batches(&self) -> bool {
+        self.conn.batches()
+    }
+    async fn commit_batch(&mut self) -> Result<()> {
+        self.blocking(|this| {
+            block_on(this.conn.commit_batch())?;
+            this.in_batch = false;
+            this.rotate_after_write();
+            Ok(())
+        })
+    }
+    async fn rollback_batch(&mut self) -> Result<()> {
+        self.blocking(|this| {
+            block_on(this.conn.rollback_batch())?;
+            this.in_batch = false;
+            Ok(())
+        })
+    }
+    async fn flush(&mut self) -> Result<()> {
+        self.blocking(|this| {
+            if this.fsync.due() {
+                std::fs::File::open(&this.path)?
+                    .sync_all()
+                    .context("fsyncing database")?;
+            }
+            block_on(this.conn.flush())
+        })
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.commit()))
+    }
+    async fn rotate(&mut self) -> Result<()> {
+        if self.in_batch {
+            bail!("can't rotate while a batch is open");
+        }
+        self.blocking(Self::rotate_now)
+    }
+    async fn shutdown(&mut self) -> Result<()> {
+        self.blocking(|this| block_on(this.conn.shutdown()))
+    }
+    fn commit_on_sigint(&self) -> bool {
+        self.conn.commit_on_sigint()
+    }
+    fn compression_report(&self, limit: usize) -> Option<serde_json::Value> {
+        self.conn.compression_report(limit)
+    }
+    fn blocking_report(&self) -> Option<serde_json::Value> {
+        Some(self.blocked.to_json())
+    }
+}
@@ -0,0 +1,37 @@
+use super::*;
+use std::path::{Path, PathBuf};
+
+/// Where secrets named without a directory are looked for, in order: systemd's LoadCredential=
+/// directory, then where Docker and Compose mount secrets.
+fn secret_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(dir) = std::env::var_os("CREDENTIALS_DIRECTORY") {
+        dirs.push(PathBuf::from(dir));
+    }
+    dirs.push(PathBuf::from("/run/secrets"));
+    dirs
+}
+
+/// Where a secret file is. A bare name is looked for in [secret_dirs] before the current
+/// directory, and paths with a directory are taken as they are.
+pub(crate) fn secret_path(name: &str) -> PathBuf {
+    let path = Path::new(name);
+    if path.components().count() != 1 || path.is_absolute() {
+        return path.to_owned();
+    }
+    secret_dirs()
+        .into_iter()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+/// Reads a secret from a file found by [secret_path], without the line ending editors and `echo`
+/// leave on it.
+pub(crate) fn read_secret(name: &str) -> Result<String> {
+    let path = secret_path(name);
+    let secret = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading secret {}", path.display()))?;
+    let secret = secret.strip_suffix('\n').unwrap_or(&secret);
+    Ok(secret.strip_suffix('\r').unwrap_or(secret).to_owned())
+}
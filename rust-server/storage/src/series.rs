@@ -0,0 +1,235 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Selected events counted, or a numeric payload field aggregated, in buckets of insert time, for
+/// charting.
+#[derive(Clone, Debug)]
+pub struct SeriesQuery {
+    pub selection: EventSelection,
+    pub aggregate: SeriesAggregate,
+    /// Whole seconds.
+    pub bucket: Duration,
+}
+
+/// What a series' value is for each bucket.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SeriesAggregate {
+    Count,
+    /// Of a top-level payload field, over the events where it's a number.
+    Avg(String),
+    Sum(String),
+    Min(String),
+    Max(String),
+}
+
+/// A bucket of a series. Buckets without events, or without the field, aren't returned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeriesPoint {
+    pub bucket_start: DateTime<Utc>,
+    pub value: f64,
+}
+
+/// Parses `count`, or FUNCTION:FIELD, like `avg:latency_s`, where FUNCTION is avg, sum, min or
+/// max.
+impl FromStr for SeriesAggregate {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        if text == "count" {
+            return Ok(Self::Count);
+        }
+        let aggregate = match text.split_once(':') {
+            Some(("avg", field)) if !field.is_empty() => Self::Avg(field.to_owned()),
+            Some(("sum", field)) if !field.is_empty() => Self::Sum(field.to_owned()),
+            Some(("min", field)) if !field.is_empty() => Self::Min(field.to_owned()),
+            Some(("max", field)) if !field.is_empty() => Self::Max(field.to_owned()),
+            _ => bail!(
+                "series {:?} must be count, or avg, sum, min or max of a field, like avg:latency_s",
+                text
+            ),
+        };
+        Ok(aggregate)
+    }
+}
+
+impl Display for SeriesAggregate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.function_and_field() {
+            (_, None) => write!(f, "count"),
+            (function, Some(field)) => write!(f, "{}:{}", function, field),
+        }
+    }
+}
+
+impl SeriesAggregate {
+    /// The SQL aggregate function, and the field it's of.
+    fn function_and_field(&self) -> (&'static str, Option<&String>) {
+        match self {
+            Self::Count => ("count", None),
+            Self::Avg(field) => ("avg", Some(field)),
+            Self::Sum(field) => ("sum", Some(field)),
+            Self::Min(field) => ("min", Some(field)),
+            Self::Max(field) => ("max", Some(field)),
+        }
+    }
+
+    fn aggregate(&self, values: &[f64]) -> Option<f64> {
+        let folded = match self {
+            Self::Count | Self::Sum(_) => values.iter().sum(),
+            Self::Avg(_) => values.iter().sum::<f64>() / values.len() as f64,
+            Self::Min(_) => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Self::Max(_) => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        };
+        (!values.is_empty()).then_some(folded)
+    }
+}
+
+impl SeriesQuery {
+    fn bucket_secs(&self) -> Result<i64> {
+        if self.bucket.as_secs() == 0 || self.bucket.subsec_nanos() != 0 {
+            bail!("series bucket must be a whole number of seconds");
+        }
+        Ok(self.bucket.as_secs() as i64)
+    }
+}
+
+pub(crate) fn read_series_sqlite(
+    conn: &rusqlite::Connection,
+    query: &SeriesQuery,
+) -> Result<Vec<SeriesPoint>> {
+    let (since, until) = query.selection.text_bounds();
+    let bucket_secs = query.bucket_secs()?;
+    let (function, field) = query.aggregate.function_and_field();
+    let mut params: Vec<&dyn rusqlite::ToSql> =
+        vec![&query.selection.stream_id, &since, &until, &bucket_secs];
+    let value = match field {
+        None => "count(*)".to_owned(),
+        Some(field) => {
+            params.push(field);
+            format!(
+                "{}(case when json_type(payload -> ?5) in ('integer', 'real') \
+                then payload ->> ?5 end)",
+                function
+            )
+        }
+    };
+    let mut stmt = conn.prepare(&format!(
+        "select datetime(unixepoch(insert_datetime) / ?4 * ?4, 'unixepoch') as bucket, \
+            {} as value \
+        from events \
+        where (?1 is null or stream_id = ?1) \
+            and (?2 is null or insert_datetime >= ?2) \
+            and (?3 is null or insert_datetime < ?3) \
+        group by bucket having value is not null order by bucket",
+        value
+    ))?;
+    let points = stmt
+        .query_map(params.as_slice(), |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?))
+        })?
+        .map(|row| {
+            let (bucket_start, value) = row?;
+            Ok(SeriesPoint {
+                bucket_start: parse_datetime(&bucket_start)?,
+                value,
+            })
+        })
+        .collect();
+    points
+}
+
+pub(crate) async fn read_series_postgres(
+    client: &Client,
+    query: &SeriesQuery,
+) -> Result<Vec<SeriesPoint>> {
+    let (since, until) = query.selection.naive_bounds();
+    let stream_id = query
+        .selection
+        .stream_id
+        .map(|stream_id| stream_id.0 as i32);
+    let bucket_secs = query.bucket_secs()?;
+    let (function, field) = query.aggregate.function_and_field();
+    let mut params: Vec<PostgresParam> = vec![
+        Box::new(stream_id),
+        Box::new(since),
+        Box::new(until),
+        Box::new(bucket_secs),
+    ];
+    let value = match field {
+        None => "count(*)::float8".to_owned(),
+        Some(field) => {
+            params.push(Box::new(field.clone()));
+            format!(
+                "{}(CASE WHEN jsonb_typeof(payload->$5::text) = 'number' \
+                THEN (payload->>$5::text)::float8 END)::float8",
+                function
+            )
+        }
+    };
+    let params: Vec<_> = params.iter().map(|param| param.as_ref() as _).collect();
+    let rows = client
+        .query(
+            &format!(
+                "SELECT * FROM (SELECT to_timestamp(floor(extract(epoch FROM insert_datetime) \
+                    / $4::bigint) * $4::bigint) AT TIME ZONE 'UTC' AS bucket, {} AS value \
+                FROM events \
+                WHERE ($1::integer IS NULL OR stream_id = $1) \
+                AND ($2::timestamp IS NULL OR insert_datetime >= $2) \
+                AND ($3::timestamp IS NULL OR insert_datetime < $3) \
+                GROUP BY bucket) AS series \
+                WHERE value IS NOT NULL ORDER BY bucket",
+                value
+            ),
+            &params,
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| SeriesPoint {
+            bucket_start: row.get::<_, NaiveDateTime>(0).and_utc(),
+            value: row.get(1),
+        })
+        .collect())
+}
+
+/// For storage that has its events in memory.
+pub(crate) fn series_of<'a>(
+    events: impl Iterator<Item = &'a ImportedEvent>,
+    query: &SeriesQuery,
+) -> Result<Vec<SeriesPoint>> {
+    let bucket_secs = query.bucket_secs()?;
+    let (_, field) = query.aggregate.function_and_field();
+    let selection = &query.selection;
+    let mut buckets: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+    for event in events.filter(|event| {
+        selection
+            .stream_id
+            .is_none_or(|stream_id| event.stream_id == stream_id)
+            && selection
+                .since
+                .is_none_or(|since| event.insert_datetime >= since)
+            && selection
+                .until
+                .is_none_or(|until| event.insert_datetime < until)
+    }) {
+        let value = match field {
+            None => Some(1.0),
+            Some(field) => event.payload.get(field).and_then(|value| value.as_f64()),
+        };
+        let bucket = event.insert_datetime.timestamp().div_euclid(bucket_secs) * bucket_secs;
+        let values = buckets.entry(bucket).or_default();
+        values.extend(value);
+    }
+    Ok(buckets
+        .into_iter()
+        .filter_map(|(bucket, values)| {
+            Some(SeriesPoint {
+                bucket_start: DateTime::from_timestamp(bucket, 0)?,
+                value: query.aggregate.aggregate(&values)?,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,130 @@
+use anyhow::{bail, Context};
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Let's see if u32 is enough. Newtype for nicer formatting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StreamId(pub u32);
+
+impl Display for StreamId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl Deref for StreamId {
+    type Target = u32;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl rusqlite::types::FromSql for StreamId {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        Ok(Self(rusqlite::types::FromSql::column_result(value)?))
+    }
+}
+
+impl rusqlite::types::ToSql for StreamId {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        rusqlite::types::ToSql::to_sql(&self.0)
+    }
+}
+
+impl duckdb::types::FromSql for StreamId {
+    fn column_result(value: duckdb::types::ValueRef<'_>) -> duckdb::types::FromSqlResult<Self> {
+        Ok(Self(duckdb::types::FromSql::column_result(value)?))
+    }
+}
+
+impl duckdb::types::ToSql for StreamId {
+    fn to_sql(&self) -> duckdb::Result<duckdb::types::ToSqlOutput<'_>> {
+        duckdb::types::ToSql::to_sql(&self.0)
+    }
+}
+
+/// A UUIDv7 given to each stream as it starts. Unlike [StreamId]s, which come from a database
+/// sequence, or at random for JSON files, they don't collide between servers or restarts, and they
+/// sort by when streams started. Streams from before they were added only have a [StreamId].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamUuid(pub u128);
+
+impl StreamUuid {
+    /// Milliseconds since the Unix epoch in the top 48 bits, then the version and variant, with
+    /// random bits in between and after.
+    pub fn now_v7() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let random: u128 = rand::random();
+        Self(
+            ((millis & 0xffff_ffff_ffff) << 80)
+                | (0x7 << 76)
+                | (((random >> 64) & 0xfff) << 64)
+                | (0b10 << 62)
+                | (random & ((1 << 62) - 1)),
+        )
+    }
+}
+
+impl Display for StreamUuid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+impl FromStr for StreamUuid {
+    type Err = anyhow::Error;
+
+    /// Hyphens are optional, as Postgres accepts.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let hex: String = s.chars().filter(|c| *c != '-').collect();
+        if hex.len() != 32 {
+            bail!("stream uuid {:?} should have 32 hex digits", s);
+        }
+        Ok(Self(
+            u128::from_str_radix(&hex, 16)
+                .with_context(|| format!("parsing stream uuid {:?}", s))?,
+        ))
+    }
+}
+
+impl serde::Serialize for StreamUuid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl rusqlite::types::FromSql for StreamUuid {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err: anyhow::Error| rusqlite::types::FromSqlError::Other(err.into()))
+    }
+}
+
+impl rusqlite::types::ToSql for StreamUuid {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(self.to_string().into())
+    }
+}
+
+impl duckdb::types::ToSql for StreamUuid {
+    fn to_sql(&self) -> duckdb::Result<duckdb::types::ToSqlOutput<'_>> {
+        Ok(self.to_string().into())
+    }
+}
@@ -0,0 +1,971 @@
+use super::*;
+use clap::Parser;
+
+/// A payload as storage takes it.
+fn raw(json: &str) -> &RawValue {
+    serde_json::from_str(json).expect("test payload is json")
+}
+
+/// A program embedding the storage, with its own arguments.
+#[derive(clap::Parser)]
+struct EmbeddingArgs {
+    #[arg(long)]
+    name: String,
+    #[command(flatten)]
+    storage: SqliteOpen,
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_embedded_storage_open() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("embedded.db");
+    let args = EmbeddingArgs::try_parse_from([
+        "embedding".as_ref(),
+        "--name".as_ref(),
+        "test".as_ref(),
+        "--db-path".as_ref(),
+        db_path.as_os_str(),
+    ])?;
+    assert_eq!(args.name, "test");
+    let opener: &dyn StorageOpen<Conn = RotatingSqlite> = &args.storage;
+    assert_eq!(opener.info()["backend"], "sqlite");
+    opener.check().await?;
+    let mut conn = opener.open_boxed().await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    conn.insert_event(stream_id, 1, raw("{}"), None, None, None)
+        .await?;
+    conn.shutdown().await?;
+    drop(conn);
+    assert_eq!(opener.open().await?.stats().await?.events, 1);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rotating_sqlite_rotate() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("embedded.db");
+    let args = EmbeddingArgs::try_parse_from([
+        "embedding".as_ref(),
+        "--name".as_ref(),
+        "test".as_ref(),
+        "--db-path".as_ref(),
+        db_path.as_os_str(),
+    ])?;
+    let mut conn = args.storage.open().await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    conn.insert_event(stream_id, 1, raw("{}"), Some("a"), None, None)
+        .await?;
+    let closed = conn.new_stream(json!({})).await?;
+    conn.close_stream(closed).await?;
+    let last = conn.new_stream(json!({})).await?;
+    conn.close_stream(last).await?;
+    conn.rotate().await?;
+    // The stream carries on in the new file, without its events, but after them.
+    assert_eq!(conn.resume_stream(stream_id).await?, 1);
+    conn.insert_event(stream_id, 2, raw("{}"), None, None, None)
+        .await?;
+    assert_eq!(conn.stats().await?.events, 1);
+    // Event IDs from before still catch repeats, through later rotations too.
+    conn.rotate().await?;
+    assert_eq!(conn.resume_stream(stream_id).await?, 2);
+    assert!(matches!(
+        conn.insert_event(stream_id, 3, raw("{}"), Some("a"), None, None)
+            .await?,
+        Inserted::Duplicate
+    ));
+    assert_eq!(conn.stats().await?.events, 0);
+    // Closed streams are left behind, except the last, which new streams' IDs follow on from.
+    assert!(conn.resume_stream(closed).await.is_err());
+    assert!(conn.new_stream(json!({})).await?.0 > last.0);
+    let rotated = std::fs::read_dir(dir.path())?
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with("embedded.db.20")
+        })
+        .count();
+    assert_eq!(rotated, 2);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rotating_sqlite_batch() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("embedded.db");
+    let args = EmbeddingArgs::try_parse_from([
+        "embedding".as_ref(),
+        "--name".as_ref(),
+        "test".as_ref(),
+        "--db-path".as_ref(),
+        db_path.as_os_str(),
+    ])?;
+    let mut conn = args.storage.open_boxed().await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    assert!(conn.begin_batch().await?);
+    conn.insert_event(stream_id, 1, raw("{}"), None, None, None)
+        .await?;
+    // Not while the batch's transaction is open.
+    assert!(conn.rotate().await.is_err());
+    conn.rollback_batch().await?;
+    assert_eq!(conn.stats().await?.events, 0);
+    assert!(conn.begin_batch().await?);
+    conn.insert_event(stream_id, 1, raw("{}"), None, None, None)
+        .await?;
+    conn.insert_event(stream_id, 2, raw("{}"), None, None, None)
+        .await?;
+    conn.commit_batch().await?;
+    conn.shutdown().await?;
+    drop(conn);
+    assert_eq!(args.storage.open().await?.stats().await?.events, 2);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_storage_layer() -> anyhow::Result<()> {
+    use tracing_subscriber::layer::SubscriberExt;
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("traces.db");
+    let args = EmbeddingArgs::try_parse_from([
+        "embedding".as_ref(),
+        "--name".as_ref(),
+        "test".as_ref(),
+        "--db-path".as_ref(),
+        db_path.as_os_str(),
+    ])?;
+    let conn = args.storage.open_boxed().await?;
+    let (layer, writer) = StorageLayer::new(conn, json!({"tool": "test"}), 10);
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("work").entered();
+        // Events from this crate are ignored, tests included.
+        tracing::info!(target: "app", answer = 42, "hello");
+        tracing::info!("ignored");
+        tracing::warn!(target: "app", "done");
+    });
+    writer.flush().await?;
+    writer.close().await?;
+
+    let mut conn = args.storage.open().await?;
+    let events = conn
+        .query_events(&EventQuery {
+            limit: 10,
+            ..Default::default()
+        })
+        .await?;
+    let payloads: Vec<_> = events.iter().map(|event| &event["payload"]).collect();
+    assert_eq!(payloads.len(), 2);
+    assert_eq!(
+        payloads[0]["fields"],
+        json!({"message": "hello", "answer": 42})
+    );
+    assert_eq!(payloads[0]["spans"], json!(["work"]));
+    assert_eq!(payloads[1]["level"], "WARN");
+    let stats = conn.stats().await?;
+    assert_eq!((stats.streams, stats.ended_streams), (1, 1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_prune() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let old_stream_id = conn.new_stream(json!({})).await?;
+    for index in 1..=2 {
+        conn.insert_event(old_stream_id, index, raw("{}"), None, None, None)
+            .await?;
+    }
+    conn.close_stream(old_stream_id).await?;
+    // Still open, so it outlives its events.
+    let quiet_stream_id = conn.new_stream(json!({})).await?;
+    conn.insert_event(quiet_stream_id, 1, raw("{}"), None, None, None)
+        .await?;
+    conn.execute(
+        "update events set insert_datetime = '2000-01-01 00:00:00'",
+        [],
+    )?;
+    conn.execute(
+        "update streams set start_datetime = '2000-01-01 00:00:00'",
+        [],
+    )?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    for index in 1..=3 {
+        conn.insert_event(stream_id, index, raw("{}"), None, None, None)
+            .await?;
+    }
+    let policy = RetentionPolicy {
+        before: Some("2001-01-01T00:00:00Z".parse()?),
+        max_events: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(
+        conn.prune(&policy).await?,
+        Pruned {
+            events: 4,
+            streams: 1,
+            files: 0,
+            partitions: 0
+        }
+    );
+    let remaining = conn
+        .prepare("select stream_id, stream_event_index from events order by rowid")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(StreamId, u64)>>>()?;
+    assert_eq!(remaining, [(stream_id, 2), (stream_id, 3)]);
+    let streams = conn
+        .prepare("select stream_id from streams order by stream_id")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<StreamId>>>()?;
+    assert_eq!(streams, [quiet_stream_id, stream_id]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_merge_and_split_streams() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let first = conn.new_stream(json!({"device": "a"})).await?;
+    let second = conn.new_stream(json!({"device": "a"})).await?;
+    for index in 1..=2 {
+        conn.insert_event(first, index, raw("{}"), None, None, None)
+            .await?;
+        conn.insert_event(second, index, raw("{}"), None, None, None)
+            .await?;
+    }
+    assert_eq!(conn.merge_streams(second, first).await?, 2);
+    let indexes = |conn: &rusqlite::Connection| -> rusqlite::Result<Vec<(StreamId, u64)>> {
+        conn.prepare("select stream_id, stream_event_index from events order by rowid")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    };
+    assert_eq!(
+        indexes(&conn)?,
+        [(first, 1), (first, 3), (first, 2), (first, 4)]
+    );
+    conn.resume_stream(second)
+        .await
+        .expect_err("merged stream should be gone");
+
+    conn.execute(
+        "update events set insert_datetime = '2000-01-01 00:00:00' where stream_event_index <= 2",
+        [],
+    )?;
+    let (third, moved) = conn
+        .split_stream(first, "2001-01-01T00:00:00Z".parse()?)
+        .await?;
+    assert_eq!(moved, 2);
+    assert_eq!(
+        indexes(&conn)?,
+        [(first, 1), (third, 1), (first, 2), (third, 2)]
+    );
+    let operations = conn
+        .prepare("select operation from audit_log order by rowid")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    assert_eq!(operations, ["merge_streams", "split_stream"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_stream_stats() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let first = conn.new_stream(json!({})).await?;
+    let second = conn.new_stream(json!({})).await?;
+    for index in 1..=3 {
+        let payload = json!({ "n": index }).to_string();
+        conn.insert_event(first, index, raw(&payload), None, None, None)
+            .await?;
+        conn.insert_event(second, index, raw("{}"), None, None, None)
+            .await?;
+    }
+    // The counts match counting the events, as they change.
+    let check = |conn: &rusqlite::Connection| -> anyhow::Result<u64> {
+        let stream: serde_json::Value = conn.query_row(
+            &format!(
+                "select {} from streams where stream_id = ?",
+                SQLITE_STREAM_OBJECT
+            ),
+            [first],
+            |row| row.get(0),
+        )?;
+        let (count, bytes, last): (u64, u64, String) = conn.query_row(
+            "select count(*), sum(length(payload)), max(insert_datetime) from events \
+            where stream_id = ?",
+            [first],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!(stream["stored_payload_bytes"], bytes);
+        assert_eq!(stream["last_insert_datetime"], last);
+        assert_eq!(stream["stored_event_count"], count);
+        Ok(count)
+    };
+    assert_eq!(check(&conn)?, 3);
+    conn.merge_streams(second, first).await?;
+    assert_eq!(check(&conn)?, 6);
+    let policy = RetentionPolicy {
+        max_events: Some(4),
+        ..Default::default()
+    };
+    conn.prune(&policy).await?;
+    assert_eq!(check(&conn)?, 4);
+    conn.close_stream(first).await?;
+    let event_count: u64 = conn.query_row(
+        "select event_count from streams where stream_id = ?",
+        [first],
+        |row| row.get(0),
+    )?;
+    assert_eq!(event_count, 4);
+    Ok(())
+}
+
+#[test]
+fn test_stream_uuid() -> anyhow::Result<()> {
+    let first = StreamUuid::now_v7();
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let second = StreamUuid::now_v7();
+    assert!(first < second);
+    let text = first.to_string();
+    assert_eq!(text.len(), 36);
+    assert_eq!(&text[14..15], "7");
+    assert!("89ab".contains(&text[19..20]), "{}", text);
+    assert_eq!(text.parse::<StreamUuid>()?, first);
+    assert_eq!(text.replace('-', "").parse::<StreamUuid>()?, first);
+    assert_eq!(serde_json::to_value(first)?, json!(text));
+    assert!("not-a-uuid".parse::<StreamUuid>().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_read_secret() -> anyhow::Result<()> {
+    use secrets::read_secret;
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("db-password"), "hunter2\r\n")?;
+    std::fs::write(dir.path().join("conn-str"), "host=db\n\n")?;
+    // Bare names are looked for where systemd puts credentials.
+    std::env::set_var("CREDENTIALS_DIRECTORY", dir.path());
+    assert_eq!(read_secret("db-password")?, "hunter2");
+    // Only the one line ending is taken off.
+    let path = dir.path().join("conn-str");
+    assert_eq!(read_secret(path.to_str().unwrap())?, "host=db\n");
+    assert!(read_secret("missing").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_postgres_tls_params() -> anyhow::Result<()> {
+    use postgres_tls::{take_tls_params, TlsParams};
+    assert_eq!(
+        take_tls_params(
+            "postgres://db/telemetry?sslmode=verify-full&sslcert=%2Fcerts%2Fclient.crt\
+            &application_name=t&sslkey=/certs/client.key"
+        )?,
+        (
+            "postgres://db/telemetry?sslmode=require&application_name=t".to_owned(),
+            TlsParams {
+                ssl_mode: Some(SslMode::VerifyFull),
+                cert: Some("/certs/client.crt".to_owned()),
+                key: Some("/certs/client.key".to_owned()),
+                ..Default::default()
+            }
+        )
+    );
+    assert_eq!(
+        take_tls_params("host=db sslmode = 'verify-ca' sslrootcert='/my certs/ca.crt' user=t")?,
+        (
+            "host=db sslmode=require user=t".to_owned(),
+            TlsParams {
+                ssl_mode: Some(SslMode::VerifyCa),
+                root_cert: Some("/my certs/ca.crt".to_owned()),
+                ..Default::default()
+            }
+        )
+    );
+    assert_eq!(
+        take_tls_params("postgres://db/telemetry?sslmode=prefer")?,
+        (
+            "postgres://db/telemetry?sslmode=prefer".to_owned(),
+            TlsParams {
+                ssl_mode: Some(SslMode::Prefer),
+                ..Default::default()
+            }
+        )
+    );
+    // Only the keys themselves count, not values that look like them.
+    assert_eq!(
+        take_tls_params("host=db password='sslmode=disable x\\'y'")?,
+        (
+            "host=db password='sslmode=disable x\\'y'".to_owned(),
+            TlsParams::default()
+        )
+    );
+    assert!(take_tls_params("host=db sslmode=allow").is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_mark_stale_streams() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let quiet = conn.new_stream(json!({})).await?;
+    let active = conn.new_stream(json!({})).await?;
+    let closed = conn.new_stream(json!({})).await?;
+    conn.insert_event(active, 1, raw("{}"), None, None, None)
+        .await?;
+    conn.close_stream(closed).await?;
+    conn.execute(
+        "update streams set start_datetime = '2000-01-01 00:00:00'",
+        [],
+    )?;
+    let quiet_since = chrono::Utc::now() - chrono::Duration::minutes(10);
+    let stale = |conn: &rusqlite::Connection| -> rusqlite::Result<Vec<StreamId>> {
+        conn.prepare("select stream_id from streams where stale_datetime is not null")?
+            .query_map([], |row| row.get(0))?
+            .collect()
+    };
+    assert_eq!(
+        conn.mark_stale_streams(quiet_since).await?,
+        StaleStreams {
+            marked: 1,
+            revived: 0
+        }
+    );
+    assert_eq!(stale(&conn)?, [quiet]);
+    // Marking again changes nothing, until the quiet stream has an event.
+    assert_eq!(
+        conn.mark_stale_streams(quiet_since).await?,
+        StaleStreams::default()
+    );
+    conn.insert_event(quiet, 1, raw("{}"), None, None, None)
+        .await?;
+    assert_eq!(
+        conn.mark_stale_streams(quiet_since).await?,
+        StaleStreams {
+            marked: 0,
+            revived: 1
+        }
+    );
+    assert!(stale(&conn)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_encrypt_file() -> anyhow::Result<()> {
+    use std::io::Read;
+    let dir = tempfile::tempdir()?;
+    let key = EncryptionKey::from_hex(&"ab".repeat(32))?;
+    let decrypt = |path: &std::path::Path, key: &EncryptionKey| -> anyhow::Result<Vec<u8>> {
+        let mut plaintext = vec![];
+        DecryptingReader::new(key, std::fs::File::open(path)?)?.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    };
+    // Empty, exactly one chunk, and more than one.
+    for len in [0, 64 << 10, 150_000] {
+        let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let path = dir.path().join(format!("{}.json.zst", len));
+        std::fs::write(&path, &contents)?;
+        let encrypted = encryption::encrypt_file(&key, &path)?;
+        assert!(!path.exists());
+        assert_eq!(encrypted, dir.path().join(format!("{}.json.zst.enc", len)));
+        assert_eq!(decrypt(&encrypted, &key)?, contents);
+    }
+
+    let encrypted = dir.path().join("150000.json.zst.enc");
+    let other_key = EncryptionKey::from_hex(&"cd".repeat(32))?;
+    decrypt(&encrypted, &other_key).expect_err("wrong key");
+    let mut sealed = std::fs::read(&encrypted)?;
+    sealed.truncate(sealed.len() / 2);
+    std::fs::write(&encrypted, &sealed)?;
+    decrypt(&encrypted, &key).expect_err("cut short");
+    assert!(EncryptionKey::from_hex("abcd").is_err());
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+#[tokio::test]
+async fn test_sqlite_encryption_needs_sqlcipher() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("encrypted.db");
+    let key_command = format!("echo {}", "ab".repeat(32));
+    let args = EmbeddingArgs::try_parse_from([
+        "embedding".as_ref(),
+        "--name".as_ref(),
+        "test".as_ref(),
+        "--db-path".as_ref(),
+        db_path.as_os_str(),
+        "--encryption-key-command".as_ref(),
+        key_command.as_ref(),
+    ])?;
+    let err = args.storage.open().await.err().expect("plain SQLite");
+    assert!(format!("{:#}", err).contains("sqlcipher"), "{:#}", err);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_durability() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("durability.db");
+    let parse = |durability: Option<&str>| {
+        let mut args = vec![
+            "embedding",
+            "--name",
+            "test",
+            "--db-path",
+            db_path.to_str().unwrap(),
+        ];
+        args.extend(
+            durability
+                .map(|durability| ["--durability", durability])
+                .into_iter()
+                .flatten(),
+        );
+        EmbeddingArgs::try_parse_from(args)
+    };
+    // SQLite's default is full.
+    for (durability, synchronous) in [
+        (None, 2),
+        (Some("fsync-every-commit"), 2),
+        (Some("fsync-interval"), 0),
+        (Some("os-buffered"), 0),
+    ] {
+        let mut conn = parse(durability)?.storage.open().await?;
+        let stream_id = conn.new_stream(json!({})).await?;
+        conn.insert_event(stream_id, 1, raw("{}"), None, None, None)
+            .await?;
+        conn.flush().await?;
+        let set: i64 = conn
+            .conn
+            .pragma_query_value(None, "synchronous", |row| row.get(0))?;
+        assert_eq!(set, synchronous, "{:?}", durability);
+    }
+    assert_eq!(
+        parse(Some("fsync-interval"))?.storage.info()["durability"],
+        "each event committed, fsynced every 1s"
+    );
+    assert!(parse(Some("sometimes")).is_err());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_extract_field() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("extracted.db");
+    let parse = |pointer: &str| {
+        EmbeddingArgs::try_parse_from([
+            "embedding".as_ref(),
+            "--name".as_ref(),
+            "test".as_ref(),
+            "--db-path".as_ref(),
+            db_path.as_os_str(),
+            "--extract-field".as_ref(),
+            format!("level={}", pointer).as_ref(),
+        ])
+    };
+    let args = parse("/level")?;
+    assert_eq!(
+        args.storage.info()["extracted_fields"],
+        json!({"level": "/level"})
+    );
+    let mut conn = args.storage.open().await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    let payload = r#"{"level": "warn", "context": {"level": "debug"}}"#;
+    conn.insert_event(stream_id, 1, raw(payload), None, None, None)
+        .await?;
+    conn.shutdown().await?;
+    drop(conn);
+    let level = |db: &rusqlite::Connection| -> rusqlite::Result<String> {
+        db.query_row("select level from events", [], |row| row.get(0))
+    };
+    let db = rusqlite::Connection::open(&db_path)?;
+    assert_eq!(level(&db)?, "warn");
+    let plan: String = db.query_row(
+        "explain query plan select * from events where level = 'warn'",
+        [],
+        |row| row.get(3),
+    )?;
+    assert!(plan.contains("events_extracted_level"), "{}", plan);
+    drop(db);
+
+    // A changed pointer replaces the column.
+    parse("/context/level")?
+        .storage
+        .open()
+        .await?
+        .shutdown()
+        .await?;
+    let db = rusqlite::Connection::open(&db_path)?;
+    assert_eq!(level(&db)?, "debug");
+
+    // Columns of the schema can't be taken.
+    let args = EmbeddingArgs::try_parse_from([
+        "embedding".as_ref(),
+        "--name".as_ref(),
+        "test".as_ref(),
+        "--db-path".as_ref(),
+        db_path.as_os_str(),
+        "--extract-field".as_ref(),
+        "payload=/payload".as_ref(),
+    ])?;
+    assert!(args.storage.open().await.is_err());
+    assert!("Level=/level".parse::<ExtractedField>().is_err());
+    assert!("level".parse::<ExtractedField>().is_err());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_json_index() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("indexed.db");
+    let parse = |paths: &[&str]| {
+        let mut argv = vec![
+            "embedding".into(),
+            "--name".into(),
+            "test".into(),
+            "--db-path".into(),
+            db_path.clone().into_os_string(),
+        ];
+        for path in paths {
+            argv.extend(["--json-index".into(), path.into()]);
+        }
+        EmbeddingArgs::try_parse_from(argv)
+    };
+    let plan = |path: &str| -> anyhow::Result<String> {
+        let db = rusqlite::Connection::open(&db_path)?;
+        Ok(db.query_row(
+            &format!(
+                "explain query plan select * from events where json_extract(payload, '{}') = 1",
+                path
+            ),
+            [],
+            |row| row.get(3),
+        )?)
+    };
+    let args = parse(&["$.level", "$.device.id"])?;
+    assert_eq!(
+        args.storage.info()["json_indexes"],
+        json!(["$.level", "$.device.id"])
+    );
+    args.storage.open().await?.shutdown().await?;
+    assert!(plan("$.level")?.contains("events_json_level"));
+    assert!(plan("$.device.id")?.contains("events_json_device_id"));
+
+    // A path with the same name replaces the index, and others are left.
+    parse(&["$.device_id"])?
+        .storage
+        .open()
+        .await?
+        .shutdown()
+        .await?;
+    assert!(plan("$.device_id")?.contains("events_json_device_id"));
+    assert!(!plan("$.device.id")?.contains("events_json_device_id"));
+    assert!(plan("$.level")?.contains("events_json_level"));
+
+    assert!(parse(&["$.a.b", "$.a_b"])?.storage.open().await.is_err());
+    assert!(parse(&["level"]).is_err());
+    Ok(())
+}
+
+/// A rollup's rows as "HH:MM group count".
+async fn rollup_rows(conn: &mut rusqlite::Connection, name: &str) -> anyhow::Result<Vec<String>> {
+    Ok(conn
+        .read_rollup(name, &Default::default())
+        .await?
+        .iter()
+        .map(|row| {
+            let group = row.group.as_deref().unwrap_or("-");
+            format!(
+                "{} {} {}",
+                row.bucket_start.format("%H:%M"),
+                group,
+                row.count
+            )
+        })
+        .collect())
+}
+
+#[tokio::test]
+async fn test_sqlite_rollups() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    for (index, level) in [(1, "error"), (2, "info"), (3, "error")] {
+        let payload = json!({ "level": level }).to_string();
+        conn.insert_event(stream_id, index, raw(&payload), None, None, None)
+            .await?;
+    }
+    conn.execute(
+        "update events set insert_datetime = format('2024-01-01 00:0%d:30', stream_event_index / 3)",
+        [],
+    )?;
+    let per_stream: Rollup = "per_stream=stream".parse()?;
+    let levels: Rollup = "levels=payload.level/1h".parse()?;
+    assert_eq!(conn.roll_up(&per_stream).await?, 2);
+    assert_eq!(conn.roll_up(&levels).await?, 2);
+    let stream = stream_id.0;
+    assert_eq!(
+        rollup_rows(&mut conn, "per_stream").await?,
+        [format!("00:00 {} 2", stream), format!("00:01 {} 1", stream)]
+    );
+    assert_eq!(
+        rollup_rows(&mut conn, "levels").await?,
+        ["00:00 error 2", "00:00 info 1"]
+    );
+
+    // Only the latest bucket is counted again, for events inserted in it since.
+    conn.insert_event(stream_id, 4, raw("{}"), None, None, None)
+        .await?;
+    conn.execute(
+        "update events set insert_datetime = '2024-01-01 00:01:45' where stream_event_index = 4",
+        [],
+    )?;
+    conn.execute("delete from events where stream_event_index = 1", [])?;
+    assert_eq!(conn.roll_up(&per_stream).await?, 1);
+    assert_eq!(
+        rollup_rows(&mut conn, "per_stream").await?,
+        [format!("00:00 {} 2", stream), format!("00:01 {} 2", stream)]
+    );
+    let selection = EventSelection {
+        since: Some("2024-01-01T00:01:00Z".parse()?),
+        ..Default::default()
+    };
+    assert_eq!(conn.read_rollup("per_stream", &selection).await?.len(), 1);
+
+    // Changing a rollup counts it all again.
+    let levels: Rollup = "levels=payload.level/1m".parse()?;
+    conn.roll_up(&levels).await?;
+    assert_eq!(
+        rollup_rows(&mut conn, "levels").await?,
+        ["00:00 info 1", "00:01 - 1", "00:01 error 1"]
+    );
+    assert!("levels=payload".parse::<Rollup>().is_err());
+    assert!("levels=stream/1ms".parse::<Rollup>().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_event_cursor() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let first = conn.new_stream(json!({})).await?;
+    let second = conn.new_stream(json!({})).await?;
+    for index in 1..=3 {
+        for stream_id in [first, second] {
+            let payload = json!({ "n": index }).to_string();
+            conn.insert_event(stream_id, index, raw(&payload), None, None, None)
+                .await?;
+        }
+    }
+    // Events inserted in the same second are ordered by stream, then index.
+    conn.execute(
+        "update events set insert_datetime = '2024-01-01 00:00:00'",
+        [],
+    )?;
+    let mut query = EventQuery {
+        limit: 4,
+        ..Default::default()
+    };
+    let page = conn.query_events(&query).await?;
+    assert_eq!(page.len(), 4);
+    query.after = Some(EventCursor::after_json(&page[3])?);
+    let rest = conn.query_events(&query).await?;
+    let keys: Vec<_> = page
+        .iter()
+        .chain(&rest)
+        .map(|event| (event["stream_id"].clone(), event["payload"]["n"].clone()))
+        .collect();
+    let recent = conn.recent_streams(1, &[]).await?;
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0]["stream_id"], second.0);
+    assert_eq!(
+        keys,
+        [
+            (json!(first.0), json!(1)),
+            (json!(first.0), json!(2)),
+            (json!(first.0), json!(3)),
+            (json!(second.0), json!(1)),
+            (json!(second.0), json!(2)),
+            (json!(second.0), json!(3)),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_full_text_search() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("search.db");
+    let open = |full_text: bool| {
+        let mut args = vec![
+            "embedding".as_ref(),
+            "--name".as_ref(),
+            "test".as_ref(),
+            "--db-path".as_ref(),
+            db_path.as_os_str(),
+        ];
+        if full_text {
+            args.push("--full-text-search".as_ref());
+        }
+        EmbeddingArgs::try_parse_from(args)
+    };
+    let search = |text: &str| EventQuery {
+        limit: 10,
+        text: Some(text.to_owned()),
+        ..Default::default()
+    };
+
+    // Events from before the index are indexed when it's created.
+    let mut conn = open(false)?.storage.open().await?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    conn.insert_event(
+        stream_id,
+        1,
+        raw(r#"{"msg": "disk full"}"#),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    assert!(conn.query_events(&search("disk")).await.is_err());
+    drop(conn);
+    let mut conn = open(true)?.storage.open().await?;
+    conn.insert_event(stream_id, 2, raw(r#"{"msg": "Disk ok"}"#), None, None, None)
+        .await?;
+    conn.insert_event(
+        stream_id,
+        3,
+        raw(r#"{"msg": "fan \"failed\""}"#),
+        None,
+        None,
+        None,
+    )
+    .await?;
+    let indexes = |events: Vec<serde_json::Value>| -> Vec<_> {
+        events
+            .iter()
+            .map(|event| event["stream_event_index"].clone())
+            .collect()
+    };
+    assert_eq!(indexes(conn.query_events(&search("disk")).await?), [1, 2]);
+    assert_eq!(indexes(conn.query_events(&search("DISK full")).await?), [1]);
+    // Query syntax is taken as words.
+    assert_eq!(indexes(conn.query_events(&search("\"failed")).await?), [3]);
+    assert!(conn.query_events(&search("msg OR")).await?.is_empty());
+    assert_eq!(indexes(conn.query_events(&search(" ")).await?).len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_series() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    for (index, payload) in [
+        (1, json!({"latency_s": 1})),
+        (2, json!({"latency_s": 2.5})),
+        (3, json!({"latency_s": "slow"})),
+        (4, json!({"latency_s": 4})),
+    ] {
+        conn.insert_event(
+            stream_id,
+            index,
+            raw(&payload.to_string()),
+            None,
+            None,
+            None,
+        )
+        .await?;
+    }
+    conn.execute(
+        "update events set insert_datetime = format('2024-01-01 00:0%d:10', stream_event_index / 4)",
+        [],
+    )?;
+    let series = |aggregate: &str| -> anyhow::Result<SeriesQuery> {
+        Ok(SeriesQuery {
+            selection: Default::default(),
+            aggregate: aggregate.parse()?,
+            bucket: std::time::Duration::from_secs(60),
+        })
+    };
+    let values = |points: Vec<SeriesPoint>| -> Vec<_> {
+        points
+            .iter()
+            .map(|point| (point.bucket_start.format("%H:%M").to_string(), point.value))
+            .collect()
+    };
+    assert_eq!(
+        values(conn.read_series(&series("count")?).await?),
+        [("00:00".to_owned(), 3.0), ("00:01".to_owned(), 1.0)]
+    );
+    // Values that aren't numbers are left out.
+    assert_eq!(
+        values(conn.read_series(&series("avg:latency_s")?).await?),
+        [("00:00".to_owned(), 1.75), ("00:01".to_owned(), 4.0)]
+    );
+    assert!(values(conn.read_series(&series("max:missing")?).await?).is_empty());
+    assert!("median:latency_s".parse::<SeriesAggregate>().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_metric_points() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let point = MetricPoint {
+        name: "queue.depth".to_owned(),
+        resource: json!({"service.name": "checkout"}),
+        attributes: json!({"queue": "orders"}),
+        datetime: parse_datetime("2024-07-03T05:00:00.25Z")?,
+        value: 2.5,
+    };
+    assert_eq!(conn.insert_metric_points(&[point]).await?, 1);
+    let row: (String, String, String, f64) = conn.query_row(
+        "select name, attributes ->> 'queue', datetime, value from metric_points",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+    assert_eq!(
+        row,
+        (
+            "queue.depth".to_owned(),
+            "orders".to_owned(),
+            "2024-07-03 05:00:00.250".to_owned(),
+            2.5
+        )
+    );
+    assert!(Memory::default().insert_metric_points(&[]).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sqlite_insert_events() -> anyhow::Result<()> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../../sql/sqlite.sql"))?;
+    let stream_id = conn.new_stream(json!({})).await?;
+    let events = [
+        NewEvent {
+            event_id: Some("a"),
+            ..NewEvent::new(1, raw(r#"{"n": 1}"#))
+        },
+        NewEvent {
+            event_id: Some("a"),
+            ..NewEvent::new(2, raw(r#"{"n": 2}"#))
+        },
+        NewEvent::new(3, raw(r#"{"n": 3}"#)),
+    ];
+    assert_eq!(
+        conn.insert_events(stream_id, &events).await?,
+        [Inserted::Stored, Inserted::Duplicate, Inserted::Stored]
+    );
+    // Inside a batch too, which the inserts nest in.
+    assert!(conn.begin_batch().await?);
+    let events = [NewEvent::new(4, raw("{}")), NewEvent::new(5, raw("[]"))];
+    assert_eq!(
+        conn.insert_events(stream_id, &events).await?,
+        [Inserted::Stored, Inserted::Stored]
+    );
+    conn.commit_batch().await?;
+    let indexes = conn
+        .prepare("select stream_event_index from events order by rowid")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<u64>>>()?;
+    assert_eq!(indexes, [1, 3, 4, 5]);
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use super::*;
+use crate::partitions::{uncount_events, UNIQUE_EVENT_IDS};
+use std::time::Duration;
+
+/// Keeping Postgres's events in a TimescaleDB hypertable, which splits them into chunks by insert
+/// time and can compress the older ones.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct TimescaleArgs {
+    /// Makes the events table a TimescaleDB hypertable, creating the extension if the server has
+    /// it, and drops whole chunks that are past --retain-for. Existing events are moved into
+    /// chunks the first time, which takes a while on a large table. Without the extension on the
+    /// server, the table is left as it is.
+    #[arg(long, conflicts_with = "partition_events")]
+    pub timescale: bool,
+    /// How much insert time each chunk covers. Changing it only applies to new chunks.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1day")]
+    pub timescale_chunk_interval: Duration,
+    /// Compresses chunks once their events are this old, segmented by stream.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub timescale_compress_after: Option<Duration>,
+}
+
+impl TimescaleArgs {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        if !self.timescale {
+            return json!(false);
+        }
+        json!({
+            "chunk_interval": humantime::format_duration(self.timescale_chunk_interval).to_string(),
+            "compress_after": self
+                .timescale_compress_after
+                .map(|after| humantime::format_duration(after).to_string()),
+        })
+    }
+
+    /// Makes the events table a hypertable if asked to and the extension is available, and sets
+    /// its chunk interval and compression policy. Returns whether the table is a hypertable, as it
+    /// stays once it has been.
+    pub(crate) async fn apply(&self, client: &mut Client) -> Result<bool> {
+        let installed: bool = client
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb')",
+                &[],
+            )
+            .await?
+            .get(0);
+        if !self.timescale {
+            return match installed {
+                true => is_hypertable(client).await,
+                false => Ok(false),
+            };
+        }
+        if !installed {
+            let available: bool = client
+                .query_one(
+                    "SELECT EXISTS \
+                    (SELECT 1 FROM pg_available_extensions WHERE name = 'timescaledb')",
+                    &[],
+                )
+                .await?
+                .get(0);
+            if !available {
+                warn!("timescaledb isn't available on the server, so events stay a plain table");
+                return Ok(false);
+            }
+            client
+                .batch_execute("CREATE EXTENSION IF NOT EXISTS timescaledb")
+                .await
+                .context("creating the timescaledb extension")?;
+        }
+        let chunk_interval = self.timescale_chunk_interval.as_secs_f64();
+        if is_hypertable(client).await? {
+            client
+                .execute(
+                    "SELECT set_chunk_time_interval('events', make_interval(secs => $1))",
+                    &[&chunk_interval],
+                )
+                .await
+                .context("setting chunk interval")?;
+        } else {
+            let tx = client.transaction().await?;
+            tx.batch_execute(include_str!("../../sql/postgres-hypertable-events.sql"))
+                .await?;
+            tx.batch_execute(UNIQUE_EVENT_IDS).await?;
+            tx.execute(
+                "SELECT create_hypertable('events', 'insert_datetime', \
+                chunk_time_interval => make_interval(secs => $1), migrate_data => true)",
+                &[&chunk_interval],
+            )
+            .await
+            .context("creating hypertable")?;
+            tx.commit().await?;
+            info!("made events a hypertable");
+        }
+        if let Some(compress_after) = self.timescale_compress_after {
+            let compression_enabled: bool = client
+                .query_one(
+                    "SELECT compression_enabled FROM timescaledb_information.hypertables \
+                    WHERE hypertable_schema = current_schema() AND hypertable_name = 'events'",
+                    &[],
+                )
+                .await?
+                .get(0);
+            // Compression settings can't be changed once chunks have been compressed with them.
+            if !compression_enabled {
+                client
+                    .batch_execute(
+                        "ALTER TABLE events SET (timescaledb.compress, \
+                        timescaledb.compress_segmentby = 'stream_id', \
+                        timescaledb.compress_orderby = 'insert_datetime, stream_event_index')",
+                    )
+                    .await
+                    .context("enabling compression")?;
+            }
+            client
+                .batch_execute("SELECT remove_compression_policy('events', if_exists => true)")
+                .await?;
+            client
+                .execute(
+                    "SELECT add_compression_policy('events', \
+                    compress_after => make_interval(secs => $1))",
+                    &[&compress_after.as_secs_f64()],
+                )
+                .await
+                .context("adding compression policy")?;
+        }
+        Ok(true)
+    }
+}
+
+async fn is_hypertable(client: &Client) -> Result<bool> {
+    Ok(client
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM timescaledb_information.hypertables \
+            WHERE hypertable_schema = current_schema() AND hypertable_name = 'events')",
+            &[],
+        )
+        .await?
+        .get(0))
+}
+
+/// Drops the chunks holding only events inserted before `before`. Returns how many chunks and
+/// events were dropped.
+pub(crate) async fn drop_chunks(
+    tx: &tokio_postgres::Transaction<'_>,
+    before: NaiveDateTime,
+) -> Result<(u64, u64)> {
+    let chunks: Vec<String> = tx
+        .query(
+            "SELECT show_chunks('events', older_than => $1::timestamp)::text",
+            &[&before],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let mut events = 0;
+    for chunk in &chunks {
+        events += uncount_events(tx, chunk).await?;
+    }
+    tx.execute(
+        "SELECT drop_chunks('events', older_than => $1::timestamp)",
+        &[&before],
+    )
+    .await
+    .context("dropping chunks")?;
+    if !chunks.is_empty() {
+        info!(chunks = chunks.len(), events, "dropped chunks");
+    }
+    Ok((chunks.len() as u64, events))
+}
@@ -0,0 +1,228 @@
+use super::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{mpsc, oneshot};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Events from these targets aren't stored, since storing them makes more of them.
+const IGNORED_TARGETS: [&str; 7] = [
+    "telemetry_storage",
+    "tokio_postgres",
+    "rusqlite",
+    "duckdb",
+    "reqwest",
+    "hyper",
+    "tokio",
+];
+
+enum Message {
+    Event(serde_json::Value),
+    Flush(oneshot::Sender<Result<()>>),
+    Close(oneshot::Sender<Result<()>>),
+}
+
+/// Stores `tracing` events in a stream of their own, as JSON objects with the time, level,
+/// target, fields and the names of the spans the event is in, outermost first. The same payloads
+/// as the client crate's layer, without going through a server. Events are queued for a writer
+/// task that owns the connection, and dropped rather than waited on when the queue is full.
+pub struct StorageLayer {
+    sender: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Flushes and closes what a [StorageLayer] writes to.
+pub struct StorageWriter {
+    sender: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl StorageLayer {
+    /// Starts the writer task, which needs a Tokio runtime. The stream is created with the
+    /// headers on the first event. Up to `queue_size` events wait to be written.
+    pub fn new(
+        conn: Box<dyn Connection + Send>,
+        headers: SerializedHeaders,
+        queue_size: usize,
+    ) -> (Self, StorageWriter) {
+        let (sender, receiver) = mpsc::channel(queue_size.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_events(conn, headers, receiver));
+        let writer = StorageWriter {
+            sender: sender.clone(),
+            dropped: Arc::clone(&dropped),
+        };
+        (Self { sender, dropped }, writer)
+    }
+}
+
+impl StorageWriter {
+    /// Waits for the events queued so far to be written and committed. Fails with the first
+    /// error writing events since the last flush.
+    pub async fn flush(&self) -> Result<()> {
+        self.request(Message::Flush).await
+    }
+
+    /// Writes the events queued, closes the stream and shuts the storage down. Later events are
+    /// dropped.
+    pub async fn close(self) -> Result<()> {
+        self.request(Message::Close).await
+    }
+
+    /// How many events were dropped because the queue was full or the writer had closed.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    async fn request(&self, message: fn(oneshot::Sender<Result<()>>) -> Message) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        self.sender
+            .send(message(done))
+            .await
+            .map_err(|_| anyhow!("storage writer closed"))?;
+        result.await.map_err(|_| anyhow!("storage writer closed"))?
+    }
+}
+
+/// Owns the connection, writing events in the order they were queued.
+async fn write_events(
+    mut conn: Box<dyn Connection + Send>,
+    headers: SerializedHeaders,
+    mut receiver: mpsc::Receiver<Message>,
+) {
+    let mut headers = Some(headers);
+    let mut stream = None;
+    let mut first_error = None;
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Event(payload) => {
+                let result = write_event(&mut *conn, &mut headers, &mut stream, &payload).await;
+                if let Err(err) = result {
+                    first_error.get_or_insert(err);
+                }
+            }
+            Message::Flush(done) => {
+                let result = match first_error.take() {
+                    Some(err) => Err(err),
+                    None => conn.commit().await,
+                };
+                let _ = done.send(result);
+            }
+            Message::Close(done) => {
+                let _ = done.send(close(&mut *conn, stream, first_error).await);
+                return;
+            }
+        }
+    }
+    // Every sender was dropped without closing, so finish up as well as we can.
+    let _ = close(&mut *conn, stream, first_error).await;
+}
+
+async fn write_event(
+    conn: &mut (dyn Connection + Send),
+    headers: &mut Option<SerializedHeaders>,
+    stream: &mut Option<(StreamId, StreamEventIndex)>,
+    payload: &serde_json::Value,
+) -> Result<()> {
+    let (stream_id, last_index) = match stream {
+        Some(stream) => stream,
+        None => {
+            let headers = headers.take().unwrap_or_default();
+            stream.insert((conn.new_stream(headers).await?, 0))
+        }
+    };
+    *last_index += 1;
+    // Events wait in the queue, so they're stored a little after they happened.
+    let client_datetime = payload["timestamp"]
+        .as_str()
+        .and_then(|timestamp| parse_datetime(timestamp).ok());
+    conn.insert_event(
+        *stream_id,
+        *last_index,
+        &serde_json::value::to_raw_value(payload)?,
+        None,
+        None,
+        client_datetime,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn close(
+    conn: &mut (dyn Connection + Send),
+    stream: Option<(StreamId, StreamEventIndex)>,
+    first_error: Option<anyhow::Error>,
+) -> Result<()> {
+    if let Some((stream_id, _)) = stream {
+        conn.close_stream(stream_id).await?;
+    }
+    conn.shutdown().await?;
+    match first_error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+impl<S> Layer<S> for StorageLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let metadata = event.metadata();
+        if IGNORED_TARGETS
+            .iter()
+            .any(|target| metadata.target().starts_with(target))
+        {
+            return;
+        }
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        let spans: Vec<&str> = ctx
+            .event_scope(event)
+            .map(|scope| scope.from_root().map(|span| span.name()).collect())
+            .unwrap_or_default();
+        let payload = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+            "spans": spans,
+        });
+        if self.sender.try_send(Message::Event(payload)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An event's fields as JSON values, with the types tracing records them as.
+#[derive(Default)]
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value).into());
+    }
+}